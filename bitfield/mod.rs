@@ -2,11 +2,35 @@
 #![deny(clippy::implicit_return)]
 #![allow(clippy::needless_return)]
 
+/// A mask with bits h down to l, inclusive, set in a value bits wide. Asking
+/// for bits the value doesn't have, or for h below l, is a bug in the caller:
+/// a compile error when evaluated as a constant & a panic otherwise.
+pub const fn mask(h: u32, l: u32, bits: u32) -> u64
+{
+	assert!(l <= h, "mask with its high bit below its low bit");
+	assert!(h < bits && bits <= 64, "mask wider than its type");
+
+	return (!0_u64 >> (63 - h)) & (!0_u64 << l);
+}
+
+/// How far to shift a value bits wide left, then arithmetically right, to
+/// sign extend it from top_bit. A top_bit the value doesn't have is a bug in
+/// the caller, same as for mask().
+pub const fn sign_extension_shift(top_bit: u32, bits: u32) -> u32
+{
+	assert!(top_bit < bits, "sign extending from beyond the type's MSB");
+
+	return bits - (top_bit + 1);
+}
+
 #[macro_export]
 macro_rules! gen_mask {
 	($h:expr, $l:expr, $typ:ty) => {
-		(((!0) - (1_u64.wrapping_shl($l)) + 1)
-			& (!0 & (!0_u64 >> (64 - 1 - ($h)) as u64))) as $typ
+		$crate::bitfield::mask(
+			($h) as u32,
+			($l) as u32,
+			8 * std::mem::size_of::<$typ>() as u32,
+		) as $typ
 	};
 }
 
@@ -24,7 +48,58 @@ macro_rules! field_get {
 /// type.
 macro_rules! sign_extend {
 	($input:expr, $top_bit:expr, $typ:ty) => {{
-		let shift = 8 * std::mem::size_of::<$typ>() - ($top_bit + 1);
+		let shift = $crate::bitfield::sign_extension_shift(
+			($top_bit) as u32,
+			8 * std::mem::size_of::<$typ>() as u32,
+		);
 		($input as $typ) << shift >> shift
 	}};
 }
+
+#[cfg(test)]
+mod test
+{
+	#[test]
+	fn masks_at_the_edges()
+	{
+		assert_eq!(gen_mask!(0, 0, u64), 0x1);
+		assert_eq!(gen_mask!(63, 63, u64), 0x8000_0000_0000_0000);
+		assert_eq!(gen_mask!(63, 0, u64), u64::MAX);
+		assert_eq!(gen_mask!(31, 0, u32), u32::MAX);
+		assert_eq!(gen_mask!(62, 1, u64), 0x7fff_ffff_ffff_fffe);
+		assert_eq!(gen_mask!(12, 11, u64), 0x1800);
+	}
+
+	#[test]
+	#[should_panic(expected = "high bit below its low bit")]
+	fn backwards_masks_panic()
+	{
+		let low = 4;
+		gen_mask!(3, low, u64);
+	}
+
+	#[test]
+	#[should_panic(expected = "wider than its type")]
+	fn masks_wider_than_their_type_panic()
+	{
+		let high = 32;
+		gen_mask!(high, 0, u32);
+	}
+
+	#[test]
+	fn sign_extension_at_the_msb()
+	{
+		assert_eq!(sign_extend!(0x8000_0000_u32, 31, i32), i32::MIN);
+		assert_eq!(sign_extend!(0x7fff_ffff_u32, 31, i32), i32::MAX);
+		assert_eq!(sign_extend!(0x800, 11, i32), -2048);
+		assert_eq!(sign_extend!(0x7ff, 11, i32), 2047);
+	}
+
+	#[test]
+	#[should_panic(expected = "beyond the type's MSB")]
+	fn sign_extension_past_the_msb_panics()
+	{
+		let top_bit = 32;
+		sign_extend!(0x8000_0000_u32, top_bit, i32);
+	}
+}
@@ -3,8 +3,10 @@
 #![allow(clippy::needless_return)]
 
 use crate::lebytes::LeBytes;
+use std::any::Any;
 use std::fmt;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum ErrorKind
 {
@@ -38,6 +40,8 @@ impl fmt::Display for Error
 	}
 }
 
+impl std::error::Error for Error {}
+
 pub trait Bus
 {
 	fn read<T>(&self, address: usize) -> Result<T, Error>
@@ -51,3 +55,446 @@ pub trait Bus
 		U: Into<usize>,
 		[(); <T as LeBytes>::SIZE]:;
 }
+
+/// An object-safe view of a Bus, so that devices of different types can be
+/// held together in a DeviceMap. Anything implementing Bus gets this for free,
+/// with the access size taken from the length of bytes.
+pub trait Device
+{
+	fn read_bytes(&self, address: usize, bytes: &mut [u8])
+		-> Result<(), Error>;
+	fn write_bytes(
+		&mut self, address: usize, bytes: &[u8],
+	) -> Result<(), Error>;
+	fn as_any(&self) -> &dyn Any;
+	fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<D: Bus + 'static> Device for D
+{
+	fn read_bytes(&self, address: usize, bytes: &mut [u8])
+		-> Result<(), Error>
+	{
+		let value: u128 = match bytes.len() {
+			1 => self.read::<u8>(address)? as u128,
+			2 => self.read::<u16>(address)? as u128,
+			4 => self.read::<u32>(address)? as u128,
+			8 => self.read::<u64>(address)? as u128,
+			16 => self.read::<u128>(address)?,
+			size => {
+				return Err(Error::new(
+					ErrorKind::Unimplemented,
+					&format!("read of {} bytes", size),
+				));
+			},
+		};
+
+		bytes.copy_from_slice(&value.to_le_bytes()[..bytes.len()]);
+
+		return Ok(());
+	}
+
+	fn write_bytes(&mut self, address: usize, bytes: &[u8])
+		-> Result<(), Error>
+	{
+		let size = bytes.len();
+		if !matches!(size, 1 | 2 | 4 | 8 | 16) {
+			return Err(Error::new(
+				ErrorKind::Unimplemented,
+				&format!("write of {} bytes", size),
+			));
+		}
+
+		let mut value = [0_u8; 16];
+		value[..size].copy_from_slice(bytes);
+		let value = u128::from_le_bytes(value);
+
+		match size {
+			1 => return self.write(address, value as u8),
+			2 => return self.write(address, value as u16),
+			4 => return self.write(address, value as u32),
+			8 => return self.write(address, value as u64),
+			_ => return self.write(address, value),
+		}
+	}
+
+	fn as_any(&self) -> &dyn Any
+	{
+		return self;
+	}
+
+	fn as_any_mut(&mut self) -> &mut dyn Any
+	{
+		return self;
+	}
+}
+
+struct Mapping
+{
+	name: &'static str,
+	start: usize,
+	end: usize,
+	device: Box<dyn Device>,
+}
+
+/// Routes accesses to whichever registered device covers the address, with
+/// the address made relative to the start of the device.
+#[derive(Default)]
+pub struct DeviceMap
+{
+	mappings: Vec<Mapping>,
+}
+
+impl DeviceMap
+{
+	/// Map a device at start, covering size bytes. Devices may not
+	/// overlap.
+	pub fn register(
+		&mut self, name: &'static str, start: usize, size: usize,
+		device: Box<dyn Device>,
+	) -> Result<(), Error>
+	{
+		let end = self.check_free(name, start, size)?;
+
+		self.mappings.push(Mapping {
+			name,
+			start,
+			end,
+			device,
+		});
+
+		return Ok(());
+	}
+
+	/// Check that a device called name could be mapped at start, covering
+	/// size bytes, without overlapping another or running off the top of
+	/// the address space. Returns where it would end.
+	pub fn check_free(
+		&self, name: &str, start: usize, size: usize,
+	) -> Result<usize, Error>
+	{
+		let Some(end) = start.checked_add(size) else {
+			return Err(Error::new(
+				ErrorKind::OutOfBounds,
+				&format!(
+					"{} at {:x} of size {:x} runs off the end of the \
+					 address space",
+					name, start, size
+				),
+			));
+		};
+
+		if let Some(other) = self
+			.mappings
+			.iter()
+			.find(|mapping| return start < mapping.end && mapping.start < end)
+		{
+			return Err(Error::new(
+				ErrorKind::OutOfBounds,
+				&format!(
+					"{} at {:x}..{:x} overlaps {}",
+					name, start, end, other.name
+				),
+			));
+		}
+
+		return Ok(end);
+	}
+
+	/// The names of the registered devices, in registration order.
+	pub fn names(&self) -> Vec<&'static str>
+	{
+		return self
+			.mappings
+			.iter()
+			.map(|mapping| return mapping.name)
+			.collect();
+	}
+
+	/// The name, start & size of each registered device, in registration
+	/// order.
+	pub fn regions(&self) -> Vec<(&'static str, usize, usize)>
+	{
+		return self
+			.mappings
+			.iter()
+			.map(|mapping| {
+				return (
+					mapping.name,
+					mapping.start,
+					mapping.end - mapping.start,
+				);
+			})
+			.collect();
+	}
+
+	/// The first registered device of type T.
+	pub fn get<T: 'static>(&self) -> Option<&T>
+	{
+		return self
+			.mappings
+			.iter()
+			.find_map(|mapping| return mapping.device.as_any().downcast_ref());
+	}
+
+	pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T>
+	{
+		return self.mappings.iter_mut().find_map(|mapping| {
+			return mapping.device.as_any_mut().downcast_mut();
+		});
+	}
+
+	/// Run f on the first registered device of type T, along with the rest
+	/// of the map, which lets a device make DMA accesses to the others.
+	/// The device is missing from the map until f returns.
+	pub fn with_device<T: 'static, R>(
+		&mut self, f: impl FnOnce(&mut T, &mut DeviceMap) -> R,
+	) -> Option<R>
+	{
+		let index = self
+			.mappings
+			.iter()
+			.position(|mapping| return mapping.device.as_any().is::<T>())?;
+		let mut mapping = self.mappings.remove(index);
+
+		let device = mapping.device.as_any_mut().downcast_mut().unwrap();
+		let result = f(device, self);
+		self.mappings.insert(index, mapping);
+
+		return Some(result);
+	}
+
+	/// The device covering address, if there is one.
+	pub fn device_at(&self, address: usize) -> Option<&dyn Device>
+	{
+		let index = self.locate(address, 1).ok()?;
+		return Some(self.mappings[index].device.as_ref());
+	}
+
+	/// Find the mapping wholly containing an access of size bytes.
+	fn locate(&self, address: usize, size: usize) -> Result<usize, Error>
+	{
+		// An access running off the top of the address space reaches no
+		// device, rather than wrapping round to one at the bottom
+		let end = address.checked_add(size);
+		let index = self.mappings.iter().position(|mapping| {
+			let within = end.map_or(false, |end| return end <= mapping.end);
+			return mapping.start <= address && within;
+		});
+
+		return index.ok_or_else(|| {
+			return Error::new(
+				ErrorKind::OutOfBounds,
+				&format!("no device at addr: {:x}", address),
+			);
+		});
+	}
+}
+
+impl Bus for DeviceMap
+{
+	fn read<T>(&self, address: usize) -> Result<T, Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let mapping =
+			&self.mappings[self.locate(address, <T as LeBytes>::SIZE)?];
+		let mut bytes = [0_u8; <T as LeBytes>::SIZE];
+
+		mapping.device.read_bytes(address - mapping.start, &mut bytes)?;
+
+		return Ok(T::from_le_bytes(bytes));
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address = address.into();
+		let index = self.locate(address, <T as LeBytes>::SIZE)?;
+		let mapping = &mut self.mappings[index];
+
+		return mapping
+			.device
+			.write_bytes(address - mapping.start, &value.to_le_bytes());
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{Bus, DeviceMap, Error, ErrorKind};
+	use crate::clint::Clint;
+	use crate::lebytes::LeBytes;
+
+	/// A few bytes of storage, tagged so tests can tell instances apart.
+	struct Scratch
+	{
+		tag: u8,
+		bytes: [u8; 16],
+	}
+
+	impl Bus for Scratch
+	{
+		fn read<T>(&self, address: usize) -> Result<T, Error>
+		where
+			T: LeBytes,
+			[(); <T as LeBytes>::SIZE]:,
+		{
+			let size = <T as LeBytes>::SIZE;
+			return Ok(T::from_le_bytes(
+				self.bytes[address..address + size].try_into().unwrap(),
+			));
+		}
+
+		fn write<T, U>(&mut self, address: U, value: T) -> Result<(), Error>
+		where
+			T: LeBytes,
+			U: Into<usize>,
+			[(); <T as LeBytes>::SIZE]:,
+		{
+			let address = address.into();
+			let size = <T as LeBytes>::SIZE;
+			self.bytes[address..address + size]
+				.copy_from_slice(&value.to_le_bytes());
+			return Ok(());
+		}
+	}
+
+	/// Only answers reads, with its own id.
+	struct Id
+	{
+		id: u32,
+	}
+
+	impl Bus for Id
+	{
+		fn read<T>(&self, _address: usize) -> Result<T, Error>
+		where
+			T: LeBytes,
+			[(); <T as LeBytes>::SIZE]:,
+		{
+			let mut bytes = [0_u8; <T as LeBytes>::SIZE];
+			bytes[0] = self.id as u8;
+			return Ok(T::from_le_bytes(bytes));
+		}
+
+		fn write<T, U>(&mut self, _address: U, _value: T) -> Result<(), Error>
+		where
+			T: LeBytes,
+			U: Into<usize>,
+			[(); <T as LeBytes>::SIZE]:,
+		{
+			return Err(Error::new(ErrorKind::Unimplemented, "read only"));
+		}
+	}
+
+	fn device_map() -> DeviceMap
+	{
+		let mut map = DeviceMap::default();
+		let scratch = Scratch {
+			tag: 7,
+			bytes: [0; 16],
+		};
+
+		map.register("scratch", 0x1000, 0x10, Box::new(scratch)).unwrap();
+		map.register(
+			"id",
+			0x2000,
+			0x10,
+			Box::new(Id {
+				id: 0x42,
+			}),
+		)
+		.unwrap();
+
+		return map;
+	}
+
+	#[test]
+	fn accesses_route_to_the_covering_device()
+	{
+		let mut map = device_map();
+
+		map.write(0x1004_usize, 0x1234_5678_u32).unwrap();
+		assert_eq!(map.read::<u32>(0x1004).unwrap(), 0x1234_5678);
+		assert_eq!(map.read::<u32>(0x2004).unwrap(), 0x42);
+		assert!(map.write(0x2004_usize, 0_u8).is_err());
+
+		// Addresses are made relative to the start of the device
+		let scratch = map.get::<Scratch>().unwrap();
+		assert_eq!(scratch.tag, 7);
+		assert_eq!(scratch.bytes[4..8], [0x78, 0x56, 0x34, 0x12]);
+		assert_eq!(map.get::<Id>().unwrap().id, 0x42);
+	}
+
+	#[test]
+	fn unmapped_accesses_are_out_of_bounds()
+	{
+		let map = device_map();
+
+		let error = map.read::<u8>(0x3000).unwrap_err();
+		assert!(error.to_string().contains("OutOfBounds"));
+
+		// As are accesses running off the end of a device
+		assert!(map.read::<u64>(0x100c).is_err());
+		// Or off the top of the address space
+		assert!(map.read::<u64>(usize::MAX - 3).is_err());
+	}
+
+	/// Peek at a bus, which only needs it shared.
+	fn peek<B: Bus>(bus: &B, address: usize) -> u32
+	{
+		return bus.read::<u32>(address).unwrap();
+	}
+
+	/// Store through any Bus & read the value back.
+	fn round_trip<B: Bus>(bus: &mut B, address: usize) -> u32
+	{
+		bus.write(address, 0xcafe_f00d_u32).unwrap();
+		return peek(bus, address);
+	}
+
+	#[test]
+	fn every_bus_shares_one_signature()
+	{
+		let mut scratch = Scratch {
+			tag: 0,
+			bytes: [0; 16],
+		};
+		assert_eq!(round_trip(&mut scratch, 4), 0xcafe_f00d);
+		assert_eq!(round_trip(&mut device_map(), 0x1008), 0xcafe_f00d);
+
+		let mut clint = Clint::default();
+		assert_eq!(round_trip(&mut clint, 0x4000), 0xcafe_f00d);
+		assert_eq!(peek(&clint, 0x4004), u32::MAX);
+	}
+
+	#[test]
+	fn overlapping_devices_are_refused()
+	{
+		let mut map = device_map();
+
+		let id = Box::new(Id {
+			id: 0,
+		});
+		assert!(map.register("clash", 0x1008, 0x10, id).is_err());
+		assert_eq!(map.names(), vec!["scratch", "id"]);
+	}
+
+	#[test]
+	fn devices_off_the_top_are_refused()
+	{
+		let mut map = device_map();
+
+		let id = Box::new(Id {
+			id: 0,
+		});
+		assert!(map.check_free("top", usize::MAX - 0xf, 0x8).is_ok());
+		assert!(map.register("wrap", usize::MAX - 0xf, 0x20, id).is_err());
+		assert_eq!(map.names(), vec!["scratch", "id"]);
+	}
+}
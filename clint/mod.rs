@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::{self, Bus};
+use crate::lebytes::LeBytes;
+use crate::snapshot::{Decoder, Encoder};
+use std::error::Error;
+
+pub const CLINT_BASE: usize = 0x0200_0000;
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+// The layout used by the SiFive CLINT, which everyone else copies. The
+// registers are offsets from CLINT_BASE, msip is one 32-bit register per hart
+// and mtimecmp is one 64-bit register per hart.
+const MSIP_OFFSET: usize = 0x0;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xbff8;
+
+/// Core-local interruptor, providing the machine timer.
+#[derive(Debug)]
+pub struct Clint
+{
+	pub mtime: u64,
+	pub mtimecmp: Vec<u64>,
+	pub msip: Vec<u64>,
+}
+
+impl Default for Clint
+{
+	fn default() -> Clint
+	{
+		return Clint::new(1);
+	}
+}
+
+impl Clint
+{
+	/// A CLINT with an msip & mtimecmp for each of harts harts.
+	pub fn new(harts: usize) -> Clint
+	{
+		// mtimecmp has no defined reset value, but starting it at the
+		// maximum stops a timer interrupt being pending before software
+		// has had a chance to program it.
+		return Clint {
+			mtime: 0,
+			mtimecmp: vec![u64::MAX; harts],
+			msip: vec![0; harts],
+		};
+	}
+
+	/// Advance mtime, which happens once per retired instruction.
+	pub fn tick(&mut self)
+	{
+		self.mtime = self.mtime.wrapping_add(1);
+	}
+
+	/// Whether the machine timer interrupt is pending for a hart.
+	pub fn timer_pending(&self, hart_id: usize) -> bool
+	{
+		return self.mtime >= self.mtimecmp[hart_id];
+	}
+
+	/// Whether the machine software interrupt is pending for a hart.
+	pub fn software_pending(&self, hart_id: usize) -> bool
+	{
+		return self.msip[hart_id] & 1 != 0;
+	}
+
+	pub fn save(&self, out: &mut Encoder)
+	{
+		out.u64(self.mtime);
+		out.u64(self.mtimecmp.len() as u64);
+		for hart_id in 0..self.mtimecmp.len() {
+			out.u64(self.mtimecmp[hart_id]);
+			out.u64(self.msip[hart_id]);
+		}
+	}
+
+	pub fn restore(&mut self, input: &mut Decoder)
+		-> Result<(), Box<dyn Error>>
+	{
+		self.mtime = input.u64()?;
+		input.expect("harts", self.mtimecmp.len())?;
+		for hart_id in 0..self.mtimecmp.len() {
+			self.mtimecmp[hart_id] = input.u64()?;
+			self.msip[hart_id] = input.u64()?;
+		}
+
+		return Ok(());
+	}
+
+	/// Find the register containing address, along with the offset of
+	/// address into it.
+	fn locate(&self, address: usize) -> Option<(Register, usize)>
+	{
+		if address < MTIMECMP_OFFSET {
+			let hart_id = (address - MSIP_OFFSET) / 4;
+			if hart_id < self.msip.len() {
+				return Some((Register::Msip(hart_id), address % 4));
+			}
+			return None;
+		}
+
+		let offset = address % 8;
+		let aligned = address - offset;
+
+		if aligned == MTIME_OFFSET {
+			return Some((Register::Mtime, offset));
+		}
+
+		if aligned >= MTIMECMP_OFFSET {
+			let hart_id = (aligned - MTIMECMP_OFFSET) / 8;
+			if hart_id < self.mtimecmp.len() {
+				return Some((Register::Mtimecmp(hart_id), offset));
+			}
+		}
+
+		return None;
+	}
+
+	fn register_mut(&mut self, register: Register) -> &mut u64
+	{
+		return match register {
+			Register::Mtime => &mut self.mtime,
+			Register::Mtimecmp(hart_id) => &mut self.mtimecmp[hart_id],
+			Register::Msip(hart_id) => &mut self.msip[hart_id],
+		};
+	}
+}
+
+enum Register
+{
+	Mtime,
+	Mtimecmp(usize),
+	Msip(usize),
+}
+
+impl Register
+{
+	fn size(&self) -> usize
+	{
+		return match self {
+			Register::Msip(_) => 4,
+			_ => 8,
+		};
+	}
+}
+
+fn check_access(
+	location: Option<(Register, usize)>, address: usize, size: usize,
+) -> Result<(Register, usize), bus::Error>
+{
+	let Some((register, offset)) = location else {
+		return Err(bus::Error::new(
+			bus::ErrorKind::OutOfBounds,
+			&format!("clint offset: {:x}", address),
+		));
+	};
+
+	if offset + size > register.size() {
+		return Err(bus::Error::new(
+			bus::ErrorKind::OutOfBounds,
+			&format!("clint access crosses register: {:x}", address),
+		));
+	}
+
+	return Ok((register, offset));
+}
+
+impl Bus for Clint
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let size = <T as LeBytes>::SIZE;
+		let (register, offset) =
+			check_access(self.locate(address), address, size)?;
+		let value = match register {
+			Register::Mtime => self.mtime,
+			Register::Mtimecmp(hart_id) => self.mtimecmp[hart_id],
+			Register::Msip(hart_id) => self.msip[hart_id],
+		};
+
+		let bytes = value.to_le_bytes();
+		return Ok(T::from_le_bytes(
+			bytes[offset..offset + size].try_into().unwrap(),
+		));
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address = address.into();
+		let size = <T as LeBytes>::SIZE;
+		let (register, offset) =
+			check_access(self.locate(address), address, size)?;
+		// Only the bottom bit of msip is implemented
+		let writable = match register {
+			Register::Msip(_) => 1,
+			_ => u64::MAX,
+		};
+		let register = self.register_mut(register);
+
+		let mut bytes = register.to_le_bytes();
+		bytes[offset..offset + size].copy_from_slice(&value.to_le_bytes());
+		*register = u64::from_le_bytes(bytes) & writable;
+
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{Clint, MSIP_OFFSET, MTIMECMP_OFFSET, MTIME_OFFSET};
+	use crate::bus::Bus;
+
+	#[test]
+	fn mtimecmp_halves_are_writable()
+	{
+		let mut clint = Clint::default();
+
+		clint.write(MTIMECMP_OFFSET, 0x1234_5678_u32).unwrap();
+		clint.write(MTIMECMP_OFFSET + 4, 0x0_u32).unwrap();
+
+		assert_eq!(clint.mtimecmp[0], 0x1234_5678);
+		assert_eq!(clint.read::<u64>(MTIMECMP_OFFSET).unwrap(), 0x1234_5678);
+	}
+
+	#[test]
+	fn timer_pends_once_mtime_reaches_mtimecmp()
+	{
+		let mut clint = Clint::default();
+
+		clint.write(MTIMECMP_OFFSET, 2_u64).unwrap();
+		clint.tick();
+		assert!(!clint.timer_pending(0));
+
+		clint.tick();
+		assert!(clint.timer_pending(0));
+		assert_eq!(clint.read::<u64>(MTIME_OFFSET).unwrap(), 2);
+	}
+
+	#[test]
+	fn msip_only_implements_bit_zero()
+	{
+		let mut clint = Clint::default();
+
+		clint.write(MSIP_OFFSET, 0xffff_ffff_u32).unwrap();
+		assert!(clint.software_pending(0));
+		assert_eq!(clint.read::<u32>(MSIP_OFFSET).unwrap(), 1);
+
+		// msip is only 32 bits wide
+		assert!(clint.write(MSIP_OFFSET, 0_u64).is_err());
+
+		clint.write(MSIP_OFFSET, 0_u32).unwrap();
+		assert!(!clint.software_pending(0));
+	}
+}
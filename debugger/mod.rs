@@ -0,0 +1,588 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::{self, Bus};
+use crate::hart::register_index;
+use crate::hart::{CAUSE_BREAKPOINT, CSR_MCAUSE, REGISTER_NAMES};
+use crate::mmu::Access;
+use crate::platform::{HaltReason, Platform};
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, Write};
+
+#[derive(Debug, PartialEq)]
+pub enum Command
+{
+	/// Execute this many instructions
+	Step(usize),
+	/// Run until something halts the platform
+	Continue,
+	/// Print a single register by ABI name
+	Reg(usize),
+	/// Print the pc, all registers & the trap CSRs
+	Regs,
+	/// Print this many 32-bit words of memory starting at an address
+	Mem(usize, usize),
+	/// Write a 32-bit word to memory
+	Write(usize, u32),
+	/// Print the instruction words either side of the pc
+	Dis,
+	/// Stop before executing the instruction at an address
+	Break(u64),
+	Quit,
+}
+
+/// Parse a number in either hex, with a leading 0x, or decimal.
+pub fn parse_number(input: &str) -> Result<u64, String>
+{
+	let parsed = match input.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => input.parse::<u64>(),
+	};
+
+	return parsed.map_err(|_| return format!("invalid number: {}", input));
+}
+
+/// A value to write into guest memory before it starts running.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Patch
+{
+	Word(usize, u32),
+	Byte(usize, u8),
+}
+
+/// Parse a patch of the form address=value, writing a 32-bit word, or
+/// address=value:b, writing a single byte.
+pub fn parse_patch(input: &str) -> Result<Patch, String>
+{
+	let Some((address, value)) = input.split_once('=') else {
+		return Err(format!("patch needs an address=value: {}", input));
+	};
+	let address = parse_number(address)? as usize;
+
+	if let Some(value) = value.strip_suffix(":b") {
+		let value = parse_number(value)?;
+		let byte = u8::try_from(value)
+			.map_err(|_| return format!("patch byte too big: {:#x}", value))?;
+		return Ok(Patch::Byte(address, byte));
+	}
+
+	let value = parse_number(value)?;
+	let word = u32::try_from(value)
+		.map_err(|_| return format!("patch word too big: {:#x}", value))?;
+
+	return Ok(Patch::Word(address, word));
+}
+
+pub fn apply_patch(
+	platform: &mut Platform, patch: &Patch,
+) -> Result<(), bus::Error>
+{
+	let result = match *patch {
+		Patch::Word(address, word) => platform.write(address, word),
+		Patch::Byte(address, byte) => platform.write(address, byte),
+	};
+	platform.invalidate_decode_caches();
+
+	return result;
+}
+
+/// A value to put in a register before the guest starts running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterValue
+{
+	pub index: usize,
+	pub value: u64,
+}
+
+/// Parse a register value of the form name=value, name being an ABI name.
+pub fn parse_register_value(input: &str) -> Result<RegisterValue, String>
+{
+	let Some((name, value)) = input.split_once('=') else {
+		return Err(format!("register needs a name=value: {}", input));
+	};
+	let Some(index) = register_index(name) else {
+		return Err(format!("unknown register: {}", name));
+	};
+
+	return Ok(RegisterValue {
+		index,
+		value: parse_number(value)?,
+	});
+}
+
+pub fn apply_register_value(platform: &mut Platform, register: &RegisterValue)
+{
+	platform.hart.write_register(register.index, register.value);
+}
+
+/// A physical range to write out to a file once the guest stops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryDump
+{
+	pub address: usize,
+	pub length: usize,
+	pub path: String,
+}
+
+/// Parse a memory dump of the form start:length:path.
+pub fn parse_memory_dump(input: &str) -> Result<MemoryDump, String>
+{
+	let mut parts = input.splitn(3, ':');
+	let (Some(address), Some(length), Some(path)) =
+		(parts.next(), parts.next(), parts.next())
+	else {
+		return Err(format!("dump needs a start:length:path: {}", input));
+	};
+
+	return Ok(MemoryDump {
+		address: parse_number(address)? as usize,
+		length: parse_number(length)? as usize,
+		path: String::from(path),
+	});
+}
+
+/// Copy a range out to its file, a byte at a time through the bus so that
+/// device registers can be dumped too. Nothing is written unless all of it
+/// can be read.
+pub fn dump_memory(
+	platform: &Platform, dump: &MemoryDump,
+) -> Result<(), Box<dyn Error>>
+{
+	let Some(end) = dump.address.checked_add(dump.length) else {
+		return Err(format!(
+			"can't dump {:#x}:{:#x} to {}: past the end of the address space",
+			dump.address, dump.length, dump.path
+		)
+		.into());
+	};
+	let bytes = (dump.address..end)
+		.map(|address| return platform.read::<u8>(address))
+		.collect::<Result<Vec<u8>, bus::Error>>()
+		.map_err(|error| {
+			return format!(
+				"can't dump {:#x}:{:#x} to {}: {}",
+				dump.address, dump.length, dump.path, error
+			);
+		})?;
+
+	fs::write(&dump.path, bytes)?;
+	return Ok(());
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String>
+{
+	let mut words = line.split_whitespace();
+	let Some(command) = words.next() else {
+		return Err(String::from("no command"));
+	};
+	let args: Vec<&str> = words.collect();
+
+	match (command, args.as_slice()) {
+		("step" | "s", []) => return Ok(Command::Step(1)),
+		("step" | "s", [count]) => {
+			return Ok(Command::Step(parse_number(count)? as usize));
+		},
+		("continue" | "c", []) => return Ok(Command::Continue),
+		("reg" | "r", []) => return Ok(Command::Regs),
+		("reg" | "r", [name]) => {
+			let Some(index) = register_index(name) else {
+				return Err(format!("unknown register: {}", name));
+			};
+			return Ok(Command::Reg(index));
+		},
+		("mem" | "m", [address]) => {
+			return Ok(Command::Mem(parse_number(address)? as usize, 4));
+		},
+		("mem" | "m", [address, count]) => {
+			return Ok(Command::Mem(
+				parse_number(address)? as usize,
+				parse_number(count)? as usize,
+			));
+		},
+		("write" | "w", [address, value]) => {
+			return Ok(Command::Write(
+				parse_number(address)? as usize,
+				parse_number(value)? as u32,
+			));
+		},
+		("dis" | "d", []) => return Ok(Command::Dis),
+		("break" | "b", [address]) => {
+			return Ok(Command::Break(parse_number(address)?));
+		},
+		("quit" | "q", []) => return Ok(Command::Quit),
+		_ => return Err(format!("unknown command: {}", line.trim())),
+	}
+}
+
+/// Step the platform up to count times, stopping early if it halts.
+pub fn run(
+	platform: &mut Platform, count: usize,
+) -> Result<HaltReason, Box<dyn Error>>
+{
+	for _ in 0..count {
+		if let Some(reason) = platform.step()? {
+			return Ok(reason);
+		}
+	}
+
+	return Ok(HaltReason::InstructionLimit);
+}
+
+/// Move pc past an ebreak that break_on_cause stopped on, so that carrying
+/// on doesn't stop on it again. The guest never sees the exception, so
+/// mepc, mcause & mtval go back to what it left in them. This lets an
+/// ebreak in guest code drop into the debugger.
+pub fn step_over_ebreak(platform: &mut Platform, reason: &HaltReason)
+{
+	let hart = &platform.hart;
+	if *reason != HaltReason::TrapBreak
+		|| hart.read_csr(CSR_MCAUSE) != CAUSE_BREAKPOINT
+	{
+		return;
+	}
+
+	// It was fetched moments ago, so neither of these should fail
+	let Ok(physical) = platform.translate(hart.pc, Access::Fetch) else {
+		return;
+	};
+	let Ok(halfword) = platform.read::<u16>(physical as usize) else {
+		return;
+	};
+
+	// c.ebreak is only two bytes long
+	let length = if halfword & 0b11 == 0b11 { 4 } else { 2 };
+	let hart = &mut platform.hart;
+	hart.pc = hart.zero_extend(hart.pc.wrapping_add(length));
+	platform.dismiss_trap_break();
+}
+
+fn execute<W: Write>(
+	platform: &mut Platform, command: Command, output: &mut W,
+) -> Result<(), Box<dyn Error>>
+{
+	let hart = &platform.hart;
+
+	match command {
+		Command::Step(count) => {
+			let reason = run(platform, count)?;
+			if reason != HaltReason::InstructionLimit {
+				writeln!(output, "halted: {:?}", reason)?;
+			}
+			step_over_ebreak(platform, &reason);
+			writeln!(output, "pc: {:#x}", platform.hart.pc)?;
+		},
+
+		Command::Continue => {
+			let reason = platform.emulate()?;
+			writeln!(output, "halted: {:?}", reason)?;
+			step_over_ebreak(platform, &reason);
+			writeln!(output, "pc: {:#x}", platform.hart.pc)?;
+		},
+
+		Command::Reg(index) => {
+			let value = hart.read_register(index);
+			writeln!(output, "{}: {:#x}", REGISTER_NAMES[index], value)?;
+		},
+
+		Command::Regs => write!(output, "{}", platform.dump_state())?,
+
+		Command::Mem(address, count) => {
+			for n in 0..count {
+				let address = address + 4 * n;
+				match platform.read::<u32>(address) {
+					Ok(value) => {
+						writeln!(output, "{:#x}: {:#010x}", address, value)?;
+					},
+					Err(error) => writeln!(output, "{}", error)?,
+				}
+			}
+		},
+
+		Command::Write(address, value) => {
+			if let Err(error) = platform.write(address, value) {
+				writeln!(output, "{}", error)?;
+			}
+			platform.invalidate_decode_caches();
+		},
+
+		Command::Dis => {
+			let pc = hart.pc as usize;
+			for address in (pc.saturating_sub(8)..=pc + 8).step_by(4) {
+				let marker = if address == pc { "=>" } else { "  " };
+				match platform.read::<u32>(address) {
+					Ok(value) => {
+						writeln!(
+							output,
+							"{} {:#x}: {:#010x}",
+							marker, address, value
+						)?
+					},
+					Err(error) => writeln!(output, "{} {}", marker, error)?,
+				}
+			}
+		},
+
+		Command::Break(address) => platform.add_breakpoint(address),
+
+		Command::Quit => (),
+	}
+
+	return Ok(());
+}
+
+/// Read commands from input until it runs dry or a quit command is seen.
+pub fn repl<R: BufRead, W: Write>(
+	platform: &mut Platform, input: R, mut output: W,
+) -> Result<(), Box<dyn Error>>
+{
+	write!(output, "(thing) ")?;
+	output.flush()?;
+
+	for line in input.lines() {
+		let line = line?;
+
+		if !line.trim().is_empty() {
+			match parse_command(&line) {
+				Ok(Command::Quit) => return Ok(()),
+				Ok(command) => execute(platform, command, &mut output)?,
+				Err(error) => writeln!(output, "{}", error)?,
+			}
+		}
+
+		write!(output, "(thing) ")?;
+		output.flush()?;
+	}
+
+	return Ok(());
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		apply_patch, apply_register_value, dump_memory, parse_command,
+		parse_memory_dump, parse_patch, parse_register_value, repl, run,
+		Command, MemoryDump, Patch, RegisterValue,
+	};
+	use crate::hart::{
+		RegisterNames, CAUSE_BREAKPOINT, CAUSE_ILLEGAL_INSN, CSR_MCAUSE,
+		CSR_MEPC, CSR_MINSTRET, CSR_MTVEC,
+	};
+	use crate::platform::test::load_program;
+	use crate::platform::{HaltReason, Platform};
+
+	#[test]
+	fn parses_commands()
+	{
+		assert_eq!(parse_command("step"), Ok(Command::Step(1)));
+		assert_eq!(parse_command("s 0x10"), Ok(Command::Step(16)));
+		assert_eq!(parse_command("reg a0"), Ok(Command::Reg(10)));
+		assert_eq!(
+			parse_command("m 0x80000000 2"),
+			Ok(Command::Mem(0x8000_0000, 2))
+		);
+		assert_eq!(
+			parse_command("b 0x80000010"),
+			Ok(Command::Break(0x8000_0010))
+		);
+		assert!(parse_command("reg q7").is_err());
+		assert!(parse_command("frobnicate").is_err());
+	}
+
+	#[test]
+	fn step_then_print_register()
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 2] = [
+			0x0050_0513, // li a0, 5
+			0x0070_0513, // li a0, 7
+		];
+		load_program(&mut platform, &program);
+
+		let input = "reg a0\nstep\nreg a0\nstep\nreg a0\n";
+		let mut output: Vec<u8> = Vec::new();
+		repl(&mut platform, input.as_bytes(), &mut output).unwrap();
+
+		let output = String::from_utf8(output).unwrap();
+		let values: Vec<&str> =
+			output.lines().filter(|line| return line.contains("a0:")).collect();
+		assert_eq!(
+			values,
+			vec!["(thing) a0: 0x0", "(thing) a0: 0x5", "(thing) a0: 0x7"]
+		);
+		assert_eq!(platform.hart.pc, 0x8000_0008);
+	}
+
+	#[test]
+	fn ebreak_drops_into_the_script()
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 5] = [
+			0x0000_0013, // nop
+			0x0010_0073, // ebreak
+			0x0050_0513, // li a0, 5
+			0x0070_0513, // li a0, 7
+			0x0000_006f, // j .
+		];
+		load_program(&mut platform, &program);
+		platform.break_on_cause = Some(CAUSE_BREAKPOINT);
+		platform.hart.write_csr(CSR_MEPC, 0x8000_0100);
+		platform.hart.write_csr(CSR_MCAUSE, CAUSE_ILLEGAL_INSN);
+
+		// Run to the ebreak, which is stepped over, step once, then run
+		// to a breakpoint short of the loop
+		let input = "c\ns\nb 0x80000010\nc\nr\n";
+		let mut output: Vec<u8> = Vec::new();
+		repl(&mut platform, input.as_bytes(), &mut output).unwrap();
+
+		let output = String::from_utf8(output).unwrap();
+		let pcs: Vec<&str> = output
+			.lines()
+			.map(|line| return line.trim_start_matches("(thing) "))
+			.filter(|line| return line.starts_with("pc: "))
+			.collect();
+		assert_eq!(
+			pcs,
+			vec!["pc: 0x80000008", "pc: 0x8000000c", "pc: 0x80000010"]
+		);
+		assert!(output.contains("hart 0 pc: 0x0000000080000010"));
+
+		let hart = &platform.hart;
+		assert_eq!(hart.read_csr(CSR_MINSTRET), 3);
+		assert_eq!(hart.read_register(10_usize), 7);
+		// The guest never took the trap, so still has its own
+		assert_eq!(hart.read_csr(CSR_MEPC), 0x8000_0100);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_ILLEGAL_INSN);
+	}
+
+	#[test]
+	fn limit_at_a_trap_leaves_it_fully_taken()
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 2] = [
+			0x0000_0013, // nop
+			0xffff_ffff, // not a valid instruction
+		];
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+		// Stopping just short of the trap leaves it untouched
+		assert_eq!(
+			run(&mut platform, 1).unwrap(),
+			HaltReason::InstructionLimit
+		);
+		assert_eq!(platform.hart.pc, 0x8000_0004);
+		assert_eq!(platform.hart.read_csr(CSR_MEPC), 0);
+
+		// Stopping on it leaves it taken in full
+		assert_eq!(
+			run(&mut platform, 1).unwrap(),
+			HaltReason::InstructionLimit
+		);
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MEPC), 0x8000_0004);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_ILLEGAL_INSN);
+	}
+
+	#[test]
+	fn parses_patches()
+	{
+		assert_eq!(
+			parse_patch("0x80000000=0x13"),
+			Ok(Patch::Word(0x8000_0000, 0x13))
+		);
+		assert_eq!(
+			parse_patch("0x80000003=0xff:b"),
+			Ok(Patch::Byte(0x8000_0003, 0xff))
+		);
+		assert!(parse_patch("0x80000000").is_err());
+		assert!(parse_patch("0x80000000=0x100:b").is_err());
+		assert!(parse_patch("0x80000000=0x100000000").is_err());
+	}
+
+	#[test]
+	fn register_values_are_applied()
+	{
+		let mut platform = Platform::default();
+		let register = parse_register_value("a0=0x10").unwrap();
+
+		assert_eq!(
+			register,
+			RegisterValue {
+				index: RegisterNames::a0 as usize,
+				value: 0x10,
+			}
+		);
+		apply_register_value(&mut platform, &register);
+		assert_eq!(
+			platform.hart.read_register(RegisterNames::a0 as usize),
+			0x10
+		);
+
+		assert!(parse_register_value("a0").is_err());
+		assert!(parse_register_value("x99=1").is_err());
+		assert!(parse_register_value("a0=zz").is_err());
+	}
+
+	#[test]
+	fn dumped_memory_holds_what_the_guest_stored()
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 3] = [
+			0x0000_1297, // auipc t0, 1
+			0x1230_0313, // li t1, 0x123
+			0x0062_a023, // sw t1, 0(t0)
+		];
+		load_program(&mut platform, &program);
+		platform.max_insns = Some(3);
+		assert!(platform.emulate().is_err());
+
+		let path = std::env::temp_dir()
+			.join(format!("thing-dump-{}", std::process::id()));
+		let path = path.to_str().unwrap();
+		let dump =
+			parse_memory_dump(&format!("0x80001000:6:{}", path)).unwrap();
+		assert_eq!(
+			dump,
+			MemoryDump {
+				address: 0x8000_1000,
+				length: 6,
+				path: String::from(path),
+			}
+		);
+		dump_memory(&platform, &dump).unwrap();
+		let dumped = std::fs::read(path).unwrap();
+		std::fs::remove_file(path).unwrap();
+		assert_eq!(dumped, [0x23, 0x01, 0, 0, 0, 0]);
+
+		// Off the end of memory
+		let dump = parse_memory_dump(&format!("0x7ffffffe:4:{}", path));
+		assert!(dump_memory(&platform, &dump.unwrap()).is_err());
+		// & off the end of the address space
+		let dump = parse_memory_dump(&format!("0xffffffffffffffff:2:{}", path));
+		let error = dump_memory(&platform, &dump.unwrap()).unwrap_err();
+		assert!(error.to_string().contains("address space"));
+		assert!(!std::path::Path::new(path).exists());
+		assert!(parse_memory_dump("0x80000000:4").is_err());
+	}
+
+	#[test]
+	fn patched_instruction_runs_instead()
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 1] = [
+			0x0050_0513, // li a0, 5
+		];
+		load_program(&mut platform, &program);
+
+		// li a0, 7
+		let patch = parse_patch("0x80000000=0x00700513").unwrap();
+		apply_patch(&mut platform, &patch).unwrap();
+		run(&mut platform, 1).unwrap();
+
+		assert_eq!(platform.hart.read_register(10_usize), 7);
+	}
+}
@@ -0,0 +1,417 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::error::Error;
+
+// Flattened devicetree layout, per the devicetree specification. Everything
+// in the blob is big-endian.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+// The oldest version whose layout we understand
+const FDT_COMPAT_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+struct Header
+{
+	total_size: usize,
+	off_dt_struct: usize,
+	off_dt_strings: usize,
+	off_mem_rsvmap: usize,
+	last_comp_version: u32,
+	size_dt_strings: usize,
+	size_dt_struct: usize,
+}
+
+fn invalid(reason: String) -> Box<dyn Error>
+{
+	return Box::<dyn Error>::from(format!("invalid dtb: {}", reason));
+}
+
+fn be32(blob: &[u8], offset: usize) -> Result<u32, Box<dyn Error>>
+{
+	let Some(bytes) = blob.get(offset..offset + 4) else {
+		return Err(invalid(format!("truncated at offset {:#x}", offset)));
+	};
+
+	return Ok(u32::from_be_bytes(bytes.try_into().unwrap()));
+}
+
+fn align4(offset: usize) -> usize
+{
+	return (offset + 3) & !3;
+}
+
+fn parse_header(blob: &[u8]) -> Result<Header, Box<dyn Error>>
+{
+	if blob.len() < FDT_HEADER_SIZE {
+		return Err(invalid(format!(
+			"{} bytes is too small for a header",
+			blob.len()
+		)));
+	}
+
+	let magic = be32(blob, 0)?;
+	if magic != FDT_MAGIC {
+		return Err(invalid(format!(
+			"bad magic {:#010x}, expected {:#010x}",
+			magic, FDT_MAGIC
+		)));
+	}
+
+	return Ok(Header {
+		total_size: be32(blob, 4)? as usize,
+		off_dt_struct: be32(blob, 8)? as usize,
+		off_dt_strings: be32(blob, 12)? as usize,
+		off_mem_rsvmap: be32(blob, 16)? as usize,
+		last_comp_version: be32(blob, 24)?,
+		size_dt_strings: be32(blob, 32)? as usize,
+		size_dt_struct: be32(blob, 36)? as usize,
+	});
+}
+
+/// Walk the structure block, checking that every token is known and that the
+/// nodes nest properly.
+fn check_structure(header: &Header, blob: &[u8]) -> Result<(), Box<dyn Error>>
+{
+	let end = header.off_dt_struct + header.size_dt_struct;
+	let mut offset = header.off_dt_struct;
+	let mut depth: usize = 0;
+
+	if be32(blob, offset)? != FDT_BEGIN_NODE {
+		return Err(invalid(String::from(
+			"structure block does not start with a node",
+		)));
+	}
+
+	while offset < end {
+		let token = be32(blob, offset)?;
+		offset += 4;
+
+		match token {
+			FDT_BEGIN_NODE => {
+				let Some(name) = blob.get(offset..end) else {
+					return Err(invalid(format!(
+						"structure block truncated at offset {:#x}",
+						offset
+					)));
+				};
+				let length = name.iter().position(|byte| return *byte == 0);
+				let Some(length) = length else {
+					return Err(invalid(String::from("unterminated node name")));
+				};
+				offset = align4(offset + length + 1);
+				depth += 1;
+			},
+			FDT_END_NODE => {
+				if depth == 0 {
+					return Err(invalid(format!(
+						"unbalanced node end at offset {:#x}",
+						offset - 4
+					)));
+				}
+				depth -= 1;
+			},
+			FDT_PROP => {
+				if depth == 0 {
+					return Err(invalid(format!(
+						"property outside a node at offset {:#x}",
+						offset - 4
+					)));
+				}
+				let length = be32(blob, offset)? as usize;
+				let name_offset = be32(blob, offset + 4)? as usize;
+				if name_offset >= header.size_dt_strings {
+					return Err(invalid(format!(
+						"property name offset {:#x} outside strings block",
+						name_offset
+					)));
+				}
+				offset = align4(offset + 8 + length);
+			},
+			FDT_NOP => (),
+			FDT_END => {
+				if depth != 0 {
+					return Err(invalid(format!("{} unclosed nodes", depth)));
+				}
+				return Ok(());
+			},
+			_ => {
+				return Err(invalid(format!(
+					"unknown token {:#x} at offset {:#x}",
+					token,
+					offset - 4
+				)));
+			},
+		}
+	}
+
+	return Err(invalid(String::from("structure block has no end token")));
+}
+
+/// Check that blob looks like a flattened devicetree a kernel could use: the
+/// header is sane, the blocks it describes fit inside the blob and the
+/// structure block is well formed.
+pub fn validate(blob: &[u8]) -> Result<(), Box<dyn Error>>
+{
+	let header = parse_header(blob)?;
+
+	if header.total_size < FDT_HEADER_SIZE || header.total_size > blob.len() {
+		return Err(invalid(format!(
+			"total size {:#x} does not fit a blob of {:#x} bytes",
+			header.total_size,
+			blob.len()
+		)));
+	}
+
+	if header.last_comp_version > FDT_COMPAT_VERSION + 1 {
+		return Err(invalid(format!(
+			"unsupported version, compatible back to {}",
+			header.last_comp_version
+		)));
+	}
+
+	let blocks = [
+		("structure", header.off_dt_struct, header.size_dt_struct),
+		("strings", header.off_dt_strings, header.size_dt_strings),
+		// Its length is only known by walking it, but it at least needs
+		// room for the terminating entry.
+		("memory reservation", header.off_mem_rsvmap, 16),
+	];
+	for (name, offset, size) in blocks {
+		if offset < FDT_HEADER_SIZE || offset + size > header.total_size {
+			return Err(invalid(format!(
+				"{} block at {:#x} + {:#x} is outside the blob",
+				name, offset, size
+			)));
+		}
+	}
+
+	if header.off_dt_struct % 4 != 0 || header.off_mem_rsvmap % 8 != 0 {
+		return Err(invalid(String::from("misaligned block")));
+	}
+
+	return check_structure(&header, &blob[..header.total_size]);
+}
+
+/// Builds a flattened devicetree a node at a time, for machines without one
+/// supplied. Nodes are closed in the reverse order to being opened.
+#[derive(Default)]
+pub struct Builder
+{
+	structure: Vec<u8>,
+	strings: Vec<u8>,
+}
+
+impl Builder
+{
+	fn token(&mut self, token: u32)
+	{
+		self.structure.extend_from_slice(&token.to_be_bytes());
+	}
+
+	fn pad(&mut self)
+	{
+		self.structure.resize(align4(self.structure.len()), 0);
+	}
+
+	/// Where name is in the strings block, adding it if it isn't already.
+	fn string_offset(&mut self, name: &str) -> u32
+	{
+		let mut offset = 0;
+		for string in self.strings.split(|byte| return *byte == 0) {
+			if string == name.as_bytes() {
+				return offset as u32;
+			}
+			offset += string.len() + 1;
+		}
+
+		let offset = self.strings.len();
+		self.strings.extend_from_slice(name.as_bytes());
+		self.strings.push(0);
+
+		return offset as u32;
+	}
+
+	pub fn begin_node(&mut self, name: &str)
+	{
+		self.token(FDT_BEGIN_NODE);
+		self.structure.extend_from_slice(name.as_bytes());
+		self.structure.push(0);
+		self.pad();
+	}
+
+	pub fn end_node(&mut self)
+	{
+		self.token(FDT_END_NODE);
+	}
+
+	pub fn property(&mut self, name: &str, value: &[u8])
+	{
+		let name_offset = self.string_offset(name);
+
+		self.token(FDT_PROP);
+		self.token(value.len() as u32);
+		self.token(name_offset);
+		self.structure.extend_from_slice(value);
+		self.pad();
+	}
+
+	pub fn property_cells(&mut self, name: &str, cells: &[u32])
+	{
+		let value: Vec<u8> =
+			cells.iter().flat_map(|cell| return cell.to_be_bytes()).collect();
+		self.property(name, &value);
+	}
+
+	/// A property holding one or more strings, each nul terminated.
+	pub fn property_strings(&mut self, name: &str, strings: &[&str])
+	{
+		let mut value: Vec<u8> = Vec::new();
+		for string in strings {
+			value.extend_from_slice(string.as_bytes());
+			value.push(0);
+		}
+		self.property(name, &value);
+	}
+
+	/// Lay out the blob, with an empty memory reservation block.
+	pub fn finish(mut self) -> Vec<u8>
+	{
+		self.token(FDT_END);
+
+		let rsvmap_offset = FDT_HEADER_SIZE as u32;
+		let struct_offset = rsvmap_offset + 16;
+		let struct_size = self.structure.len() as u32;
+		let strings_offset = struct_offset + struct_size;
+		let strings_size = self.strings.len() as u32;
+
+		let header: [u32; 10] = [
+			FDT_MAGIC,
+			strings_offset + strings_size,
+			struct_offset,
+			strings_offset,
+			rsvmap_offset,
+			FDT_COMPAT_VERSION + 1,
+			FDT_COMPAT_VERSION,
+			0,
+			strings_size,
+			struct_size,
+		];
+
+		let mut blob: Vec<u8> = Vec::new();
+		for word in header {
+			blob.extend_from_slice(&word.to_be_bytes());
+		}
+		blob.extend_from_slice(&[0; 16]);
+		blob.extend_from_slice(&self.structure);
+		blob.extend_from_slice(&self.strings);
+
+		return blob;
+	}
+}
+
+#[cfg(test)]
+pub mod test
+{
+	use super::{
+		validate, Builder, FDT_BEGIN_NODE, FDT_END, FDT_END_NODE, FDT_MAGIC,
+	};
+
+	/// A devicetree with an empty root node and nothing else.
+	pub fn minimal_fdt() -> Vec<u8>
+	{
+		let rsvmap_offset: u32 = 40;
+		let struct_offset: u32 = rsvmap_offset + 16;
+		let structure: [u32; 4] = [FDT_BEGIN_NODE, 0, FDT_END_NODE, FDT_END];
+		let struct_size: u32 = 4 * structure.len() as u32;
+		let strings_offset: u32 = struct_offset + struct_size;
+		let total_size: u32 = strings_offset;
+
+		let header: [u32; 10] = [
+			FDT_MAGIC,
+			total_size,
+			struct_offset,
+			strings_offset,
+			rsvmap_offset,
+			17,
+			16,
+			0,
+			0,
+			struct_size,
+		];
+
+		let mut blob: Vec<u8> = Vec::new();
+		for word in header {
+			blob.extend_from_slice(&word.to_be_bytes());
+		}
+		blob.extend_from_slice(&[0; 16]);
+		for word in structure {
+			blob.extend_from_slice(&word.to_be_bytes());
+		}
+
+		return blob;
+	}
+
+	#[test]
+	fn minimal_fdt_is_valid()
+	{
+		validate(&minimal_fdt()).unwrap();
+	}
+
+	#[test]
+	fn truncated_fdt_is_rejected()
+	{
+		let blob = minimal_fdt();
+		let error = validate(&blob[..blob.len() - 4]).unwrap_err();
+
+		assert!(error.to_string().contains("total size"));
+	}
+
+	#[test]
+	fn truncated_structure_block_is_rejected()
+	{
+		let mut blob = minimal_fdt();
+		// Cut the structure block off part way through the root's token
+		blob[36..40].copy_from_slice(&2_u32.to_be_bytes());
+
+		let error = validate(&blob).unwrap_err();
+		assert!(error.to_string().contains("truncated"));
+	}
+
+	#[test]
+	fn unbalanced_nodes_are_rejected()
+	{
+		let mut blob = minimal_fdt();
+		// Turn the root's end node into a nop, leaving it open
+		let end_node = blob.len() - 8;
+		blob[end_node + 3] = 0x4;
+
+		let error = validate(&blob).unwrap_err();
+		assert!(error.to_string().contains("unclosed"));
+	}
+
+	#[test]
+	fn built_fdt_is_valid()
+	{
+		let mut builder = Builder::default();
+		builder.begin_node("");
+		builder.property_cells("#address-cells", &[2]);
+		builder.begin_node("chosen");
+		builder.property_strings("bootargs", &["console=hvc0"]);
+		builder.end_node();
+		builder.property_cells("#size-cells", &[2]);
+		builder.end_node();
+		let blob = builder.finish();
+
+		validate(&blob).unwrap();
+		// Property names are stored in the order they are first used
+		let strings = b"#address-cells\0bootargs\0#size-cells\0";
+		assert!(blob.ends_with(strings));
+	}
+}
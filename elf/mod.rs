@@ -0,0 +1,448 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::error::Error;
+
+// Just enough of the ELF64 layout to find the loadable segments of a
+// little-endian RISC-V executable.
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+const SHT_SYMTAB: u32 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// A segment to be copied into memory, with anything past the end of data
+/// up to memory_size being zeroed.
+#[derive(Debug)]
+pub struct Segment
+{
+	pub physical_address: usize,
+	pub data: Vec<u8>,
+	pub memory_size: usize,
+	pub executable: bool,
+}
+
+#[derive(Debug)]
+pub struct Elf
+{
+	pub entry: usize,
+	pub segments: Vec<Segment>,
+	/// Named symbols & their values, empty if the file was stripped
+	pub symbols: Vec<(String, usize)>,
+}
+
+impl Elf
+{
+	pub fn symbol(&self, name: &str) -> Option<usize>
+	{
+		return self
+			.symbols
+			.iter()
+			.find(|(symbol, _)| return symbol == name)
+			.map(|(_, value)| return *value);
+	}
+}
+
+fn invalid(reason: String) -> Box<dyn Error>
+{
+	return Box::<dyn Error>::from(format!("invalid elf: {}", reason));
+}
+
+/// base + offset, either of which may have come out of the file & so be
+/// anything at all.
+fn add(base: usize, offset: usize) -> Result<usize, Box<dyn Error>>
+{
+	return base.checked_add(offset).ok_or_else(|| {
+		return invalid(format!("{:#x} + {:#x} overflows", base, offset));
+	});
+}
+
+/// The size bytes of blob at offset.
+fn slice(
+	blob: &[u8], offset: usize, size: usize,
+) -> Result<&[u8], Box<dyn Error>>
+{
+	let Some(bytes) = blob.get(offset..add(offset, size)?) else {
+		return Err(invalid(format!("truncated at offset {:#x}", offset)));
+	};
+
+	return Ok(bytes);
+}
+
+fn bytes<const N: usize>(
+	blob: &[u8], offset: usize,
+) -> Result<[u8; N], Box<dyn Error>>
+{
+	return Ok(slice(blob, offset, N)?.try_into().unwrap());
+}
+
+fn le16(blob: &[u8], offset: usize) -> Result<u16, Box<dyn Error>>
+{
+	return Ok(u16::from_le_bytes(bytes(blob, offset)?));
+}
+
+fn le32(blob: &[u8], offset: usize) -> Result<u32, Box<dyn Error>>
+{
+	return Ok(u32::from_le_bytes(bytes(blob, offset)?));
+}
+
+fn le64(blob: &[u8], offset: usize) -> Result<usize, Box<dyn Error>>
+{
+	return Ok(u64::from_le_bytes(bytes(blob, offset)?) as usize);
+}
+
+pub fn is_elf(blob: &[u8]) -> bool
+{
+	return blob.starts_with(ELF_MAGIC);
+}
+
+pub fn parse(blob: &[u8]) -> Result<Elf, Box<dyn Error>>
+{
+	if !is_elf(blob) || blob.len() < ELF_HEADER_SIZE {
+		return Err(invalid(String::from("no elf header")));
+	}
+
+	if blob[4] != ELFCLASS64 || blob[5] != ELFDATA2LSB {
+		return Err(invalid(String::from("not a little-endian 64-bit elf")));
+	}
+
+	let machine = le16(blob, 0x12)?;
+	if machine != EM_RISCV {
+		return Err(invalid(format!("machine {} is not RISC-V", machine)));
+	}
+
+	let entry = le64(blob, 0x18)?;
+	let program_headers = le64(blob, 0x20)?;
+	let program_header_size = le16(blob, 0x36)? as usize;
+	let program_header_count = le16(blob, 0x38)? as usize;
+
+	if program_header_size < PROGRAM_HEADER_SIZE {
+		return Err(invalid(format!(
+			"program headers of {} bytes are too small",
+			program_header_size
+		)));
+	}
+
+	let mut segments: Vec<Segment> = Vec::new();
+	for index in 0..program_header_count {
+		// Neither of these is more than 16 bits, so can't overflow
+		let header = add(program_headers, index * program_header_size)?;
+		let header = slice(blob, header, PROGRAM_HEADER_SIZE)?;
+
+		if le32(header, 0)? != PT_LOAD {
+			continue;
+		}
+
+		let flags = le32(header, 0x04)?;
+		let offset = le64(header, 0x08)?;
+		let physical_address = le64(header, 0x18)?;
+		let file_size = le64(header, 0x20)?;
+		let memory_size = le64(header, 0x28)?;
+
+		let Ok(data) = slice(blob, offset, file_size) else {
+			return Err(invalid(format!(
+				"segment {} runs past the end of the file",
+				index
+			)));
+		};
+
+		if physical_address.checked_add(memory_size).is_none() {
+			return Err(invalid(format!(
+				"segment {} runs past the end of the address space",
+				index
+			)));
+		}
+
+		if memory_size < file_size {
+			return Err(invalid(format!(
+				"segment {} is smaller in memory than in the file",
+				index
+			)));
+		}
+
+		segments.push(Segment {
+			physical_address,
+			data: data.to_vec(),
+			memory_size,
+			executable: flags & PF_X != 0,
+		});
+	}
+
+	return Ok(Elf {
+		entry,
+		segments,
+		symbols: parse_symbols(blob)?,
+	});
+}
+
+/// Read a NUL terminated string starting at offset.
+fn string(blob: &[u8], offset: usize) -> Result<String, Box<dyn Error>>
+{
+	let Some(tail) = blob.get(offset..) else {
+		return Err(invalid(format!("string at {:#x} out of bounds", offset)));
+	};
+	let Some(length) = tail.iter().position(|byte| return *byte == 0) else {
+		return Err(invalid(format!("unterminated string at {:#x}", offset)));
+	};
+
+	return Ok(String::from_utf8_lossy(&tail[..length]).into_owned());
+}
+
+/// Collect the named symbols from every symbol table in the file.
+fn parse_symbols(blob: &[u8]) -> Result<Vec<(String, usize)>, Box<dyn Error>>
+{
+	let section_headers = le64(blob, 0x28)?;
+	let section_header_size = le16(blob, 0x3a)? as usize;
+	let section_header_count = le16(blob, 0x3c)? as usize;
+	let mut symbols: Vec<(String, usize)> = Vec::new();
+
+	if section_header_count == 0 {
+		return Ok(symbols);
+	}
+
+	if section_header_size < SECTION_HEADER_SIZE {
+		return Err(invalid(format!(
+			"section headers of {} bytes are too small",
+			section_header_size
+		)));
+	}
+
+	// The index is from the file, but no more than 32 bits, & the size no
+	// more than 16
+	let section = |index: usize| {
+		let header = add(section_headers, index * section_header_size)?;
+		return slice(blob, header, SECTION_HEADER_SIZE);
+	};
+
+	for index in 0..section_header_count {
+		let header = section(index)?;
+		if le32(header, 0x04)? != SHT_SYMTAB {
+			continue;
+		}
+
+		let offset = le64(header, 0x18)?;
+		let size = le64(header, 0x20)?;
+		let strings = le64(section(le32(header, 0x28)? as usize)?, 0x18)?;
+
+		for symbol in (offset..add(offset, size)?).step_by(SYMBOL_SIZE) {
+			let symbol = slice(blob, symbol, SYMBOL_SIZE)?;
+			let name = le32(symbol, 0)? as usize;
+			if name == 0 {
+				continue;
+			}
+
+			symbols.push((
+				string(blob, add(strings, name)?)?,
+				le64(symbol, 0x08)?,
+			));
+		}
+	}
+
+	return Ok(symbols);
+}
+
+#[cfg(test)]
+pub mod test
+{
+	use super::{
+		parse, ELFCLASS64, ELFDATA2LSB, ELF_MAGIC, EM_RISCV, PT_LOAD,
+		SHT_SYMTAB,
+	};
+
+	/// A loadable segment for elf_with_segments(): (address, flags,
+	/// contents, bss size).
+	pub type TestSegment<'a> = (u64, u32, &'a [u8], u64);
+
+	pub const PF_RWX: u32 = 0x7;
+	pub const PF_RW: u32 = 0x6;
+
+	/// An executable with a single RWX segment, holding code, loaded at
+	/// address & followed by bss_size bytes of bss.
+	pub fn minimal_elf(
+		address: u64, entry: u64, code: &[u8], bss_size: u64,
+	) -> Vec<u8>
+	{
+		return elf_with_segments(entry, &[(address, PF_RWX, code, bss_size)]);
+	}
+
+	/// An executable with a loadable segment for each entry in segments,
+	/// with their contents laid out back to back after the headers.
+	pub fn elf_with_segments(entry: u64, segments: &[TestSegment]) -> Vec<u8>
+	{
+		let mut data_offset: u64 = 64 + 56 * segments.len() as u64;
+		let mut blob: Vec<u8> = Vec::new();
+
+		// ELF header
+		blob.extend_from_slice(ELF_MAGIC);
+		blob.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, 1, 0]);
+		blob.extend_from_slice(&[0; 8]);
+		blob.extend_from_slice(&2_u16.to_le_bytes()); // ET_EXEC
+		blob.extend_from_slice(&EM_RISCV.to_le_bytes());
+		blob.extend_from_slice(&1_u32.to_le_bytes());
+		blob.extend_from_slice(&entry.to_le_bytes());
+		blob.extend_from_slice(&64_u64.to_le_bytes()); // e_phoff
+		blob.extend_from_slice(&0_u64.to_le_bytes()); // e_shoff
+		blob.extend_from_slice(&0_u32.to_le_bytes()); // e_flags
+		blob.extend_from_slice(&64_u16.to_le_bytes()); // e_ehsize
+		blob.extend_from_slice(&56_u16.to_le_bytes()); // e_phentsize
+		blob.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+		blob.extend_from_slice(&[0; 6]); // no section headers
+
+		// Program headers, with virtual addresses that differ from the
+		// physical ones, as in vmlinux
+		for (address, flags, contents, bss_size) in segments {
+			let file_size = contents.len() as u64;
+
+			blob.extend_from_slice(&PT_LOAD.to_le_bytes());
+			blob.extend_from_slice(&flags.to_le_bytes());
+			blob.extend_from_slice(&data_offset.to_le_bytes());
+			blob.extend_from_slice(
+				&(address | 0xffff_ffff << 32).to_le_bytes(),
+			);
+			blob.extend_from_slice(&address.to_le_bytes());
+			blob.extend_from_slice(&file_size.to_le_bytes());
+			blob.extend_from_slice(&(file_size + bss_size).to_le_bytes());
+			blob.extend_from_slice(&0x1000_u64.to_le_bytes());
+
+			data_offset += file_size;
+		}
+
+		for (_, _, contents, _) in segments {
+			blob.extend_from_slice(contents);
+		}
+
+		return blob;
+	}
+
+	/// As elf_with_segments(), with a symbol table holding symbols.
+	pub fn elf_with_symbols(
+		entry: u64, segments: &[TestSegment], symbols: &[(&str, u64)],
+	) -> Vec<u8>
+	{
+		const SHT_STRTAB: u32 = 3;
+		let mut blob = elf_with_segments(entry, segments);
+
+		let strings_offset = blob.len() as u64;
+		let mut names: Vec<u32> = Vec::new();
+		blob.push(0);
+		for (name, _) in symbols {
+			names.push(blob.len() as u32 - strings_offset as u32);
+			blob.extend_from_slice(name.as_bytes());
+			blob.push(0);
+		}
+		let strings_size = blob.len() as u64 - strings_offset;
+
+		// The first symbol is always the null one
+		let symbols_offset = blob.len() as u64;
+		blob.extend_from_slice(&[0; 24]);
+		for (name, (_, value)) in names.iter().zip(symbols) {
+			blob.extend_from_slice(&name.to_le_bytes());
+			blob.extend_from_slice(&[0; 4]); // info, other & shndx
+			blob.extend_from_slice(&value.to_le_bytes());
+			blob.extend_from_slice(&0_u64.to_le_bytes());
+		}
+		let symbols_size = blob.len() as u64 - symbols_offset;
+
+		// Section headers: null, .symtab & .strtab
+		let section_headers = blob.len() as u64;
+		blob.extend_from_slice(&[0; 64]);
+		for (kind, offset, size, link) in [
+			(SHT_SYMTAB, symbols_offset, symbols_size, 2_u32),
+			(SHT_STRTAB, strings_offset, strings_size, 0),
+		] {
+			blob.extend_from_slice(&0_u32.to_le_bytes()); // sh_name
+			blob.extend_from_slice(&kind.to_le_bytes());
+			blob.extend_from_slice(&[0; 16]); // sh_flags & sh_addr
+			blob.extend_from_slice(&offset.to_le_bytes());
+			blob.extend_from_slice(&size.to_le_bytes());
+			blob.extend_from_slice(&link.to_le_bytes());
+			blob.extend_from_slice(&[0; 12]); // sh_info & sh_addralign
+			blob.extend_from_slice(&24_u64.to_le_bytes()); // sh_entsize
+		}
+
+		blob[0x28..0x30].copy_from_slice(&section_headers.to_le_bytes());
+		blob[0x3a..0x3c].copy_from_slice(&64_u16.to_le_bytes());
+		blob[0x3c..0x3e].copy_from_slice(&3_u16.to_le_bytes());
+
+		return blob;
+	}
+
+	#[test]
+	fn finds_load_segments()
+	{
+		let blob = minimal_elf(0x8020_0000, 0x8020_0004, &[1, 2, 3, 4], 8);
+		let elf = parse(&blob).unwrap();
+
+		assert_eq!(elf.entry, 0x8020_0004);
+		assert_eq!(elf.segments.len(), 1);
+		assert_eq!(elf.segments[0].physical_address, 0x8020_0000);
+		assert_eq!(elf.segments[0].data, vec![1, 2, 3, 4]);
+		assert_eq!(elf.segments[0].memory_size, 12);
+		assert!(elf.segments[0].executable);
+	}
+
+	#[test]
+	fn execute_permission_comes_from_segment_flags()
+	{
+		let blob = elf_with_segments(
+			0x8020_0000,
+			&[(0x8020_0000, PF_RWX, &[1, 2], 0), (0x8020_1000, PF_RW, &[3], 0)],
+		);
+		let elf = parse(&blob).unwrap();
+
+		assert_eq!(elf.segments[1].data, vec![3]);
+		assert!(elf.segments[0].executable);
+		assert!(!elf.segments[1].executable);
+	}
+
+	#[test]
+	fn finds_symbols()
+	{
+		let blob = elf_with_symbols(
+			0x8000_0000,
+			&[(0x8000_0000, PF_RWX, &[0; 4], 0)],
+			&[("_start", 0x8000_0000), ("tohost", 0x8000_1000)],
+		);
+		let elf = parse(&blob).unwrap();
+
+		assert_eq!(elf.symbol("tohost"), Some(0x8000_1000));
+		assert_eq!(elf.symbol("_start"), Some(0x8000_0000));
+		assert_eq!(elf.symbol("fromhost"), None);
+		assert!(parse(&minimal_elf(0, 0, &[], 0)).unwrap().symbols.is_empty());
+	}
+
+	#[test]
+	fn truncated_segments_are_rejected()
+	{
+		let mut blob = minimal_elf(0x8020_0000, 0x8020_0000, &[1, 2, 3, 4], 0);
+		blob.truncate(blob.len() - 2);
+
+		let error = parse(&blob).unwrap_err();
+		assert!(error.to_string().contains("past the end"));
+	}
+
+	#[test]
+	fn overflowing_offsets_are_rejected()
+	{
+		let blob = minimal_elf(0x8020_0000, 0x8020_0000, &[1, 2, 3, 4], 0);
+		let patched = |offset: usize, value: u64| {
+			let mut blob = blob.clone();
+			blob[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+			return parse(&blob);
+		};
+
+		// e_phoff, then the segment's p_offset & p_memsz
+		assert!(patched(0x20, u64::MAX - 8).is_err());
+		let error = patched(64 + 0x08, u64::MAX).unwrap_err();
+		assert!(error.to_string().contains("past the end of the file"));
+		let error = patched(64 + 0x28, u64::MAX).unwrap_err();
+		assert!(error.to_string().contains("address space"));
+	}
+}
@@ -3,6 +3,12 @@
 #![allow(clippy::needless_return)]
 #![allow(non_camel_case_types)]
 
+use crate::gen_mask;
+use crate::snapshot::{Decoder, Encoder};
+use std::error::Error;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RegisterNames
 {
 	zero,
@@ -38,30 +44,541 @@ pub enum RegisterNames
 	t5,
 	t6,
 }
+
+/// ABI names of the floating point registers, in register number order.
+pub const FREGISTER_NAMES: [&str; 32] = [
+	"ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1",
+	"fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3",
+	"fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9",
+	"ft10", "ft11",
+];
+
+/// ABI names of the integer registers, in register number order.
+pub const REGISTER_NAMES: [&str; 32] = [
+	"zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1",
+	"a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+	"s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+impl RegisterNames
+{
+	/// Every register, in register number order.
+	const ALL: [RegisterNames; 32] = [
+		RegisterNames::zero,
+		RegisterNames::ra,
+		RegisterNames::sp,
+		RegisterNames::gp,
+		RegisterNames::tp,
+		RegisterNames::t0,
+		RegisterNames::t1,
+		RegisterNames::t2,
+		RegisterNames::s0,
+		RegisterNames::s1,
+		RegisterNames::a0,
+		RegisterNames::a1,
+		RegisterNames::a2,
+		RegisterNames::a3,
+		RegisterNames::a4,
+		RegisterNames::a5,
+		RegisterNames::a6,
+		RegisterNames::a7,
+		RegisterNames::s2,
+		RegisterNames::s3,
+		RegisterNames::s4,
+		RegisterNames::s5,
+		RegisterNames::s6,
+		RegisterNames::s7,
+		RegisterNames::s8,
+		RegisterNames::s9,
+		RegisterNames::s10,
+		RegisterNames::s11,
+		RegisterNames::t3,
+		RegisterNames::t4,
+		RegisterNames::t5,
+		RegisterNames::t6,
+	];
+
+	pub fn from_index(index: usize) -> Option<RegisterNames>
+	{
+		return RegisterNames::ALL.get(index).copied();
+	}
+}
+
+impl std::str::FromStr for RegisterNames
+{
+	type Err = String;
+
+	/// Either an ABI name, fp for s0, or x followed by the register number.
+	fn from_str(name: &str) -> Result<RegisterNames, String>
+	{
+		let index = match name {
+			"fp" => Some(RegisterNames::s0 as usize),
+			_ => REGISTER_NAMES.iter().position(|abi| return *abi == name),
+		};
+		let index = index.or_else(|| {
+			let number = name.strip_prefix('x')?;
+			// No leading zeroes or signs, so each register has one name
+			let digits = number.chars().all(|c| return c.is_ascii_digit());
+			if !digits || (number.len() > 1 && number.starts_with('0')) {
+				return None;
+			}
+			return number.parse::<usize>().ok();
+		});
+
+		return index
+			.and_then(RegisterNames::from_index)
+			.ok_or_else(|| return format!("unknown register: {}", name));
+	}
+}
+
+impl std::fmt::Display for RegisterNames
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		return write!(f, "{}", REGISTER_NAMES[*self as usize]);
+	}
+}
+
+/// Look up a register number from its ABI name or x number.
+pub fn register_index(name: &str) -> Option<usize>
+{
+	return name
+		.parse::<RegisterNames>()
+		.ok()
+		.map(|register| return register as usize);
+}
+
+// fflags & frm are views of the fields in fcsr
+pub const CSR_FFLAGS: usize = 0x001;
+pub const CSR_FRM: usize = 0x002;
+pub const CSR_FCSR: usize = 0x003;
+pub const CSR_SSTATUS: usize = 0x100;
+// sie & sip are views of mie & mip, showing the interrupts in mideleg
+pub const CSR_SIE: usize = 0x104;
+pub const CSR_STVEC: usize = 0x105;
+pub const CSR_SCOUNTEREN: usize = 0x106;
+pub const CSR_SSCRATCH: usize = 0x140;
+pub const CSR_SEPC: usize = 0x141;
+pub const CSR_SCAUSE: usize = 0x142;
+pub const CSR_STVAL: usize = 0x143;
+pub const CSR_SIP: usize = 0x144;
+pub const CSR_SATP: usize = 0x180;
+pub const CSR_MSTATUS: usize = 0x300;
+pub const CSR_MISA: usize = 0x301;
+pub const CSR_MEDELEG: usize = 0x302;
+pub const CSR_MIDELEG: usize = 0x303;
+pub const CSR_MIE: usize = 0x304;
+pub const CSR_MTVEC: usize = 0x305;
+pub const CSR_MCOUNTEREN: usize = 0x306;
+pub const CSR_MSCRATCH: usize = 0x340;
+pub const CSR_MEPC: usize = 0x341;
+pub const CSR_MCAUSE: usize = 0x342;
+pub const CSR_MTVAL: usize = 0x343;
+pub const CSR_MIP: usize = 0x344;
+pub const CSR_MCYCLE: usize = 0xb00;
+pub const CSR_MINSTRET: usize = 0xb02;
+// Read-only mirrors of the machine counters & mtime. RV64 has no need of
+// the high-half aliases, cycleh & co, so they are not provided.
+pub const CSR_CYCLE: usize = 0xc00;
+pub const CSR_TIME: usize = 0xc01;
+pub const CSR_INSTRET: usize = 0xc02;
+pub const CSR_MVENDORID: usize = 0xf11;
+pub const CSR_MARCHID: usize = 0xf12;
+pub const CSR_MIMPID: usize = 0xf13;
+pub const CSR_MHARTID: usize = 0xf14;
+
+// Bits of mcounteren & scounteren, one per counter mirror, which let the
+// next privilege level down read it.
+pub const COUNTEREN_CY: u64 = 1 << 0;
+pub const COUNTEREN_TM: u64 = 1 << 1;
+pub const COUNTEREN_IR: u64 = 1 << 2;
+const COUNTEREN_MASK: u64 = COUNTEREN_CY | COUNTEREN_TM | COUNTEREN_IR;
+
+/// Names of the CSRs the hart knows about, as used by assemblers.
+pub const CSR_NAMES: [(&str, usize); 34] = [
+	("fflags", CSR_FFLAGS),
+	("frm", CSR_FRM),
+	("fcsr", CSR_FCSR),
+	("sstatus", CSR_SSTATUS),
+	("sie", CSR_SIE),
+	("stvec", CSR_STVEC),
+	("scounteren", CSR_SCOUNTEREN),
+	("sscratch", CSR_SSCRATCH),
+	("sepc", CSR_SEPC),
+	("scause", CSR_SCAUSE),
+	("stval", CSR_STVAL),
+	("sip", CSR_SIP),
+	("satp", CSR_SATP),
+	("mstatus", CSR_MSTATUS),
+	("misa", CSR_MISA),
+	("medeleg", CSR_MEDELEG),
+	("mideleg", CSR_MIDELEG),
+	("mie", CSR_MIE),
+	("mtvec", CSR_MTVEC),
+	("mcounteren", CSR_MCOUNTEREN),
+	("mscratch", CSR_MSCRATCH),
+	("mepc", CSR_MEPC),
+	("mcause", CSR_MCAUSE),
+	("mtval", CSR_MTVAL),
+	("mip", CSR_MIP),
+	("mcycle", CSR_MCYCLE),
+	("minstret", CSR_MINSTRET),
+	("cycle", CSR_CYCLE),
+	("time", CSR_TIME),
+	("instret", CSR_INSTRET),
+	("mvendorid", CSR_MVENDORID),
+	("marchid", CSR_MARCHID),
+	("mimpid", CSR_MIMPID),
+	("mhartid", CSR_MHARTID),
+];
+
+/// Look up the name of a CSR from its number.
+pub fn csr_name(csr: usize) -> Option<&'static str>
+{
+	return CSR_NAMES
+		.iter()
+		.find(|(_, number)| return *number == csr)
+		.map(|(name, _)| return *name);
+}
+
+/// The quiet NaN that FP instructions produce, whatever NaNs went in.
+pub const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+/// The standard extensions every hart implements, as reported in misa.
+pub const EXTENSIONS: &[char] = &['I', 'M', 'A', 'F', 'C', 'S', 'U'];
+pub const MISA_C: u64 = 1 << (b'C' - b'A');
+
+// Zero is what a non-commercial implementation without an allocated
+// architecture ID reports in mvendorid & marchid.
+pub const MVENDORID: u64 = 0;
+pub const MARCHID: u64 = 0;
+
+/// The emulator's version, for mimpid, as major.minor.patch a byte each.
+pub fn mimpid() -> u64
+{
+	let version = [
+		env!("CARGO_PKG_VERSION_MAJOR"),
+		env!("CARGO_PKG_VERSION_MINOR"),
+		env!("CARGO_PKG_VERSION_PATCH"),
+	];
+
+	return version.iter().fold(0, |mimpid, part| {
+		return mimpid << 8 | part.parse::<u64>().unwrap_or(0);
+	});
+}
+
+pub const MSTATUS_SIE: u64 = gen_mask!(1, 1, u64);
+pub const MSTATUS_MIE: u64 = gen_mask!(3, 3, u64);
+pub const MSTATUS_SPIE: u64 = gen_mask!(5, 5, u64);
+pub const MSTATUS_MPIE: u64 = gen_mask!(7, 7, u64);
+pub const MSTATUS_SPP: u64 = gen_mask!(8, 8, u64);
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP_MASK: u64 = gen_mask!(12, 11, u64);
+// FS tracks whether the f registers & fcsr are in use, to save kernels
+// saving them on every context switch. Off makes FP instructions illegal.
+pub const MSTATUS_FS_SHIFT: u64 = 13;
+pub const MSTATUS_FS_MASK: u64 = gen_mask!(14, 13, u64);
+pub const FS_OFF: u64 = 0;
+pub const FS_INITIAL: u64 = 1;
+pub const FS_DIRTY: u64 = 3;
+pub const MSTATUS_MPRV: u64 = gen_mask!(17, 17, u64);
+pub const MSTATUS_SUM: u64 = gen_mask!(18, 18, u64);
+pub const MSTATUS_MXR: u64 = gen_mask!(19, 19, u64);
+/// The parts of mstatus visible through sstatus
+const SSTATUS_MASK: u64 = MSTATUS_SIE
+	| MSTATUS_SPIE
+	| MSTATUS_SPP
+	| MSTATUS_FS_MASK
+	| MSTATUS_SUM
+	| MSTATUS_MXR;
+
+// fcsr holds the accrued exception flags & the dynamic rounding mode
+pub const FCSR_FFLAGS_MASK: u64 = gen_mask!(4, 0, u64);
+pub const FCSR_FRM_MASK: u64 = gen_mask!(7, 5, u64);
+
+// mie & mip share a layout, with one bit per interrupt cause
+pub const MIP_SSIP: u64 = gen_mask!(1, 1, u64);
+pub const MIP_MSIP: u64 = gen_mask!(3, 3, u64);
+pub const MIP_STIP: u64 = gen_mask!(5, 5, u64);
+pub const MIP_MTIP: u64 = gen_mask!(7, 7, u64);
+pub const MIP_SEIP: u64 = gen_mask!(9, 9, u64);
+pub const MIP_MEIP: u64 = gen_mask!(11, 11, u64);
+/// The interrupts that may be delegated to S-mode
+const MIP_SUPERVISOR_MASK: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+// stvec shares mtvec's layout
+pub const MTVEC_MODE_MASK: u64 = gen_mask!(1, 0, u64);
+pub const MTVEC_MODE_VECTORED: u64 = 0b01;
+
+// Privilege levels, encoded as they are in mstatus.MPP & bits 9:8 of CSR
+// numbers.
+pub const PRIV_USER: u64 = 0b00;
+pub const PRIV_SUPERVISOR: u64 = 0b01;
+pub const PRIV_MACHINE: u64 = 0b11;
+
+/// Set in mcause when the trap is an interrupt rather than an exception.
+pub const CAUSE_INTERRUPT: u64 = 1 << 63;
+
+pub const CAUSE_SUPERVISOR_SOFTWARE: u64 = CAUSE_INTERRUPT | 1;
+pub const CAUSE_MACHINE_SOFTWARE: u64 = CAUSE_INTERRUPT | 3;
+pub const CAUSE_SUPERVISOR_TIMER: u64 = CAUSE_INTERRUPT | 5;
+pub const CAUSE_MACHINE_TIMER: u64 = CAUSE_INTERRUPT | 7;
+pub const CAUSE_SUPERVISOR_EXTERNAL: u64 = CAUSE_INTERRUPT | 9;
+pub const CAUSE_MACHINE_EXTERNAL: u64 = CAUSE_INTERRUPT | 11;
+
+/// The order interrupts pending at the same time are taken in, once those
+/// for M-mode have been taken ahead of those delegated to S-mode.
+const INTERRUPT_PRIORITY: [u64; 6] = [
+	CAUSE_MACHINE_EXTERNAL,
+	CAUSE_MACHINE_SOFTWARE,
+	CAUSE_MACHINE_TIMER,
+	CAUSE_SUPERVISOR_EXTERNAL,
+	CAUSE_SUPERVISOR_SOFTWARE,
+	CAUSE_SUPERVISOR_TIMER,
+];
+
+pub const CAUSE_INSN_MISALIGNED: u64 = 0;
+pub const CAUSE_INSN_ACCESS_FAULT: u64 = 1;
+pub const CAUSE_ILLEGAL_INSN: u64 = 2;
+pub const CAUSE_BREAKPOINT: u64 = 3;
+pub const CAUSE_LOAD_MISALIGNED: u64 = 4;
+pub const CAUSE_LOAD_ACCESS_FAULT: u64 = 5;
+pub const CAUSE_STORE_MISALIGNED: u64 = 6;
+pub const CAUSE_STORE_ACCESS_FAULT: u64 = 7;
+// ecall's cause is this plus the privilege level it was made from
+pub const CAUSE_ECALL_UMODE: u64 = 8;
+pub const CAUSE_ECALL_SMODE: u64 = 9;
+pub const CAUSE_ECALL_MMODE: u64 = 11;
+pub const CAUSE_INSN_PAGE_FAULT: u64 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: u64 = 13;
+pub const CAUSE_STORE_PAGE_FAULT: u64 = 15;
+
+/// An exception raised while executing an instruction, along with the value
+/// destined for mtval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trap
+{
+	pub cause: u64,
+	pub tval: u64,
+}
+
+/// Whether a hart is running, in the sense of the SBI HSM extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HartState
+{
+	Started,
+	Stopped,
+}
+
+/// The width of the integer registers. RV32 harts keep their registers
+/// sign-extended to 64 bits, so that the signed comparisons & arithmetic
+/// work unchanged, & zero-extend them where they are used unsigned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Xlen
+{
+	Rv32,
+	Rv64,
+}
+
+impl Xlen
+{
+	pub fn bits(self) -> u32
+	{
+		match self {
+			Xlen::Rv32 => return 32,
+			Xlen::Rv64 => return 64,
+		}
+	}
+
+	/// The MXL field of misa
+	fn mxl(self) -> u64
+	{
+		match self {
+			Xlen::Rv32 => return 1,
+			Xlen::Rv64 => return 2,
+		}
+	}
+}
+
+/// Parse an XLEN of 32 or 64, for the command line.
+pub fn parse_xlen(input: &str) -> Result<Xlen, String>
+{
+	match input {
+		"32" => return Ok(Xlen::Rv32),
+		"64" => return Ok(Xlen::Rv64),
+		_ => return Err(format!("xlen must be 32 or 64: {}", input)),
+	}
+}
+
 #[derive(Debug)]
 pub struct Hart
 {
 	pub registers: [u64; 32],
+	/// The f registers. Single precision values are NaN-boxed, with every
+	/// bit above them set.
+	pub fregisters: [u64; 32],
 	pub csrs: [u64; 4096],
 	pub pc: u64,
 	pub id: usize,
+	pub state: HartState,
+	pub xlen: Xlen,
+	/// The privilege level the hart is executing at, one of PRIV_*.
+	pub privilege: u64,
+	/// Whether a CSR instruction wrote mcycle or minstret since these were
+	/// last cleared, so that the count doesn't go on top of the write
+	pub mcycle_written: bool,
+	pub minstret_written: bool,
 }
 
 impl Default for Hart
 {
 	fn default() -> Hart
 	{
-		return Hart {
+		return Hart::new(0);
+	}
+}
+
+impl Hart
+{
+	/// A hart with mhartid set to id. Only hart 0 starts out running,
+	/// the rest wait to be started.
+	pub fn new(id: usize) -> Hart
+	{
+		let mut hart = Hart {
 			registers: [0; 32],
+			fregisters: [0; 32],
 			csrs: [0; 4096],
 			pc: 0,
-			id: 0,
+			id,
+			state: HartState::Stopped,
+			xlen: Xlen::Rv64,
+			privilege: PRIV_MACHINE,
+			mcycle_written: false,
+			minstret_written: false,
 		};
+
+		hart.set_xlen(Xlen::Rv64);
+		hart.write_csr(CSR_MVENDORID, MVENDORID);
+		hart.write_csr(CSR_MARCHID, MARCHID);
+		hart.write_csr(CSR_MIMPID, mimpid());
+		hart.write_csr(CSR_MHARTID, id as u64);
+		if id == 0 {
+			hart.state = HartState::Started;
+		}
+
+		return hart;
+	}
+
+	/// Put the hart back as it was when new, with its registers, CSRs & pc
+	/// zeroed & in M-mode. It keeps its id & XLEN.
+	pub fn reset(&mut self)
+	{
+		let xlen = self.xlen;
+		*self = Hart::new(self.id);
+		self.set_xlen(xlen);
+	}
+
+	/// Write the hart's state out for a snapshot. Most CSRs are zero, so
+	/// only the others are saved.
+	pub fn save(&self, out: &mut Encoder)
+	{
+		out.u64(self.id as u64);
+		out.u64(self.pc);
+		out.u64((self.state == HartState::Started) as u64);
+		out.u64(self.xlen.bits() as u64);
+		out.u64(self.privilege);
+		for register in self.registers {
+			out.u64(register);
+		}
+		for register in self.fregisters {
+			out.u64(register);
+		}
+
+		let csrs: Vec<(usize, &u64)> = self
+			.csrs
+			.iter()
+			.enumerate()
+			.filter(|(_, value)| return **value != 0)
+			.collect();
+		out.u64(csrs.len() as u64);
+		for (csr, value) in csrs {
+			out.u64(csr as u64);
+			out.u64(*value);
+		}
+	}
+
+	/// Read back what save() wrote, which must be for this hart.
+	pub fn restore(&mut self, input: &mut Decoder)
+		-> Result<(), Box<dyn Error>>
+	{
+		input.expect("hart id", self.id)?;
+		self.pc = input.u64()?;
+		self.state =
+			if input.bool()? { HartState::Started } else { HartState::Stopped };
+		self.xlen = match input.u64()? {
+			32 => Xlen::Rv32,
+			64 => Xlen::Rv64,
+			bits => return Err(format!("no {}-bit xlen", bits).into()),
+		};
+		self.privilege = match input.u64()? {
+			privilege @ (PRIV_USER | PRIV_SUPERVISOR | PRIV_MACHINE) => {
+				privilege
+			},
+			privilege => {
+				return Err(format!("no privilege level {}", privilege).into());
+			},
+		};
+		for register in &mut self.registers {
+			*register = input.u64()?;
+		}
+		for register in &mut self.fregisters {
+			*register = input.u64()?;
+		}
+
+		self.csrs = [0; 4096];
+		for _ in 0..input.u64()? {
+			let csr = input.usize()?;
+			let Some(value) = self.csrs.get_mut(csr) else {
+				return Err(format!("no such csr: {:#x}", csr).into());
+			};
+			*value = input.u64()?;
+		}
+
+		return Ok(());
+	}
+
+	/// Change the width of the registers, updating misa to match.
+	pub fn set_xlen(&mut self, xlen: Xlen)
+	{
+		self.xlen = xlen;
+
+		let extensions = EXTENSIONS.iter().fold(0, |bits, extension| {
+			return bits | 1 << (*extension as u8 - b'A');
+		});
+		self.write_csr(CSR_MISA, xlen.mxl() << (xlen.bits() - 2) | extensions);
+	}
+
+	/// The alignment jump & branch targets need, which is 2 bytes while
+	/// compressed instructions are enabled in misa & 4 otherwise.
+	pub fn ialign(&self) -> u64
+	{
+		if self.read_csr(CSR_MISA) & MISA_C != 0 {
+			return 2;
+		}
+
+		return 4;
+	}
+
+	/// A register value as an unsigned XLEN-bit number, as used for
+	/// addresses & unsigned arithmetic.
+	pub fn zero_extend(&self, value: u64) -> u64
+	{
+		match self.xlen {
+			Xlen::Rv32 => return value & gen_mask!(31, 0, u64),
+			Xlen::Rv64 => return value,
+		}
 	}
-}
 
-impl Hart
-{
 	pub fn write_register<T>(&mut self, offset: T, value: u64)
 	where
 		T: Into<usize>,
@@ -71,6 +588,12 @@ impl Hart
 			return;
 		}
 
+		// Only the low XLEN bits of a result are kept
+		let value = match self.xlen {
+			Xlen::Rv32 => value as i32 as i64 as u64,
+			Xlen::Rv64 => value,
+		};
+
 		self.registers[offset] = value;
 	}
 
@@ -86,12 +609,57 @@ impl Hart
 		return self.registers[offset];
 	}
 
+	/// Counter mirrors share storage with the counter they mirror. time
+	/// has its own, as mtime lives in the CLINT & is copied in by the
+	/// platform.
+	fn csr_storage(offset: usize) -> usize
+	{
+		match offset {
+			CSR_CYCLE => return CSR_MCYCLE,
+			CSR_INSTRET => return CSR_MINSTRET,
+			_ => return offset,
+		}
+	}
+
 	pub fn write_csr<T>(&mut self, offset: T, value: u64)
 	where
 		T: Into<usize>,
 	{
 		let offset = usize::try_from(offset).unwrap();
-		self.csrs[offset] = value;
+		let mut value = self.zero_extend(value);
+
+		// sstatus is a restricted view of mstatus
+		if offset == CSR_SSTATUS {
+			let mstatus = self.csrs[CSR_MSTATUS] & !SSTATUS_MASK;
+			self.csrs[CSR_MSTATUS] = mstatus | (value & SSTATUS_MASK);
+			return;
+		}
+
+		if let Some(register) = Hart::interrupt_view(offset) {
+			let mask = self.csrs[CSR_MIDELEG] & MIP_SUPERVISOR_MASK;
+			let rest = self.csrs[register] & !mask;
+			self.csrs[register] = rest | (value & mask);
+			return;
+		}
+
+		if let Some(mask) = Hart::fcsr_field(offset) {
+			let shift = mask.trailing_zeros();
+			let fcsr = self.csrs[CSR_FCSR] & !mask;
+			self.csrs[CSR_FCSR] = fcsr | (value << shift & mask);
+			self.set_fs(FS_DIRTY);
+			return;
+		}
+
+		// MPP is WARL, & 0b10 isn't a privilege level. Keep the old one
+		if offset == CSR_MSTATUS {
+			let mpp = (value & MSTATUS_MPP_MASK) >> MSTATUS_MPP_SHIFT;
+			if mpp == 0b10 {
+				value &= !MSTATUS_MPP_MASK;
+				value |= self.csrs[CSR_MSTATUS] & MSTATUS_MPP_MASK;
+			}
+		}
+
+		self.csrs[Hart::csr_storage(offset)] = value;
 	}
 
 	pub fn read_csr<T>(&self, offset: T) -> u64
@@ -99,6 +667,710 @@ impl Hart
 		T: Into<usize>,
 	{
 		let offset = usize::try_from(offset).unwrap();
-		return self.csrs[offset];
+		if let Some(mask) = Hart::fcsr_field(offset) {
+			return (self.csrs[CSR_FCSR] & mask) >> mask.trailing_zeros();
+		}
+
+		if let Some(register) = Hart::interrupt_view(offset) {
+			let mask = self.csrs[CSR_MIDELEG] & MIP_SUPERVISOR_MASK;
+			return self.csrs[register] & mask;
+		}
+
+		let mut value = self.csrs[Hart::csr_storage(offset)];
+		if offset == CSR_SSTATUS {
+			value = self.csrs[CSR_MSTATUS] & SSTATUS_MASK;
+		}
+
+		// SD, the top bit, summarises whether FS is dirty
+		let status = offset == CSR_MSTATUS || offset == CSR_SSTATUS;
+		if status && self.fs() == FS_DIRTY {
+			value |= 1 << (self.xlen.bits() - 1);
+		}
+
+		return value;
+	}
+
+	/// Check a CSR instruction may access a CSR at all: bits 9:8 of its
+	/// number hold the lowest privilege level that may, the FP CSRs go
+	/// away along with the FP instructions, & the counter mirrors need
+	/// enabling in mcounteren, then scounteren too for U-mode.
+	/// The instruction fills in tval, as only it knows its bits.
+	fn check_csr_access(&self, offset: usize) -> Result<(), Trap>
+	{
+		let illegal = Trap {
+			cause: CAUSE_ILLEGAL_INSN,
+			tval: 0,
+		};
+
+		if (offset >> 8) as u64 & 0b11 > self.privilege {
+			return Err(illegal);
+		}
+
+		if Hart::fcsr_field(offset).is_some() && self.fs() == FS_OFF {
+			return Err(illegal);
+		}
+
+		if (CSR_CYCLE..=CSR_INSTRET).contains(&offset) {
+			let enable = 1 << (offset - CSR_CYCLE);
+			let machine = self.read_csr(CSR_MCOUNTEREN) & enable != 0;
+			let supervisor = self.read_csr(CSR_SCOUNTEREN) & enable != 0;
+			if self.privilege < PRIV_MACHINE && !machine {
+				return Err(illegal);
+			}
+			if self.privilege < PRIV_SUPERVISOR && !supervisor {
+				return Err(illegal);
+			}
+		}
+
+		return Ok(());
+	}
+
+	/// Read a CSR for a CSR instruction, which may not be allowed to.
+	pub fn csr_read(&self, offset: usize) -> Result<u64, Trap>
+	{
+		self.check_csr_access(offset)?;
+		return Ok(self.read_csr(offset));
+	}
+
+	/// Write a CSR for a CSR instruction. Unlike write_csr, this fails on
+	/// read-only CSRs & only lets legal values into WARL fields.
+	pub fn csr_write(&mut self, offset: usize, value: u64) -> Result<(), Trap>
+	{
+		self.check_csr_access(offset)?;
+
+		// CSRs with both of the top two address bits set are read-only
+		if offset >> 10 == 0b11 {
+			return Err(Trap {
+				cause: CAUSE_ILLEGAL_INSN,
+				tval: 0,
+			});
+		}
+
+		let old = self.read_csr(offset);
+		let value = match offset {
+			// Only compressed instructions can be turned on & off, the
+			// rest of misa is fixed
+			CSR_MISA => old & !MISA_C | value & MISA_C,
+			// Only the counters that exist can be enabled
+			CSR_MCOUNTEREN | CSR_SCOUNTEREN => value & COUNTEREN_MASK,
+			// M-mode interrupts can't be delegated
+			CSR_MIDELEG => value & MIP_SUPERVISOR_MASK,
+			// The M-mode interrupts are pending when a device says so, &
+			// of the S-mode ones, S-mode may only raise its own software
+			// interrupt
+			CSR_MIP => old & !MIP_SUPERVISOR_MASK | value & MIP_SUPERVISOR_MASK,
+			CSR_SIP => old & !MIP_SSIP | value & MIP_SSIP,
+			// Modes past vectored are reserved, so keep the old one
+			CSR_MTVEC | CSR_STVEC
+				if value & MTVEC_MODE_MASK > MTVEC_MODE_VECTORED =>
+			{
+				value & !MTVEC_MODE_MASK | old & MTVEC_MODE_MASK
+			},
+			_ => value,
+		};
+		self.write_csr(offset, value);
+
+		match offset {
+			CSR_MCYCLE => self.mcycle_written = true,
+			CSR_MINSTRET => self.minstret_written = true,
+			_ => (),
+		}
+
+		return Ok(());
+	}
+
+	/// The CSR sie or sip is a view of, if offset is one of them.
+	fn interrupt_view(offset: usize) -> Option<usize>
+	{
+		match offset {
+			CSR_SIE => return Some(CSR_MIE),
+			CSR_SIP => return Some(CSR_MIP),
+			_ => return None,
+		}
+	}
+
+	/// The part of fcsr a CSR number gives access to, if it's one of them.
+	fn fcsr_field(offset: usize) -> Option<u64>
+	{
+		match offset {
+			CSR_FFLAGS => return Some(FCSR_FFLAGS_MASK),
+			CSR_FRM => return Some(FCSR_FRM_MASK),
+			CSR_FCSR => return Some(FCSR_FFLAGS_MASK | FCSR_FRM_MASK),
+			_ => return None,
+		}
+	}
+
+	/// The state of the floating point unit, as tracked by mstatus.FS.
+	pub fn fs(&self) -> u64
+	{
+		return (self.csrs[CSR_MSTATUS] & MSTATUS_FS_MASK) >> MSTATUS_FS_SHIFT;
+	}
+
+	pub fn set_fs(&mut self, fs: u64)
+	{
+		let mstatus = self.csrs[CSR_MSTATUS] & !MSTATUS_FS_MASK;
+		self.csrs[CSR_MSTATUS] = mstatus | fs << MSTATUS_FS_SHIFT;
+	}
+
+	/// A single precision f register. Anything not properly NaN-boxed
+	/// reads as the canonical NaN.
+	pub fn read_fregister(&self, index: usize) -> f32
+	{
+		let value = self.fregisters[index];
+		if value >> 32 != 0xffff_ffff {
+			return f32::from_bits(CANONICAL_NAN);
+		}
+
+		return f32::from_bits(value as u32);
+	}
+
+	pub fn write_fregister(&mut self, index: usize, value: f32)
+	{
+		self.fregisters[index] =
+			gen_mask!(63, 32, u64) | value.to_bits() as u64;
+		self.set_fs(FS_DIRTY);
+	}
+
+	/// Accumulate exception flags into fflags.
+	pub fn raise_fflags(&mut self, flags: u64)
+	{
+		if flags != 0 {
+			self.csrs[CSR_FCSR] |= flags & FCSR_FFLAGS_MASK;
+			self.set_fs(FS_DIRTY);
+		}
+	}
+
+	/// Set or clear a bit in mip, for devices driving the interrupt lines.
+	pub fn set_pending(&mut self, bit: u64, pending: bool)
+	{
+		let mut mip = self.read_csr(CSR_MIP);
+		if pending {
+			mip |= bit;
+		} else {
+			mip &= !bit;
+		}
+		self.write_csr(CSR_MIP, mip);
+	}
+
+	/// The interrupt that should be taken before the next instruction, if
+	/// any. An interrupt must be both pending in mip & enabled in mie. One
+	/// that traps to M-mode is then taken whenever the hart is below
+	/// M-mode, or in it with mstatus.MIE set. One delegated to S-mode in
+	/// mideleg is taken below S-mode, or in it with mstatus.SIE set, but
+	/// never in M-mode. When several are, the priority order is external,
+	/// software, then timer, M-mode's first. Taking one leaves the rest
+	/// pending in mip.
+	pub fn pending_interrupt(&self) -> Option<u64>
+	{
+		let mstatus = self.read_csr(CSR_MSTATUS);
+		let pending = self.read_csr(CSR_MIP) & self.read_csr(CSR_MIE);
+		let delegated = self.read_csr(CSR_MIDELEG);
+
+		let mut machine = pending & !delegated;
+		if self.privilege == PRIV_MACHINE && mstatus & MSTATUS_MIE == 0 {
+			machine = 0;
+		}
+
+		let mut supervisor = pending & delegated;
+		let supervisor_enabled = self.privilege < PRIV_SUPERVISOR
+			|| self.privilege == PRIV_SUPERVISOR && mstatus & MSTATUS_SIE != 0;
+		if !supervisor_enabled {
+			supervisor = 0;
+		}
+
+		for interrupts in [machine, supervisor] {
+			let cause = INTERRUPT_PRIORITY.iter().find(|cause| {
+				return interrupts & 1 << (*cause & !CAUSE_INTERRUPT) != 0;
+			});
+			if cause.is_some() {
+				return cause.copied();
+			}
+		}
+
+		return None;
+	}
+
+	/// The privilege level loads & stores are checked & translated at.
+	/// With mstatus.MPRV set this is the one held in mstatus.MPP rather
+	/// than the current one, letting M-mode firmware access memory as the
+	/// software it is servicing would. Fetches always use the current
+	/// privilege.
+	pub fn data_privilege(&self) -> u64
+	{
+		let mstatus = self.read_csr(CSR_MSTATUS);
+
+		if mstatus & MSTATUS_MPRV == 0 {
+			return self.privilege;
+		}
+
+		return (mstatus & MSTATUS_MPP_MASK) >> MSTATUS_MPP_SHIFT;
+	}
+
+	/// Record a trap in the machine trap CSRs without redirecting pc, so
+	/// that the faulting instruction can be inspected.
+	pub fn record_trap(&mut self, cause: u64, tval: u64)
+	{
+		self.write_csr(CSR_MEPC, self.pc);
+		self.write_csr(CSR_MCAUSE, self.xcause(cause));
+		self.write_csr(CSR_MTVAL, tval);
+	}
+
+	/// A cause as mcause & scause hold it, with the interrupt flag in the
+	/// top bit of an XLEN-bit register rather than bit 63.
+	fn xcause(&self, cause: u64) -> u64
+	{
+		if cause & CAUSE_INTERRUPT == 0 {
+			return cause;
+		}
+
+		let flag = 1 << (self.xlen.bits() - 1);
+		return cause & !CAUSE_INTERRUPT | flag;
+	}
+
+	/// Whether a trap should be handled in S-mode rather than M-mode.
+	/// Traps are only delegated if the cause's bit is set in medeleg, or
+	/// mideleg for interrupts, and are never taken to a lower privilege
+	/// than the one they were raised at.
+	fn is_delegated(&self, cause: u64) -> bool
+	{
+		if self.privilege > PRIV_SUPERVISOR {
+			return false;
+		}
+
+		let delegation = if cause & CAUSE_INTERRUPT != 0 {
+			self.read_csr(CSR_MIDELEG)
+		} else {
+			self.read_csr(CSR_MEDELEG)
+		};
+		let code = cause & !CAUSE_INTERRUPT;
+
+		return code < 64 && delegation & (1 << code) != 0;
+	}
+
+	/// The handler address for a trap, given the mtvec or stvec value.
+	/// In vectored mode, only interrupts get their own entry in the
+	/// table, synchronous exceptions all go to the base address.
+	fn trap_vector(tvec: u64, cause: u64) -> u64
+	{
+		let base = tvec & !MTVEC_MODE_MASK;
+		let is_interrupt = cause & CAUSE_INTERRUPT != 0;
+		if tvec & MTVEC_MODE_MASK == MTVEC_MODE_VECTORED && is_interrupt {
+			let code = cause & !CAUSE_INTERRUPT;
+			return base.wrapping_add(4 * code);
+		}
+
+		return base;
+	}
+
+	/// Take a trap, recording it & jumping to the handler at mtvec, or
+	/// the one at stvec if it has been delegated to S-mode.
+	/// The previous interrupt enable & privilege are stacked in mstatus
+	/// so that the handler can return to the interrupted code.
+	/// What goes in mtval, or stval, depends on the cause, & is up to the
+	/// caller:
+	/// - illegal instruction: the instruction bits, only the low 16 for a
+	///   compressed one
+	/// - breakpoint: the pc of the ebreak
+	/// - misaligned, access fault or page fault: the faulting address
+	/// - ecall & interrupts: zero
+	pub fn take_trap(&mut self, cause: u64, tval: u64)
+	{
+		if self.is_delegated(cause) {
+			return self.take_supervisor_trap(cause, tval);
+		}
+
+		self.record_trap(cause, tval);
+
+		let mut mstatus = self.read_csr(CSR_MSTATUS);
+		if mstatus & MSTATUS_MIE != 0 {
+			mstatus |= MSTATUS_MPIE;
+		} else {
+			mstatus &= !MSTATUS_MPIE;
+		}
+		mstatus &= !MSTATUS_MIE;
+		mstatus &= !MSTATUS_MPP_MASK;
+		mstatus |= self.privilege << MSTATUS_MPP_SHIFT;
+		self.write_csr(CSR_MSTATUS, mstatus);
+		self.privilege = PRIV_MACHINE;
+
+		self.pc = Hart::trap_vector(self.read_csr(CSR_MTVEC), cause);
+	}
+
+	/// Take a trap delegated to S-mode, as take_trap does but using the
+	/// S-mode copies: sepc, scause, stval, SPIE, SPP & stvec.
+	fn take_supervisor_trap(&mut self, cause: u64, tval: u64)
+	{
+		self.write_csr(CSR_SEPC, self.pc);
+		self.write_csr(CSR_SCAUSE, self.xcause(cause));
+		self.write_csr(CSR_STVAL, tval);
+
+		let mut mstatus = self.read_csr(CSR_MSTATUS);
+		if mstatus & MSTATUS_SIE != 0 {
+			mstatus |= MSTATUS_SPIE;
+		} else {
+			mstatus &= !MSTATUS_SPIE;
+		}
+		mstatus &= !MSTATUS_SIE;
+		if self.privilege == PRIV_SUPERVISOR {
+			mstatus |= MSTATUS_SPP;
+		} else {
+			mstatus &= !MSTATUS_SPP;
+		}
+		self.write_csr(CSR_MSTATUS, mstatus);
+		self.privilege = PRIV_SUPERVISOR;
+
+		self.pc = Hart::trap_vector(self.read_csr(CSR_STVEC), cause);
+	}
+
+	/// Return from a trap, as MRET does, undoing what take_trap did. The
+	/// privilege level is restored from MPP, the interrupt enable from MPIE
+	/// & pc from mepc. MPP is left holding U, the least privileged level.
+	/// Returning to anything less privileged than M clears MPRV.
+	pub fn trap_return(&mut self)
+	{
+		let mut mstatus = self.read_csr(CSR_MSTATUS);
+		let previous = (mstatus & MSTATUS_MPP_MASK) >> MSTATUS_MPP_SHIFT;
+
+		if mstatus & MSTATUS_MPIE != 0 {
+			mstatus |= MSTATUS_MIE;
+		} else {
+			mstatus &= !MSTATUS_MIE;
+		}
+		mstatus |= MSTATUS_MPIE;
+		mstatus &= !MSTATUS_MPP_MASK;
+		mstatus |= PRIV_USER << MSTATUS_MPP_SHIFT;
+		if previous != PRIV_MACHINE {
+			mstatus &= !MSTATUS_MPRV;
+		}
+		self.write_csr(CSR_MSTATUS, mstatus);
+		self.privilege = previous;
+
+		// mepc can hold a misaligned address, but can't return to one
+		self.pc = self.read_csr(CSR_MEPC) & !(self.ialign() - 1);
+	}
+
+	/// Return from a supervisor trap, as SRET does. Like trap_return, but
+	/// using the S-mode copies: SPP, SPIE & sepc. SPP can only hold S or U.
+	pub fn supervisor_trap_return(&mut self)
+	{
+		let mut mstatus = self.read_csr(CSR_MSTATUS);
+		let previous = if mstatus & MSTATUS_SPP != 0 {
+			PRIV_SUPERVISOR
+		} else {
+			PRIV_USER
+		};
+
+		if mstatus & MSTATUS_SPIE != 0 {
+			mstatus |= MSTATUS_SIE;
+		} else {
+			mstatus &= !MSTATUS_SIE;
+		}
+		mstatus |= MSTATUS_SPIE;
+		mstatus &= !MSTATUS_SPP;
+		mstatus &= !MSTATUS_MPRV;
+		self.write_csr(CSR_MSTATUS, mstatus);
+		self.privilege = previous;
+
+		self.pc = self.read_csr(CSR_SEPC) & !(self.ialign() - 1);
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::*;
+	use crate::snapshot::Snapshot;
+
+	#[test]
+	fn registers_are_named_either_way()
+	{
+		let abi = "a0".parse::<RegisterNames>().unwrap();
+		let numbered = "x10".parse::<RegisterNames>().unwrap();
+
+		assert_eq!(abi as usize, 10);
+		assert_eq!(numbered, abi);
+		assert_eq!(register_index("fp"), Some(8));
+		assert_eq!(RegisterNames::from_index(2).unwrap().to_string(), "sp");
+		assert!("x32".parse::<RegisterNames>().is_err());
+		assert!("x010".parse::<RegisterNames>().is_err());
+		assert!("x+1".parse::<RegisterNames>().is_err());
+		assert!("q0".parse::<RegisterNames>().is_err());
+	}
+
+	#[test]
+	fn take_trap_stacks_interrupt_enable()
+	{
+		let mut hart = Hart {
+			pc: 0x8000_0100,
+			..Default::default()
+		};
+		hart.write_csr(CSR_MTVEC, 0x8000_4000);
+		hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+
+		hart.take_trap(CAUSE_BREAKPOINT, 0x8000_0100);
+
+		let mstatus = hart.read_csr(CSR_MSTATUS);
+		assert_eq!(hart.pc, 0x8000_4000);
+		assert_eq!(hart.read_csr(CSR_MEPC), 0x8000_0100);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_BREAKPOINT);
+		assert_eq!(mstatus & MSTATUS_MIE, 0);
+		assert_eq!(mstatus & MSTATUS_MPIE, MSTATUS_MPIE);
+		assert_eq!(mstatus & MSTATUS_MPP_MASK, MSTATUS_MPP_MASK);
+	}
+
+	#[test]
+	fn vectored_mtvec_only_offsets_interrupts()
+	{
+		let mut hart = Hart::default();
+		hart.write_csr(CSR_MTVEC, 0x8000_4000 | MTVEC_MODE_VECTORED);
+
+		hart.take_trap(CAUSE_INTERRUPT | 7, 0);
+		assert_eq!(hart.pc, 0x8000_4000 + 4 * 7);
+
+		hart.take_trap(CAUSE_ILLEGAL_INSN, 0);
+		assert_eq!(hart.pc, 0x8000_4000);
+	}
+
+	#[test]
+	fn rv32_interrupts_set_the_top_bit_of_xcause()
+	{
+		let mut hart = Hart::default();
+		hart.set_xlen(Xlen::Rv32);
+
+		hart.take_trap(CAUSE_MACHINE_TIMER, 0);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), 0x8000_0007);
+
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.write_csr(CSR_MIDELEG, 1 << 5);
+		hart.take_trap(CAUSE_INTERRUPT | 5, 0);
+		assert_eq!(hart.read_csr(CSR_SCAUSE), 0x8000_0005);
+	}
+
+	#[test]
+	fn machine_interrupts_are_always_enabled_below_machine_mode()
+	{
+		let mut hart = Hart::default();
+		hart.write_csr(CSR_MIE, MIP_MTIP);
+		hart.set_pending(MIP_MTIP, true);
+
+		assert_eq!(hart.pending_interrupt(), None);
+		for privilege in [PRIV_SUPERVISOR, PRIV_USER] {
+			hart.privilege = privilege;
+			assert_eq!(hart.pending_interrupt(), Some(CAUSE_MACHINE_TIMER));
+		}
+
+		hart.privilege = PRIV_MACHINE;
+		hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		assert_eq!(hart.pending_interrupt(), Some(CAUSE_MACHINE_TIMER));
+	}
+
+	#[test]
+	fn delegated_interrupts_follow_sie()
+	{
+		let mut hart = Hart::default();
+		hart.write_csr(CSR_MIDELEG, MIP_STIP | MIP_SSIP);
+		hart.write_csr(CSR_MIE, MIP_STIP | MIP_SSIP);
+		hart.write_csr(CSR_MIP, MIP_STIP | MIP_SSIP);
+
+		// Never taken in M-mode, even with MIE set
+		hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		assert_eq!(hart.pending_interrupt(), None);
+
+		hart.privilege = PRIV_SUPERVISOR;
+		assert_eq!(hart.pending_interrupt(), None);
+		hart.write_csr(CSR_MSTATUS, MSTATUS_SIE);
+		assert_eq!(hart.pending_interrupt(), Some(CAUSE_SUPERVISOR_SOFTWARE));
+
+		hart.privilege = PRIV_USER;
+		hart.write_csr(CSR_MSTATUS, 0);
+		assert_eq!(hart.pending_interrupt(), Some(CAUSE_SUPERVISOR_SOFTWARE));
+
+		// Anything for M-mode goes first
+		hart.set_pending(MIP_MTIP, true);
+		hart.write_csr(CSR_MIE, MIP_STIP | MIP_SSIP | MIP_MTIP);
+		assert_eq!(hart.pending_interrupt(), Some(CAUSE_MACHINE_TIMER));
+	}
+
+	#[test]
+	fn sie_and_sip_only_show_delegated_interrupts()
+	{
+		let mut hart = Hart::default();
+		hart.write_csr(CSR_MIE, MIP_MTIP | MIP_STIP | MIP_SSIP);
+		hart.write_csr(CSR_MIP, MIP_MTIP | MIP_STIP);
+		assert_eq!(hart.read_csr(CSR_SIE), 0);
+
+		hart.csr_write(CSR_MIDELEG, MIP_MTIP | MIP_STIP).unwrap();
+		assert_eq!(hart.read_csr(CSR_MIDELEG), MIP_STIP);
+		assert_eq!(hart.read_csr(CSR_SIE), MIP_STIP);
+		assert_eq!(hart.read_csr(CSR_SIP), MIP_STIP);
+
+		hart.write_csr(CSR_SIE, 0);
+		assert_eq!(hart.read_csr(CSR_MIE), MIP_MTIP | MIP_SSIP);
+
+		// Only the software interrupt can be raised through sip, & only
+		// once it's delegated
+		hart.write_csr(CSR_MIDELEG, MIP_STIP | MIP_SSIP);
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.csr_write(CSR_SIP, MIP_SSIP).unwrap();
+		assert_eq!(hart.read_csr(CSR_SIP), MIP_STIP | MIP_SSIP);
+
+		// As M-mode's interrupts are raised by devices
+		hart.privilege = PRIV_MACHINE;
+		hart.csr_write(CSR_MIP, 0).unwrap();
+		assert_eq!(hart.read_csr(CSR_MIP), MIP_MTIP);
+	}
+
+	#[test]
+	fn csr_names_match_their_numbers()
+	{
+		assert_eq!(csr_name(0x340), Some("mscratch"));
+		assert_eq!(csr_name(0x140), Some("sscratch"));
+		assert_eq!(csr_name(0xc00), Some("cycle"));
+		assert_eq!(csr_name(0x7c0), None);
+	}
+
+	#[test]
+	fn mprv_only_changes_data_privilege()
+	{
+		let mut hart = Hart::default();
+
+		hart.write_csr(CSR_MSTATUS, PRIV_USER << MSTATUS_MPP_SHIFT);
+		assert_eq!(hart.data_privilege(), PRIV_MACHINE);
+
+		hart.write_csr(
+			CSR_MSTATUS,
+			MSTATUS_MPRV | (PRIV_USER << MSTATUS_MPP_SHIFT),
+		);
+		assert_eq!(hart.data_privilege(), PRIV_USER);
+	}
+
+	#[test]
+	fn rv32_keeps_registers_32_bit()
+	{
+		let mut hart = Hart::default();
+		assert_eq!(hart.read_csr(CSR_MISA) >> 62, 2);
+
+		hart.set_xlen(Xlen::Rv32);
+		assert_eq!(hart.read_csr(CSR_MISA) >> 30, 1);
+
+		hart.write_register(10_usize, 0x1_8000_0000);
+		assert_eq!(hart.read_register(10_usize), 0xffff_ffff_8000_0000);
+		assert_eq!(hart.zero_extend(hart.read_register(10_usize)), 0x8000_0000);
+
+		hart.write_csr(CSR_MSCRATCH, 0xffff_ffff_8000_0000);
+		assert_eq!(hart.read_csr(CSR_MSCRATCH), 0x8000_0000);
+	}
+
+	#[test]
+	fn traps_from_user_mode_return_to_it()
+	{
+		let mut hart = Hart {
+			pc: 0x1000,
+			privilege: PRIV_USER,
+			..Default::default()
+		};
+
+		hart.take_trap(CAUSE_ECALL_UMODE, 0);
+		let mstatus = hart.read_csr(CSR_MSTATUS);
+		assert_eq!(hart.privilege, PRIV_MACHINE);
+		assert_eq!(mstatus & MSTATUS_MPP_MASK, PRIV_USER << MSTATUS_MPP_SHIFT);
+
+		hart.write_csr(CSR_MEPC, 0x1004);
+		hart.trap_return();
+		assert_eq!(hart.privilege, PRIV_USER);
+		assert_eq!(hart.pc, 0x1004);
+	}
+
+	#[test]
+	fn sret_returns_to_spp()
+	{
+		let mut hart = Hart {
+			privilege: PRIV_SUPERVISOR,
+			..Default::default()
+		};
+		hart.write_csr(CSR_SSTATUS, MSTATUS_SPP | MSTATUS_SPIE);
+		hart.write_csr(CSR_SEPC, 0x2000);
+
+		hart.supervisor_trap_return();
+		assert_eq!(hart.privilege, PRIV_SUPERVISOR);
+		assert_eq!(hart.pc, 0x2000);
+		assert_eq!(hart.read_csr(CSR_SSTATUS), MSTATUS_SIE | MSTATUS_SPIE);
+
+		hart.supervisor_trap_return();
+		assert_eq!(hart.privilege, PRIV_USER);
+	}
+
+	#[test]
+	fn delegation_never_lowers_privilege()
+	{
+		let mut hart = Hart::default();
+		hart.write_csr(CSR_MEDELEG, 1 << CAUSE_ILLEGAL_INSN);
+		hart.write_csr(CSR_MTVEC, 0x8000_4000);
+		hart.write_csr(CSR_STVEC, 0x8000_8000);
+
+		hart.take_trap(CAUSE_ILLEGAL_INSN, 0);
+		assert_eq!(hart.pc, 0x8000_4000);
+		assert_eq!(hart.privilege, PRIV_MACHINE);
+
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.take_trap(CAUSE_ILLEGAL_INSN, 0);
+		assert_eq!(hart.pc, 0x8000_8000);
+		assert_eq!(hart.privilege, PRIV_SUPERVISOR);
+		assert_ne!(hart.read_csr(CSR_SSTATUS) & MSTATUS_SPP, 0);
+	}
+
+	#[test]
+	fn sstatus_only_shows_supervisor_bits()
+	{
+		let mut hart = Hart::default();
+
+		hart.write_csr(CSR_MSTATUS, MSTATUS_MIE | MSTATUS_SIE);
+		assert_eq!(hart.read_csr(CSR_SSTATUS), MSTATUS_SIE);
+
+		hart.write_csr(CSR_SSTATUS, 0);
+		assert_eq!(hart.read_csr(CSR_MSTATUS), MSTATUS_MIE);
+	}
+
+	#[test]
+	fn identification_csrs_describe_the_hart()
+	{
+		let hart = Hart::new(3);
+		let misa = hart.read_csr(CSR_MISA);
+		let bit = |extension: u8| return 1 << (extension - b'A');
+
+		assert_eq!(misa >> 62, 2);
+		for extension in [b'I', b'M', b'A', b'F', b'C', b'S', b'U'] {
+			assert_ne!(misa & bit(extension), 0);
+		}
+		assert_eq!(misa & (bit(b'D') | bit(b'E')), 0);
+
+		assert_eq!(hart.read_csr(CSR_MHARTID), 3);
+		assert_eq!(hart.read_csr(CSR_MVENDORID), MVENDORID);
+		assert_eq!(hart.read_csr(CSR_MARCHID), MARCHID);
+		assert_eq!(hart.read_csr(CSR_MIMPID), mimpid());
+	}
+
+	#[test]
+	fn restore_rejects_impossible_harts()
+	{
+		let restore = |out: Encoder| {
+			let mut snapshot = Snapshot::default();
+			snapshot.add("hart", out);
+			return Hart::new(0)
+				.restore(&mut snapshot.section("hart").unwrap());
+		};
+
+		let mut hart = Hart::new(0);
+		hart.privilege = 0b10;
+		let mut out = Encoder::default();
+		hart.save(&mut out);
+		assert!(restore(out).is_err());
+
+		// id, pc, started & then xlen
+		let mut out = Encoder::default();
+		for value in [0, 0x8000_0000, 1, 48] {
+			out.u64(value);
+		}
+		assert!(restore(out).is_err());
 	}
 }
@@ -0,0 +1,716 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+// Compressed instructions are handled by expanding them into the 32-bit
+// instruction they are shorthand for, and running that instead.
+
+use super::float::{FUNC3_FLW, FUNC3_FSW};
+use super::{
+	FUNC3_ADD, FUNC3_ADDI, FUNC3_ADDIW, FUNC3_ADDW, FUNC3_AND, FUNC3_ANDI,
+	FUNC3_BEQ, FUNC3_BNE, FUNC3_LD, FUNC3_LW, FUNC3_OR, FUNC3_PRIV, FUNC3_SD,
+	FUNC3_SHIFT, FUNC3_SLLI, FUNC3_SRAI, FUNC3_SRLI, FUNC3_SUB, FUNC3_SUBW,
+	FUNC3_SW, FUNC3_XOR, FUNC7_ADD, FUNC7_AND, FUNC7_OR, FUNC7_SHIFT,
+	FUNC7_SLLI, FUNC7_SRAI, FUNC7_SRLI, FUNC7_SUB, FUNC7_XOR,
+	IMM11_5_STYPE_SHIFT, IMM4_0_STYPE_SHIFT, IMM_EBREAK, IMM_ITYPE_SHIFT,
+	IMM_UTYPE_SHIFT, OPCODE_BRANCH, OPCODE_INT_REG_IMM, OPCODE_INT_REG_IMM_32,
+	OPCODE_INT_REG_REG, OPCODE_INT_REG_REG_32, OPCODE_JAL, OPCODE_JALR,
+	OPCODE_LOAD, OPCODE_LOAD_FP, OPCODE_LUI, OPCODE_STORE, OPCODE_STORE_FP,
+	OPCODE_SYSTEM, RD_SHIFT, RS1_SHIFT, RS2_SHIFT,
+};
+use crate::gen_mask;
+use crate::hart::Xlen;
+use crate::sign_extend;
+
+const QUADRANT_MASK: u16 = 0b11;
+const QUADRANT_0: u16 = 0b00;
+const QUADRANT_1: u16 = 0b01;
+const QUADRANT_2: u16 = 0b10;
+
+// Where RV32 & RV64 differ, RV32 has the single precision FP loads &
+// stores, or C.JAL, in place of RV64's doubleword ones & C.ADDIW. The
+// double precision ones need D, so are missing along with it.
+const C0_ADDI4SPN: u32 = 0b000;
+const C0_LW: u32 = 0b010;
+const C0_LD_FLW: u32 = 0b011;
+const C0_SW: u32 = 0b110;
+const C0_SD_FSW: u32 = 0b111;
+
+const C1_ADDI: u32 = 0b000;
+const C1_ADDIW_JAL: u32 = 0b001;
+const C1_LI: u32 = 0b010;
+// C.ADDI16SP is C.LUI with sp as rd
+const C1_LUI_ADDI16SP: u32 = 0b011;
+const C1_MISC_ALU: u32 = 0b100;
+const C1_J: u32 = 0b101;
+const C1_BEQZ: u32 = 0b110;
+const C1_BNEZ: u32 = 0b111;
+
+// The C1_MISC_ALU operations, picked by bits 11:10
+const MISC_ALU_SRLI: u32 = 0b00;
+const MISC_ALU_SRAI: u32 = 0b01;
+const MISC_ALU_ANDI: u32 = 0b10;
+const MISC_ALU_REG_REG: u32 = 0b11;
+
+const C2_SLLI: u32 = 0b000;
+const C2_LWSP: u32 = 0b010;
+const C2_LDSP_FLWSP: u32 = 0b011;
+// C.JR, C.MV, C.EBREAK, C.JALR & C.ADD, told apart by bit 12 & the
+// register fields
+const C2_JR_MV_ADD: u32 = 0b100;
+const C2_SWSP: u32 = 0b110;
+const C2_SDSP_FSWSP: u32 = 0b111;
+
+const REG_ZERO: u32 = 0;
+const REG_RA: u32 = 1;
+const REG_SP: u32 = 2;
+
+/// Extract bits high:low of a compressed instruction, shifted down to bit 0.
+fn bits(input: u16, high: u32, low: u32) -> u32
+{
+	return (input as u32 & gen_mask!(high, low, u32)) >> low;
+}
+
+/// The 3-bit register fields in the CL & CS formats can only name x8-x15.
+fn compressed_reg(input: u16, low: u32) -> u32
+{
+	return 8 + bits(input, low + 2, low);
+}
+
+// The offsets are all unsigned & scaled by the access size, so the low bits
+// are never encoded and the remaining ones are scattered about the
+// instruction differently for each format. The comments give the offset bits
+// held in each instruction field, from the top of the instruction down.
+
+/// C.LW & C.SW: [12:10] uimm[5:3], [6] uimm[2], [5] uimm[6]
+fn word_offset(input: u16) -> u32
+{
+	return bits(input, 12, 10) << 3
+		| bits(input, 6, 6) << 2
+		| bits(input, 5, 5) << 6;
+}
+
+/// C.LD & C.SD: [12:10] uimm[5:3], [6:5] uimm[7:6]
+fn doubleword_offset(input: u16) -> u32
+{
+	return bits(input, 12, 10) << 3 | bits(input, 6, 5) << 6;
+}
+
+/// C.LWSP: [12] uimm[5], [6:4] uimm[4:2], [3:2] uimm[7:6]
+fn lwsp_offset(input: u16) -> u32
+{
+	return bits(input, 12, 12) << 5
+		| bits(input, 6, 4) << 2
+		| bits(input, 3, 2) << 6;
+}
+
+/// C.LDSP: [12] uimm[5], [6:5] uimm[4:3], [4:2] uimm[8:6]
+fn ldsp_offset(input: u16) -> u32
+{
+	return bits(input, 12, 12) << 5
+		| bits(input, 6, 5) << 3
+		| bits(input, 4, 2) << 6;
+}
+
+/// C.SWSP: [12:9] uimm[5:2], [8:7] uimm[7:6]
+fn swsp_offset(input: u16) -> u32
+{
+	return bits(input, 12, 9) << 2 | bits(input, 8, 7) << 6;
+}
+
+/// C.SDSP: [12:10] uimm[5:3], [9:7] uimm[8:6]
+fn sdsp_offset(input: u16) -> u32
+{
+	return bits(input, 12, 10) << 3 | bits(input, 9, 7) << 6;
+}
+
+/// C.ADDI4SPN: [12:11] uimm[5:4], [10:7] uimm[9:6], [6] uimm[2], [5] uimm[3]
+fn addi4spn_imm(input: u16) -> u32
+{
+	return bits(input, 12, 11) << 4
+		| bits(input, 10, 7) << 6
+		| bits(input, 6, 6) << 2
+		| bits(input, 5, 5) << 3;
+}
+
+/// C.ADDI, C.ADDIW, C.LI & C.ANDI: [12] imm[5], [6:2] imm[4:0], sign
+/// extended. C.LUI's is the same, but for bits 17:12.
+fn addi_imm(input: u16) -> i32
+{
+	let imm = bits(input, 12, 12) << 5 | bits(input, 6, 2);
+
+	return sign_extend!(imm, 5, i32);
+}
+
+/// C.ADDI16SP: [12] imm[9], [6] imm[4], [5] imm[6], [4:3] imm[8:7],
+/// [2] imm[5], sign extended
+fn addi16sp_imm(input: u16) -> i32
+{
+	let imm = bits(input, 12, 12) << 9
+		| bits(input, 6, 6) << 4
+		| bits(input, 5, 5) << 6
+		| bits(input, 4, 3) << 7
+		| bits(input, 2, 2) << 5;
+
+	return sign_extend!(imm, 9, i32);
+}
+
+/// C.J: [12] imm[11], [11] imm[4], [10:9] imm[9:8], [8] imm[10], [7] imm[6],
+/// [6] imm[7], [5:3] imm[3:1], [2] imm[5], sign extended
+fn jump_offset(input: u16) -> i32
+{
+	let offset = bits(input, 12, 12) << 11
+		| bits(input, 11, 11) << 4
+		| bits(input, 10, 9) << 8
+		| bits(input, 8, 8) << 10
+		| bits(input, 7, 7) << 6
+		| bits(input, 6, 6) << 7
+		| bits(input, 5, 3) << 1
+		| bits(input, 2, 2) << 5;
+
+	return sign_extend!(offset, 11, i32);
+}
+
+/// C.BEQZ & C.BNEZ: [12] imm[8], [11:10] imm[4:3], [6:5] imm[7:6],
+/// [4:3] imm[2:1], [2] imm[5], sign extended
+fn branch_offset(input: u16) -> i32
+{
+	let offset = bits(input, 12, 12) << 8
+		| bits(input, 11, 10) << 3
+		| bits(input, 6, 5) << 6
+		| bits(input, 4, 3) << 1
+		| bits(input, 2, 2) << 5;
+
+	return sign_extend!(offset, 8, i32);
+}
+
+fn itype(opcode: u32, func3: u32, rd: u32, rs1: u32, imm: i32) -> u32
+{
+	return (imm as u32 & gen_mask!(11, 0, u32)) << IMM_ITYPE_SHIFT
+		| rs1 << RS1_SHIFT
+		| func3 << FUNC3_SHIFT
+		| rd << RD_SHIFT
+		| opcode;
+}
+
+fn load(func3: u32, rd: u32, rs1: u32, offset: u32) -> u32
+{
+	return itype(OPCODE_LOAD, func3, rd, rs1, offset as i32);
+}
+
+fn rtype(
+	opcode: u32, func3: u32, func7: u32, rd: u32, rs1: u32, rs2: u32,
+) -> u32
+{
+	return func7 << FUNC7_SHIFT
+		| rs2 << RS2_SHIFT
+		| rs1 << RS1_SHIFT
+		| func3 << FUNC3_SHIFT
+		| rd << RD_SHIFT
+		| opcode;
+}
+
+fn add(rd: u32, rs1: u32, rs2: u32) -> u32
+{
+	return rtype(OPCODE_INT_REG_REG, FUNC3_ADD, FUNC7_ADD, rd, rs1, rs2);
+}
+
+/// A shift of rd by the amount in [12] & [6:2]. RV32 only has room for 5
+/// bits of it, so the sixth being set is reserved.
+fn shift(func3: u32, func7: u32, rd: u32, input: u16, xlen: Xlen)
+	-> Option<u32>
+{
+	let shamt = bits(input, 12, 12) << 5 | bits(input, 6, 2);
+	if shamt >= xlen.bits() {
+		return None;
+	}
+
+	let imm = (func7 << 5 | shamt) as i32;
+	return Some(itype(OPCODE_INT_REG_IMM, func3, rd, rd, imm));
+}
+
+fn lui(rd: u32, imm: i32) -> u32
+{
+	return (imm as u32) << IMM_UTYPE_SHIFT | rd << RD_SHIFT | OPCODE_LUI;
+}
+
+fn jal(rd: u32, offset: i32) -> u32
+{
+	let offset = offset as u32;
+
+	return bits_of(offset, 20, 20) << 31
+		| bits_of(offset, 10, 1) << 21
+		| bits_of(offset, 11, 11) << 20
+		| bits_of(offset, 19, 12) << 12
+		| rd << RD_SHIFT
+		| OPCODE_JAL;
+}
+
+fn branch(func3: u32, rs1: u32, rs2: u32, offset: i32) -> u32
+{
+	let offset = offset as u32;
+
+	return bits_of(offset, 12, 12) << 31
+		| bits_of(offset, 10, 5) << 25
+		| rs2 << RS2_SHIFT
+		| rs1 << RS1_SHIFT
+		| func3 << FUNC3_SHIFT
+		| bits_of(offset, 4, 1) << 8
+		| bits_of(offset, 11, 11) << 7
+		| OPCODE_BRANCH;
+}
+
+/// Extract bits high:low of an offset, shifted down to bit 0.
+fn bits_of(offset: u32, high: u32, low: u32) -> u32
+{
+	return (offset & gen_mask!(high, low, u32)) >> low;
+}
+
+fn stype(opcode: u32, func3: u32, rs1: u32, rs2: u32, offset: u32) -> u32
+{
+	return (offset >> 5) << IMM11_5_STYPE_SHIFT
+		| rs2 << RS2_SHIFT
+		| rs1 << RS1_SHIFT
+		| func3 << FUNC3_SHIFT
+		| (offset & gen_mask!(4, 0, u32)) << IMM4_0_STYPE_SHIFT
+		| opcode;
+}
+
+fn store(func3: u32, rs1: u32, rs2: u32, offset: u32) -> u32
+{
+	return stype(OPCODE_STORE, func3, rs1, rs2, offset);
+}
+
+/// C.SRLI, C.SRAI, C.ANDI & the register to register operations on x8-x15.
+fn expand_misc_alu(input: u16, xlen: Xlen) -> Option<u32>
+{
+	let rd = compressed_reg(input, 7);
+	let rs2 = compressed_reg(input, 2);
+
+	match bits(input, 11, 10) {
+		MISC_ALU_SRLI => return shift(FUNC3_SRLI, FUNC7_SRLI, rd, input, xlen),
+		MISC_ALU_SRAI => return shift(FUNC3_SRAI, FUNC7_SRAI, rd, input, xlen),
+		MISC_ALU_ANDI => {
+			let imm = addi_imm(input);
+			return Some(itype(OPCODE_INT_REG_IMM, FUNC3_ANDI, rd, rd, imm));
+		},
+		MISC_ALU_REG_REG => (),
+		_ => unreachable!(),
+	}
+
+	// Bit 12 picks the 32-bit forms, which only RV64 has, & bits 6:5 the
+	// operation
+	let (opcode, func3, func7) = match (bits(input, 12, 12), bits(input, 6, 5))
+	{
+		(0, 0b00) => (OPCODE_INT_REG_REG, FUNC3_SUB, FUNC7_SUB),
+		(0, 0b01) => (OPCODE_INT_REG_REG, FUNC3_XOR, FUNC7_XOR),
+		(0, 0b10) => (OPCODE_INT_REG_REG, FUNC3_OR, FUNC7_OR),
+		(0, 0b11) => (OPCODE_INT_REG_REG, FUNC3_AND, FUNC7_AND),
+		(1, 0b00) if xlen == Xlen::Rv64 => {
+			(OPCODE_INT_REG_REG_32, FUNC3_SUBW, FUNC7_SUB)
+		},
+		(1, 0b01) if xlen == Xlen::Rv64 => {
+			(OPCODE_INT_REG_REG_32, FUNC3_ADDW, FUNC7_ADD)
+		},
+		_ => return None,
+	};
+
+	return Some(rtype(opcode, func3, func7, rd, rd, rs2));
+}
+
+/// Expand a compressed instruction into its 32-bit equivalent for a hart of
+/// xlen bits, or None if it is reserved or needs the D extension.
+pub fn expand(input: u16, xlen: Xlen) -> Option<u32>
+{
+	let func3 = bits(input, 15, 13);
+	let rv64 = xlen == Xlen::Rv64;
+
+	match (input & QUADRANT_MASK, func3) {
+		(QUADRANT_0, C0_ADDI4SPN) => {
+			// A zero immediate is reserved, which makes all zeroes illegal
+			let imm = addi4spn_imm(input);
+			if imm == 0 {
+				return None;
+			}
+			let rd = compressed_reg(input, 2);
+			let imm = imm as i32;
+			return Some(itype(
+				OPCODE_INT_REG_IMM,
+				FUNC3_ADDI,
+				rd,
+				REG_SP,
+				imm,
+			));
+		},
+		(QUADRANT_0, C0_LW) => {
+			let rd = compressed_reg(input, 2);
+			let rs1 = compressed_reg(input, 7);
+			return Some(load(FUNC3_LW, rd, rs1, word_offset(input)));
+		},
+		(QUADRANT_0, C0_LD_FLW) => {
+			let rd = compressed_reg(input, 2);
+			let rs1 = compressed_reg(input, 7);
+			if !rv64 {
+				let offset = word_offset(input) as i32;
+				return Some(itype(OPCODE_LOAD_FP, FUNC3_FLW, rd, rs1, offset));
+			}
+			return Some(load(FUNC3_LD, rd, rs1, doubleword_offset(input)));
+		},
+		(QUADRANT_0, C0_SW) => {
+			let rs2 = compressed_reg(input, 2);
+			let rs1 = compressed_reg(input, 7);
+			return Some(store(FUNC3_SW, rs1, rs2, word_offset(input)));
+		},
+		(QUADRANT_0, C0_SD_FSW) => {
+			let rs2 = compressed_reg(input, 2);
+			let rs1 = compressed_reg(input, 7);
+			if !rv64 {
+				let offset = word_offset(input);
+				return Some(stype(
+					OPCODE_STORE_FP,
+					FUNC3_FSW,
+					rs1,
+					rs2,
+					offset,
+				));
+			}
+			return Some(store(FUNC3_SD, rs1, rs2, doubleword_offset(input)));
+		},
+		(QUADRANT_1, C1_ADDI) => {
+			let rd = bits(input, 11, 7);
+			let imm = addi_imm(input);
+			return Some(itype(OPCODE_INT_REG_IMM, FUNC3_ADDI, rd, rd, imm));
+		},
+		(QUADRANT_1, C1_ADDIW_JAL) => {
+			if !rv64 {
+				return Some(jal(REG_RA, jump_offset(input)));
+			}
+			// rd == x0 is reserved for C.ADDIW
+			let rd = bits(input, 11, 7);
+			if rd == REG_ZERO {
+				return None;
+			}
+			let imm = addi_imm(input);
+			return Some(itype(
+				OPCODE_INT_REG_IMM_32,
+				FUNC3_ADDIW,
+				rd,
+				rd,
+				imm,
+			));
+		},
+		(QUADRANT_1, C1_LI) => {
+			let rd = bits(input, 11, 7);
+			let imm = addi_imm(input);
+			return Some(itype(
+				OPCODE_INT_REG_IMM,
+				FUNC3_ADDI,
+				rd,
+				REG_ZERO,
+				imm,
+			));
+		},
+		(QUADRANT_1, C1_LUI_ADDI16SP) => {
+			// A zero immediate is reserved for both
+			let rd = bits(input, 11, 7);
+			if rd == REG_SP {
+				let imm = addi16sp_imm(input);
+				if imm == 0 {
+					return None;
+				}
+				return Some(itype(
+					OPCODE_INT_REG_IMM,
+					FUNC3_ADDI,
+					REG_SP,
+					REG_SP,
+					imm,
+				));
+			}
+
+			let imm = addi_imm(input);
+			if imm == 0 {
+				return None;
+			}
+			return Some(lui(rd, imm));
+		},
+		(QUADRANT_1, C1_MISC_ALU) => return expand_misc_alu(input, xlen),
+		(QUADRANT_1, C1_J) => {
+			return Some(jal(REG_ZERO, jump_offset(input)));
+		},
+		(QUADRANT_1, C1_BEQZ) => {
+			let rs1 = compressed_reg(input, 7);
+			let offset = branch_offset(input);
+			return Some(branch(FUNC3_BEQ, rs1, REG_ZERO, offset));
+		},
+		(QUADRANT_1, C1_BNEZ) => {
+			let rs1 = compressed_reg(input, 7);
+			let offset = branch_offset(input);
+			return Some(branch(FUNC3_BNE, rs1, REG_ZERO, offset));
+		},
+		(QUADRANT_2, C2_JR_MV_ADD) => {
+			let rd = bits(input, 11, 7);
+			let rs2 = bits(input, 6, 2);
+			let link = bits(input, 12, 12) != 0;
+
+			match (link, rd, rs2) {
+				// C.JR with rs1 == x0 is reserved
+				(false, REG_ZERO, REG_ZERO) => return None,
+				(true, REG_ZERO, REG_ZERO) => {
+					return Some(itype(
+						OPCODE_SYSTEM,
+						FUNC3_PRIV,
+						REG_ZERO,
+						REG_ZERO,
+						IMM_EBREAK,
+					));
+				},
+				(false, _, REG_ZERO) => {
+					return Some(itype(OPCODE_JALR, 0, REG_ZERO, rd, 0));
+				},
+				(true, _, REG_ZERO) => {
+					return Some(itype(OPCODE_JALR, 0, REG_RA, rd, 0));
+				},
+				(false, _, _) => return Some(add(rd, REG_ZERO, rs2)),
+				(true, _, _) => return Some(add(rd, rd, rs2)),
+			}
+		},
+		(QUADRANT_2, C2_SLLI) => {
+			let rd = bits(input, 11, 7);
+			return shift(FUNC3_SLLI, FUNC7_SLLI, rd, input, xlen);
+		},
+		(QUADRANT_2, C2_LWSP) => {
+			// rd == x0 is reserved for the SP-relative loads
+			let rd = bits(input, 11, 7);
+			if rd == 0 {
+				return None;
+			}
+			return Some(load(FUNC3_LW, rd, REG_SP, lwsp_offset(input)));
+		},
+		(QUADRANT_2, C2_LDSP_FLWSP) => {
+			let rd = bits(input, 11, 7);
+			if !rv64 {
+				let offset = lwsp_offset(input) as i32;
+				return Some(itype(
+					OPCODE_LOAD_FP,
+					FUNC3_FLW,
+					rd,
+					REG_SP,
+					offset,
+				));
+			}
+			if rd == 0 {
+				return None;
+			}
+			return Some(load(FUNC3_LD, rd, REG_SP, ldsp_offset(input)));
+		},
+		(QUADRANT_2, C2_SWSP) => {
+			let rs2 = bits(input, 6, 2);
+			return Some(store(FUNC3_SW, REG_SP, rs2, swsp_offset(input)));
+		},
+		(QUADRANT_2, C2_SDSP_FSWSP) => {
+			let rs2 = bits(input, 6, 2);
+			if !rv64 {
+				let offset = swsp_offset(input);
+				return Some(stype(
+					OPCODE_STORE_FP,
+					FUNC3_FSW,
+					REG_SP,
+					rs2,
+					offset,
+				));
+			}
+			return Some(store(FUNC3_SD, REG_SP, rs2, sdsp_offset(input)));
+		},
+		_ => return None,
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::expand;
+	use crate::bus::Bus;
+	use crate::hart::Xlen;
+	use crate::insn::Insn;
+	use crate::platform::Platform;
+
+	/// Expand & decode, returning (base register, offset, data register).
+	fn operands(input: u16) -> (u32, i32, u32)
+	{
+		let insn = Insn::from(expand(input, Xlen::Rv64).unwrap());
+		let data = if insn.rd != 0 { insn.rd } else { insn.rs2 };
+
+		return (insn.rs1, insn.imm, data);
+	}
+
+	#[test]
+	fn register_relative_offsets_are_scaled()
+	{
+		// c.lw a0, 4(a1)
+		assert_eq!(operands(0x41c8), (11, 4, 10));
+		// c.lw a0, 124(a1)
+		assert_eq!(operands(0x5de8), (11, 124, 10));
+		// c.ld s0, 8(s1)
+		assert_eq!(operands(0x6480), (9, 8, 8));
+		// c.ld s0, 248(s1)
+		assert_eq!(operands(0x7ce0), (9, 248, 8));
+		// c.sw a2, 64(a3)
+		assert_eq!(operands(0xc2b0), (13, 64, 12));
+		// c.sw a2, 124(a3)
+		assert_eq!(operands(0xdef0), (13, 124, 12));
+		// c.sd a4, 136(a5)
+		assert_eq!(operands(0xe7d8), (15, 136, 14));
+		// c.sd a4, 248(a5)
+		assert_eq!(operands(0xfff8), (15, 248, 14));
+	}
+
+	#[test]
+	fn stack_relative_offsets_are_scaled()
+	{
+		// c.lwsp t0, 36(sp)
+		assert_eq!(operands(0x5292), (2, 36, 5));
+		// c.lwsp ra, 252(sp)
+		assert_eq!(operands(0x50fe), (2, 252, 1));
+		// c.ldsp s1, 72(sp)
+		assert_eq!(operands(0x64a6), (2, 72, 9));
+		// c.ldsp s1, 504(sp)
+		assert_eq!(operands(0x74fe), (2, 504, 9));
+		// c.swsp t1, 20(sp)
+		assert_eq!(operands(0xca1a), (2, 20, 6));
+		// c.swsp t1, 252(sp)
+		assert_eq!(operands(0xdf9a), (2, 252, 6));
+		// c.sdsp s2, 264(sp)
+		assert_eq!(operands(0xe64a), (2, 264, 18));
+		// c.sdsp s2, 504(sp)
+		assert_eq!(operands(0xffca), (2, 504, 18));
+	}
+
+	#[test]
+	fn c_addi_executes_like_addi()
+	{
+		for (compressed, full) in [
+			(0x0505, 0x0015_0513), // c.addi a0, 1
+			(0x157d, 0xfff5_0513), // c.addi a0, -1
+		] {
+			assert_eq!(expand(compressed, Xlen::Rv64), Some(full));
+
+			let mut results = Vec::new();
+			for mut insn in [
+				Insn::from_compressed(compressed, Xlen::Rv64).unwrap(),
+				Insn::from(full),
+			] {
+				let mut platform = Platform::default();
+				platform.hart.write_register(10_usize, 0x100);
+				insn.handle(&mut platform).unwrap();
+				results.push((
+					insn.mnemonic,
+					platform.hart.read_register(10_usize),
+				));
+			}
+
+			assert_eq!(results[0], results[1]);
+		}
+	}
+
+	#[test]
+	fn control_flow_and_register_moves_expand()
+	{
+		// c.li a1, -3
+		assert_eq!(expand(0x55f5, Xlen::Rv64), Some(0xffd0_0593));
+		// c.j 8 & c.j -2
+		assert_eq!(expand(0xa021, Xlen::Rv64), Some(0x0080_006f));
+		assert_eq!(expand(0xbffd, Xlen::Rv64), Some(0xffff_f06f));
+		// c.beqz s0, 8 & c.bnez s0, -2
+		assert_eq!(expand(0xc401, Xlen::Rv64), Some(0x0004_0463));
+		assert_eq!(expand(0xfc7d, Xlen::Rv64), Some(0xfe04_1fe3));
+		// c.jr ra & c.jalr a5
+		assert_eq!(expand(0x8082, Xlen::Rv64), Some(0x0000_8067));
+		assert_eq!(expand(0x9782, Xlen::Rv64), Some(0x0007_80e7));
+		// c.mv a0, a1 & c.add a0, a1
+		assert_eq!(expand(0x852e, Xlen::Rv64), Some(0x00b0_0533));
+		assert_eq!(expand(0x952e, Xlen::Rv64), Some(0x00b5_0533));
+		// c.ebreak
+		assert_eq!(expand(0x9002, Xlen::Rv64), Some(0x0010_0073));
+	}
+
+	#[test]
+	fn arithmetic_expands()
+	{
+		for (compressed, full) in [
+			(0x0808, 0x0101_0513), // c.addi4spn a0, sp, 16
+			(0x1fe4, 0x3fc1_0493), // c.addi4spn s1, sp, 1020
+			(0x357d, 0xfff5_051b), // c.addiw a0, -1
+			(0x7101, 0xe001_0113), // c.addi16sp sp, -512
+			(0x617d, 0x1f01_0113), // c.addi16sp sp, 496
+			(0x75fd, 0xffff_f5b7), // c.lui a1, 0xfffff
+			(0x6585, 0x0000_15b7), // c.lui a1, 1
+			(0x907d, 0x03f4_5413), // c.srli s0, 63
+			(0x8405, 0x4014_5413), // c.srai s0, 1
+			(0x9801, 0xfe04_7413), // c.andi s0, -32
+			(0x8c05, 0x4094_0433), // c.sub s0, s1
+			(0x8c25, 0x0094_4433), // c.xor s0, s1
+			(0x8c45, 0x0094_6433), // c.or s0, s1
+			(0x8c65, 0x0094_7433), // c.and s0, s1
+			(0x9c05, 0x4094_043b), // c.subw s0, s1
+			(0x9c25, 0x0094_043b), // c.addw s0, s1
+			(0x157e, 0x03f5_1513), // c.slli a0, 63
+		] {
+			assert_eq!(expand(compressed, Xlen::Rv64), Some(full));
+		}
+	}
+
+	#[test]
+	fn rv32_has_its_own_expansions()
+	{
+		for (compressed, full) in [
+			(0x2021, 0x0080_00ef), // c.jal 8
+			(0x3ffd, 0xffff_f0ef), // c.jal -2
+			(0x7de8, 0x07c5_a507), // c.flw fa0, 124(a1)
+			(0xe1c8, 0x00a5_a227), // c.fsw fa0, 4(a1)
+			(0x707e, 0x0fc1_2007), // c.flwsp ft0, 252(sp)
+			(0xe206, 0x0011_2227), // c.fswsp ft1, 4(sp)
+			(0x057e, 0x01f5_1513), // c.slli a0, 31
+		] {
+			assert_eq!(expand(compressed, Xlen::Rv32), Some(full));
+		}
+
+		// Shifts by more than 31 & the 32-bit operations are RV64's
+		for reserved in [0x157e, 0x907d, 0x9c05, 0x9c25] {
+			assert_eq!(expand(reserved, Xlen::Rv32), None);
+		}
+	}
+
+	#[test]
+	fn reserved_encodings_are_not_expanded()
+	{
+		for reserved in [
+			0x0000, // c.addi4spn with a zero immediate, all zeroes
+			0x2001, // c.addiw x0
+			0x6101, // c.addi16sp with a zero immediate
+			0x6581, // c.lui with a zero immediate
+			0x8002, // c.jr x0
+			0x5002, // c.lwsp with rd == x0
+			0x7002, // c.ldsp with rd == x0
+			0x9c45, // a reserved misc-alu operation
+			0x2000, // c.fld, which needs D
+		] {
+			assert_eq!(expand(reserved, Xlen::Rv64), None);
+		}
+	}
+
+	#[test]
+	fn expanded_ldsp_loads_from_maximum_offset()
+	{
+		let mut platform = Platform::default();
+		let sp: u64 = 0x8000_1000;
+
+		platform.hart.write_register(2_usize, sp);
+		platform.write(sp as usize + 504, 0x1122_3344_5566_7788_u64).unwrap();
+
+		// c.ldsp s1, 504(sp)
+		let mut insn = Insn::from(expand(0x74fe, Xlen::Rv64).unwrap());
+		insn.handle(&mut platform).unwrap();
+
+		assert_eq!(platform.hart.read_register(9_usize), 0x1122_3344_5566_7788);
+	}
+}
@@ -0,0 +1,773 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+// The F extension, single precision floating point. Rust's f32 only rounds
+// to nearest, so results are worked out in f64, which holds any sum,
+// product, quotient or square root of two f32s closely enough that knowing
+// which side of it the exact answer lies is all it takes to round it to
+// f32 in any of the five rounding modes.
+
+use super::{
+	illegal_insn, Insn, Mnemonic, FREGISTER_NAMES, OPCODE_LOAD_FP,
+	OPCODE_OP_FP, OPCODE_STORE_FP, REGISTER_NAMES,
+};
+use crate::hart::{Trap, CANONICAL_NAN, CSR_FRM, FS_OFF};
+use crate::platform::Platform;
+use std::cmp::Ordering;
+
+pub(super) const FUNC3_FLW: u32 = 0b010;
+pub(super) const FUNC3_FSW: u32 = 0b010;
+
+// OP-FP keeps the operation in the top five bits of func7 & the format in
+// the bottom two, which is 0 for single precision
+const FMT_MASK: u32 = 0b11;
+const FMT_S: u32 = 0b00;
+
+const FUNC5_FADD: u32 = 0b00000;
+const FUNC5_FSUB: u32 = 0b00001;
+const FUNC5_FMUL: u32 = 0b00010;
+const FUNC5_FDIV: u32 = 0b00011;
+const FUNC5_FSGNJ: u32 = 0b00100;
+const FUNC5_FMINMAX: u32 = 0b00101;
+const FUNC5_FSQRT: u32 = 0b01011;
+const FUNC5_FCMP: u32 = 0b10100;
+const FUNC5_FCVT_INT_S: u32 = 0b11000;
+const FUNC5_FCVT_S_INT: u32 = 0b11010;
+const FUNC5_FMV_X_W_FCLASS: u32 = 0b11100;
+const FUNC5_FMV_W_X: u32 = 0b11110;
+
+const FUNC3_FSGNJ: u32 = 0b000;
+const FUNC3_FSGNJN: u32 = 0b001;
+const FUNC3_FSGNJX: u32 = 0b010;
+const FUNC3_FMIN: u32 = 0b000;
+const FUNC3_FMAX: u32 = 0b001;
+const FUNC3_FLE: u32 = 0b000;
+const FUNC3_FLT: u32 = 0b001;
+const FUNC3_FEQ: u32 = 0b010;
+const FUNC3_FMV_X_W: u32 = 0b000;
+const FUNC3_FCLASS: u32 = 0b001;
+
+// Conversions pick the integer type with rs2
+const CVT_W: u32 = 0;
+const CVT_WU: u32 = 1;
+const CVT_L: u32 = 2;
+const CVT_LU: u32 = 3;
+
+// The rounding mode field, where DYN means use frm
+const RM_DYN: u32 = 0b111;
+
+pub const FFLAG_NX: u64 = 1 << 0;
+pub const FFLAG_UF: u64 = 1 << 1;
+pub const FFLAG_OF: u64 = 1 << 2;
+pub const FFLAG_DZ: u64 = 1 << 3;
+pub const FFLAG_NV: u64 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rounding
+{
+	NearestEven,
+	TowardZero,
+	Down,
+	Up,
+	NearestMaxMagnitude,
+}
+
+fn canonical_nan() -> f32
+{
+	return f32::from_bits(CANONICAL_NAN);
+}
+
+fn is_signaling(value: f32) -> bool
+{
+	return value.is_nan() && value.to_bits() & 0x0040_0000 == 0;
+}
+
+/// The f32 next to value, either above it or below it.
+fn next_to(value: f32, up: bool) -> f32
+{
+	if value == 0.0 {
+		let smallest = f32::from_bits(1);
+		return if up { smallest } else { -smallest };
+	}
+
+	let bits = value.to_bits();
+	if (value > 0.0) == up {
+		return f32::from_bits(bits + 1);
+	}
+	return f32::from_bits(bits - 1);
+}
+
+/// An f32 as an f64, with infinity standing in for the power of two past
+/// the largest finite value, as if the exponent kept on going.
+fn widen(value: f32) -> f64
+{
+	if value.is_infinite() {
+		return 2_f64.powi(128).copysign(value as f64);
+	}
+
+	return value as f64;
+}
+
+/// Round an exact value to f32. nearest must be one of the two f32s either
+/// side of it, & compare says which side of a given f64 the exact value
+/// lies. Returns the result along with the exception flags it raises.
+fn round(
+	nearest: f32, compare: impl Fn(f64) -> Ordering, rounding: Rounding,
+) -> (f32, u64)
+{
+	let toward = compare(nearest as f64);
+	if toward == Ordering::Equal {
+		return (nearest, 0);
+	}
+
+	let other = next_to(nearest, toward == Ordering::Greater);
+	let (low, high) = if toward == Ordering::Greater {
+		(nearest, other)
+	} else {
+		(other, nearest)
+	};
+	let smaller = if low.is_sign_positive() { low } else { high };
+	let larger = if low.is_sign_positive() { high } else { low };
+
+	let result = match rounding {
+		Rounding::TowardZero => smaller,
+		Rounding::Down => low,
+		Rounding::Up => high,
+		Rounding::NearestEven | Rounding::NearestMaxMagnitude => {
+			match compare((widen(low) + widen(high)) / 2.0) {
+				Ordering::Less => low,
+				Ordering::Greater => high,
+				Ordering::Equal
+					if rounding == Rounding::NearestMaxMagnitude =>
+				{
+					larger
+				},
+				Ordering::Equal if low.to_bits() & 1 == 0 => low,
+				Ordering::Equal => high,
+			}
+		},
+	};
+
+	let mut flags = FFLAG_NX;
+	let limit = 2_f64.powi(128);
+	if result.is_infinite()
+		|| compare(limit) != Ordering::Less
+		|| compare(-limit) != Ordering::Greater
+	{
+		flags |= FFLAG_OF;
+	}
+	if result.abs() < f32::MIN_POSITIVE {
+		flags |= FFLAG_UF;
+	}
+
+	return (result, flags);
+}
+
+/// Round an f64 result to f32, where error says which side of value the
+/// exact result was. NaNs are invalid unless one went in, & then only if
+/// it was a signalling one.
+fn finish(
+	value: f64, error: Ordering, inputs: &[f32], rounding: Rounding,
+) -> (f32, u64)
+{
+	if value.is_nan() {
+		let quiet = inputs.iter().any(|input| return input.is_nan());
+		let signaling = inputs.iter().any(|input| return is_signaling(*input));
+		let flags = if signaling || !quiet { FFLAG_NV } else { 0 };
+		return (canonical_nan(), flags);
+	}
+
+	if value.is_infinite() {
+		return (value as f32, 0);
+	}
+
+	let compare = |other: f64| {
+		return match value.partial_cmp(&other).unwrap() {
+			Ordering::Equal => error,
+			ordering => ordering,
+		};
+	};
+
+	return round(value as f32, compare, rounding);
+}
+
+fn add(a: f32, b: f32, rounding: Rounding) -> (f32, u64)
+{
+	let (a64, b64) = (a as f64, b as f64);
+	let sum = a64 + b64;
+
+	// Knuth's TwoSum: what was lost rounding the sum
+	let b_part = sum - a64;
+	let lost = (a64 - (sum - b_part)) + (b64 - b_part);
+	let error = lost.partial_cmp(&0.0).unwrap_or(Ordering::Equal);
+
+	let (mut result, flags) = finish(sum, error, &[a, b], rounding);
+
+	// An exact zero is positive, unless rounding down
+	let negative = a.is_sign_negative() || b.is_sign_negative();
+	if sum == 0.0 && rounding == Rounding::Down && negative {
+		result = -0.0;
+	}
+
+	return (result, flags);
+}
+
+fn multiply(a: f32, b: f32, rounding: Rounding) -> (f32, u64)
+{
+	// 24 bits by 24 bits fits in f64 without any rounding
+	let product = a as f64 * b as f64;
+	return finish(product, Ordering::Equal, &[a, b], rounding);
+}
+
+fn divide(a: f32, b: f32, rounding: Rounding) -> (f32, u64)
+{
+	let (a64, b64) = (a as f64, b as f64);
+	let quotient = a64 / b64;
+
+	// The remainder, exactly, tells which side of the quotient the real
+	// one is on
+	let mut error = Ordering::Equal;
+	if a.is_finite() && b.is_finite() && b != 0.0 {
+		let remainder = (-quotient).mul_add(b64, a64);
+		error = remainder.partial_cmp(&0.0).unwrap();
+		if b < 0.0 {
+			error = error.reverse();
+		}
+	}
+
+	let (result, mut flags) = finish(quotient, error, &[a, b], rounding);
+	if b == 0.0 && a.is_finite() && a != 0.0 {
+		flags |= FFLAG_DZ;
+	}
+
+	return (result, flags);
+}
+
+fn sqrt(a: f32, rounding: Rounding) -> (f32, u64)
+{
+	let a64 = a as f64;
+	let root = a64.sqrt();
+
+	let mut error = Ordering::Equal;
+	if root.is_finite() {
+		error = (-root).mul_add(root, a64).partial_cmp(&0.0).unwrap();
+	}
+
+	return finish(root, error, &[a], rounding);
+}
+
+/// fmin & fmax, where a NaN only wins if both are NaNs & -0 is less than
+/// +0.
+fn min_max(a: f32, b: f32, max: bool) -> (f32, u64)
+{
+	let flags = if is_signaling(a) || is_signaling(b) { FFLAG_NV } else { 0 };
+
+	let result = match (a.is_nan(), b.is_nan()) {
+		(true, true) => canonical_nan(),
+		(true, false) => b,
+		(false, true) => a,
+		(false, false) => {
+			let a_less = a < b || (a == b && a.is_sign_negative());
+			if a_less != max {
+				a
+			} else {
+				b
+			}
+		},
+	};
+
+	return (result, flags);
+}
+
+/// Round to an integer, saturating at min & max. NaNs convert to max.
+fn to_integer(
+	value: f32, min: i128, max: i128, rounding: Rounding,
+) -> (i128, u64)
+{
+	if value.is_nan() {
+		return (max, FFLAG_NV);
+	}
+
+	let value = value as f64;
+	let rounded = match rounding {
+		Rounding::TowardZero => value.trunc(),
+		Rounding::Down => value.floor(),
+		Rounding::Up => value.ceil(),
+		Rounding::NearestMaxMagnitude => value.round(),
+		Rounding::NearestEven if (value - value.trunc()).abs() == 0.5 => {
+			2.0 * (value / 2.0).round()
+		},
+		Rounding::NearestEven => value.round(),
+	};
+
+	// Anything too big for an i128 saturates to its limits, which are
+	// outside every range asked for
+	let integer = rounded as i128;
+	if integer < min {
+		return (min, FFLAG_NV);
+	}
+	if integer > max {
+		return (max, FFLAG_NV);
+	}
+
+	let flags = if rounded != value { FFLAG_NX } else { 0 };
+	return (integer, flags);
+}
+
+fn from_integer(value: i128, rounding: Rounding) -> (f32, u64)
+{
+	// Doubling keeps the halfway points between f32s integers
+	let compare = |other: f64| return (2 * value).cmp(&((2.0 * other) as i128));
+	return round(value as f32, compare, rounding);
+}
+
+/// fclass's one-hot classification.
+fn classify(value: f32) -> u64
+{
+	let negative = value.is_sign_negative();
+	let bit = if value.is_nan() {
+		if is_signaling(value) {
+			8
+		} else {
+			9
+		}
+	} else if value.is_infinite() {
+		if negative {
+			0
+		} else {
+			7
+		}
+	} else if value == 0.0 {
+		if negative {
+			3
+		} else {
+			4
+		}
+	} else if value.is_subnormal() {
+		if negative {
+			2
+		} else {
+			5
+		}
+	} else if negative {
+		1
+	} else {
+		6
+	};
+
+	return 1 << bit;
+}
+
+impl Insn
+{
+	/// Which rounding mode the instruction asks for, with DYN meaning
+	/// frm's. The reserved modes are illegal.
+	fn rounding(&self, platform: &Platform) -> Result<Rounding, Trap>
+	{
+		let rm = match self.func3 {
+			RM_DYN => platform.hart.read_csr(CSR_FRM) as u32,
+			rm => rm,
+		};
+
+		match rm {
+			0 => return Ok(Rounding::NearestEven),
+			1 => return Ok(Rounding::TowardZero),
+			2 => return Ok(Rounding::Down),
+			3 => return Ok(Rounding::Up),
+			4 => return Ok(Rounding::NearestMaxMagnitude),
+			_ => return Err(illegal_insn(self.bits)),
+		}
+	}
+
+	/// Every FP instruction is illegal while mstatus.FS is off.
+	fn check_fs(&self, platform: &Platform) -> Result<(), Trap>
+	{
+		if platform.hart.fs() == FS_OFF {
+			return Err(illegal_insn(self.bits));
+		}
+
+		return Ok(());
+	}
+
+	pub(super) fn handle_load_fp_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
+	{
+		self.check_fs(platform)?;
+		if self.func3 != FUNC3_FLW {
+			return Err(illegal_insn(self.bits));
+		}
+
+		let offset: i64 = self.imm.into();
+		let hart = &platform.hart;
+		let base: u64 = hart.read_register(self.rs1 as usize);
+		let address: u64 = hart.zero_extend(base.wrapping_add_signed(offset));
+		let value = platform.load::<u32>(address)?;
+
+		let hart = &mut platform.hart;
+		hart.write_fregister(self.rd as usize, f32::from_bits(value));
+
+		return Ok(());
+	}
+
+	pub(super) fn handle_store_fp_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
+	{
+		self.check_fs(platform)?;
+		if self.func3 != FUNC3_FSW {
+			return Err(illegal_insn(self.bits));
+		}
+
+		// FSW stores the bottom of the register as it is, boxed or not
+		let offset: i64 = self.imm.into();
+		let hart = &platform.hart;
+		let base: u64 = hart.read_register(self.rs1 as usize);
+		let address: u64 = hart.zero_extend(base.wrapping_add_signed(offset));
+		let value = hart.fregisters[self.rs2 as usize] as u32;
+		platform.store(address, value)?;
+
+		return Ok(());
+	}
+
+	pub(super) fn handle_op_fp_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
+	{
+		self.check_fs(platform)?;
+		if self.func7 & FMT_MASK != FMT_S {
+			return Err(illegal_insn(self.bits));
+		}
+
+		let hart = &platform.hart;
+		let a = hart.read_fregister(self.rs1 as usize);
+		let b = hart.read_fregister(self.rs2 as usize);
+		let rd = self.rd as usize;
+
+		// Most results go to an f register, the rest to an x register
+		let (result, flags) = match (self.func7 >> 2, self.func3) {
+			(FUNC5_FADD, _) => add(a, b, self.rounding(platform)?),
+			(FUNC5_FSUB, _) => add(a, -b, self.rounding(platform)?),
+			(FUNC5_FMUL, _) => multiply(a, b, self.rounding(platform)?),
+			(FUNC5_FDIV, _) => divide(a, b, self.rounding(platform)?),
+			(FUNC5_FSQRT, _) if self.rs2 == 0 => {
+				sqrt(a, self.rounding(platform)?)
+			},
+
+			(FUNC5_FSGNJ, func3) => {
+				let sign = match func3 {
+					FUNC3_FSGNJ => b.to_bits(),
+					FUNC3_FSGNJN => !b.to_bits(),
+					FUNC3_FSGNJX => a.to_bits() ^ b.to_bits(),
+					_ => return Err(illegal_insn(self.bits)),
+				};
+				let sign_bit = 1 << 31;
+				let bits = (a.to_bits() & !sign_bit) | (sign & sign_bit);
+				(f32::from_bits(bits), 0)
+			},
+
+			(FUNC5_FMINMAX, FUNC3_FMIN) => min_max(a, b, false),
+			(FUNC5_FMINMAX, FUNC3_FMAX) => min_max(a, b, true),
+
+			(FUNC5_FCMP, func3) => {
+				let any_nan = a.is_nan() || b.is_nan();
+				let any_signaling = is_signaling(a) || is_signaling(b);
+				// feq is quiet, the others signal on any NaN
+				let (result, invalid) = match func3 {
+					FUNC3_FEQ => (a == b, any_signaling),
+					FUNC3_FLT => (a < b, any_nan),
+					FUNC3_FLE => (a <= b, any_nan),
+					_ => return Err(illegal_insn(self.bits)),
+				};
+				let flags = if invalid { FFLAG_NV } else { 0 };
+				return self.finish_integer(platform, result as u64, flags);
+			},
+
+			(FUNC5_FCVT_INT_S, _) => {
+				let rounding = self.rounding(platform)?;
+				let (min, max) = match self.rs2 {
+					CVT_W => (i32::MIN as i128, i32::MAX as i128),
+					CVT_WU => (0, u32::MAX as i128),
+					CVT_L => (i64::MIN as i128, i64::MAX as i128),
+					CVT_LU => (0, u64::MAX as i128),
+					_ => return Err(illegal_insn(self.bits)),
+				};
+				let (result, flags) = to_integer(a, min, max, rounding);
+				// Even the unsigned word results are sign-extended
+				let result = match self.rs2 {
+					CVT_W | CVT_WU => result as i32 as u64,
+					_ => result as u64,
+				};
+				return self.finish_integer(platform, result, flags);
+			},
+
+			(FUNC5_FCVT_S_INT, _) => {
+				let rounding = self.rounding(platform)?;
+				let x = hart.read_register(self.rs1 as usize);
+				let value = match self.rs2 {
+					CVT_W => x as i32 as i128,
+					CVT_WU => x as u32 as i128,
+					CVT_L => x as i64 as i128,
+					CVT_LU => x as i128,
+					_ => return Err(illegal_insn(self.bits)),
+				};
+				from_integer(value, rounding)
+			},
+
+			// The moves copy bits, without unboxing or canonicalising
+			(FUNC5_FMV_X_W_FCLASS, FUNC3_FMV_X_W) if self.rs2 == 0 => {
+				let bits = hart.fregisters[self.rs1 as usize] as u32;
+				return self.finish_integer(platform, bits as i32 as u64, 0);
+			},
+			(FUNC5_FMV_X_W_FCLASS, FUNC3_FCLASS) if self.rs2 == 0 => {
+				return self.finish_integer(platform, classify(a), 0);
+			},
+			(FUNC5_FMV_W_X, 0) if self.rs2 == 0 => {
+				let x = hart.read_register(self.rs1 as usize);
+				(f32::from_bits(x as u32), 0)
+			},
+
+			_ => return Err(illegal_insn(self.bits)),
+		};
+
+		let hart = &mut platform.hart;
+		hart.write_fregister(rd, result);
+		hart.raise_fflags(flags);
+
+		return Ok(());
+	}
+
+	/// Write the result of an FP instruction that produces an integer.
+	fn finish_integer(
+		&self, platform: &mut Platform, result: u64, flags: u64,
+	) -> Result<(), Trap>
+	{
+		let hart = &mut platform.hart;
+		hart.write_register(self.rd as usize, result);
+		hart.raise_fflags(flags);
+
+		return Ok(());
+	}
+
+	/// Conversions to & from longs only exist on RV64.
+	pub(super) fn fp_rv64_only(&self) -> bool
+	{
+		let func5 = self.func7 >> 2;
+		let converts = func5 == FUNC5_FCVT_INT_S || func5 == FUNC5_FCVT_S_INT;
+		return self.opcode == OPCODE_OP_FP
+			&& converts && matches!(self.rs2, CVT_L | CVT_LU);
+	}
+
+	pub(super) fn decode_fp_mnemonic(&self) -> Option<Mnemonic>
+	{
+		if self.opcode == OPCODE_LOAD_FP {
+			return (self.func3 == FUNC3_FLW).then_some(Mnemonic::Flw);
+		}
+		if self.opcode == OPCODE_STORE_FP {
+			return (self.func3 == FUNC3_FSW).then_some(Mnemonic::Fsw);
+		}
+		if self.func7 & FMT_MASK != FMT_S {
+			return None;
+		}
+
+		let mnemonic = match (self.func7 >> 2, self.func3, self.rs2) {
+			(FUNC5_FADD, _, _) => Mnemonic::FaddS,
+			(FUNC5_FSUB, _, _) => Mnemonic::FsubS,
+			(FUNC5_FMUL, _, _) => Mnemonic::FmulS,
+			(FUNC5_FDIV, _, _) => Mnemonic::FdivS,
+			(FUNC5_FSQRT, _, 0) => Mnemonic::FsqrtS,
+			(FUNC5_FSGNJ, FUNC3_FSGNJ, _) => Mnemonic::FsgnjS,
+			(FUNC5_FSGNJ, FUNC3_FSGNJN, _) => Mnemonic::FsgnjnS,
+			(FUNC5_FSGNJ, FUNC3_FSGNJX, _) => Mnemonic::FsgnjxS,
+			(FUNC5_FMINMAX, FUNC3_FMIN, _) => Mnemonic::FminS,
+			(FUNC5_FMINMAX, FUNC3_FMAX, _) => Mnemonic::FmaxS,
+			(FUNC5_FCMP, FUNC3_FEQ, _) => Mnemonic::FeqS,
+			(FUNC5_FCMP, FUNC3_FLT, _) => Mnemonic::FltS,
+			(FUNC5_FCMP, FUNC3_FLE, _) => Mnemonic::FleS,
+			(FUNC5_FCVT_INT_S, _, CVT_W) => Mnemonic::FcvtWS,
+			(FUNC5_FCVT_INT_S, _, CVT_WU) => Mnemonic::FcvtWuS,
+			(FUNC5_FCVT_INT_S, _, CVT_L) => Mnemonic::FcvtLS,
+			(FUNC5_FCVT_INT_S, _, CVT_LU) => Mnemonic::FcvtLuS,
+			(FUNC5_FCVT_S_INT, _, CVT_W) => Mnemonic::FcvtSW,
+			(FUNC5_FCVT_S_INT, _, CVT_WU) => Mnemonic::FcvtSWu,
+			(FUNC5_FCVT_S_INT, _, CVT_L) => Mnemonic::FcvtSL,
+			(FUNC5_FCVT_S_INT, _, CVT_LU) => Mnemonic::FcvtSLu,
+			(FUNC5_FMV_X_W_FCLASS, FUNC3_FMV_X_W, 0) => Mnemonic::FmvXW,
+			(FUNC5_FMV_X_W_FCLASS, FUNC3_FCLASS, 0) => Mnemonic::FclassS,
+			(FUNC5_FMV_W_X, 0, 0) => Mnemonic::FmvWX,
+			_ => return None,
+		};
+
+		return Some(mnemonic);
+	}
+
+	/// Operands of FP instructions, which use f registers, x registers or
+	/// a mix depending on the instruction.
+	pub(super) fn fp_operands(&self) -> String
+	{
+		let rd = FREGISTER_NAMES[self.rd as usize];
+		let rs1 = FREGISTER_NAMES[self.rs1 as usize];
+		let rs2 = FREGISTER_NAMES[self.rs2 as usize];
+		let xrd = REGISTER_NAMES[self.rd as usize];
+		let xrs1 = REGISTER_NAMES[self.rs1 as usize];
+
+		match self.opcode {
+			OPCODE_LOAD_FP => return format!("{}, {}({})", rd, self.imm, xrs1),
+			OPCODE_STORE_FP => {
+				return format!("{}, {}({})", rs2, self.imm, xrs1);
+			},
+			_ => (),
+		}
+
+		match self.func7 >> 2 {
+			FUNC5_FSQRT => return format!("{}, {}", rd, rs1),
+			FUNC5_FCMP => return format!("{}, {}, {}", xrd, rs1, rs2),
+			FUNC5_FCVT_INT_S | FUNC5_FMV_X_W_FCLASS => {
+				return format!("{}, {}", xrd, rs1);
+			},
+			FUNC5_FCVT_S_INT | FUNC5_FMV_W_X => {
+				return format!("{}, {}", rd, xrs1);
+			},
+			_ => return format!("{}, {}, {}", rd, rs1, rs2),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		add, divide, from_integer, to_integer, Rounding, FFLAG_DZ, FFLAG_NV,
+		FFLAG_NX, FFLAG_OF,
+	};
+	use crate::bus::Bus;
+	use crate::hart::{
+		CAUSE_ILLEGAL_INSN, CSR_FFLAGS, CSR_MSTATUS, FS_DIRTY, FS_INITIAL,
+		MSTATUS_FS_SHIFT,
+	};
+	use crate::insn::Insn;
+	use crate::platform::Platform;
+
+	fn fp_platform() -> Platform
+	{
+		let mut platform = Platform::default();
+		platform.hart.write_csr(CSR_MSTATUS, FS_INITIAL << MSTATUS_FS_SHIFT);
+		return platform;
+	}
+
+	#[test]
+	fn fadd_s_adds_and_dirties_fs()
+	{
+		let mut platform = fp_platform();
+		// fadd.s fa0, fa1, fa2
+		let mut insn = Insn::from(0x00c5_f553);
+
+		platform.hart.write_fregister(11, 1.5);
+		platform.hart.write_fregister(12, 2.25);
+		platform.hart.set_fs(FS_INITIAL);
+		insn.handle(&mut platform).unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.read_fregister(10), 3.75);
+		assert_eq!(hart.fregisters[10] >> 32, 0xffff_ffff);
+		assert_eq!(hart.fs(), FS_DIRTY);
+		assert_eq!(hart.read_csr(CSR_FFLAGS), 0);
+		// SD summarises a dirty FS
+		assert_eq!(hart.read_csr(CSR_MSTATUS) >> 63, 1);
+
+		// 2^24 + 1 isn't representable, so it rounds
+		platform.hart.write_fregister(11, 16_777_216.0);
+		platform.hart.write_fregister(12, 1.0);
+		insn.handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_fregister(10), 16_777_216.0);
+		assert_eq!(platform.hart.read_csr(CSR_FFLAGS), FFLAG_NX);
+	}
+
+	#[test]
+	fn flw_fsw_round_trip()
+	{
+		let mut platform = fp_platform();
+		let address: usize = 0x8000_0100;
+		// flw fa0, 0(a1)
+		let mut load = Insn::from(0x0005_a507);
+		// fsw fa0, 4(a1)
+		let mut store = Insn::from(0x00a5_a227);
+
+		platform.write(address, 0x4049_0fdb_u32).unwrap();
+		platform.hart.write_register(11_usize, address as u64);
+		load.handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.fregisters[10], 0xffff_ffff_4049_0fdb);
+
+		store.handle(&mut platform).unwrap();
+		assert_eq!(platform.read::<u32>(address + 4).unwrap(), 0x4049_0fdb);
+	}
+
+	#[test]
+	fn fp_instructions_need_fs_on()
+	{
+		let mut platform = Platform::default();
+		// fadd.s fa0, fa1, fa2
+		let mut insn = Insn::from(0x00c5_f553);
+
+		let trap = insn.handle(&mut platform).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+	}
+
+	#[test]
+	fn rounding_modes_round_the_exact_result()
+	{
+		let third = |rounding| return divide(1.0, 3.0, rounding).0;
+		let nearest = third(Rounding::NearestEven);
+		assert_eq!(nearest, 1.0 / 3.0);
+		// which is a little more than a third
+		assert_eq!(third(Rounding::Up), nearest);
+		assert!(third(Rounding::Down) < nearest);
+		assert_eq!(third(Rounding::TowardZero), third(Rounding::Down));
+
+		// Ties, between 2^24 & 2^24 + 2
+		let tie = 16_777_217;
+		assert_eq!(from_integer(tie, Rounding::NearestEven).0, 16_777_216.0);
+		assert_eq!(
+			from_integer(tie, Rounding::NearestMaxMagnitude).0,
+			16_777_218.0
+		);
+		assert_eq!(from_integer(-tie, Rounding::Up).0, -16_777_216.0);
+
+		// Far too small to change the sum, but not nothing
+		let tiny = f32::from_bits(1);
+		assert_eq!(add(1.0, tiny, Rounding::Up).0, 1.0 + f32::EPSILON);
+		assert_eq!(
+			add(1.0, -tiny, Rounding::TowardZero).0,
+			1.0 - f32::EPSILON / 2.0
+		);
+		assert_eq!(
+			add(1.0, -1.0, Rounding::Down).0.to_bits(),
+			(-0.0_f32).to_bits()
+		);
+	}
+
+	#[test]
+	fn exceptions_are_flagged()
+	{
+		assert_eq!(
+			divide(1.0, 0.0, Rounding::NearestEven),
+			(f32::INFINITY, FFLAG_DZ)
+		);
+		let (result, flags) = divide(0.0, 0.0, Rounding::NearestEven);
+		assert_eq!((result.to_bits(), flags), (0x7fc0_0000, FFLAG_NV));
+
+		let (result, flags) = add(f32::MAX, f32::MAX, Rounding::TowardZero);
+		assert_eq!((result, flags), (f32::MAX, FFLAG_OF | FFLAG_NX));
+
+		let word = (i32::MIN as i128, i32::MAX as i128);
+		let round = Rounding::NearestEven;
+		assert_eq!(to_integer(2.5, word.0, word.1, round), (2, FFLAG_NX));
+		assert_eq!(
+			to_integer(f32::NAN, word.0, word.1, round),
+			(word.1, FFLAG_NV)
+		);
+		assert_eq!(to_integer(-1.0, 0, u32::MAX as i128, round), (0, FFLAG_NV));
+		assert_eq!(to_integer(3e9, word.0, word.1, round), (word.1, FFLAG_NV));
+	}
+}
@@ -5,14 +5,22 @@
 use crate::bus::Bus;
 use crate::field_get;
 use crate::gen_mask;
-use crate::platform::Platform;
+use crate::hart::{
+	csr_name, Hart, Trap, Xlen, CAUSE_BREAKPOINT, CAUSE_ECALL_UMODE,
+	CAUSE_ILLEGAL_INSN, CAUSE_INSN_MISALIGNED, CAUSE_LOAD_ACCESS_FAULT,
+	CAUSE_LOAD_MISALIGNED, CAUSE_STORE_ACCESS_FAULT, CAUSE_STORE_MISALIGNED,
+	FREGISTER_NAMES, PRIV_MACHINE, PRIV_SUPERVISOR, PRIV_USER, REGISTER_NAMES,
+};
+use crate::mmu::Access;
+use crate::platform::{Platform, WatchKind};
+use crate::sbi;
 use crate::sign_extend;
 use debug_print::debug_println;
 
-use std::sync::Arc;
-use std::sync::RwLock;
+pub mod compressed;
+mod float;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum InsnType
 {
 	Invalid,
@@ -24,10 +32,276 @@ pub enum InsnType
 	J,
 }
 
-#[derive(Debug)]
+/// What a decoded instruction turned out to be, which may be a
+/// pseudo-instruction like mv, as traces show it. Set once while decoding,
+/// & only turned into text when something prints it.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mnemonic
+{
+	Add,
+	Addi,
+	Addiw,
+	Addw,
+	Amoadd,
+	Amoand,
+	Amomax,
+	Amomaxu,
+	Amomin,
+	Amominu,
+	Amoor,
+	Amoswap,
+	Amoxor,
+	And,
+	Andi,
+	Auipc,
+	Beq,
+	Bge,
+	Bgeu,
+	Blt,
+	Bltu,
+	Bne,
+	Csrrc,
+	Csrrci,
+	Csrrs,
+	Csrrsi,
+	Csrrw,
+	Csrrwi,
+	Div,
+	Divu,
+	Divuw,
+	Divw,
+	Ebreak,
+	Ecall,
+	FaddS,
+	FclassS,
+	FcvtLS,
+	FcvtLuS,
+	FcvtSL,
+	FcvtSLu,
+	FcvtSW,
+	FcvtSWu,
+	FcvtWS,
+	FcvtWuS,
+	FdivS,
+	Fence,
+	FenceI,
+	FeqS,
+	FleS,
+	FltS,
+	Flw,
+	FmaxS,
+	FminS,
+	FmulS,
+	FmvWX,
+	FmvXW,
+	FsgnjS,
+	FsgnjnS,
+	FsgnjxS,
+	FsqrtS,
+	FsubS,
+	Fsw,
+	Jal,
+	Jalr,
+	Lb,
+	Lbu,
+	Ld,
+	Lh,
+	Lhu,
+	Lr,
+	Lui,
+	Lw,
+	Lwu,
+	Mret,
+	Mul,
+	Mulh,
+	Mulhsu,
+	Mulhu,
+	Mulw,
+	Mv,
+	Nop,
+	Or,
+	Ori,
+	Rem,
+	Remu,
+	Remuw,
+	Remw,
+	Sb,
+	Sc,
+	Sd,
+	Sextw,
+	Sh,
+	Sll,
+	Slli,
+	Slliw,
+	Slt,
+	Slti,
+	Sltiu,
+	Sltu,
+	Snez,
+	Sra,
+	Srai,
+	Sraiw,
+	SfenceVma,
+	Sret,
+	Srl,
+	Srli,
+	Srliw,
+	Sub,
+	Subw,
+	Sw,
+	/// Not an instruction the emulator implements
+	Unknown,
+	Wfi,
+	Xor,
+	Xori,
+}
+
+impl std::fmt::Display for Mnemonic
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		let name = match self {
+			Mnemonic::Add => "add",
+			Mnemonic::Addi => "addi",
+			Mnemonic::Addiw => "addiw",
+			Mnemonic::Addw => "addw",
+			Mnemonic::Amoadd => "amoadd",
+			Mnemonic::Amoand => "amoand",
+			Mnemonic::Amomax => "amomax",
+			Mnemonic::Amomaxu => "amomaxu",
+			Mnemonic::Amomin => "amomin",
+			Mnemonic::Amominu => "amominu",
+			Mnemonic::Amoor => "amoor",
+			Mnemonic::Amoswap => "amoswap",
+			Mnemonic::Amoxor => "amoxor",
+			Mnemonic::And => "and",
+			Mnemonic::Andi => "andi",
+			Mnemonic::Auipc => "auipc",
+			Mnemonic::Beq => "beq",
+			Mnemonic::Bge => "bge",
+			Mnemonic::Bgeu => "bgeu",
+			Mnemonic::Blt => "blt",
+			Mnemonic::Bltu => "bltu",
+			Mnemonic::Bne => "bne",
+			Mnemonic::Csrrc => "csrrc",
+			Mnemonic::Csrrci => "csrrci",
+			Mnemonic::Csrrs => "csrrs",
+			Mnemonic::Csrrsi => "csrrsi",
+			Mnemonic::Csrrw => "csrrw",
+			Mnemonic::Csrrwi => "csrrwi",
+			Mnemonic::Div => "div",
+			Mnemonic::Divu => "divu",
+			Mnemonic::Divuw => "divuw",
+			Mnemonic::Divw => "divw",
+			Mnemonic::Ebreak => "ebreak",
+			Mnemonic::Ecall => "ecall",
+			Mnemonic::FaddS => "fadd.s",
+			Mnemonic::FclassS => "fclass.s",
+			Mnemonic::FcvtLS => "fcvt.l.s",
+			Mnemonic::FcvtLuS => "fcvt.lu.s",
+			Mnemonic::FcvtSL => "fcvt.s.l",
+			Mnemonic::FcvtSLu => "fcvt.s.lu",
+			Mnemonic::FcvtSW => "fcvt.s.w",
+			Mnemonic::FcvtSWu => "fcvt.s.wu",
+			Mnemonic::FcvtWS => "fcvt.w.s",
+			Mnemonic::FcvtWuS => "fcvt.wu.s",
+			Mnemonic::FdivS => "fdiv.s",
+			Mnemonic::Fence => "fence",
+			Mnemonic::FenceI => "fence.i",
+			Mnemonic::FeqS => "feq.s",
+			Mnemonic::FleS => "fle.s",
+			Mnemonic::FltS => "flt.s",
+			Mnemonic::Flw => "flw",
+			Mnemonic::FmaxS => "fmax.s",
+			Mnemonic::FminS => "fmin.s",
+			Mnemonic::FmulS => "fmul.s",
+			Mnemonic::FmvWX => "fmv.w.x",
+			Mnemonic::FmvXW => "fmv.x.w",
+			Mnemonic::FsgnjS => "fsgnj.s",
+			Mnemonic::FsgnjnS => "fsgnjn.s",
+			Mnemonic::FsgnjxS => "fsgnjx.s",
+			Mnemonic::FsqrtS => "fsqrt.s",
+			Mnemonic::FsubS => "fsub.s",
+			Mnemonic::Fsw => "fsw",
+			Mnemonic::Jal => "jal",
+			Mnemonic::Jalr => "jalr",
+			Mnemonic::Lb => "lb",
+			Mnemonic::Lbu => "lbu",
+			Mnemonic::Ld => "ld",
+			Mnemonic::Lh => "lh",
+			Mnemonic::Lhu => "lhu",
+			Mnemonic::Lr => "lr",
+			Mnemonic::Lui => "lui",
+			Mnemonic::Lw => "lw",
+			Mnemonic::Lwu => "lwu",
+			Mnemonic::Mret => "mret",
+			Mnemonic::Mul => "mul",
+			Mnemonic::Mulh => "mulh",
+			Mnemonic::Mulhsu => "mulhsu",
+			Mnemonic::Mulhu => "mulhu",
+			Mnemonic::Mulw => "mulw",
+			Mnemonic::Mv => "mv",
+			Mnemonic::Nop => "nop",
+			Mnemonic::Or => "or",
+			Mnemonic::Ori => "ori",
+			Mnemonic::Rem => "rem",
+			Mnemonic::Remu => "remu",
+			Mnemonic::Remuw => "remuw",
+			Mnemonic::Remw => "remw",
+			Mnemonic::Sb => "sb",
+			Mnemonic::Sc => "sc",
+			Mnemonic::Sd => "sd",
+			Mnemonic::Sextw => "sextw",
+			Mnemonic::Sh => "sh",
+			Mnemonic::Sll => "sll",
+			Mnemonic::Slli => "slli",
+			Mnemonic::Slliw => "slliw",
+			Mnemonic::Slt => "slt",
+			Mnemonic::Slti => "slti",
+			Mnemonic::Sltiu => "sltiu",
+			Mnemonic::Sltu => "sltu",
+			Mnemonic::Snez => "snez",
+			Mnemonic::Sra => "sra",
+			Mnemonic::Srai => "srai",
+			Mnemonic::Sraiw => "sraiw",
+			Mnemonic::SfenceVma => "sfence.vma",
+			Mnemonic::Sret => "sret",
+			Mnemonic::Srl => "srl",
+			Mnemonic::Srli => "srli",
+			Mnemonic::Srliw => "srliw",
+			Mnemonic::Sub => "sub",
+			Mnemonic::Subw => "subw",
+			Mnemonic::Sw => "sw",
+			Mnemonic::Unknown => "unknown",
+			Mnemonic::Wfi => "wfi",
+			Mnemonic::Xor => "xor",
+			Mnemonic::Xori => "xori",
+		};
+
+		// pad(), rather than write_str(), so that traces can align them
+		return f.pad(name);
+	}
+}
+
+impl Mnemonic
+{
+	/// The instruction a pseudo-instruction is shorthand for.
+	pub fn canonical(self) -> Mnemonic
+	{
+		return match self {
+			Mnemonic::Mv | Mnemonic::Nop => Mnemonic::Addi,
+			Mnemonic::Sextw => Mnemonic::Addiw,
+			Mnemonic::Snez => Mnemonic::Sltu,
+			_ => self,
+		};
+	}
+}
+
+#[derive(Debug, Clone)]
 pub struct Insn
 {
-	pub name: String,
+	pub mnemonic: Mnemonic,
+	pub bits: u32,
 	pub opcode: u32,
 	pub rd: u32,
 	pub rs1: u32,
@@ -36,6 +310,8 @@ pub struct Insn
 	pub func3: u32,
 	pub func7: u32,
 	pub insn_type: InsnType,
+	/// How many bytes the instruction took up, 2 for compressed ones
+	pub length: u64,
 }
 
 macro_rules! insn_mask {
@@ -47,14 +323,17 @@ macro_rules! insn_mask {
 }
 
 const OPCODE_LOAD: u32 = 0b000_0011;
+const OPCODE_LOAD_FP: u32 = 0b000_0111;
 const OPCODE_MISCMEM: u32 = 0b000_1111;
 const OPCODE_INT_REG_IMM: u32 = 0b0001_0011;
 const OPCODE_AUIPC: u32 = 0b001_0111;
 const OPCODE_INT_REG_IMM_32: u32 = 0b001_1011;
 const OPCODE_STORE: u32 = 0b010_0011;
+const OPCODE_STORE_FP: u32 = 0b010_0111;
 const OPCODE_ATOMIC: u32 = 0b010_1111;
 const OPCODE_INT_REG_REG: u32 = 0b011_0011;
 const OPCODE_LUI: u32 = 0b011_0111;
+const OPCODE_OP_FP: u32 = 0b101_0011;
 const OPCODE_INT_REG_REG_32: u32 = 0b011_1011;
 const OPCODE_BRANCH: u32 = 0b110_0011;
 const OPCODE_JALR: u32 = 0b110_0111;
@@ -150,8 +429,11 @@ const FUNC3_SRLIW: u32 = 0b101;
 const FUNC3_SRAIW: u32 = 0b101;
 const FUNC3_ADDW: u32 = 0b000;
 const FUNC3_SUBW: u32 = 0b000;
+#[allow(dead_code)]
 const FUNC3_SLLW: u32 = 0b001;
+#[allow(dead_code)]
 const FUNC3_SRLW: u32 = 0b101;
+#[allow(dead_code)]
 const FUNC3_SRAW: u32 = 0b101;
 
 const FUNC3_MUL: u32 = 0b000;
@@ -176,6 +458,10 @@ const FUNC3_LBU: u32 = 0b100;
 const FUNC3_LHU: u32 = 0b101;
 const FUNC3_LWU: u32 = 0b110;
 
+const FUNC3_FENCE: u32 = 0b000;
+const FUNC3_FENCE_I: u32 = 0b001;
+
+const FUNC3_PRIV: u32 = 0b000;
 const FUNC3_CSRRW: u32 = 0b001;
 const FUNC3_CSRRS: u32 = 0b010;
 const FUNC3_CSRRC: u32 = 0b011;
@@ -183,6 +469,14 @@ const FUNC3_CSRRWI: u32 = 0b101;
 const FUNC3_CSRRSI: u32 = 0b110;
 const FUNC3_CSRRCI: u32 = 0b111;
 
+const IMM_ECALL: i32 = 0b0000_0000_0000;
+const IMM_EBREAK: i32 = 0b0000_0000_0001;
+const IMM_WFI: i32 = 0b0001_0000_0101;
+const IMM_SRET: i32 = 0b0001_0000_0010;
+const IMM_MRET: i32 = 0b0011_0000_0010;
+// SFENCE.VMA has rs2 where the others have the rest of their immediate
+const FUNC7_SFENCE_VMA: u32 = 0b0001001;
+
 const FUNC3_BEQ: u32 = 0b000;
 const FUNC3_BNE: u32 = 0b001;
 const FUNC3_BLT: u32 = 0b100;
@@ -194,6 +488,7 @@ const FUNC3_RV32_ATOMIC: u32 = 0b010;
 const FUNC3_RV64_ATOMIC: u32 = 0b011;
 
 const FUNC7_SHIFT: u32 = IMM11_5_STYPE_SHIFT;
+#[allow(dead_code)]
 const FUNC7_WIDTH: u32 = IMM11_5_STYPE_WIDTH;
 const FUNC7_MASK: u32 = IMM11_5_STYPE_MASK;
 
@@ -204,10 +499,14 @@ const FUNC7_SRLI: u32 = 0b0000000;
 const FUNC7_SRAI: u32 = 0b0100000;
 const FUNC7_ADD: u32 = 0b0000000;
 const FUNC7_SUB: u32 = 0b0100000;
+#[allow(dead_code)]
 const FUNC7_SLL: u32 = 0b0000000;
+#[allow(dead_code)]
 const FUNC7_SLT: u32 = 0b0000000;
+#[allow(dead_code)]
 const FUNC7_SLTU: u32 = 0b0000000;
 const FUNC7_XOR: u32 = 0b0000000;
+#[allow(dead_code)]
 const FUNC7_SRL: u32 = 0b0000000;
 const FUNC7_SRA: u32 = 0b0100000;
 const FUNC7_OR: u32 = 0b0000000;
@@ -230,7 +529,8 @@ impl Default for Insn
 	fn default() -> Insn
 	{
 		return Insn {
-			name: String::from("tba"),
+			mnemonic: Mnemonic::Unknown,
+			bits: 0x0,
 			opcode: 0x0,
 			rd: 0x0,
 			rs1: 0x0,
@@ -239,6 +539,7 @@ impl Default for Insn
 			func3: 0x0,
 			func7: 0x0,
 			insn_type: InsnType::Invalid,
+			length: 4,
 		};
 	}
 }
@@ -247,6 +548,7 @@ impl Insn
 {
 	fn parse(&mut self, input: u32)
 	{
+		self.bits = input;
 		self.opcode = input & OPCODE_MASK;
 
 		match self.opcode {
@@ -279,7 +581,13 @@ impl Insn
 			},
 
 			OPCODE_SYSTEM => {
-				self.insn_type = InsnType::I;
+				let func3 = field_get!(input, FUNC3, u32);
+				let func7 = field_get!(input, FUNC7, u32);
+				if func3 == FUNC3_PRIV && func7 == FUNC7_SFENCE_VMA {
+					self.insn_type = InsnType::R;
+				} else {
+					self.insn_type = InsnType::I;
+				}
 			},
 
 			OPCODE_MISCMEM => {
@@ -307,8 +615,25 @@ impl Insn
 				self.insn_type = InsnType::R;
 			},
 
+			OPCODE_LOAD_FP => {
+				self.insn_type = InsnType::I;
+			},
+
+			OPCODE_STORE_FP => {
+				self.insn_type = InsnType::S;
+			},
+
+			OPCODE_OP_FP => {
+				self.insn_type = InsnType::R;
+			},
+
 			_ => {
-				todo!("opcode 0b{:b} .insn 0x{:x}", self.opcode, input);
+				debug_println!(
+					"opcode 0b{:b} .insn 0x{:x}",
+					self.opcode,
+					input
+				);
+				return;
 			},
 		}
 
@@ -384,23 +709,257 @@ impl Insn
 
 			_ => (),
 		}
+
+		let mnemonic = self.decode_mnemonic().unwrap_or(Mnemonic::Unknown);
+		self.mnemonic = self.alias(mnemonic);
+	}
+
+	/// The pseudo-instruction traces show mnemonic as, if it has one.
+	fn alias(&self, mnemonic: Mnemonic) -> Mnemonic
+	{
+		let no_imm = self.imm == 0;
+
+		return match mnemonic {
+			Mnemonic::Addi if no_imm && self.rd == 0 && self.rs1 == 0 => {
+				Mnemonic::Nop
+			},
+			Mnemonic::Addi if no_imm => Mnemonic::Mv,
+			Mnemonic::Addiw if no_imm => Mnemonic::Sextw,
+			Mnemonic::Sltu if self.rs1 == 0 => Mnemonic::Snez,
+			_ => mnemonic,
+		};
+	}
+
+	/// The operands in assembler syntax, as used by objdump & spike, so that
+	/// traces can be compared against theirs. Branch & jump targets are
+	/// relative to the instruction's own pc.
+	pub fn operands(&self) -> String
+	{
+		let rd = REGISTER_NAMES[self.rd as usize];
+		let rs1 = REGISTER_NAMES[self.rs1 as usize];
+		let rs2 = REGISTER_NAMES[self.rs2 as usize];
+		let imm = self.imm;
+
+		match (self.opcode, &self.insn_type) {
+			(OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_OP_FP, _) => {
+				return self.fp_operands();
+			},
+			(OPCODE_LUI | OPCODE_AUIPC, _) => {
+				return format!("{}, {:#x}", rd, (imm as u32) >> 12);
+			},
+			(OPCODE_LOAD | OPCODE_JALR, _) => {
+				return format!("{}, {}({})", rd, imm, rs1);
+			},
+			(OPCODE_MISCMEM, _) => return String::new(),
+			(OPCODE_SYSTEM, InsnType::R) => {
+				return format!("{}, {}", rs1, rs2);
+			},
+			(OPCODE_SYSTEM, _) if self.func3 == FUNC3_PRIV => {
+				return String::new();
+			},
+			(OPCODE_SYSTEM, _) => {
+				let csr = imm as usize & gen_mask!(11, 0, usize);
+				let csr = match csr_name(csr) {
+					Some(name) => String::from(name),
+					None => format!("{:#x}", csr),
+				};
+				// The immediate forms hold a uimm in rs1
+				if self.func3 & 0b100 != 0 {
+					return format!("{}, {}, {}", rd, csr, self.rs1);
+				}
+				return format!("{}, {}, {}", rd, csr, rs1);
+			},
+			(OPCODE_ATOMIC, _) if self.func7 >> 2 == FUNC7_LR >> 2 => {
+				return format!("{}, ({})", rd, rs1);
+			},
+			(OPCODE_ATOMIC, _) => {
+				return format!("{}, {}, ({})", rd, rs2, rs1);
+			},
+			// Shifts keep some of their func7 in the immediate
+			(OPCODE_INT_REG_IMM, InsnType::I)
+				if matches!(self.func3, FUNC3_SLLI | FUNC3_SRLI) =>
+			{
+				return format!("{}, {}, {}", rd, rs1, imm & 0x3f);
+			},
+			(OPCODE_INT_REG_IMM_32, InsnType::R) => {
+				return format!("{}, {}, {}", rd, rs1, self.rs2);
+			},
+			(_, InsnType::I) => return format!("{}, {}, {}", rd, rs1, imm),
+			(_, InsnType::R) => return format!("{}, {}, {}", rd, rs1, rs2),
+			(_, InsnType::S) => return format!("{}, {}({})", rs2, imm, rs1),
+			(_, InsnType::B) => {
+				return format!("{}, {}, {}", rs1, rs2, pc_relative(imm));
+			},
+			(_, InsnType::J) => return format!("{}, {}", rd, pc_relative(imm)),
+			(_, InsnType::U | InsnType::Invalid) => return String::new(),
+		}
+	}
+
+	/// What the encoding says the instruction is, or None if the emulator
+	/// doesn't implement it.
+	fn decode_mnemonic(&self) -> Option<Mnemonic>
+	{
+		// srai & co are told apart from their logical siblings by bit 30
+		let arithmetic = self.bits & (1 << 30) != 0;
+
+		if matches!(
+			self.opcode,
+			OPCODE_LOAD_FP | OPCODE_STORE_FP | OPCODE_OP_FP
+		) {
+			return self.decode_fp_mnemonic();
+		}
+
+		let mnemonic = match (self.opcode, self.func3) {
+			(OPCODE_LUI, _) => Mnemonic::Lui,
+			(OPCODE_AUIPC, _) => Mnemonic::Auipc,
+			(OPCODE_JAL, _) => Mnemonic::Jal,
+			(OPCODE_JALR, 0) => Mnemonic::Jalr,
+
+			(OPCODE_BRANCH, FUNC3_BEQ) => Mnemonic::Beq,
+			(OPCODE_BRANCH, FUNC3_BNE) => Mnemonic::Bne,
+			(OPCODE_BRANCH, FUNC3_BLT) => Mnemonic::Blt,
+			(OPCODE_BRANCH, FUNC3_BGE) => Mnemonic::Bge,
+			(OPCODE_BRANCH, FUNC3_BLTU) => Mnemonic::Bltu,
+			(OPCODE_BRANCH, FUNC3_BGEU) => Mnemonic::Bgeu,
+
+			(OPCODE_LOAD, FUNC3_LB) => Mnemonic::Lb,
+			(OPCODE_LOAD, FUNC3_LH) => Mnemonic::Lh,
+			(OPCODE_LOAD, FUNC3_LW) => Mnemonic::Lw,
+			(OPCODE_LOAD, FUNC3_LD) => Mnemonic::Ld,
+			(OPCODE_LOAD, FUNC3_LBU) => Mnemonic::Lbu,
+			(OPCODE_LOAD, FUNC3_LHU) => Mnemonic::Lhu,
+			(OPCODE_LOAD, FUNC3_LWU) => Mnemonic::Lwu,
+
+			(OPCODE_STORE, FUNC3_SB) => Mnemonic::Sb,
+			(OPCODE_STORE, FUNC3_SH) => Mnemonic::Sh,
+			(OPCODE_STORE, FUNC3_SW) => Mnemonic::Sw,
+			(OPCODE_STORE, FUNC3_SD) => Mnemonic::Sd,
+
+			(OPCODE_INT_REG_IMM, FUNC3_ADDI) => Mnemonic::Addi,
+			(OPCODE_INT_REG_IMM, FUNC3_SLTI) => Mnemonic::Slti,
+			(OPCODE_INT_REG_IMM, FUNC3_SLTIU) => Mnemonic::Sltiu,
+			(OPCODE_INT_REG_IMM, FUNC3_XORI) => Mnemonic::Xori,
+			(OPCODE_INT_REG_IMM, FUNC3_ORI) => Mnemonic::Ori,
+			(OPCODE_INT_REG_IMM, FUNC3_ANDI) => Mnemonic::Andi,
+			(OPCODE_INT_REG_IMM, FUNC3_SLLI) => Mnemonic::Slli,
+			(OPCODE_INT_REG_IMM, FUNC3_SRAI) if arithmetic => Mnemonic::Srai,
+			(OPCODE_INT_REG_IMM, FUNC3_SRLI) => Mnemonic::Srli,
+
+			(OPCODE_INT_REG_IMM_32, FUNC3_ADDIW) => Mnemonic::Addiw,
+			(OPCODE_INT_REG_IMM_32, FUNC3_SLLIW) => Mnemonic::Slliw,
+			(OPCODE_INT_REG_IMM_32, FUNC3_SRAIW) if arithmetic => {
+				Mnemonic::Sraiw
+			},
+			(OPCODE_INT_REG_IMM_32, FUNC3_SRLIW) => Mnemonic::Srliw,
+
+			(OPCODE_INT_REG_REG, func3) if self.func7 == FUNC7_MULDIV => {
+				match func3 {
+					FUNC3_MUL => Mnemonic::Mul,
+					FUNC3_MULH => Mnemonic::Mulh,
+					FUNC3_MULHSU => Mnemonic::Mulhsu,
+					FUNC3_MULHU => Mnemonic::Mulhu,
+					FUNC3_DIV => Mnemonic::Div,
+					FUNC3_DIVU => Mnemonic::Divu,
+					FUNC3_REM => Mnemonic::Rem,
+					FUNC3_REMU => Mnemonic::Remu,
+					_ => return None,
+				}
+			},
+			(OPCODE_INT_REG_REG, FUNC3_SUB) if self.func7 == FUNC7_SUB => {
+				Mnemonic::Sub
+			},
+			(OPCODE_INT_REG_REG, FUNC3_ADD) => Mnemonic::Add,
+			(OPCODE_INT_REG_REG, FUNC3_SLL) => Mnemonic::Sll,
+			(OPCODE_INT_REG_REG, FUNC3_SLT) => Mnemonic::Slt,
+			(OPCODE_INT_REG_REG, FUNC3_SLTU) => Mnemonic::Sltu,
+			(OPCODE_INT_REG_REG, FUNC3_XOR) => Mnemonic::Xor,
+			(OPCODE_INT_REG_REG, FUNC3_SRA) if arithmetic => Mnemonic::Sra,
+			(OPCODE_INT_REG_REG, FUNC3_SRL) => Mnemonic::Srl,
+			(OPCODE_INT_REG_REG, FUNC3_OR) => Mnemonic::Or,
+			(OPCODE_INT_REG_REG, FUNC3_AND) => Mnemonic::And,
+
+			(OPCODE_INT_REG_REG_32, func3) if self.func7 == FUNC7_MULDIV => {
+				match func3 {
+					FUNC3_MUL => Mnemonic::Mulw,
+					FUNC3_DIV => Mnemonic::Divw,
+					FUNC3_DIVU => Mnemonic::Divuw,
+					FUNC3_REM => Mnemonic::Remw,
+					FUNC3_REMU => Mnemonic::Remuw,
+					_ => return None,
+				}
+			},
+			(OPCODE_INT_REG_REG_32, FUNC3_ADDW) if self.func7 == FUNC7_ADD => {
+				Mnemonic::Addw
+			},
+			(OPCODE_INT_REG_REG_32, FUNC3_SUBW) if self.func7 == FUNC7_SUB => {
+				Mnemonic::Subw
+			},
+
+			(OPCODE_MISCMEM, FUNC3_FENCE) => Mnemonic::Fence,
+			(OPCODE_MISCMEM, FUNC3_FENCE_I) => Mnemonic::FenceI,
+
+			(OPCODE_SYSTEM, FUNC3_PRIV) if self.func7 == FUNC7_SFENCE_VMA => {
+				Mnemonic::SfenceVma
+			},
+			(OPCODE_SYSTEM, FUNC3_PRIV) => {
+				match self.imm {
+					IMM_ECALL => Mnemonic::Ecall,
+					IMM_EBREAK => Mnemonic::Ebreak,
+					IMM_WFI => Mnemonic::Wfi,
+					IMM_SRET => Mnemonic::Sret,
+					IMM_MRET => Mnemonic::Mret,
+					_ => return None,
+				}
+			},
+			(OPCODE_SYSTEM, FUNC3_CSRRW) => Mnemonic::Csrrw,
+			(OPCODE_SYSTEM, FUNC3_CSRRS) => Mnemonic::Csrrs,
+			(OPCODE_SYSTEM, FUNC3_CSRRC) => Mnemonic::Csrrc,
+			(OPCODE_SYSTEM, FUNC3_CSRRWI) => Mnemonic::Csrrwi,
+			(OPCODE_SYSTEM, FUNC3_CSRRSI) => Mnemonic::Csrrsi,
+			(OPCODE_SYSTEM, FUNC3_CSRRCI) => Mnemonic::Csrrci,
+
+			(OPCODE_ATOMIC, FUNC3_RV32_ATOMIC | FUNC3_RV64_ATOMIC) => {
+				// The bottom two bits of func7 are aq & rl
+				match self.func7 & !0b11 {
+					FUNC7_LR => Mnemonic::Lr,
+					FUNC7_SC => Mnemonic::Sc,
+					FUNC7_AMOSWAP => Mnemonic::Amoswap,
+					FUNC7_AMOADD => Mnemonic::Amoadd,
+					FUNC7_AMOXOR => Mnemonic::Amoxor,
+					FUNC7_AMOAND => Mnemonic::Amoand,
+					FUNC7_AMOOR => Mnemonic::Amoor,
+					FUNC7_AMOMIN => Mnemonic::Amomin,
+					FUNC7_AMOMAX => Mnemonic::Amomax,
+					FUNC7_AMOMINU => Mnemonic::Amominu,
+					FUNC7_AMOMAXU => Mnemonic::Amomaxu,
+					_ => return None,
+				}
+			},
+
+			_ => return None,
+		};
+
+		return Some(mnemonic);
 	}
 
-	fn handle_int_reg_reg_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_int_reg_reg_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 
 		let rs1: u64 = hart.read_register(self.rs1 as usize);
 		let rs2: u64 = hart.read_register(self.rs2 as usize);
 
 		// shifts encode the "shamt" in the bottom 6 bits of register
 		// rs2. It's the bottom 5 for rv32.
-		let shift: u32 = (rs2 & gen_mask!(5, 0, u64)) as u32;
+		let shift: u32 = (rs2 as u32) & (hart.xlen.bits() - 1);
+		// The upper half of a product is the bits above XLEN
+		let high = hart.xlen.bits();
 
 		if self.func7 == FUNC7_MULDIV {
 			match self.func3 {
 				FUNC3_MUL => {
-					self.name = String::from("mul");
 					// MUL performs an XLEN-bit * XLEN-bit
 					// multiplication of rs1 by rs2 and
 					// places the lower XLEN bits in the
@@ -411,40 +970,39 @@ impl Insn
 				},
 
 				FUNC3_MULH => {
-					self.name = String::from("mulh");
 					// MUL performs an XLEN-bit * XLEN-bit
 					// multiplication of rs1 by rs2 and
 					// places the upper XLEN bits in the
 					// destination register.
-					let tmp: i128 = rs1 as i128 * rs2 as i128;
-					let tmp: u64 = (tmp as u128 >> 64) as u64;
+					let tmp: i128 = rs1 as i64 as i128 * rs2 as i64 as i128;
+					let tmp: u64 = (tmp >> high) as u64;
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_MULHU => {
-					self.name = String::from("mulhu");
 					// MUL performs an XLEN-bit * XLEN-bit
 					// multiplication of rs1 by rs2 and
 					// places the lower XLEN bits in the
 					// destination register.
+					let rs1 = hart.zero_extend(rs1);
+					let rs2 = hart.zero_extend(rs2);
 					let tmp: u128 = rs1 as u128 * rs2 as u128;
-					let tmp: u64 = (tmp >> 64) as u64;
+					let tmp: u64 = (tmp >> high) as u64;
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_MULHSU => {
-					self.name = String::from("mulhsu");
 					// MUL performs an XLEN-bit * XLEN-bit
 					// multiplication of rs1 by rs2 and
 					// places the upper XLEN bits in the
 					// destination register.
-					let tmp: i128 = rs1 as i128 * rs2 as u128 as i128;
-					let tmp: u64 = (tmp as u128 >> 64) as u64;
+					let rs2 = hart.zero_extend(rs2);
+					let tmp: i128 = rs1 as i64 as i128 * rs2 as i128;
+					let tmp: u64 = (tmp >> high) as u64;
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_DIV => {
-					self.name = String::from("div");
 					// div performs an xlen bits by xlen
 					// bits signed integer division of rs1
 					// by rs2 rounding towards zero
@@ -453,16 +1011,15 @@ impl Insn
 				},
 
 				FUNC3_DIVU => {
-					self.name = String::from("divu");
 					// div performs an xlen bits by xlen
 					// bits unsigned integer division of rs1
 					// by rs2 rounding towards zero
-					let tmp: u64 = rs1 / rs2;
+					let tmp: u64 =
+						hart.zero_extend(rs1) / hart.zero_extend(rs2);
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_REM => {
-					self.name = String::from("rem");
 					// div performs an xlen bits by xlen
 					// bits signed integer division of rs1
 					// by rs2 rounding towards zero, and
@@ -472,22 +1029,21 @@ impl Insn
 				},
 
 				FUNC3_REMU => {
-					self.name = String::from("remu");
 					// div performs an xlen bits by xlen
 					// bits unsigned integer division of rs1
 					// by rs2 rounding towards zero, and
 					// returns the remainder in rd
-					let tmp: u64 = rs1 % rs2;
+					let tmp: u64 =
+						hart.zero_extend(rs1) % hart.zero_extend(rs2);
 					hart.write_register(self.rd as usize, tmp);
 				},
 
-				_ => todo!("muldiv: {:}", self.func3),
+				_ => return Err(illegal_insn(self.bits)),
 			}
 		} else {
 			match self.func3 {
 				FUNC3_ADD => {
 					if self.func7 == FUNC7_ADD {
-						self.name = String::from("add");
 						// ADD adds the value in rs1 to rs2 and
 						// stores the result in rd
 						// overflows are ignored, the lower XLEN
@@ -495,7 +1051,6 @@ impl Insn
 						let tmp: u64 = rs1.wrapping_add(rs2);
 						hart.write_register(self.rd as usize, tmp);
 					} else {
-						self.name = String::from("sub");
 						// SUB subtracts the value in rs2 from
 						// rs1 and stores the result in rd
 						// overflows are ignored, the lower XLEN
@@ -506,26 +1061,21 @@ impl Insn
 				},
 
 				FUNC3_AND => {
-					self.name = String::from("and");
 					let tmp: u64 = rs1 & rs2;
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_OR => {
-					self.name = String::from("or");
 					let tmp: u64 = rs1 | rs2;
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_XOR => {
-					self.name = String::from("xor");
 					let tmp: u64 = rs1 ^ rs2;
 					hart.write_register(self.rd as usize, tmp);
 				},
 
 				FUNC3_SLT => {
-					self.name = String::from("slt");
-
 					if (rs1 as i64) < (rs2 as i64) {
 						hart.write_register(self.rd as usize, 1);
 					} else {
@@ -534,12 +1084,6 @@ impl Insn
 				},
 
 				FUNC3_SLTU => {
-					if self.rs1 == 0 {
-						self.name = String::from("snez");
-					} else {
-						self.name = String::from("sltu");
-					}
-
 					if rs1 < rs2 {
 						hart.write_register(self.rd as usize, 1);
 					} else {
@@ -548,7 +1092,6 @@ impl Insn
 				},
 
 				FUNC3_SLL => {
-					self.name = String::from("sll");
 					let tmp = rs1.wrapping_shl(shift);
 					hart.write_register(self.rd as usize, tmp);
 				},
@@ -557,28 +1100,29 @@ impl Insn
 					// if bit 6 is set, shift the sign bit down
 					let is_sra =
 						(self.func7 & gen_mask!(6, 6, u32)) == FUNC7_SRA;
-					let tmp: u64;
-					if !is_sra {
-						self.name = String::from("srl");
-						tmp = rs1.wrapping_shr(shift);
+					let tmp: u64 = if !is_sra {
+						hart.zero_extend(rs1).wrapping_shr(shift)
 					} else {
-						self.name = String::from("sra");
-						tmp = (rs1 as i64).wrapping_shr(shift) as u64;
-					}
+						(rs1 as i64).wrapping_shr(shift) as u64
+					};
 
 					hart.write_register(self.rd as usize, tmp);
 				},
 
-				_ => todo!("reg reg: {:}", self.func3),
+				_ => return Err(illegal_insn(self.bits)),
 			}
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_int_reg_reg32_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_int_reg_reg32_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 
 		let rs1: u64 = hart.read_register(self.rs1 as usize);
 		let rs1: i32 = (rs1 & gen_mask!(31, 0, u64)) as i32;
@@ -587,16 +1131,36 @@ impl Insn
 
 		// shifts encode the "shamt" in the bottom 6 bits of rs2
 		// field. It's the bottom 5 for rv32.
-		let shift: u32 = (rs2 as u32) & gen_mask!(5, 0, u32);
+		let _shift: u32 = (rs2 as u32) & gen_mask!(5, 0, u32);
 
 		if self.func7 == FUNC7_MULDIV {
-			todo!("32-bit M extension stuff is not implemented yet!");
+			// The W forms operate on the low 32 bits of each register &
+			// sign extend the 32-bit result, even for the unsigned ones.
+			// Dividing by zero gives all ones & leaves the dividend as
+			// the remainder, while overflowing gives back the dividend
+			// with a remainder of zero, rather than trapping.
+			let tmp: i32 = match self.func3 {
+				FUNC3_MUL => rs1.wrapping_mul(rs2),
+				FUNC3_DIV if rs2 == 0 => -1,
+				FUNC3_DIV => rs1.wrapping_div(rs2),
+				FUNC3_DIVU if rs2 == 0 => -1,
+				FUNC3_DIVU => ((rs1 as u32) / (rs2 as u32)) as i32,
+				FUNC3_REM if rs2 == 0 => rs1,
+				FUNC3_REM => rs1.wrapping_rem(rs2),
+				FUNC3_REMU if rs2 == 0 => rs1,
+				FUNC3_REMU => ((rs1 as u32) % (rs2 as u32)) as i32,
+				_ => return Err(illegal_insn(self.bits)),
+			};
+			hart.write_register(self.rd as usize, tmp as i64 as u64);
+
+			debug_println!("Found {:}", self.mnemonic);
+
+			return Ok(());
 		}
 
 		match self.func3 {
 			FUNC3_ADD => {
 				if self.func7 == FUNC7_ADD {
-					self.name = String::from("addw");
 					// ADDW is like ADD, but operates on
 					// 32-bit values, producing signed
 					// 32-bit results. The results are sign
@@ -606,7 +1170,6 @@ impl Insn
 					let extended: u64 = tmp as i64 as u64;
 					hart.write_register(self.rd as usize, extended);
 				} else {
-					self.name = String::from("subw");
 					// SUBW is to SUB as ADDW is to ADD
 					let tmp: i32 = rs1.wrapping_sub(rs2);
 					let extended: u64 = tmp as i64 as u64;
@@ -614,15 +1177,19 @@ impl Insn
 				}
 			},
 
-			_ => todo!("reg reg 32: {:}", self.func3),
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_int_reg_imm_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_int_reg_imm_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 
 		// All of these functions take the sign-extended 12-bit
 		// immediate, and use it perform some calculation register rs1.
@@ -634,43 +1201,30 @@ impl Insn
 		// shifts encode the "shamt" in the bottom 6 bits of the imm
 		// field. It's the bottom 5 for rv32, but the 5th bit is always
 		// defined as 0 there.
-		let shift: u32 = ((imm as u64) & gen_mask!(5, 0, u64)) as u32;
+		let shift: u32 = (imm as u32) & (hart.xlen.bits() - 1);
 
 		match self.func3 {
 			FUNC3_ADDI => {
-				if self.imm == 0 && self.rs1 == 0 && self.rd == 0 {
-					self.name = String::from("nop");
-					return;
-				} else if self.imm == 0 {
-					self.name = String::from("mv");
-				} else {
-					self.name = String::from("addi");
-				}
-
 				src = src.wrapping_add_signed(imm);
 				hart.write_register(self.rd as usize, src);
 			},
 
 			FUNC3_ANDI => {
-				self.name = String::from("andi");
 				src &= imm as u64;
 				hart.write_register(self.rd as usize, src);
 			},
 
 			FUNC3_ORI => {
-				self.name = String::from("ori");
 				src |= imm as u64;
 				hart.write_register(self.rd as usize, src);
 			},
 
 			FUNC3_XORI => {
-				self.name = String::from("xori");
 				src ^= imm as u64;
 				hart.write_register(self.rd as usize, src);
 			},
 
 			FUNC3_SLTI => {
-				self.name = String::from("slti");
 				let tmp: i64 = src as i64;
 
 				if tmp < imm {
@@ -681,8 +1235,6 @@ impl Insn
 			},
 
 			FUNC3_SLTIU => {
-				self.name = String::from("sltiu");
-
 				if src < (imm as u64) {
 					hart.write_register(self.rd as usize, 1);
 				} else {
@@ -691,7 +1243,6 @@ impl Insn
 			},
 
 			FUNC3_SLLI => {
-				self.name = String::from("slli");
 				src = src.wrapping_shl(shift);
 				hart.write_register(self.rd as usize, src);
 			},
@@ -700,27 +1251,27 @@ impl Insn
 				// if bit 10 is set, shift the sign bit down
 				let is_srai = (imm as u64) & gen_mask!(10, 10, u64);
 				if is_srai != 0 {
-					self.name = String::from("srli");
-					src = src.wrapping_shr(shift);
-				} else {
-					self.name = String::from("srai");
 					src = (src as i64).wrapping_shr(shift) as u64;
+				} else {
+					src = hart.zero_extend(src).wrapping_shr(shift);
 				}
 
 				hart.write_register(self.rd as usize, src);
 			},
 
-			_ => todo!("reg imm: {:}", self.func3),
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
 	fn handle_int_reg_imm32_insn(
-		&mut self, platform: &Arc<RwLock<&mut Platform>>,
-	)
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 		let mut src: u64 = hart.read_register(self.rs1 as usize);
 		let imm: i64 = self.imm as i64;
 
@@ -731,12 +1282,6 @@ impl Insn
 
 		match self.func3 {
 			FUNC3_ADDIW => {
-				if self.imm == 0 {
-					self.name = String::from("sextw");
-				} else {
-					self.name = String::from("addiw");
-				}
-
 				// ADDIW adds the sign-extended 12-bit immediate
 				// to register rs1 & stores the sign extension
 				// of a 32-bit result in rd. Arithmetic overflow
@@ -753,7 +1298,6 @@ impl Insn
 				// TODO: verify that "32-bit signed result"
 				// does not mean that it should be sign extended
 				// out to 64-bits
-				self.name = String::from("slliw");
 				let tmp_src = (src & gen_mask!(31, 0, u64)) as u32;
 				src = tmp_src.wrapping_shl(shift) as u64;
 				hart.write_register(self.rd as usize, src);
@@ -767,22 +1311,23 @@ impl Insn
 				// out to 64-bits
 				let tmp_src = (src & gen_mask!(31, 0, u64)) as u32;
 				if is_sraiw == 0 {
-					self.name = String::from("srliw");
 					src = tmp_src.wrapping_shr(shift) as u64;
 				} else {
-					self.name = String::from("sraiw");
 					src = (tmp_src as i32).wrapping_shr(shift) as u32 as u64;
 				}
 
 				hart.write_register(self.rd as usize, src);
 			},
-			_ => todo!("reg imm32: {:}", self.func3),
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_store_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_store_insn(&mut self, platform: &mut Platform)
+		-> Result<(), Trap>
 	{
 		// These are all store instructions of varied widths
 		// Stores add a sign-extended 12-bit immediate to rs1, forming
@@ -790,140 +1335,174 @@ impl Insn
 		// address.
 		//
 
-		let platform_read = platform.read().unwrap();
 		let offset: i64 = self.imm.try_into().unwrap();
-		let hart = &platform_read.hart;
+		let hart = &platform.hart;
 		let base: u64 = hart.read_register(self.rs1 as usize);
-		let address: u64 = base.wrapping_add_signed(offset);
-		let mut tmp: u64 = hart.read_register(self.rs2 as usize);
-		drop(platform_read);
-		let platform_write = &mut platform.write().unwrap();
-		let hart_id = platform_write.hart.id;
+		let address: u64 = hart.zero_extend(base.wrapping_add_signed(offset));
+		let value: u64 = hart.read_register(self.rs2 as usize);
 
 		match self.func3 {
 			FUNC3_SD => {
-				self.name = String::from("sd");
-				let _ = platform_write.write_from_hart(
-					hart_id,
-					address as usize,
-					tmp,
-				);
+				platform.store(address, value)?;
 			},
 
 			FUNC3_SW => {
-				self.name = String::from("sw");
-				tmp &= gen_mask!(31, 0, u64);
-				let _ = platform_write.write_from_hart(
-					hart_id,
-					address as usize,
-					tmp as u32,
-				);
+				platform.store(address, value as u32)?;
 			},
 
 			FUNC3_SH => {
-				self.name = String::from("sh");
-				tmp &= gen_mask!(15, 0, u64);
-				let _ = platform_write.write_from_hart(
-					hart_id,
-					address as usize,
-					tmp as u16,
-				);
+				platform.store(address, value as u16)?;
 			},
 
 			FUNC3_SB => {
-				self.name = String::from("sb");
-				tmp &= gen_mask!(7, 0, u64);
-				let _ = platform_write.write_from_hart(
-					hart_id,
-					address as usize,
-					tmp as u8,
-				);
+				platform.store(address, value as u8)?;
 			},
 
-			_ => todo!("store: {:}", self.func3),
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_load_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_load_insn(&mut self, platform: &mut Platform)
+		-> Result<(), Trap>
 	{
 		// These are all load instructions of varied widths.
 		// Loads add a sign-extended 12-bit immediate to rs1, forming
 		// a memory address. The value at this memory address is put in
 		// the register in rd.
-		let platform_read = platform.read().unwrap();
 		let offset: i64 = self.imm.try_into().unwrap();
-		let hart = &platform_read.hart;
+		let hart = &platform.hart;
 		let base: u64 = hart.read_register(self.rs1 as usize);
-		let address: u64 = base.wrapping_add_signed(offset);
-		drop(platform_read);
-		let platform_bus = &mut platform.write().unwrap();
+		let address: u64 = hart.zero_extend(base.wrapping_add_signed(offset));
 
+		let value: u64 = match self.func3 {
+			FUNC3_LD => platform.load::<u64>(address)?,
+
+			FUNC3_LW => platform.load::<i32>(address)? as i64 as u64,
+
+			FUNC3_LH => platform.load::<i16>(address)? as i64 as u64,
+
+			FUNC3_LB => platform.load::<i8>(address)? as i64 as u64,
+
+			FUNC3_LWU => platform.load::<u32>(address)? as u64,
+
+			FUNC3_LHU => platform.load::<u16>(address)? as u64,
+
+			FUNC3_LBU => platform.load::<u8>(address)? as u64,
+
+			_ => return Err(illegal_insn(self.bits)),
+		};
+
+		let hart = &mut platform.hart;
+		hart.write_register(self.rd as usize, value);
+
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
+	}
+
+	fn handle_miscmem_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
+	{
 		match self.func3 {
-			FUNC3_LD => {
-				self.name = String::from("ld");
-				let tmp: u64 = platform_bus.read(address as usize).unwrap();
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, tmp);
-			},
+			// Harts take turns & each store is visible to all of them as
+			// soon as it is made, so there's nothing to order.
+			FUNC3_FENCE => (),
 
-			FUNC3_LW => {
-				self.name = String::from("lw");
-				let tmp: u32 = platform_bus.read(address as usize).unwrap();
-				let extended: u64 = tmp as i32 as i64 as u64;
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, extended);
+			// Only the executing hart's view is flushed: a store from
+			// another hart is not visible to this hart's fetches until
+			// this hart executes its own FENCE.I.
+			FUNC3_FENCE_I => {
+				platform.flush_decode_cache();
 			},
 
-			FUNC3_LH => {
-				self.name = String::from("lh");
-				let tmp: u16 = platform_bus.read(address as usize).unwrap();
-				let extended: u64 = tmp as i16 as i32 as u64;
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, extended);
-			},
+			_ => return Err(illegal_insn(self.bits)),
+		}
+
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
+	}
 
-			FUNC3_LB => {
-				self.name = String::from("lb");
-				let tmp: u8 = platform_bus.read(address as usize).unwrap();
-				let extended: u64 = tmp as i8 as i64 as u64;
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, extended);
+	fn handle_priv_insn(&mut self, platform: &mut Platform)
+		-> Result<(), Trap>
+	{
+		let pc = platform.hart.pc;
+		let privilege = platform.hart.privilege;
+
+		// These share the SYSTEM opcode with the CSR instructions, but
+		// have a func3 of zero & are told apart by their immediate.
+		match self.imm {
+			_ if self.func7 == FUNC7_SFENCE_VMA && self.rd == 0 => {
+				if privilege == PRIV_USER {
+					return Err(illegal_insn(self.bits));
+				}
+				// Every access walks the page tables afresh & the decode
+				// caches are by physical address, so there are no cached
+				// translations to flush, whatever address & ASID rs1 &
+				// rs2 pick.
+				return Ok(());
+			},
+
+			IMM_ECALL => {
+				debug_println!("ecall @ {:x}", pc);
+				// There's no firmware to field ecalls from S-mode
+				if privilege == PRIV_SUPERVISOR {
+					sbi::handle(platform);
+					return Ok(());
+				}
+				return Err(Trap {
+					cause: CAUSE_ECALL_UMODE + privilege,
+					tval: 0,
+				});
 			},
 
-			FUNC3_LWU => {
-				self.name = String::from("lwu");
-				let tmp: u32 = platform_bus.read(address as usize).unwrap();
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, tmp as u64);
+			IMM_EBREAK => {
+				debug_println!("ebreak @ {:x}", pc);
+				return Err(Trap {
+					cause: CAUSE_BREAKPOINT,
+					tval: pc,
+				});
 			},
 
-			FUNC3_LHU => {
-				self.name = String::from("lhu");
-				let tmp: u16 = platform_bus.read(address as usize).unwrap();
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, tmp as u64);
+			IMM_MRET if self.rd == 0 && self.rs1 == 0 => {
+				if privilege != PRIV_MACHINE {
+					return Err(illegal_insn(self.bits));
+				}
+
+				let hart = &mut platform.hart;
+				hart.trap_return();
+				debug_println!("mret @ {:x} to {:x}", pc, hart.pc);
+				return Ok(());
 			},
 
-			FUNC3_LBU => {
-				self.name = String::from("lbu");
-				let tmp: u8 = platform_bus.read(address as usize).unwrap();
-				let hart = &mut (platform_bus).hart;
-				hart.write_register(self.rd as usize, tmp as u64);
+			IMM_SRET if self.rd == 0 && self.rs1 == 0 => {
+				if privilege == PRIV_USER {
+					return Err(illegal_insn(self.bits));
+				}
+				let hart = &mut platform.hart;
+				hart.supervisor_trap_return();
+				debug_println!("sret @ {:x} to {:x}", pc, hart.pc);
+				return Ok(());
 			},
 
-			_ => {
-				todo!("load: {:}", self.func3);
+			IMM_WFI if self.rd == 0 && self.rs1 == 0 => {
+				debug_println!("wfi @ {:x}", pc);
+				platform.wait_for_interrupt();
+				return Ok(());
 			},
-		}
 
-		debug_println!("Found {:}", self.name);
+			_ => return Err(illegal_insn(self.bits)),
+		}
 	}
 
-	fn handle_csr_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_csr_insn(&mut self, platform: &mut Platform) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 
 		// The "funky" thing to look out for with these CSR things,
 		// is that they are I-type instructions, so use the "imm"
@@ -931,6 +1510,14 @@ impl Insn
 		// specifically use unsigned ones & those appear in the
 		// rs1 field of a regular I-type.
 		let imm: usize = (self.imm as usize) & gen_mask!(11, 0, usize);
+		let illegal = |_| return illegal_insn(self.bits);
+
+		// CSRRS & CSRRC only write if rs1 is not x0, & their immediate
+		// forms only if the immediate, held in rs1, is not zero. So
+		// they can read read-only CSRs, which CSRRW can't. The unimp
+		// pseudo-instruction relies on this, being a csrrw to the
+		// read-only cycle CSR.
+		// rd is only written once the CSR write has succeeded.
 		match self.func3 {
 			FUNC3_CSRRW => {
 				// Quoting the spec:
@@ -942,26 +1529,28 @@ impl Insn
 				// shall not read the CSR and shall not cause
 				// any of the side effects that might occur on
 				// a CSR read.
-				self.name = String::from("csrww");
 				let to_write: u64 = hart.read_register(self.rs1 as usize);
-				if self.rd != 0 {
-					let csr_old: u64 = hart.read_csr(imm);
-					hart.write_register(self.rd as usize, csr_old);
-				}
-				hart.write_csr(self.rd as usize, to_write);
+				let csr_old = if self.rd != 0 {
+					hart.csr_read(imm).map_err(illegal)?
+				} else {
+					0
+				};
+				hart.csr_write(imm, to_write).map_err(illegal)?;
+				hart.write_register(self.rd as usize, csr_old);
 			},
 
 			FUNC3_CSRRWI => {
 				// Like CSRRW, but uses an intermediate from
 				// rs1 instead of reading from a register,
 				// limiting it to the lower 5 bits.
-				self.name = String::from("csrrwi");
 				let to_write: u64 = self.rs1 as u64;
-				if self.rd != 0 {
-					let csr_old: u64 = hart.read_csr(imm);
-					hart.write_register(self.rd as usize, csr_old);
-				}
-				hart.write_csr(self.rd as usize, to_write);
+				let csr_old = if self.rd != 0 {
+					hart.csr_read(imm).map_err(illegal)?
+				} else {
+					0
+				};
+				hart.csr_write(imm, to_write).map_err(illegal)?;
+				hart.write_register(self.rd as usize, csr_old);
 			},
 
 			FUNC3_CSRRS => {
@@ -974,11 +1563,10 @@ impl Insn
 				// in the CSR. Any bit that is high in rs1 will
 				// cause the corresponding bit to be set in the
 				// CSR, if that CSR bit is writeable.
-				self.name = String::from("csrws");
-				let csr_val: u64 = hart.read_csr(imm);
+				let csr_val: u64 = hart.csr_read(imm).map_err(illegal)?;
 				if self.rs1 != 0 {
 					let mask = hart.read_register(self.rs1 as usize);
-					hart.write_csr(imm, csr_val | mask);
+					hart.csr_write(imm, csr_val | mask).map_err(illegal)?;
 				}
 				hart.write_register(self.rd as usize, csr_val);
 			},
@@ -987,11 +1575,10 @@ impl Insn
 				// Like CSRRS, but uses an intermediate from
 				// rs1 instead of reading from a register,
 				// limiting it to the lower 5 bits.
-				self.name = String::from("csrrsi");
 				let mask: u64 = self.rs1 as u64;
-				let csr_val: u64 = hart.read_csr(imm);
+				let csr_val: u64 = hart.csr_read(imm).map_err(illegal)?;
 				if mask != 0 {
-					hart.write_csr(imm, csr_val | mask);
+					hart.csr_write(imm, csr_val | mask).map_err(illegal)?;
 				}
 				hart.write_register(self.rd as usize, csr_val);
 			},
@@ -1008,10 +1595,11 @@ impl Insn
 				// corresponding bit to be cleared in the CSR,
 				// if that CSR bit is writeable.
 				// Other bits in the CSR are unaffected.
-				self.name = String::from("csrrc");
-				let csr_val: u64 = hart.read_csr(imm);
-				let mask = !hart.read_register(self.rs1 as usize);
-				hart.write_csr(imm, csr_val & mask);
+				let csr_val: u64 = hart.csr_read(imm).map_err(illegal)?;
+				if self.rs1 != 0 {
+					let mask = !hart.read_register(self.rs1 as usize);
+					hart.csr_write(imm, csr_val & mask).map_err(illegal)?;
+				}
 				hart.write_register(self.rd as usize, csr_val);
 			},
 
@@ -1019,30 +1607,32 @@ impl Insn
 				// Like CSRRC, but uses an intermediate from
 				// rs1 instead of reading from a register,
 				// limiting it to the lower 5 bits.
-				self.name = String::from("csrrci");
-				let csr_val: u64 = hart.read_csr(imm);
+				let csr_val: u64 = hart.csr_read(imm).map_err(illegal)?;
 				let mask: u64 = !(self.rs1 as u64);
 				if mask != u64::MAX {
-					hart.write_csr(imm, csr_val & mask);
+					hart.csr_write(imm, csr_val & mask).map_err(illegal)?;
 				}
 				hart.write_register(self.rd as usize, csr_val);
 			},
 
-			_ => todo!("csr: {:}", self.func3),
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_jump_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_jump_insn(&mut self, platform: &mut Platform)
+		-> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 
 		match self.opcode {
 			OPCODE_JAL => {
-				self.name = String::from("jal");
 				let tmp: i64 = self.imm as i64;
 				let target: u64 = hart.pc.wrapping_add_signed(tmp);
+				check_target_alignment(hart, target)?;
 
 				debug_println!(
 					"Jumping to {:x} (imm: {:x}) from {:x}",
@@ -1051,16 +1641,17 @@ impl Insn
 					hart.pc
 				);
 
-				hart.write_register(self.rd as usize, hart.pc + 4);
+				hart.write_register(self.rd as usize, hart.pc + self.length);
 				hart.pc = target;
 			},
 
 			OPCODE_JALR => {
-				self.name = String::from("jalr");
 				let tmp: i64 = self.imm as i64;
 				let base: u64 = hart.read_register(self.rs1 as usize);
-				let mut target: u64 = base.wrapping_add_signed(tmp);
+				let mut target: u64 =
+					hart.zero_extend(base.wrapping_add_signed(tmp));
 				target &= gen_mask!(63, 1, u64);
+				check_target_alignment(hart, target)?;
 
 				debug_println!(
 					"Jumping to {:x} (base: {:x} imm: {:x}) from {:x}",
@@ -1070,89 +1661,55 @@ impl Insn
 					hart.pc
 				);
 
-				hart.write_register(self.rd as usize, hart.pc + 4);
+				hart.write_register(self.rd as usize, hart.pc + self.length);
 				hart.pc = target;
 			},
 
-			_ => todo!("jump"),
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_branch_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_branch_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 		let src1: u64 = hart.read_register(self.rs1 as usize);
 		let src2: u64 = hart.read_register(self.rs2 as usize);
-		let mut offset: i32 = 0;
-
-		match self.func3 {
-			FUNC3_BEQ => {
-				self.name = String::from("beq");
-				if src1 == src2 {
-					offset = self.imm;
-				}
-			},
-
-			FUNC3_BNE => {
-				self.name = String::from("beq");
-				if src1 != src2 {
-					offset = self.imm;
-				}
-			},
-
-			FUNC3_BLT => {
-				self.name = String::from("blt");
-				if (src1 as i64) < (src2 as i64) {
-					offset = self.imm;
-				}
-			},
-
-			FUNC3_BLTU => {
-				self.name = String::from("bltu");
-				if src1 < src2 {
-					offset = self.imm;
-				}
-			},
-
-			FUNC3_BGE => {
-				self.name = String::from("bge");
-				if (src1 as i64) >= (src2 as i64) {
-					offset = self.imm;
-				}
-			},
-
-			FUNC3_BGEU => {
-				self.name = String::from("bgeu");
-				if src1 >= src2 {
-					offset = self.imm;
-				}
-			},
-
-			_ => {
-				todo!("branch w/ func3 {:b}", self.func3);
-			},
-		}
+		let taken: bool = match self.func3 {
+			FUNC3_BEQ => src1 == src2,
+			FUNC3_BNE => src1 != src2,
+			FUNC3_BLT => (src1 as i64) < (src2 as i64),
+			FUNC3_BLTU => src1 < src2,
+			FUNC3_BGE => (src1 as i64) >= (src2 as i64),
+			FUNC3_BGEU => src1 >= src2,
+			_ => return Err(illegal_insn(self.bits)),
+		};
 
-		if offset != 0 {
-			offset = sign_extend!(offset, 12, i32);
-			let target: u64 = hart.pc.wrapping_add_signed(offset as i64);
+		// imm was sign extended when the instruction was parsed
+		if taken {
+			let target: u64 = hart.pc.wrapping_add_signed(self.imm as i64);
+			check_target_alignment(hart, target)?;
 			debug_println!("Branching to {:x} from {:x}", target, hart.pc);
 			hart.pc = target;
 		} else {
 			debug_println!("Branch not taken @ {:x}", hart.pc);
-			hart.pc += 4;
+			hart.pc += self.length;
 		}
+
+		return Ok(());
 	}
 
-	fn handle_ui_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_ui_insn(&mut self, platform: &mut Platform) -> Result<(), Trap>
 	{
-		let hart = &mut (platform.write().unwrap()).hart;
+		let hart = &mut platform.hart;
 
 		match self.opcode {
 			OPCODE_AUIPC => {
-				self.name = String::from("auipc");
 				let tmp: i64 = self.imm.try_into().unwrap();
 				hart.write_register(
 					self.rd as usize,
@@ -1168,287 +1725,506 @@ impl Insn
 			},
 
 			OPCODE_LUI => {
-				self.name = String::from("lui");
 				let tmp: i64 = self.imm.try_into().unwrap();
 				hart.write_register(self.rd as usize, tmp as u64);
 
 				debug_println!("lui: put {:x} in {:x}", self.imm, self.rd);
 			},
 
-			_ => todo!("upper imm"),
+			_ => return Err(illegal_insn(self.bits)),
 		}
+
+		return Ok(());
 	}
 
-	fn handle_atomic_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_atomic_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
 		let func5 = self.func7 & gen_mask!(6, 2, u32);
+
+		// Unlike regular loads & stores, atomics must be naturally
+		// aligned. LR reports a load fault, everything else a store.
+		let address = platform.hart.read_register(self.rs1 as usize);
+		let size: u64 = if self.func3 == FUNC3_RV32_ATOMIC { 4 } else { 8 };
+		if address % size != 0 {
+			let cause = if func5 == FUNC7_LR {
+				CAUSE_LOAD_MISALIGNED
+			} else {
+				CAUSE_STORE_MISALIGNED
+			};
+
+			return Err(Trap {
+				cause,
+				tval: address,
+			});
+		}
+
+		// AMOs both read & write
+		let kind = match func5 {
+			FUNC7_LR => WatchKind::Read,
+			FUNC7_SC => WatchKind::Write,
+			_ => WatchKind::ReadWrite,
+		};
+		platform.check_watchpoints(address, size, kind);
+
 		if func5 == FUNC7_LR {
-			self.handle_lr_insn(platform);
+			self.handle_lr_insn(platform)?;
 		} else if func5 == FUNC7_SC {
-			self.handle_sc_insn(platform);
+			self.handle_sc_insn(platform)?;
 		} else if self.func3 == FUNC3_RV32_ATOMIC {
-			self.handle_atomic_rv32_insn(platform);
+			self.handle_atomic_rv32_insn(platform)?;
 		} else {
-			self.handle_atomic_rv64_insn(platform);
+			self.handle_atomic_rv64_insn(platform)?;
 		}
 
-		debug_println!("Found {:}", self.name);
+		debug_println!("Found {:}", self.mnemonic);
+
+		return Ok(());
 	}
 
-	fn handle_sc_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_sc_insn(&mut self, platform: &mut Platform) -> Result<(), Trap>
 	{
-		self.name = String::from("sc");
-		let platform_bus = &mut platform.write().unwrap();
-		let hart_id = platform_bus.hart.id;
-		let address: u64 = platform_bus.hart.read_register(self.rs1 as usize);
-		let val: u64 = platform_bus.hart.read_register(self.rs2 as usize);
-		let mut write_size = 4;
-
-		if self.func3 == 0b010 {
-			write_size = 2;
-		}
+		let hart_id = platform.hart.id;
+		let address: u64 = platform.hart.read_register(self.rs1 as usize);
+		let address = platform.hart.zero_extend(address);
+		let physical = platform.translate(address, Access::Store)?;
+		let val: u64 = platform.hart.read_register(self.rs2 as usize);
+		let write_size = if self.func3 == FUNC3_RV32_ATOMIC { 4 } else { 8 };
 
 		// If we do not have a reservation, then abort leaving a
 		// non-zero value in rd.
-		if !platform_bus.check_invalidate_reservation_set(
+		if !platform.check_invalidate_reservation_set(
 			hart_id,
-			address as usize,
+			physical as usize,
 			write_size,
 		) {
-			platform_bus.hart.write_register(self.rd as usize, 1);
-			return;
+			platform.hart.write_register(self.rd as usize, 1);
+			return Ok(());
 		}
 
-		if self.func3 == 0b010 {
+		if self.func3 == FUNC3_RV32_ATOMIC {
 			let val = (val & gen_mask!(31, 0, u64)) as u32;
-			let _ =
-				platform_bus.write_from_hart(hart_id, address as usize, val);
+			platform
+				.write_from_hart(hart_id, physical as usize, val)
+				.map_err(|_| return store_access_fault(address))?;
 		} else {
-			let _ =
-				platform_bus.write_from_hart(hart_id, address as usize, val);
+			platform
+				.write_from_hart(hart_id, physical as usize, val)
+				.map_err(|_| return store_access_fault(address))?;
 		}
 
-		platform_bus.hart.write_register(self.rd as usize, 0);
+		platform.hart.write_register(self.rd as usize, 0);
+
+		return Ok(());
 	}
 
-	fn handle_lr_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_lr_insn(&mut self, platform: &mut Platform) -> Result<(), Trap>
 	{
-		self.name = String::from("lr");
-		let platform_bus = &mut platform.write().unwrap();
-		let hart_id = platform_bus.hart.id;
-		let address: u64 = platform_bus.hart.read_register(self.rs1 as usize);
-		let mut read_size = 4;
+		let hart_id = platform.hart.id;
+		let address: u64 = platform.hart.read_register(self.rs1 as usize);
+		let address = platform.hart.zero_extend(address);
+		let physical = platform.translate(address, Access::Load)?;
+		let mut read_size = 8;
 		let val: u64;
 
-		if self.func3 == 0b010 {
-			read_size = 2;
-			let tmp: u32 = platform_bus.read(address as usize).unwrap();
-			val = tmp as i32 as i64 as u64;
+		if self.func3 == FUNC3_RV32_ATOMIC {
+			read_size = 4;
+			let tmp: i32 = platform
+				.read(physical as usize)
+				.map_err(|_| return load_access_fault(address))?;
+			val = tmp as i64 as u64;
 		} else {
-			val = platform_bus.read(address as usize).unwrap();
+			val = platform
+				.read(physical as usize)
+				.map_err(|_| return load_access_fault(address))?;
 		}
 
-		platform_bus.claim_reservation_set(
-			hart_id,
-			address as usize,
-			read_size,
-		);
-		platform_bus.hart.write_register(self.rd as usize, val);
+		platform.claim_reservation_set(hart_id, physical as usize, read_size);
+		platform.hart.write_register(self.rd as usize, val);
+
+		return Ok(());
 	}
 
-	fn handle_atomic_rv64_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_atomic_rv64_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let platform_bus = &mut platform.write().unwrap();
-
 		// Quoting the spec:
 		// AMO instructions atomically load a data value from the
 		// address in rs1, place the value into register rd, apply a
 		// binary operator to the loaded value and the original value
 		// in rs2, then store the result back to the address in rs1
 		// I am just ignoring aq/rl here, because this system is super
-		// trivial, and a lock is taken for all memory access anyway
-		let address: u64 = platform_bus.hart.read_register(self.rs1 as usize);
-		let mut val: u64 = platform_bus.read(address as usize).unwrap();
-		platform_bus.hart.write_register(self.rd as usize, val);
-		let other_val: u64 = platform_bus.hart.read_register(self.rs2 as usize);
+		// trivial, and harts take turns so nothing can get between the
+		// load & the store
+		let address: u64 = platform.hart.read_register(self.rs1 as usize);
+		let address = platform.hart.zero_extend(address);
+		let physical = platform.translate(address, Access::Store)?;
+		let mut val: u64 = platform
+			.read(physical as usize)
+			.map_err(|_| return store_access_fault(address))?;
+		let rd = val;
+		// rs2 may be rd, so has to be read before rd is written
+		let other_val: u64 = platform.hart.read_register(self.rs2 as usize);
 
 		match self.func7 & gen_mask!(6, 2, u32) {
 			FUNC7_AMOADD => {
-				self.name = String::from("amoadd");
-				val += other_val;
+				val = val.wrapping_add(other_val);
 			},
 
 			FUNC7_AMOAND => {
-				self.name = String::from("amoand");
 				val &= other_val;
 			},
 
 			FUNC7_AMOOR => {
-				self.name = String::from("amoor");
 				val |= other_val;
 			},
 
 			FUNC7_AMOXOR => {
-				self.name = String::from("amoadd");
 				val ^= other_val;
 			},
 
 			FUNC7_AMOSWAP => {
-				self.name = String::from("amoswap");
 				val = other_val;
 			},
 
-			_ => todo!("atomic {:b}", (self.func7 & gen_mask!(6, 2, u32)) >> 2),
+			FUNC7_AMOMIN => {
+				val = (val as i64).min(other_val as i64) as u64;
+			},
+
+			FUNC7_AMOMAX => {
+				val = (val as i64).max(other_val as i64) as u64;
+			},
+
+			FUNC7_AMOMINU => {
+				val = val.min(other_val);
+			},
+
+			FUNC7_AMOMAXU => {
+				val = val.max(other_val);
+			},
+
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		let hart_id = platform_bus.hart.id;
-		let _ = platform_bus.write_from_hart(hart_id, address as usize, val);
+		let hart_id = platform.hart.id;
+		platform
+			.write_from_hart(hart_id, physical as usize, val)
+			.map_err(|_| return store_access_fault(address))?;
+		// With rd as x0, only the loaded value is thrown away
+		platform.hart.write_register(self.rd as usize, rd);
+
+		debug_println!("Found {:}", self.mnemonic);
 
-		debug_println!("Found {:}", self.name);
+		return Ok(());
 	}
 
-	fn handle_atomic_rv32_insn(&mut self, platform: &Arc<RwLock<&mut Platform>>)
+	fn handle_atomic_rv32_insn(
+		&mut self, platform: &mut Platform,
+	) -> Result<(), Trap>
 	{
-		let platform_bus = &mut platform.write().unwrap();
-
 		// Quoting the spec:
 		// AMO instructions atomically load a data value from the
 		// address in rs1, place the value into register rd, apply a
 		// binary operator to the loaded value and the original value
 		// in rs2, then store the result back to the address in rs1
 		// I am just ignoring aq/rl here, because this system is super
-		// trivial, and a lock is taken for all memory access anyway
-		let address: u64 = platform_bus.hart.read_register(self.rs1 as usize);
-		let mut val: u32 = platform_bus.read(address as usize).unwrap();
+		// trivial, and harts take turns so nothing can get between the
+		// load & the store
+		let address: u64 = platform.hart.read_register(self.rs1 as usize);
+		let address = platform.hart.zero_extend(address);
+		let physical = platform.translate(address, Access::Store)?;
+		let mut val: u32 = platform
+			.read(physical as usize)
+			.map_err(|_| return store_access_fault(address))?;
 		let rd: u64 = val as i32 as i64 as u64;
-		platform_bus.hart.write_register(self.rd as usize, rd);
-		// check this to make sure the mask is okay to do
-		let other_val: u32 =
-			(platform_bus.hart.read_register(self.rs2 as usize)
-				& gen_mask!(31, 0, u64)) as u32;
+		// rs2 may be rd, so has to be read before rd is written
+		let other_val: u32 = (platform.hart.read_register(self.rs2 as usize)
+			& gen_mask!(31, 0, u64)) as u32;
 
 		match self.func7 & gen_mask!(6, 2, u32) {
 			FUNC7_AMOADD => {
-				self.name = String::from("amoadd");
-				val += other_val;
+				val = val.wrapping_add(other_val);
 			},
 
 			FUNC7_AMOAND => {
-				self.name = String::from("amoand");
 				val &= other_val;
 			},
 
 			FUNC7_AMOOR => {
-				self.name = String::from("amoor");
 				val |= other_val;
 			},
 
 			FUNC7_AMOXOR => {
-				self.name = String::from("amoadd");
 				val ^= other_val;
 			},
 
 			FUNC7_AMOSWAP => {
-				self.name = String::from("amoswap");
 				val = other_val;
 			},
 
-			_ => todo!("atomic {:b}", (self.func7 & gen_mask!(6, 2, u32)) >> 2),
+			FUNC7_AMOMIN => {
+				val = (val as i32).min(other_val as i32) as u32;
+			},
+
+			FUNC7_AMOMAX => {
+				val = (val as i32).max(other_val as i32) as u32;
+			},
+
+			FUNC7_AMOMINU => {
+				val = val.min(other_val);
+			},
+
+			FUNC7_AMOMAXU => {
+				val = val.max(other_val);
+			},
+
+			_ => return Err(illegal_insn(self.bits)),
 		}
 
-		let hart_id = platform_bus.hart.id;
-		let _ = platform_bus.write_from_hart(hart_id, address as usize, val);
+		let hart_id = platform.hart.id;
+		platform
+			.write_from_hart(hart_id, physical as usize, val)
+			.map_err(|_| return store_access_fault(address))?;
+		// With rd as x0, only the loaded value is thrown away
+		platform.hart.write_register(self.rd as usize, rd);
+
+		debug_println!("Found {:}", self.mnemonic);
 
-		debug_println!("Found {:}", self.name);
+		return Ok(());
+	}
+
+	fn is_trap_return(&self) -> bool
+	{
+		return matches!(self.mnemonic, Mnemonic::Mret | Mnemonic::Sret);
 	}
 
-	fn increment_pc(&self, platform: &Arc<RwLock<&mut Platform>>)
+	fn increment_pc(&self, platform: &mut Platform)
 	{
 		match self.opcode {
 			OPCODE_JAL | OPCODE_JALR | OPCODE_BRANCH => (),
+			// Trap returns set pc themselves
+			OPCODE_SYSTEM if self.is_trap_return() => (),
 
 			_ => {
-				let hart = &mut (platform.write().unwrap()).hart;
-				hart.pc += 4;
+				let hart = &mut platform.hart;
+				hart.pc += self.length;
 			},
 		}
 	}
 
-	pub fn handle(&mut self, platform: &mut Platform)
+	/// Whether this instruction only exists in RV64, & so is illegal in
+	/// RV32. This covers the shifts by more than 31 too.
+	fn rv64_only(&self) -> bool
 	{
-		let arc = Arc::new(std::sync::RwLock::new(platform));
+		match self.opcode {
+			OPCODE_INT_REG_IMM_32 | OPCODE_INT_REG_REG_32 => return true,
+			OPCODE_LOAD => return matches!(self.func3, FUNC3_LD | FUNC3_LWU),
+			OPCODE_STORE => return self.func3 == FUNC3_SD,
+			OPCODE_ATOMIC => return self.func3 == FUNC3_RV64_ATOMIC,
+			OPCODE_OP_FP => return self.fp_rv64_only(),
+			OPCODE_INT_REG_IMM => {
+				let is_shift = matches!(self.func3, FUNC3_SLLI | FUNC3_SRLI);
+				return is_shift && self.imm & 0b10_0000 != 0;
+			},
+			_ => return false,
+		}
+	}
+
+	pub fn handle(&mut self, platform: &mut Platform) -> Result<(), Trap>
+	{
+		if platform.hart.xlen == Xlen::Rv32 && self.rv64_only() {
+			return Err(illegal_insn(self.bits));
+		}
 
 		match self.opcode {
 			OPCODE_LUI | OPCODE_AUIPC => {
-				self.handle_ui_insn(&arc);
+				self.handle_ui_insn(platform)?;
 			},
 
 			OPCODE_INT_REG_REG => {
-				self.handle_int_reg_reg_insn(&arc);
+				self.handle_int_reg_reg_insn(platform)?;
 			},
 
 			OPCODE_INT_REG_IMM => {
-				self.handle_int_reg_imm_insn(&arc);
+				self.handle_int_reg_imm_insn(platform)?;
 			},
 
 			OPCODE_STORE => {
-				self.handle_store_insn(&arc);
+				self.handle_store_insn(platform)?;
 			},
 
 			OPCODE_LOAD => {
-				self.handle_load_insn(&arc);
+				self.handle_load_insn(platform)?;
 			},
 
 			OPCODE_SYSTEM => {
-				self.handle_csr_insn(&arc);
+				if self.func3 == FUNC3_PRIV {
+					self.handle_priv_insn(platform)?;
+				} else {
+					self.handle_csr_insn(platform)?;
+				}
 			},
 
 			OPCODE_JAL | OPCODE_JALR => {
-				self.handle_jump_insn(&arc);
+				self.handle_jump_insn(platform)?;
 			},
 
 			OPCODE_MISCMEM => {
-				debug_println!("fence.i");
+				self.handle_miscmem_insn(platform)?;
 			},
 
 			OPCODE_BRANCH => {
-				self.handle_branch_insn(&arc);
+				self.handle_branch_insn(platform)?;
 			},
 
 			OPCODE_INT_REG_IMM_32 => {
-				self.handle_int_reg_imm32_insn(&arc);
+				self.handle_int_reg_imm32_insn(platform)?;
 			},
 
 			OPCODE_INT_REG_REG_32 => {
-				self.handle_int_reg_reg32_insn(&arc);
+				self.handle_int_reg_reg32_insn(platform)?;
 			},
 
 			OPCODE_ATOMIC => {
-				self.handle_atomic_insn(&arc);
+				self.handle_atomic_insn(platform)?;
+			},
+
+			OPCODE_LOAD_FP => {
+				self.handle_load_fp_insn(platform)?;
+			},
+
+			OPCODE_STORE_FP => {
+				self.handle_store_fp_insn(platform)?;
+			},
+
+			OPCODE_OP_FP => {
+				self.handle_op_fp_insn(platform)?;
 			},
 
 			_ => {
 				debug_println!("unimplemented instruction {:x}", self.opcode);
-				dump_unimplemented_insn(self, &arc);
-				panic!();
+				dump_unimplemented_insn(self, platform);
+				return Err(illegal_insn(self.bits));
 			},
 		}
 
-		self.increment_pc(&arc);
+		self.increment_pc(platform);
 
-		return;
+		return Ok(());
 	}
 }
 
-fn dump_unimplemented_insn(insn: &Insn, platform: &Arc<RwLock<&mut Platform>>)
+fn dump_unimplemented_insn(insn: &Insn, platform: &Platform)
+{
+	debug_println!("insn {:?}\n{}", insn, platform.dump_state());
+}
+
+/// A branch or jump offset, as spike shows them.
+fn pc_relative(offset: i32) -> String
+{
+	if offset < 0 {
+		return format!("pc - {:#x}", offset.unsigned_abs());
+	}
+
+	return format!("pc + {:#x}", offset);
+}
+
+/// Disassembly, with the canonical name rather than any pseudo-instruction
+/// & the width & ordering suffixes atomics take. Compressed instructions
+/// are shown as what they expand to.
+impl std::fmt::Display for Insn
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		if self.mnemonic == Mnemonic::Unknown {
+			return write!(f, ".{}byte {:#x}", self.length, self.bits);
+		}
+
+		let mut name = self.mnemonic.canonical().to_string();
+		if self.opcode == OPCODE_ATOMIC {
+			name += if self.func3 == FUNC3_RV32_ATOMIC { ".w" } else { ".d" };
+			name += match self.func7 & 0b11 {
+				0b10 => ".aq",
+				0b01 => ".rl",
+				0b11 => ".aqrl",
+				_ => "",
+			};
+		}
+
+		let operands = self.operands();
+		if operands.is_empty() {
+			return f.write_str(&name);
+		}
+
+		return write!(f, "{} {}", name, operands);
+	}
+}
+
+/// An illegal instruction trap, with the offending instruction in mtval.
+/// Compressed instructions are only 16 bits long, so anything above that
+/// belongs to the next instruction & is left out. Among them is the
+/// all-zero halfword, which is defined to be illegal, so that executing
+/// zeroed memory traps.
+fn illegal_insn(bits: u32) -> Trap
+{
+	let tval =
+		if bits & 0b11 != 0b11 { bits & gen_mask!(15, 0, u32) } else { bits };
+
+	return Trap {
+		cause: CAUSE_ILLEGAL_INSN,
+		tval: tval as u64,
+	};
+}
+
+/// Jumps & taken branches trap, without writing rd, if their target isn't
+/// aligned to an instruction boundary.
+fn check_target_alignment(hart: &Hart, target: u64) -> Result<(), Trap>
+{
+	if target % hart.ialign() != 0 {
+		return Err(Trap {
+			cause: CAUSE_INSN_MISALIGNED,
+			tval: target,
+		});
+	}
+
+	return Ok(());
+}
+
+pub(crate) fn load_access_fault(address: u64) -> Trap
+{
+	return Trap {
+		cause: CAUSE_LOAD_ACCESS_FAULT,
+		tval: address,
+	};
+}
+
+pub(crate) fn store_access_fault(address: u64) -> Trap
+{
+	return Trap {
+		cause: CAUSE_STORE_ACCESS_FAULT,
+		tval: address,
+	};
+}
+
+impl Insn
 {
-	let hart = &mut (platform.write().unwrap()).hart;
-	debug_println!(
-		"insn {:?}\n hart registers {:?}\n pc {:x}",
-		insn,
-		hart.registers,
-		hart.pc
-	);
+	/// Decode a compressed instruction, by expanding it into the 32-bit
+	/// one it is shorthand for. The fetched bits are kept, so that traces
+	/// & illegal instruction traps show what was actually executed.
+	pub fn from_compressed(input: u16, xlen: Xlen) -> Result<Insn, Trap>
+	{
+		let Some(expanded) = compressed::expand(input, xlen) else {
+			return Err(illegal_insn(input as u32));
+		};
+
+		let mut insn = Insn::from(expanded);
+		insn.bits = input as u32;
+		insn.length = 2;
+
+		return Ok(insn);
+	}
 }
 
 impl From<u32> for Insn
@@ -1462,3 +2238,638 @@ impl From<u32> for Insn
 		return insn;
 	}
 }
+
+#[cfg(test)]
+mod test
+{
+	use super::{Insn, Mnemonic};
+	use crate::bus::Bus;
+	use crate::hart::{Trap, Xlen, CSR_MSTATUS, MSTATUS_MIE, MSTATUS_MPIE};
+	use crate::hart::{
+		CAUSE_BREAKPOINT, CAUSE_ECALL_MMODE, CAUSE_ECALL_UMODE,
+		CAUSE_ILLEGAL_INSN, CAUSE_LOAD_MISALIGNED, CAUSE_STORE_MISALIGNED,
+	};
+	use crate::hart::{CSR_MISA, MISA_C};
+	use crate::hart::{PRIV_MACHINE, PRIV_SUPERVISOR, PRIV_USER};
+	use crate::platform::{MisalignedAccesses, Platform};
+
+	const CSR_MTVEC: usize = 0x305;
+
+	#[test]
+	fn csrrw_writes_the_encoded_csr()
+	{
+		let mut platform = Platform::default();
+		// csrrw t0, mtvec, t1
+		let mut insn = Insn::from(0x3053_12f3);
+
+		platform.hart.write_register(6_usize, 0x8000_1000);
+		platform.hart.write_csr(CSR_MTVEC, 0x1234);
+		insn.handle(&mut platform).unwrap();
+
+		assert_eq!(platform.hart.read_csr(CSR_MTVEC), 0x8000_1000);
+		assert_eq!(platform.hart.read_register(5_usize), 0x1234);
+		assert_eq!(platform.hart.read_csr(5_usize), 0);
+	}
+
+	#[test]
+	fn csr_operands_use_csr_names()
+	{
+		// csrrw sp, mscratch, sp
+		assert_eq!(Insn::from(0x3401_1173).operands(), "sp, mscratch, sp");
+		// csrrwi t0, mtvec, 0x1f
+		assert_eq!(Insn::from(0x305f_d2f3).operands(), "t0, mtvec, 31");
+		// csrr a0, cycle
+		assert_eq!(Insn::from(0xc000_2573).operands(), "a0, cycle, zero");
+		// csrr a0, 0x7c0
+		assert_eq!(Insn::from(0x7c00_2573).operands(), "a0, 0x7c0, zero");
+	}
+
+	#[test]
+	fn display_disassembles()
+	{
+		for (bits, text) in [
+			(0x00c5_8533, "add a0, a1, a2"),
+			(0x40c5_8533, "sub a0, a1, a2"),
+			(0xffc5_8513, "addi a0, a1, -4"),
+			(0x0000_0013, "addi zero, zero, 0"),
+			(0x0005_8513, "addi a0, a1, 0"),
+			(0x4045_d513, "srai a0, a1, 4"),
+			(0x0085_b503, "ld a0, 8(a1)"),
+			(0x00a1_3823, "sd a0, 16(sp)"),
+			(0xfeb5_1ce3, "bne a0, a1, pc - 0x8"),
+			(0x1234_5537, "lui a0, 0x12345"),
+			(0x0100_00ef, "jal ra, pc + 0x10"),
+			(0x0000_8067, "jalr zero, 0(ra)"),
+			(0x3401_1173, "csrrw sp, mscratch, sp"),
+			(0x04c5_a52f, "amoadd.w.aq a0, a2, (a1)"),
+			(0x1005_b52f, "lr.d a0, (a1)"),
+			(0x0000_100f, "fence.i"),
+			(0x0000_0073, "ecall"),
+			(0x12b5_0073, "sfence.vma a0, a1"),
+			(0xffff_ffff, ".4byte 0xffffffff"),
+		] {
+			assert_eq!(Insn::from(bits).to_string(), text);
+		}
+	}
+
+	#[test]
+	fn csrrwi_writes_the_encoded_csr()
+	{
+		let mut platform = Platform::default();
+		// csrrwi t0, mtvec, 0x1d
+		let mut insn = Insn::from(0x305e_d2f3);
+
+		insn.handle(&mut platform).unwrap();
+
+		assert_eq!(platform.hart.read_csr(CSR_MTVEC), 0x1d);
+		assert_eq!(platform.hart.read_csr(5_usize), 0);
+	}
+
+	/// Run a branch comparing a0 & a1 from pc 0x8000_0100, returning the
+	/// pc it leaves behind.
+	fn branch(bits: u32, a0: u64, a1: u64) -> u64
+	{
+		let mut platform = Platform::default();
+		let mut insn = Insn::from(bits);
+
+		platform.hart.pc = 0x8000_0100;
+		platform.hart.write_register(10_usize, a0);
+		platform.hart.write_register(11_usize, a1);
+		insn.handle(&mut platform).unwrap();
+
+		return platform.hart.pc;
+	}
+
+	#[test]
+	fn taken_forward_branch()
+	{
+		// beq a0, a1, 16
+		assert_eq!(branch(0x00b5_0863, 3, 3), 0x8000_0110);
+	}
+
+	#[test]
+	fn taken_backward_branch()
+	{
+		// bne a0, a1, -8
+		assert_eq!(branch(0xfeb5_1ce3, 3, 4), 0x8000_00f8);
+		assert_eq!(Insn::from(0xfeb5_1ce3).imm, -8);
+	}
+
+	#[test]
+	fn not_taken_branch_falls_through()
+	{
+		// beq a0, a1, 16
+		assert_eq!(branch(0x00b5_0863, 3, 4), 0x8000_0104);
+		// bne a0, a1, -8
+		assert_eq!(branch(0xfeb5_1ce3, 3, 3), 0x8000_0104);
+	}
+
+	#[test]
+	fn decode_yields_mnemonic()
+	{
+		for (bits, mnemonic) in [
+			(0x0000_0013, Mnemonic::Nop),   // nop
+			(0x0005_8513, Mnemonic::Mv),    // mv a0, a1
+			(0x00b0_3533, Mnemonic::Snez),  // snez a0, a1
+			(0x0005_851b, Mnemonic::Sextw), // sext.w a0, a1
+			(0x0015_0513, Mnemonic::Addi),  // addi a0, a0, 1
+			(0x00b5_0533, Mnemonic::Add),   // add a0, a0, a1
+			(0x40b5_0533, Mnemonic::Sub),   // sub a0, a0, a1
+			(0x02b5_0533, Mnemonic::Mul),   // mul a0, a0, a1
+			(0x0015_151b, Mnemonic::Slliw), // slliw a0, a0, 1
+			(0x02b5_053b, Mnemonic::Mulw),  // mulw a0, a0, a1
+			(0x02b5_753b, Mnemonic::Remuw), // remuw a0, a0, a1
+			(0x0000_0537, Mnemonic::Lui),   // lui a0, 0x0
+		] {
+			assert_eq!(Insn::from(bits).mnemonic, mnemonic);
+		}
+		assert_eq!(Insn::from(0xffff_ffff).mnemonic, Mnemonic::Unknown);
+
+		assert_eq!(format!("{:<6}|", Mnemonic::Addi), "addi  |");
+	}
+
+	#[test]
+	fn srai_fills_with_sign_bits()
+	{
+		let mut platform = Platform::default();
+		// srai a0, a1, 4
+		let mut insn = Insn::from(0x4045_d513);
+
+		platform.hart.write_register(11_usize, -256_i64 as u64);
+		insn.handle(&mut platform).unwrap();
+
+		assert_eq!(insn.mnemonic, Mnemonic::Srai);
+		assert_eq!(platform.hart.read_register(10_usize), -16_i64 as u64);
+	}
+
+	#[test]
+	fn srli_fills_with_zeroes()
+	{
+		let mut platform = Platform::default();
+		// srli a0, a1, 4
+		let mut insn = Insn::from(0x0045_d513);
+
+		platform.hart.write_register(11_usize, -256_i64 as u64);
+		insn.handle(&mut platform).unwrap();
+
+		assert_eq!(insn.mnemonic, Mnemonic::Srli);
+		assert_eq!(
+			platform.hart.read_register(10_usize),
+			0x0fff_ffff_ffff_fff0
+		);
+	}
+
+	fn rv32(bits: u32, a0: u64, a1: u64) -> Result<u64, Trap>
+	{
+		let mut platform = Platform::default();
+		platform.set_xlen(Xlen::Rv32);
+		platform.hart.write_register(10_usize, a0);
+		platform.hart.write_register(11_usize, a1);
+
+		Insn::from(bits).handle(&mut platform)?;
+
+		return Ok(platform.hart.read_register(10_usize));
+	}
+
+	#[test]
+	fn rv32_shift_amounts_are_5_bits()
+	{
+		// sll a0, a0, a1
+		assert_eq!(rv32(0x00b5_1533, 0x1234, 32), Ok(0x1234));
+		assert_eq!(rv32(0x00b5_1533, 0x1234, 33), Ok(0x2468));
+		// slli a0, a0, 32 is reserved
+		let trap = rv32(0x0205_1513, 0x1234, 0).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+	}
+
+	#[test]
+	fn rv32_results_stay_32_bit()
+	{
+		// add a0, a0, a1
+		let sum = rv32(0x00b5_0533, 0x7fff_ffff, 1);
+		assert_eq!(sum, Ok(0xffff_ffff_8000_0000));
+		// srli a0, a1, 4 shifts in zeroes above bit 31
+		assert_eq!(rv32(0x0045_d513, 0, -256_i64 as u64), Ok(0x0fff_fff0));
+		// & addw doesn't exist
+		let trap = rv32(0x00b5_053b, 0, 0).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+	}
+
+	fn rv64(bits: u32, a0: u64, a1: u64) -> u64
+	{
+		let mut platform = Platform::default();
+		platform.hart.write_register(10_usize, a0);
+		platform.hart.write_register(11_usize, a1);
+
+		Insn::from(bits).handle(&mut platform).unwrap();
+
+		return platform.hart.read_register(10_usize);
+	}
+
+	#[test]
+	fn m_word_results_are_sign_extended()
+	{
+		// mulw a0, a0, a1 ignores the upper halves of its operands
+		let product = rv64(0x02b5_053b, 0x1234_0000_0001_0000, 0x8000);
+		assert_eq!(product, 0xffff_ffff_8000_0000);
+		// divuw a0, a0, a1 still sign extends bit 31
+		let quotient = rv64(0x02b5_553b, 0xffff_fffe, 1);
+		assert_eq!(quotient, 0xffff_ffff_ffff_fffe);
+		// divw a0, a0, a1
+		assert_eq!(rv64(0x02b5_453b, -7_i64 as u64, 2), -3_i64 as u64);
+		// remw a0, a0, a1
+		assert_eq!(rv64(0x02b5_653b, -7_i64 as u64, 2), -1_i64 as u64);
+		// remuw a0, a0, a1
+		assert_eq!(rv64(0x02b5_753b, 0x1_0000_0007, 4), 3);
+	}
+
+	#[test]
+	fn m_word_division_by_zero_does_not_trap()
+	{
+		let dividend = 0xdead_0000_8000_0005;
+		// divw & divuw give all ones
+		assert_eq!(rv64(0x02b5_453b, dividend, 0), u64::MAX);
+		assert_eq!(rv64(0x02b5_553b, dividend, 0), u64::MAX);
+		// remw & remuw give the sign extended low half of the dividend
+		assert_eq!(rv64(0x02b5_653b, dividend, 0), 0xffff_ffff_8000_0005);
+		assert_eq!(rv64(0x02b5_753b, dividend, 0), 0xffff_ffff_8000_0005);
+		// the divisor is only the low 32 bits too
+		assert_eq!(rv64(0x02b5_453b, 6, 0x1_0000_0000), u64::MAX);
+	}
+
+	#[test]
+	fn m_word_signed_overflow_does_not_trap()
+	{
+		let min = i32::MIN as i64 as u64;
+		// divw a0, a0, a1 of -2^31 by -1 gives back the dividend
+		assert_eq!(rv64(0x02b5_453b, min, u64::MAX), min);
+		// remw a0, a0, a1 gives zero
+		assert_eq!(rv64(0x02b5_653b, min, u64::MAX), 0);
+	}
+
+	#[test]
+	fn ecall_raises_environment_call_trap()
+	{
+		let mut platform = Platform::default();
+		// ecall
+		let mut insn = Insn::from(0x0000_0073);
+
+		platform.hart.pc = 0x8000_0000;
+		let trap = insn.handle(&mut platform).unwrap_err();
+
+		assert_eq!(insn.mnemonic, Mnemonic::Ecall);
+		assert_eq!(trap.cause, CAUSE_ECALL_MMODE);
+		assert_eq!(trap.tval, 0);
+		assert_eq!(platform.hart.pc, 0x8000_0000);
+	}
+
+	#[test]
+	fn ecall_cause_depends_on_privilege()
+	{
+		for (privilege, cause) in
+			[(PRIV_USER, CAUSE_ECALL_UMODE), (PRIV_MACHINE, CAUSE_ECALL_MMODE)]
+		{
+			let mut platform = Platform::default();
+			platform.hart.privilege = privilege;
+
+			let trap = Insn::from(0x0000_0073).handle(&mut platform);
+			assert_eq!(trap.unwrap_err().cause, cause);
+		}
+	}
+
+	#[test]
+	fn csr_access_needs_enough_privilege()
+	{
+		// csrr a0, mstatus & csrr a0, sstatus
+		let csrr_mstatus = 0x3000_2573;
+		let csrr_sstatus = 0x1000_2573;
+		let access = |bits: u32, privilege: u64| {
+			let mut platform = Platform::default();
+			platform.hart.privilege = privilege;
+			return Insn::from(bits).handle(&mut platform);
+		};
+
+		let trap = access(csrr_mstatus, PRIV_USER).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+		assert_eq!(trap.tval, csrr_mstatus as u64);
+		assert!(access(csrr_mstatus, PRIV_SUPERVISOR).is_err());
+		assert!(access(csrr_sstatus, PRIV_USER).is_err());
+		assert!(access(csrr_sstatus, PRIV_SUPERVISOR).is_ok());
+		assert!(access(csrr_mstatus, PRIV_MACHINE).is_ok());
+	}
+
+	#[test]
+	fn read_only_csrs_cannot_be_written()
+	{
+		let mut platform = Platform::default();
+		// csrrw a0, mvendorid, a1 & csrrs a0, mvendorid, zero
+		let csrrw = 0xf115_9573;
+		let csrrs = 0xf110_2573;
+		platform.hart.write_register(10_usize, 7);
+
+		let trap = Insn::from(csrrw).handle(&mut platform).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+		assert_eq!(trap.tval, csrrw as u64);
+		// rd is left alone when the write fails
+		assert_eq!(platform.hart.read_register(10_usize), 7);
+
+		Insn::from(csrrs).handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_register(10_usize), 0);
+	}
+
+	#[test]
+	fn warl_fields_keep_legal_values()
+	{
+		let mut platform = Platform::default();
+		// csrw mtvec, a1 & csrw misa, a1
+		let csrw_mtvec = 0x3055_9073;
+		let csrw_misa = 0x3015_9073;
+		let misa = platform.hart.read_csr(CSR_MISA);
+
+		// Mode 3 is reserved
+		platform.hart.write_register(11_usize, 0x8000_1003);
+		Insn::from(csrw_mtvec).handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MTVEC), 0x8000_1000);
+
+		platform.hart.write_register(11_usize, 0x8000_2001);
+		Insn::from(csrw_mtvec).handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MTVEC), 0x8000_2001);
+
+		// Only C may be turned off
+		platform.hart.write_register(11_usize, 0);
+		Insn::from(csrw_misa).handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MISA), misa & !MISA_C);
+	}
+
+	#[test]
+	fn sfence_vma_needs_supervisor()
+	{
+		// sfence.vma a0, a1
+		let sfence_vma = 0x12b5_0073;
+		let run = |privilege: u64| {
+			let mut platform = Platform::default();
+			platform.hart.privilege = privilege;
+			return Insn::from(sfence_vma).handle(&mut platform);
+		};
+
+		let trap = run(PRIV_USER).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+		assert_eq!(trap.tval, sfence_vma as u64);
+		assert!(run(PRIV_SUPERVISOR).is_ok());
+	}
+
+	#[test]
+	fn mret_undoes_take_trap()
+	{
+		let mut platform = Platform::default();
+		let pc = 0x8000_0100;
+		platform.hart.pc = pc;
+		platform.hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_4000);
+
+		platform.hart.take_trap(CAUSE_BREAKPOINT, pc);
+		assert_eq!(platform.hart.read_csr(CSR_MSTATUS) & MSTATUS_MIE, 0);
+
+		let mut insn = Insn::from(0x3020_0073);
+		insn.handle(&mut platform).unwrap();
+
+		let mstatus = platform.hart.read_csr(CSR_MSTATUS);
+		assert_eq!(insn.mnemonic, Mnemonic::Mret);
+		assert_eq!(platform.hart.pc, pc);
+		assert_eq!(mstatus & MSTATUS_MIE, MSTATUS_MIE);
+		assert_eq!(mstatus & MSTATUS_MPIE, MSTATUS_MPIE);
+	}
+
+	#[test]
+	fn ebreak_raises_breakpoint_trap()
+	{
+		let mut platform = Platform::default();
+		// ebreak
+		let mut insn = Insn::from(0x0010_0073);
+
+		platform.hart.pc = 0x8000_0000;
+		let trap = insn.handle(&mut platform).unwrap_err();
+
+		assert_eq!(insn.mnemonic, Mnemonic::Ebreak);
+		assert_eq!(trap.cause, CAUSE_BREAKPOINT);
+		assert_eq!(trap.tval, 0x8000_0000);
+		assert_eq!(platform.hart.pc, 0x8000_0000);
+	}
+
+	#[test]
+	fn unimp_raises_illegal_instruction()
+	{
+		let mut platform = Platform::default();
+		// unimp
+		let mut insn = Insn::from(0xc000_1073);
+
+		platform.hart.pc = 0x8000_0000;
+		platform.hart.write_csr(0xc00_usize, 0x1234);
+		let trap = insn.handle(&mut platform).unwrap_err();
+
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+		assert_eq!(trap.tval, 0xc000_1073);
+		assert_eq!(platform.hart.read_csr(0xc00_usize), 0x1234);
+		assert_eq!(platform.hart.pc, 0x8000_0000);
+
+		// Reading a read-only CSR is fine, it is only writes that trap
+		// csrr a0, cycle
+		let mut insn = Insn::from(0xc000_2573);
+		insn.handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_register(10_usize), 0x1234);
+	}
+
+	#[test]
+	fn compressed_unimp_raises_illegal_instruction()
+	{
+		let mut platform = Platform::default();
+		// c.unimp, followed by a c.nop that is not part of it
+		let mut insn = Insn::from(0x0001_0000);
+
+		let trap = insn.handle(&mut platform).unwrap_err();
+
+		assert_eq!(trap.cause, CAUSE_ILLEGAL_INSN);
+		assert_eq!(trap.tval, 0);
+	}
+
+	#[test]
+	fn misaligned_amo_raises_store_misaligned()
+	{
+		let mut platform = Platform::default();
+		// amoadd.w a0, a2, (a1)
+		let mut insn = Insn::from(0x00c5_a52f);
+
+		platform.hart.write_register(11_usize, 0x8000_0002);
+		let trap = insn.handle(&mut platform).unwrap_err();
+
+		assert_eq!(trap.cause, CAUSE_STORE_MISALIGNED);
+		assert_eq!(trap.tval, 0x8000_0002);
+	}
+
+	#[test]
+	fn misaligned_word_accesses_are_split_by_default()
+	{
+		let mut platform = Platform::default();
+		// sw a2, 0(a1)
+		let mut store = Insn::from(0x00c5_a023);
+		// lw a0, 0(a1)
+		let mut load = Insn::from(0x0005_a503);
+
+		platform.hart.write_register(11_usize, 0x8000_0101);
+		platform.hart.write_register(12_usize, 0x8899_aabb);
+		store.handle(&mut platform).unwrap();
+		load.handle(&mut platform).unwrap();
+
+		let memory = platform.read::<u64>(0x8000_0100).unwrap();
+		assert_eq!(memory, 0x0000_0088_99aa_bb00);
+		let rd = platform.hart.read_register(10_usize);
+		assert_eq!(rd, 0xffff_ffff_8899_aabb);
+	}
+
+	#[test]
+	fn misaligned_word_accesses_can_trap()
+	{
+		let mut platform = Platform::default();
+		platform.misaligned_accesses = MisalignedAccesses::Trap;
+		// sw a2, 0(a1)
+		let mut store = Insn::from(0x00c5_a023);
+		// lw a0, 0(a1)
+		let mut load = Insn::from(0x0005_a503);
+
+		platform.hart.write_register(11_usize, 0x8000_0101);
+		platform.hart.write_register(12_usize, 0x8899_aabb);
+		let trap = store.handle(&mut platform).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_STORE_MISALIGNED);
+		assert_eq!(trap.tval, 0x8000_0101);
+		assert_eq!(platform.read::<u64>(0x8000_0100).unwrap(), 0);
+
+		let trap = load.handle(&mut platform).unwrap_err();
+		assert_eq!(trap.cause, CAUSE_LOAD_MISALIGNED);
+		assert_eq!(trap.tval, 0x8000_0101);
+	}
+
+	/// Run an AMO with a0 as rd, a1 as the address & a2 as rs2, returning
+	/// rd & what was left in memory.
+	fn amo(bits: u32, memory: u64, rs2: u64) -> (u64, u64)
+	{
+		let mut platform = Platform::default();
+		let address: usize = 0x8000_0100;
+		let mut insn = Insn::from(bits);
+
+		platform.write(address, memory).unwrap();
+		platform.hart.write_register(11_usize, address as u64);
+		platform.hart.write_register(12_usize, rs2);
+		insn.handle(&mut platform).unwrap();
+
+		let rd = platform.hart.read_register(10_usize);
+		return (rd, platform.read::<u64>(address).unwrap());
+	}
+
+	#[test]
+	fn amo_min_max_word()
+	{
+		let minus_five: u64 = 0xffff_fffb;
+		let extended: u64 = 0xffff_ffff_ffff_fffb;
+
+		// amomin.w a0, a2, (a1). Only the low word of rs2 counts, & is
+		// signed even without the upper bits.
+		assert_eq!(amo(0x80c5_a52f, minus_five, 3), (extended, minus_five));
+		assert_eq!(amo(0x80c5_a52f, 3, minus_five), (3, minus_five));
+		// amomax.w a0, a2, (a1)
+		assert_eq!(amo(0xa0c5_a52f, minus_five, 3), (extended, 3));
+		assert_eq!(amo(0xa0c5_a52f, 7, extended), (7, 7));
+		// amominu.w a0, a2, (a1)
+		assert_eq!(amo(0xc0c5_a52f, minus_five, 3), (extended, 3));
+		assert_eq!(amo(0xc0c5_a52f, 1, 3), (1, 1));
+		// amomaxu.w a0, a2, (a1)
+		assert_eq!(amo(0xe0c5_a52f, minus_five, 3), (extended, minus_five));
+		assert_eq!(amo(0xe0c5_a52f, 1, 3), (1, 3));
+	}
+
+	#[test]
+	fn amoxor_returns_the_old_value_and_stores_the_xor()
+	{
+		// amoxor.w a0, a2, (a1). The sum would be 0x8000_0012, & the
+		// word above the one operated on is left alone.
+		let (rd, memory) =
+			amo(0x20c5_a52f, 0x1234_5678_8000_000f, 0xffff_ffff_0000_0003);
+		assert_eq!(rd, 0xffff_ffff_8000_000f);
+		assert_eq!(memory, 0x1234_5678_8000_000c);
+
+		// amoxor.d a0, a2, (a1)
+		let (rd, memory) =
+			amo(0x20c5_b52f, 0xf0f0_0000_0000_000f, 0x0ff0_0000_0000_0003);
+		assert_eq!(rd, 0xf0f0_0000_0000_000f);
+		assert_eq!(memory, 0xff00_0000_0000_000c);
+
+		for bits in [0x20c5_a52f, 0x20c5_b52f] {
+			let mut platform = Platform::default();
+			let mut insn = Insn::from(bits);
+			platform.hart.write_register(11_usize, 0x8000_0100);
+			insn.handle(&mut platform).unwrap();
+			assert_eq!(insn.mnemonic, Mnemonic::Amoxor);
+		}
+	}
+
+	#[test]
+	fn amo_min_max_doubleword()
+	{
+		let minus_five: u64 = -5_i64 as u64;
+
+		// amomin.d a0, a2, (a1)
+		assert_eq!(amo(0x80c5_b52f, minus_five, 3), (minus_five, minus_five));
+		assert_eq!(amo(0x80c5_b52f, 3, minus_five), (3, minus_five));
+		// amomax.d a0, a2, (a1)
+		assert_eq!(amo(0xa0c5_b52f, minus_five, 3), (minus_five, 3));
+		assert_eq!(amo(0xa0c5_b52f, 7, 3), (7, 7));
+		// amominu.d a0, a2, (a1)
+		assert_eq!(amo(0xc0c5_b52f, minus_five, 3), (minus_five, 3));
+		assert_eq!(amo(0xc0c5_b52f, 1, 3), (1, 1));
+		// amomaxu.d a0, a2, (a1)
+		assert_eq!(amo(0xe0c5_b52f, minus_five, 3), (minus_five, minus_five));
+		assert_eq!(amo(0xe0c5_b52f, 1, 3), (1, 3));
+	}
+
+	#[test]
+	fn amoadd_to_x0_still_updates_memory()
+	{
+		let mut platform = Platform::default();
+		let address: usize = 0x8000_0100;
+		platform.write(address, 40_u64).unwrap();
+		platform.hart.write_register(11_usize, address as u64);
+		platform.hart.write_register(12_usize, 2);
+
+		// amoadd.w x0, a2, (a1)
+		let mut insn = Insn::from(0x00c5_a02f);
+		insn.handle(&mut platform).unwrap();
+		assert_eq!(platform.read::<u64>(address).unwrap(), 42);
+		assert_eq!(platform.hart.read_register(0_usize), 0);
+
+		// amoadd.d x0, a2, (a1)
+		let mut insn = Insn::from(0x00c5_b02f);
+		insn.handle(&mut platform).unwrap();
+		assert_eq!(platform.read::<u64>(address).unwrap(), 44);
+		assert_eq!(platform.hart.read_register(0_usize), 0);
+	}
+
+	#[test]
+	fn amo_operands_are_read_before_rd_is_written()
+	{
+		// amoadd.w a0, x0, (a1) adds zero
+		assert_eq!(amo(0x0005_a52f, 7, 3), (7, 7));
+		// amoadd.d a0, a2, (a1) wraps
+		assert_eq!(amo(0x00c5_b52f, u64::MAX, 2), (u64::MAX, 1));
+
+		let mut platform = Platform::default();
+		let address: usize = 0x8000_0100;
+		platform.write(address, 9_u64).unwrap();
+		platform.hart.write_register(10_usize, 5);
+		platform.hart.write_register(11_usize, address as u64);
+
+		// amoswap.d a0, a0, (a1) swaps the register with memory
+		let mut insn = Insn::from(0x08a5_b52f);
+		insn.handle(&mut platform).unwrap();
+		assert_eq!(platform.hart.read_register(10_usize), 9);
+		assert_eq!(platform.read::<u64>(address).unwrap(), 5);
+	}
+}
@@ -68,3 +68,134 @@ impl LeBytes for u64
 		return u64::from_le_bytes(input);
 	}
 }
+
+impl LeBytes for u128
+{
+	const SIZE: usize = 16;
+
+	fn to_le_bytes(self) -> [u8; <Self as LeBytes>::SIZE]
+	{
+		return u128::to_le_bytes(self);
+	}
+
+	fn from_le_bytes(input: [u8; <Self as LeBytes>::SIZE]) -> Self
+	{
+		return u128::from_le_bytes(input);
+	}
+}
+
+impl LeBytes for i8
+{
+	const SIZE: usize = 1;
+
+	fn to_le_bytes(self) -> [u8; <Self as LeBytes>::SIZE]
+	{
+		return i8::to_le_bytes(self);
+	}
+
+	fn from_le_bytes(input: [u8; <Self as LeBytes>::SIZE]) -> Self
+	{
+		return i8::from_le_bytes(input);
+	}
+}
+
+impl LeBytes for i16
+{
+	const SIZE: usize = 2;
+
+	fn to_le_bytes(self) -> [u8; <Self as LeBytes>::SIZE]
+	{
+		return i16::to_le_bytes(self);
+	}
+
+	fn from_le_bytes(input: [u8; <Self as LeBytes>::SIZE]) -> Self
+	{
+		return i16::from_le_bytes(input);
+	}
+}
+
+impl LeBytes for i32
+{
+	const SIZE: usize = 4;
+
+	fn to_le_bytes(self) -> [u8; <Self as LeBytes>::SIZE]
+	{
+		return i32::to_le_bytes(self);
+	}
+
+	fn from_le_bytes(input: [u8; <Self as LeBytes>::SIZE]) -> Self
+	{
+		return i32::from_le_bytes(input);
+	}
+}
+
+impl LeBytes for i64
+{
+	const SIZE: usize = 8;
+
+	fn to_le_bytes(self) -> [u8; <Self as LeBytes>::SIZE]
+	{
+		return i64::to_le_bytes(self);
+	}
+
+	fn from_le_bytes(input: [u8; <Self as LeBytes>::SIZE]) -> Self
+	{
+		return i64::from_le_bytes(input);
+	}
+}
+
+impl LeBytes for i128
+{
+	const SIZE: usize = 16;
+
+	fn to_le_bytes(self) -> [u8; <Self as LeBytes>::SIZE]
+	{
+		return i128::to_le_bytes(self);
+	}
+
+	fn from_le_bytes(input: [u8; <Self as LeBytes>::SIZE]) -> Self
+	{
+		return i128::from_le_bytes(input);
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::LeBytes;
+
+	fn round_trip<T>(value: T) -> T
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		return T::from_le_bytes(LeBytes::to_le_bytes(value));
+	}
+
+	#[test]
+	fn signed_types_round_trip()
+	{
+		for value in [i8::MIN, -1, 0, 1, i8::MAX] {
+			assert_eq!(round_trip(value), value);
+		}
+		for value in [i16::MIN, -2, 0, 0x1234, i16::MAX] {
+			assert_eq!(round_trip(value), value);
+		}
+		for value in [i32::MIN, -3, 0, 0x1234_5678, i32::MAX] {
+			assert_eq!(round_trip(value), value);
+		}
+		for value in [i64::MIN, -4, 0, 0x1234_5678_9abc_def0, i64::MAX] {
+			assert_eq!(round_trip(value), value);
+		}
+		for value in [i128::MIN, -5, 0, i64::MAX as i128 + 1, i128::MAX] {
+			assert_eq!(round_trip(value), value);
+		}
+	}
+
+	#[test]
+	fn signed_bytes_are_twos_complement()
+	{
+		assert_eq!(LeBytes::to_le_bytes(-2_i16), [0xfe, 0xff]);
+		assert_eq!(<i32 as LeBytes>::from_le_bytes([0, 0, 0, 0x80]), i32::MIN);
+	}
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![feature(generic_const_exprs)]
+#![feature(concat_idents)]
+#![allow(incomplete_features)]
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+//! The emulator, for embedding elsewhere. Build a Platform, load something
+//! into it & call step() to run it an instruction at a time, or emulate() to
+//! run it until it halts.
+
+pub mod bitfield;
+pub mod bus;
+pub mod clint;
+pub mod debugger;
+pub mod dtb;
+pub mod elf;
+pub mod hart;
+pub mod insn;
+pub mod lebytes;
+pub mod lockstep;
+pub mod mmu;
+pub mod platform;
+pub mod plic;
+pub mod rtc;
+pub mod sbi;
+pub mod snapshot;
+pub mod uart;
+pub mod virtio;
+
+pub use bus::Bus;
+pub use hart::Hart;
+pub use insn::Insn;
+pub use platform::Platform;
+
+#[cfg(test)]
+mod test
+{
+	use crate::hart::RegisterNames;
+	use crate::platform::test::load_program;
+	use crate::Platform;
+
+	#[test]
+	fn step_through_a_program()
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 2] = [
+			0x0050_0513, // addi a0, zero, 5
+			0x0035_0593, // addi a1, a0, 3
+		];
+		load_program(&mut platform, &program);
+
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_register(RegisterNames::a0 as usize), 5);
+		assert_eq!(platform.hart.pc, 0x8000_0004);
+
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_register(RegisterNames::a1 as usize), 8);
+		assert_eq!(platform.hart.pc, 0x8000_0008);
+	}
+}
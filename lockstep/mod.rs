@@ -0,0 +1,314 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::debugger::parse_number;
+use crate::hart::{csr_name, HartState, RegisterNames, CSR_NAMES};
+use crate::platform::Platform;
+use std::error::Error;
+use std::fmt;
+
+// A reference trace has a line for each instruction a single hart retires:
+// the pc it was at, then anything it wrote as name=value, eg
+// "0x80000000 a0=0x5". Names are those of registers, either ABI or x
+// numbers, or of CSRs. Blank lines & ones starting with # are skipped.
+
+/// A register or CSR written by an instruction in a reference trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceWrite
+{
+	Register(usize, u64),
+	Csr(usize, u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry
+{
+	/// Where in the trace file this came from, counting from 1
+	pub line: usize,
+	pub pc: u64,
+	pub writes: Vec<TraceWrite>,
+}
+
+fn parse_write(input: &str) -> Result<TraceWrite, String>
+{
+	let Some((name, value)) = input.split_once('=') else {
+		return Err(format!("write needs a name=value: {}", input));
+	};
+	let value = parse_number(value)?;
+
+	if let Ok(register) = name.parse::<RegisterNames>() {
+		return Ok(TraceWrite::Register(register as usize, value));
+	}
+
+	return CSR_NAMES
+		.iter()
+		.find(|(csr, _)| return *csr == name)
+		.map(|(_, csr)| return TraceWrite::Csr(*csr, value))
+		.ok_or_else(|| return format!("unknown register or csr: {}", name));
+}
+
+pub fn parse_trace(text: &str) -> Result<Vec<TraceEntry>, String>
+{
+	let mut entries = Vec::new();
+
+	for (index, line) in text.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let within = |error: String| {
+			return format!("trace line {}: {}", index + 1, error);
+		};
+		let mut words = line.split_whitespace();
+		let pc = parse_number(words.next().unwrap()).map_err(within)?;
+		let writes = words
+			.map(parse_write)
+			.collect::<Result<Vec<TraceWrite>, String>>()
+			.map_err(within)?;
+
+		entries.push(TraceEntry {
+			line: index + 1,
+			pc,
+			writes,
+		});
+	}
+
+	return Ok(entries);
+}
+
+/// Where the emulator first disagreed with a reference trace, & how.
+#[derive(Debug, PartialEq)]
+pub struct Divergence
+{
+	pub line: usize,
+	pub pc: u64,
+	pub differences: Vec<String>,
+}
+
+impl fmt::Display for Divergence
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		write!(
+			f,
+			"diverged from the trace at line {}, pc {:#x}:",
+			self.line, self.pc
+		)?;
+		for difference in &self.differences {
+			write!(f, "\n  {}", difference)?;
+		}
+
+		return Ok(());
+	}
+}
+
+impl Error for Divergence {}
+
+/// How the hart differs from what entry says it should be after retiring
+/// an instruction at pc, given its registers from before.
+fn differences(
+	platform: &Platform, entry: &TraceEntry, pc: u64, before: &[u64; 32],
+) -> Vec<String>
+{
+	let hart = &platform.hart;
+	let mut differences = Vec::new();
+
+	if pc != hart.zero_extend(entry.pc) {
+		differences.push(format!("pc = {:#x}, expected {:#x}", pc, entry.pc));
+	}
+
+	let mut written = [false; 32];
+	for write in &entry.writes {
+		let (name, actual, expected) = match *write {
+			TraceWrite::Register(index, value) => {
+				written[index] = true;
+				let name = RegisterNames::from_index(index).unwrap();
+				(name.to_string(), hart.read_register(index), value)
+			},
+			TraceWrite::Csr(csr, value) => {
+				let name = csr_name(csr).unwrap();
+				(String::from(name), hart.read_csr(csr), value)
+			},
+		};
+
+		// RV32 registers hold their values sign extended
+		let actual = hart.zero_extend(actual);
+		if actual != hart.zero_extend(expected) {
+			differences.push(format!(
+				"{} = {:#x}, expected {:#x}",
+				name, actual, expected
+			));
+		}
+	}
+
+	for index in 1..32 {
+		let value = hart.read_register(index);
+		if !written[index] && value != before[index] {
+			differences.push(format!(
+				"{} = {:#x}, expected it unchanged",
+				RegisterNames::from_index(index).unwrap(),
+				hart.zero_extend(value)
+			));
+		}
+	}
+
+	return differences;
+}
+
+/// Step platform an instruction at a time, checking each one retired
+/// against the next entry of trace, until the trace runs out or the
+/// platform halts. Steps that take a trap retire nothing, so don't use up
+/// an entry. A trace is of a single hart, so it is an error for any more
+/// to be running. Returns how many instructions matched, or the first
+/// Divergence.
+pub fn compare(
+	platform: &mut Platform, trace: &[TraceEntry],
+) -> Result<usize, Box<dyn Error>>
+{
+	for (matched, entry) in trace.iter().enumerate() {
+		loop {
+			// Which hart that is needn't be platform.hart until it steps
+			let mut running = (0..platform.hart_count()).filter(|id| {
+				return platform.hart_by_id(*id).state == HartState::Started;
+			});
+			let (Some(id), None) = (running.next(), running.next()) else {
+				return Err("a trace can only be compared with one hart".into());
+			};
+
+			let hart = platform.hart_by_id(id);
+			let pc = hart.pc;
+			let before = hart.registers;
+
+			let reason = platform.step()?;
+			let retired = platform.retired();
+			if retired {
+				let differences = differences(platform, entry, pc, &before);
+				if !differences.is_empty() {
+					return Err(Box::new(Divergence {
+						line: entry.line,
+						pc,
+						differences,
+					}));
+				}
+			}
+
+			if reason.is_some() {
+				return Ok(matched + retired as usize);
+			}
+			if retired {
+				break;
+			}
+		}
+	}
+
+	return Ok(trace.len());
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{compare, parse_trace, Divergence, TraceEntry, TraceWrite};
+	use crate::hart::{CSR_MSCRATCH, CSR_MTVEC};
+	use crate::platform::test::load_program;
+	use crate::platform::{Platform, MEMORY_BASE, MEMORY_SIZE};
+
+	fn test_platform() -> Platform
+	{
+		let mut platform = Platform::default();
+		let program: [u32; 4] = [
+			0x0050_0513, // li a0, 5
+			0x0035_0593, // addi a1, a0, 3
+			0x3405_9073, // csrw mscratch, a1
+			0x0000_0013, // nop
+		];
+		load_program(&mut platform, &program);
+
+		return platform;
+	}
+
+	#[test]
+	fn parses_traces()
+	{
+		let trace = parse_trace("# pc writes\n0x80000000 x10=5\n\n0x4 mtvec=0")
+			.unwrap();
+
+		assert_eq!(
+			trace,
+			[
+				TraceEntry {
+					line: 2,
+					pc: 0x8000_0000,
+					writes: vec![TraceWrite::Register(10, 5)],
+				},
+				TraceEntry {
+					line: 4,
+					pc: 0x4,
+					writes: vec![TraceWrite::Csr(CSR_MTVEC, 0)],
+				},
+			]
+		);
+		assert!(parse_trace("0x80000000 q0=1").is_err());
+		assert!(parse_trace("0x80000000 a0").is_err());
+		assert!(parse_trace("pc a0=1").is_err());
+	}
+
+	#[test]
+	fn matching_trace_passes()
+	{
+		let mut platform = test_platform();
+		let trace = parse_trace(
+			"0x80000000 a0=0x5\n\
+			 0x80000004 a1=0x8\n\
+			 0x80000008 mscratch=0x8\n\
+			 0x8000000c\n",
+		)
+		.unwrap();
+
+		assert_eq!(compare(&mut platform, &trace).unwrap(), 4);
+		assert_eq!(platform.hart.read_csr(CSR_MSCRATCH), 8);
+	}
+
+	#[test]
+	fn first_divergence_is_reported()
+	{
+		let mut platform = test_platform();
+		let trace = parse_trace(
+			"0x80000000 a0=0x5\n\
+			 0x80000004 a1=0x9\n\
+			 0x80000008 mscratch=0x9\n",
+		)
+		.unwrap();
+
+		let error = compare(&mut platform, &trace).unwrap_err();
+		assert_eq!(
+			*error.downcast::<Divergence>().unwrap(),
+			Divergence {
+				line: 2,
+				pc: 0x8000_0004,
+				differences: vec![String::from("a1 = 0x8, expected 0x9")],
+			}
+		);
+
+		// Writes the trace doesn't mention count too
+		let mut platform = test_platform();
+		let trace = parse_trace("0x80000000\n").unwrap();
+		let error = compare(&mut platform, &trace).unwrap_err();
+		assert_eq!(
+			error.to_string(),
+			"diverged from the trace at line 1, pc 0x80000000:\n  a0 = 0x5, \
+			 expected it unchanged"
+		);
+	}
+
+	#[test]
+	fn only_one_hart_may_run()
+	{
+		let mut platform = Platform::new(MEMORY_BASE, MEMORY_SIZE, 2).unwrap();
+		let trace = parse_trace("0x80000000\n").unwrap();
+		platform.start_hart(1, MEMORY_BASE as u64, 0);
+
+		assert!(compare(&mut platform, &trace).is_err());
+	}
+}
@@ -1,19 +1,20 @@
 // SPDX-License-Identifier: GPL-2.0-only
-#![feature(generic_const_exprs)]
-#![feature(concat_idents)]
 #![deny(clippy::implicit_return)]
 #![allow(clippy::needless_return)]
 
+use clap::builder::RangedU64ValueParser;
 use clap::Parser;
-use platform::Platform;
 use std::fs;
-
-mod bitfield;
-mod bus;
-mod hart;
-mod insn;
-mod lebytes;
-mod platform;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use thing::hart::{self, Xlen};
+use thing::platform::{
+	FixedLatency, HaltReason, MisalignedAccesses, Platform, MEMORY_BASE,
+	MEMORY_SIZE, RESET_ADDRESS,
+};
+use thing::snapshot::Snapshot;
+use thing::uart::NonBlockingStdin;
+use thing::{debugger, dtb, elf, lockstep};
 
 /// thing
 #[derive(Parser, Debug)]
@@ -24,29 +25,216 @@ struct Args
 	#[clap(short, long, default_value = "vmlinux")]
 	kernel: String,
 
-	/// kernel load address
+	/// where memory starts in the physical address space
+	#[clap(long, default_value_t = MEMORY_BASE)]
+	memory_base: usize,
+
+	/// how many bytes of memory there are
+	#[clap(long, default_value_t = MEMORY_SIZE)]
+	memory_size: usize,
+
+	/// how many harts there are, only hart 0 runs until others are started
+	#[clap(
+		long,
+		default_value_t = 1,
+		value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+	)]
+	harts: usize,
+
+	/// register width, 32 or 64
+	#[clap(long, default_value = "64", value_parser = hart::parse_xlen)]
+	xlen: Xlen,
+
+	/// kernel load address, for raw binaries, defaults to the start of
+	/// memory
 	#[clap(long)]
 	kernel_load_address: Option<usize>,
 
-	/// entry point
+	/// entry point, for raw binaries
 	#[clap(long)]
 	entry_point: Option<usize>,
 
-	/// dtb
-	#[clap(short, long, default_value = "emu.dtb")]
-	dtb: String,
+	/// the kernel is position independent, so enter it at its load address
+	#[clap(long, conflicts_with = "entry-point")]
+	relocatable: bool,
+
+	/// dtb, generated from the configured memory & devices if not given
+	#[clap(short, long)]
+	dtb: Option<String>,
 
 	/// dtb load address
 	#[clap(long)]
 	dtb_load_address: Option<usize>,
+
+	/// start from a trampoline in a ROM, which enters the kernel with the
+	/// hart id in a0 & the dtb in a1, as firmware expects
+	#[clap(long)]
+	reset_trampoline: bool,
+
+	/// where the reset trampoline goes, defaults to 0x1000
+	#[clap(long, requires = "reset-trampoline")]
+	reset_address: Option<usize>,
+
+	/// an initramfs, which the dtb needs to describe if one is given
+	#[clap(long)]
+	initrd: Option<String>,
+
+	/// initrd load address, defaults to the end of memory
+	#[clap(long, requires = "initrd")]
+	initrd_load_address: Option<usize>,
+
+	/// a disk image, attached as a virtio block device, which the dtb
+	/// needs to describe
+	#[clap(long)]
+	disk: Option<String>,
+
+	/// stop before taking a trap with this mcause
+	#[clap(long)]
+	break_on_cause: Option<u64>,
+
+	/// trap on misaligned loads & stores, rather than splitting them
+	#[clap(long)]
+	trap_misaligned: bool,
+
+	/// print what the emulator supports and exit
+	#[clap(long)]
+	capabilities: bool,
+
+	/// stop after executing this many instructions
+	#[clap(long)]
+	run: Option<usize>,
+
+	/// give up with an error once a hart has retired this many
+	/// instructions
+	#[clap(long)]
+	max_insns: Option<u64>,
+
+	/// accept debugger commands on stdin, after --run if given
+	#[clap(long)]
+	interactive: bool,
+
+	/// accept debugger commands on stdin once the guest executes an
+	/// ebreak, instead of taking the exception
+	#[clap(long, conflicts_with = "break-on-cause")]
+	debug_on_ebreak: bool,
+
+	/// check that the dtb is a valid devicetree and exit
+	#[clap(long, requires = "dtb")]
+	validate_dtb: bool,
+
+	/// write to guest memory after loading, as address=word or
+	/// address=byte:b, may be repeated
+	#[clap(long, value_parser = debugger::parse_patch)]
+	patch: Vec<debugger::Patch>,
+
+	/// set a register before starting, as name=value using its ABI name,
+	/// after the kernel & dtb have been loaded, may be repeated
+	#[clap(long, value_parser = debugger::parse_register_value)]
+	set_reg: Vec<debugger::RegisterValue>,
+
+	/// start executing here rather than wherever loading left the pc
+	#[clap(long, value_parser = debugger::parse_number)]
+	pc: Option<u64>,
+
+	/// print LR/SC reservation counters on exit
+	#[clap(long)]
+	reservation_stats: bool,
+
+	/// print each instruction retired to stderr, in spike's log format
+	#[clap(long)]
+	trace: bool,
+
+	/// charge each memory access this many cycles, printing the total
+	/// stall cycles on exit
+	#[clap(long)]
+	memory_latency: Option<u64>,
+
+	/// send the guest's console output to this file instead of stdout
+	#[clap(long)]
+	console_out: Option<String>,
+
+	/// carry on from a snapshot instead of loading a kernel & dtb
+	#[clap(long)]
+	restore: Option<String>,
+
+	/// check every instruction retired against a reference trace, a line
+	/// per instruction of its pc & then any writes as name=value, stopping
+	/// at the first difference
+	#[clap(long, conflicts_with = "interactive")]
+	compare_trace: Option<String>,
+
+	/// write a physical range to a file once the guest stops, even if it
+	/// fails, as start:length:path, may be repeated
+	#[clap(long, value_parser = debugger::parse_memory_dump)]
+	dump_memory: Vec<debugger::MemoryDump>,
+
+	/// write a snapshot of the machine to this file once it halts
+	#[clap(long)]
+	snapshot_on_exit: Option<String>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>>
+/// Run the guest, dumping the hart's state to stderr & any memory asked for
+/// if it panics or errors out. The panic or error itself is carried on with
+/// once the dump is out, as it is of more use than any the dump hits.
+fn dump_on_failure<T>(
+	platform: &mut Platform, dumps: &[debugger::MemoryDump],
+	run: impl FnOnce(&mut Platform) -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>>
 {
-	let args = Args::parse();
-	let mut kernel: Vec<u8> = fs::read(args.kernel)?;
-	let dtb: Vec<u8> = fs::read(args.dtb)?;
-	let mut kernel_load_address: usize = 0x8000_0000;
+	let result = panic::catch_unwind(AssertUnwindSafe(|| return run(platform)));
+
+	match result {
+		Ok(Ok(value)) => return Ok(value),
+		Ok(Err(error)) => {
+			eprint!("{}", platform.dump_state());
+			if let Err(dump_error) = dump_memory(platform, dumps) {
+				eprintln!("{}", dump_error);
+			}
+			return Err(error);
+		},
+		Err(panic) => {
+			eprint!("{}", platform.dump_state());
+			if let Err(dump_error) = dump_memory(platform, dumps) {
+				eprintln!("{}", dump_error);
+			}
+			panic::resume_unwind(panic);
+		},
+	}
+}
+
+fn dump_memory(
+	platform: &Platform, dumps: &[debugger::MemoryDump],
+) -> Result<(), Box<dyn std::error::Error>>
+{
+	for dump in dumps {
+		debugger::dump_memory(platform, dump)?;
+	}
+
+	return Ok(());
+}
+
+fn load(
+	platform: &mut Platform, args: &Args,
+) -> Result<(), Box<dyn std::error::Error>>
+{
+	let mut kernel: Vec<u8> = fs::read(&args.kernel)?;
+
+	// The generated dtb describes the initrd, so it has to be loaded first
+	if let Some(path) = &args.initrd {
+		let initrd: Vec<u8> = fs::read(path)?;
+		let memory_end = args.memory_base + args.memory_size;
+		let load_address = args.initrd_load_address.unwrap_or_else(|| {
+			return memory_end.saturating_sub(initrd.len()) & !0xfff;
+		});
+		platform.load_initrd(initrd, load_address)?;
+	}
+
+	let dtb: Vec<u8> = match &args.dtb {
+		Some(path) => fs::read(path)?,
+		None => platform.generate_dtb(),
+	};
+
+	let mut kernel_load_address: usize = args.memory_base;
 	let mut entry_point: usize = kernel_load_address;
 
 	if args.kernel_load_address.is_some() {
@@ -63,10 +251,190 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
 		dtb_load_address = args.dtb_load_address.unwrap();
 	}
 
-	let mut platform: Platform = Platform::default();
-
-	let stripped_blob: Vec<u8> = kernel.split_off(0x1000);
 	platform.load_dtb(dtb, dtb_load_address)?;
-	platform.load_kernel(stripped_blob, kernel_load_address, entry_point)?;
-	return platform.emulate();
+
+	// ELFs say where they go, anything else is assumed to be a raw image
+	// with a 4K header to strip
+	if elf::is_elf(&kernel) {
+		platform.load_elf(&kernel)?;
+	} else if args.relocatable {
+		let stripped_blob: Vec<u8> = kernel.split_off(0x1000);
+		platform.load_relocatable(stripped_blob, kernel_load_address)?;
+	} else {
+		let stripped_blob: Vec<u8> = kernel.split_off(0x1000);
+		platform.load_kernel(
+			stripped_blob,
+			kernel_load_address,
+			entry_point,
+		)?;
+	}
+
+	if args.reset_trampoline {
+		let reset_address = args.reset_address.unwrap_or(RESET_ADDRESS);
+		let entry = platform.hart.pc;
+		platform.install_reset_trampoline(
+			reset_address,
+			entry,
+			dtb_load_address as u64,
+		)?;
+	}
+
+	return Ok(());
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>>
+{
+	let args = Args::parse();
+
+	if args.capabilities {
+		let mut platform =
+			Platform::new(args.memory_base, args.memory_size, args.harts)?;
+		platform.set_xlen(args.xlen);
+		println!("{:#?}", platform.capabilities());
+		return Ok(());
+	}
+
+	if args.validate_dtb {
+		let path = args.dtb.as_ref().unwrap();
+		dtb::validate(&fs::read(path)?)?;
+		println!("{}: valid", path);
+		return Ok(());
+	}
+
+	let console: Box<dyn Write> = match &args.console_out {
+		Some(path) => Box::new(fs::File::create(path)?),
+		None => Box::new(std::io::stdout()),
+	};
+	let mut platform = Platform::with_console(
+		args.memory_base,
+		args.memory_size,
+		args.harts,
+		console,
+	)?;
+	platform.set_xlen(args.xlen);
+	platform.break_on_cause = args.break_on_cause;
+	if args.debug_on_ebreak {
+		platform.break_on_cause = Some(hart::CAUSE_BREAKPOINT);
+	}
+	platform.max_insns = args.max_insns;
+	if args.trap_misaligned {
+		platform.misaligned_accesses = MisalignedAccesses::Trap;
+	}
+
+	if let Some(latency) = args.memory_latency {
+		platform.set_latency_model(Box::new(FixedLatency(latency)));
+	}
+
+	// stdout already belongs to the guest's console
+	if args.trace {
+		platform.set_trace(Box::new(std::io::stderr()));
+	}
+
+	// The debugger reads its commands from stdin, so it can't also be the
+	// guest's console input
+	if !args.interactive && !args.debug_on_ebreak {
+		platform.uart_mut().set_input(Box::new(NonBlockingStdin::spawn()));
+	}
+
+	if let Some(path) = &args.disk {
+		let disk = fs::OpenOptions::new().read(true).write(true).open(path)?;
+		platform.attach_disk(Box::new(disk))?;
+	}
+
+	if let Some(path) = &args.restore {
+		platform.restore(&Snapshot::from_bytes(&fs::read(path)?)?)?;
+	} else {
+		load(&mut platform, &args)?;
+	}
+
+	for patch in &args.patch {
+		debugger::apply_patch(&mut platform, patch)?;
+	}
+
+	for register in &args.set_reg {
+		debugger::apply_register_value(&mut platform, register);
+	}
+	if let Some(pc) = args.pc {
+		platform.hart.pc = platform.hart.zero_extend(pc);
+	}
+
+	if let Some(path) = &args.compare_trace {
+		let trace = lockstep::parse_trace(&fs::read_to_string(path)?)?;
+		let matched =
+			dump_on_failure(&mut platform, &args.dump_memory, |platform| {
+				return lockstep::compare(platform, &trace);
+			})?;
+		println!("{} instructions matched {}", matched, path);
+		return dump_memory(&platform, &args.dump_memory);
+	}
+
+	let mut reason: Option<HaltReason> = None;
+	if let Some(count) = args.run {
+		reason = Some(dump_on_failure(
+			&mut platform,
+			&args.dump_memory,
+			|platform| {
+				return debugger::run(platform, count);
+			},
+		)?);
+	}
+
+	// Without --interactive, only drop into the debugger at an ebreak
+	if args.debug_on_ebreak && !args.interactive && reason.is_none() {
+		reason = Some(dump_on_failure(
+			&mut platform,
+			&args.dump_memory,
+			|platform| {
+				return Ok(platform.emulate()?);
+			},
+		)?);
+	}
+
+	let at_ebreak = reason == Some(HaltReason::TrapBreak);
+	if args.interactive || (args.debug_on_ebreak && at_ebreak) {
+		if let Some(reason) = &reason {
+			debugger::step_over_ebreak(&mut platform, reason);
+		}
+		let stdin = std::io::stdin().lock();
+		dump_on_failure(&mut platform, &args.dump_memory, |platform| {
+			return debugger::repl(platform, stdin, std::io::stdout());
+		})?;
+		return dump_memory(&platform, &args.dump_memory);
+	}
+
+	if reason.is_none() {
+		reason = Some(dump_on_failure(
+			&mut platform,
+			&args.dump_memory,
+			|platform| {
+				return Ok(platform.emulate()?);
+			},
+		)?);
+	}
+
+	println!("halted: {:?}", reason);
+	print!("{}", platform.dump_state());
+	dump_memory(&platform, &args.dump_memory)?;
+
+	if let Some(path) = &args.snapshot_on_exit {
+		fs::write(path, platform.snapshot().to_bytes())?;
+	}
+
+	for diagnostic in platform.diagnostics() {
+		eprintln!("{}", diagnostic);
+	}
+
+	if let Some(base) = platform.relocation_base() {
+		println!("relocatable kernel loaded at: {:x}", base);
+	}
+
+	if args.memory_latency.is_some() {
+		println!("stall cycles: {}", platform.stall_cycles());
+	}
+
+	if args.reservation_stats {
+		println!("{:#?}", platform.reservation_stats());
+	}
+
+	return Ok(());
 }
@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::Bus;
+use crate::gen_mask;
+use crate::hart::{
+	Trap, CAUSE_INSN_ACCESS_FAULT, CAUSE_INSN_PAGE_FAULT,
+	CAUSE_LOAD_ACCESS_FAULT, CAUSE_LOAD_PAGE_FAULT, CAUSE_STORE_ACCESS_FAULT,
+	CAUSE_STORE_PAGE_FAULT, MSTATUS_MXR, MSTATUS_SUM, PRIV_MACHINE,
+	PRIV_SUPERVISOR, PRIV_USER,
+};
+
+pub const SATP_MODE_SHIFT: u64 = 60;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_PPN_MASK: u64 = gen_mask!(43, 0, u64);
+
+pub const PTE_V: u64 = gen_mask!(0, 0, u64);
+pub const PTE_R: u64 = gen_mask!(1, 1, u64);
+pub const PTE_W: u64 = gen_mask!(2, 2, u64);
+pub const PTE_X: u64 = gen_mask!(3, 3, u64);
+pub const PTE_U: u64 = gen_mask!(4, 4, u64);
+pub const PTE_A: u64 = gen_mask!(6, 6, u64);
+pub const PTE_D: u64 = gen_mask!(7, 7, u64);
+const PTE_PPN_SHIFT: u64 = 10;
+const PTE_PPN_MASK: u64 = gen_mask!(53, 10, u64);
+const PTE_SIZE: u64 = 8;
+
+const PAGE_SHIFT: u64 = 12;
+pub const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+const SV39_LEVELS: u64 = 3;
+const SV39_VPN_BITS: u64 = 9;
+const SV39_VA_BITS: u64 = 39;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access
+{
+	Fetch,
+	Load,
+	Store,
+}
+
+impl Access
+{
+	fn page_fault(self, address: u64) -> Trap
+	{
+		let cause = match self {
+			Access::Fetch => CAUSE_INSN_PAGE_FAULT,
+			Access::Load => CAUSE_LOAD_PAGE_FAULT,
+			Access::Store => CAUSE_STORE_PAGE_FAULT,
+		};
+
+		return Trap {
+			cause,
+			tval: address,
+		};
+	}
+
+	fn access_fault(self, address: u64) -> Trap
+	{
+		let cause = match self {
+			Access::Fetch => CAUSE_INSN_ACCESS_FAULT,
+			Access::Load => CAUSE_LOAD_ACCESS_FAULT,
+			Access::Store => CAUSE_STORE_ACCESS_FAULT,
+		};
+
+		return Trap {
+			cause,
+			tval: address,
+		};
+	}
+}
+
+/// Check whether a leaf PTE allows an access made at privilege, with
+/// mstatus supplying SUM & MXR.
+/// SUM lets S-mode load from & store to user pages, but never execute
+/// them. MXR makes execute-only pages readable, for loads only.
+pub fn leaf_permits(
+	pte: u64, access: Access, privilege: u64, mstatus: u64,
+) -> bool
+{
+	if pte & PTE_U != 0 {
+		let supervisor_may_access =
+			mstatus & MSTATUS_SUM != 0 && access != Access::Fetch;
+		if privilege == PRIV_SUPERVISOR && !supervisor_may_access {
+			return false;
+		}
+	} else if privilege == PRIV_USER {
+		return false;
+	}
+
+	match access {
+		Access::Fetch => return pte & PTE_X != 0,
+		Access::Store => return pte & PTE_W != 0,
+		Access::Load => {
+			let executable_readable =
+				mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0;
+			return pte & PTE_R != 0 || executable_readable;
+		},
+	}
+}
+
+/// Translate a virtual address to a physical one by walking the page
+/// tables satp points at, reading them through bus. M-mode accesses & a
+/// satp in Bare mode are not translated. Any other mode is treated as Bare,
+/// Sv39 being the only one implemented.
+/// The A & D bits are never updated here, an access to a page without A
+/// set, or a store to one without D set, page faults so that software can
+/// set them.
+pub fn translate<B: Bus>(
+	bus: &B, satp: u64, address: u64, access: Access, privilege: u64,
+	mstatus: u64,
+) -> Result<u64, Trap>
+{
+	if privilege == PRIV_MACHINE || satp >> SATP_MODE_SHIFT != SATP_MODE_SV39 {
+		return Ok(address);
+	}
+
+	// Bits 63:39 must all be copies of bit 38
+	let upper = (address as i64) >> (SV39_VA_BITS - 1);
+	if upper != 0 && upper != -1 {
+		return Err(access.page_fault(address));
+	}
+
+	let mut table = (satp & SATP_PPN_MASK) << PAGE_SHIFT;
+
+	for level in (0..SV39_LEVELS).rev() {
+		let offset_bits = PAGE_SHIFT + level * SV39_VPN_BITS;
+		let vpn = (address >> offset_bits) & gen_mask!(8, 0, u64);
+		let pte_address = table + vpn * PTE_SIZE;
+		let pte: u64 = bus
+			.read(pte_address as usize)
+			.map_err(|_| return access.access_fault(address))?;
+
+		if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+			return Err(access.page_fault(address));
+		}
+
+		let ppn = (pte & PTE_PPN_MASK) >> PTE_PPN_SHIFT;
+		if pte & (PTE_R | PTE_X) == 0 {
+			table = ppn << PAGE_SHIFT;
+			continue;
+		}
+
+		if !leaf_permits(pte, access, privilege, mstatus) {
+			return Err(access.page_fault(address));
+		}
+
+		// Superpages must be aligned to their size
+		let superpage_mask = (1 << (level * SV39_VPN_BITS)) - 1;
+		if ppn & superpage_mask != 0 {
+			return Err(access.page_fault(address));
+		}
+
+		if pte & PTE_A == 0 || (access == Access::Store && pte & PTE_D == 0) {
+			return Err(access.page_fault(address));
+		}
+
+		let page_offset = address & ((1 << offset_bits) - 1);
+		return Ok((ppn << PAGE_SHIFT) | page_offset);
+	}
+
+	// The last level's entry was a pointer to another table
+	return Err(access.page_fault(address));
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		leaf_permits, translate, Access, PTE_A, PTE_D, PTE_R, PTE_U, PTE_V,
+		PTE_W, PTE_X, SATP_MODE_SHIFT, SATP_MODE_SV39,
+	};
+	use crate::bus::Bus;
+	use crate::hart::{
+		CAUSE_LOAD_PAGE_FAULT, CAUSE_STORE_PAGE_FAULT, MSTATUS_MXR,
+		MSTATUS_SUM, PRIV_MACHINE, PRIV_SUPERVISOR, PRIV_USER,
+	};
+	use crate::platform::Memory;
+
+	#[test]
+	fn supervisor_needs_sum_for_user_pages()
+	{
+		let pte = PTE_V | PTE_R | PTE_W | PTE_X | PTE_U;
+		let load = |mstatus: u64| {
+			return leaf_permits(pte, Access::Load, PRIV_SUPERVISOR, mstatus);
+		};
+
+		assert!(!load(0));
+		assert!(load(MSTATUS_SUM));
+		assert!(leaf_permits(pte, Access::Store, PRIV_SUPERVISOR, MSTATUS_SUM));
+		// SUM never allows executing user code
+		assert!(!leaf_permits(
+			pte,
+			Access::Fetch,
+			PRIV_SUPERVISOR,
+			MSTATUS_SUM
+		));
+		// & user mode doesn't need it
+		assert!(leaf_permits(pte, Access::Load, PRIV_USER, 0));
+		assert!(!leaf_permits(pte & !PTE_U, Access::Load, PRIV_USER, 0));
+	}
+
+	#[test]
+	fn mxr_makes_execute_only_pages_readable()
+	{
+		let pte = PTE_V | PTE_X;
+		let load = |mstatus: u64| {
+			return leaf_permits(pte, Access::Load, PRIV_SUPERVISOR, mstatus);
+		};
+
+		assert!(!load(0));
+		assert!(load(MSTATUS_MXR));
+		// but not writable
+		assert!(!leaf_permits(
+			pte,
+			Access::Store,
+			PRIV_SUPERVISOR,
+			MSTATUS_MXR
+		));
+		assert!(leaf_permits(pte, Access::Fetch, PRIV_SUPERVISOR, 0));
+	}
+
+	#[test]
+	fn gigapages_translate_their_low_bits_untouched()
+	{
+		let mut memory = Memory::new(0, 0x2000);
+		let satp = SATP_MODE_SV39 << SATP_MODE_SHIFT | 0x1;
+		// Map the gigapage at 0x4000_0000 onto 0x8000_0000
+		let pte = (0x8000_0000 >> 2) | PTE_V | PTE_R | PTE_W | PTE_A;
+		memory.write(0x1000 + 8_usize, pte).unwrap();
+
+		let load = |address: u64| {
+			return translate(
+				&memory,
+				satp,
+				address,
+				Access::Load,
+				PRIV_SUPERVISOR,
+				0,
+			);
+		};
+
+		assert_eq!(load(0x4012_3456), Ok(0x8012_3456));
+		assert_eq!(load(0x0012_3456).unwrap_err().cause, CAUSE_LOAD_PAGE_FAULT);
+		// Not sign extended from bit 38
+		assert_eq!(
+			load(0x1_4012_3456).unwrap_err().cause,
+			CAUSE_LOAD_PAGE_FAULT
+		);
+		// D is clear, so stores fault
+		let store = translate(
+			&memory,
+			satp,
+			0x4000_0000,
+			Access::Store,
+			PRIV_SUPERVISOR,
+			0,
+		);
+		assert_eq!(store.unwrap_err().cause, CAUSE_STORE_PAGE_FAULT);
+		// & M-mode isn't translated at all
+		assert_eq!(
+			translate(
+				&memory,
+				satp,
+				0x4000_0000,
+				Access::Load,
+				PRIV_MACHINE,
+				0
+			),
+			Ok(0x4000_0000)
+		);
+
+		// A misaligned gigapage is reserved
+		let pte = (0x8000_1000 >> 2) | PTE_V | PTE_R | PTE_A | PTE_D;
+		memory.write(0x1000 + 8_usize, pte).unwrap();
+		assert_eq!(
+			translate(
+				&memory,
+				satp,
+				0x4000_0000,
+				Access::Load,
+				PRIV_SUPERVISOR,
+				0
+			)
+			.unwrap_err()
+			.cause,
+			CAUSE_LOAD_PAGE_FAULT
+		);
+	}
+}
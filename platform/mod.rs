@@ -2,21 +2,69 @@
 #![deny(clippy::implicit_return)]
 #![allow(clippy::needless_return)]
 
-use crate::bus::{self, Bus};
-use crate::hart::{Hart, RegisterNames};
-use crate::insn::Insn;
+use crate::bus::{self, Bus, DeviceMap};
+use crate::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use crate::dtb;
+use crate::elf;
+use crate::hart::{
+	csr_name, CAUSE_LOAD_MISALIGNED, CAUSE_STORE_MISALIGNED, CSR_MCAUSE,
+	CSR_MEPC, CSR_MTVAL, CSR_MTVEC, REGISTER_NAMES,
+};
+use crate::hart::{
+	Hart, HartState, RegisterNames, Trap, CAUSE_INSN_ACCESS_FAULT, CSR_MCYCLE,
+	CSR_MIE, CSR_MINSTRET, CSR_MIP, CSR_MSTATUS, CSR_SATP, CSR_TIME, MIP_MEIP,
+	MIP_MSIP, MIP_MTIP, MIP_STIP,
+};
+use crate::hart::{
+	Xlen, CAUSE_ILLEGAL_INSN, CSR_MISA, EXTENSIONS, MISA_C, PRIV_SUPERVISOR,
+};
+use crate::insn::{load_access_fault, store_access_fault, Insn};
 use crate::lebytes::LeBytes;
+use crate::mmu::{self, Access, PAGE_SIZE};
+use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE, RTC_IRQ, UART_IRQ, VIRTIO_IRQ};
+use crate::rtc::{Rtc, RTC_BASE, RTC_SIZE};
+use crate::snapshot::{Decoder, Encoder, Snapshot};
+use crate::uart::{Uart, UART_BASE, UART_SIZE};
+use crate::virtio::{Backing, VirtioBlock, VIRTIO_BASE, VIRTIO_SIZE};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::io::{Read, Write};
 
-fn u8s_to_insn(input: &[u8; 4]) -> u32
+// Instructions longer than 16 bits have both of their lowest bits set
+const INSN_LENGTH_MASK: u16 = 0b11;
+const INSN_LENGTH_32: u16 = 0b11;
+
+// The CSRs dump_state() shows, those that say where & why a hart trapped
+const DUMP_CSRS: [usize; 6] =
+	[CSR_MSTATUS, CSR_MEPC, CSR_MCAUSE, CSR_MTVAL, CSR_MTVEC, CSR_SATP];
+
+// The CSRs a TrapBreak records its trap in
+const TRAP_CSRS: [usize; 3] = [CSR_MEPC, CSR_MCAUSE, CSR_MTVAL];
+
+// Snapshots only keep memory in pages of this size that aren't all zero
+const SNAPSHOT_PAGE_SIZE: usize = 0x1000;
+
+// Any more traps than this in a row without an instruction retiring & a hart
+// is assumed to be stuck faulting in its trap handler
+pub const TRAP_LOOP_LIMIT: u64 = 1000;
+
+/// The traps a hart has taken in a row without retiring an instruction.
+#[derive(Debug, Default, Clone, Copy)]
+struct TrapStreak
 {
-	return (input[0] as u32)
-		| ((input[1] as u32) << 8)
-		| ((input[2] as u32) << 16)
-		| ((input[3] as u32) << 24);
+	count: u64,
+	cause: u64,
+	epc: u64,
 }
 
-#[derive(Debug, Default)]
+// For generated devicetrees. mtime ticks once per instruction, so there's no
+// true timebase, but this is what QEMU's virt machine claims. The UART has no
+// clock at all & ignores the divisor, so any value Linux accepts will do.
+const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+const UART_CLOCK: u32 = 3_686_400;
+
+#[derive(Debug)]
 struct ReservationSet
 {
 	pub address: usize,
@@ -25,284 +73,4377 @@ struct ReservationSet
 	pub hart_id: usize,
 }
 
-#[derive(Default)]
-pub struct Platform
+/// Counters for LR/SC behaviour, to give an idea of how contended locks in
+/// the guest are without needing a full trace.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReservationStats
 {
-	pub hart: Hart,
-	memory: Memory,
-	reservation_sets: Vec<ReservationSet>,
+	pub lr: u64,
+	pub sc_success: u64,
+	pub sc_failure: u64,
+	/// Valid reservations replaced by another LR from the same hart
+	pub invalidated_by_self: u64,
+	/// Valid reservations broken by a store from another hart
+	pub invalidated_by_other: u64,
 }
 
-impl Platform
+/// What the emulator provides, for consumers embedding it. This is host-side
+/// introspection & is not visible to the guest.
+#[derive(Debug, PartialEq)]
+pub struct Capabilities
 {
-	pub fn load_dtb(
-		&mut self, dtb: Vec<u8>, load_address: usize,
-	) -> Result<(), Box<dyn Error>>
-	{
-		self.load_file(dtb, load_address)?;
+	pub xlen: usize,
+	pub extensions: Vec<char>,
+	pub harts: usize,
+	pub memory_size: usize,
+	pub devices: Vec<&'static str>,
+}
 
-		self.hart.registers[RegisterNames::a1 as usize] = load_address as u64;
+#[derive(Debug, PartialEq)]
+pub enum HaltReason
+{
+	/// A trap with the cause requested via break_on_cause was about to be
+	/// taken. mepc, mcause & mtval describe it, but pc has not moved.
+	TrapBreak,
+	/// The requested number of steps have run. Each step either retires
+	/// an instruction or takes a trap in full, so if the last one took a
+	/// trap, pc is at the handler & mepc at the trapping instruction.
+	InstructionLimit,
+	/// A riscv-tests binary reported success through tohost.
+	TestPassed,
+	/// pc reached this address, set with add_breakpoint. The instruction
+	/// there has not run yet, & runs on the next step rather than stopping
+	/// again.
+	Breakpoint(u64),
+	/// A load or store touched this address, inside a range set with
+	/// add_watchpoint. The instruction making it has completed.
+	Watchpoint(u64),
+	/// pc arrived at the address passed to run_until. The instruction
+	/// there has not run yet.
+	Reached(u64),
+}
 
-		return Ok(());
-	}
+/// Which accesses a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind
+{
+	Read,
+	Write,
+	ReadWrite,
+}
 
-	pub fn load_kernel(
-		&mut self, kernel: Vec<u8>, load_address: usize, entry_point: usize,
-	) -> Result<(), Box<dyn Error>>
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Watchpoint
+{
+	address: u64,
+	length: u64,
+	kind: WatchKind,
+}
+
+impl Watchpoint
+{
+	fn matches(&self, address: u64, size: u64, kind: WatchKind) -> bool
 	{
-		self.hart.pc = entry_point as u64;
-		return self.load_file(kernel, load_address);
+		let kinds = self.kind == kind
+			|| self.kind == WatchKind::ReadWrite
+			|| kind == WatchKind::ReadWrite;
+		let end = self.address.saturating_add(self.length);
+
+		return kinds
+			&& address < end
+			&& address.saturating_add(size) > self.address;
 	}
+}
 
-	fn load_file(
-		&mut self, blob: Vec<u8>, load_address: usize,
-	) -> Result<(), Box<dyn Error>>
-	{
-		let memory = &mut self.memory;
+/// What loads & stores that aren't naturally aligned do. Linux on the virt
+/// machine expects them to just work, but real harts may trap instead.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum MisalignedAccesses
+{
+	/// Split the access into bytes, each translated separately
+	#[default]
+	Emulate,
+	/// Raise an address misaligned exception, with the address in mtval
+	Trap,
+}
 
-		if !(memory.start..memory.end).contains(&load_address) {
-			return Err(Box::<dyn Error>::from(
-				"kernel load address out of bounds".to_string(),
-			));
-		}
+/// Why loading into or running the platform failed, as opposed to halting
+/// for one of the reasons in HaltReason.
+#[derive(Debug)]
+pub enum PlatformError
+{
+	/// A blob was to be loaded at an address outside memory
+	LoadAddressOutOfBounds
+	{
+		address: usize,
+	},
+	/// A blob of size bytes loaded at address would run off the end of
+	/// memory
+	InsufficientMemory
+	{
+		address: usize,
+		size: usize,
+	},
+	/// A blob of size bytes loaded at address would overlap the initrd,
+	/// or an initrd something loaded before it
+	InitrdOverlap
+	{
+		address: usize,
+		size: usize,
+	},
+	InvalidDtb(Box<dyn Error>),
+	InvalidElf(Box<dyn Error>),
+	/// The guest asked to stop with a non-zero exit code, which riscv-tests
+	/// binaries use for the number of the test that failed
+	Halt
+	{
+		code: u64,
+	},
+	/// A hart's minstret reached max_insns, which usually means the guest
+	/// is stuck. pc is where the hart had got to.
+	InsnLimitReached
+	{
+		pc: u64,
+		instret: u64,
+	},
+	/// A hart took TRAP_LOOP_LIMIT traps in a row without retiring an
+	/// instruction, so its trap handler is most likely faulting itself.
+	/// cause & epc are those of the last one.
+	TrapLoop
+	{
+		cause: u64,
+		epc: u64,
+	},
+	/// An access the emulator made itself, rather than on behalf of the
+	/// guest, failed
+	BusError(bus::Error),
+	/// Writing the trace failed
+	Io(std::io::Error),
+}
 
-		let blob_end = load_address + blob.len();
-		if blob_end > memory.end {
-			return Err(Box::<dyn Error>::from(
-				"insufficient memory for kernel".to_string(),
-			));
+impl std::fmt::Display for PlatformError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+	{
+		match self {
+			PlatformError::LoadAddressOutOfBounds {
+				address,
+			} => {
+				return write!(f, "load address {:#x} out of bounds", address);
+			},
+			PlatformError::InsufficientMemory {
+				address,
+				size,
+			} => {
+				return write!(
+					f,
+					"insufficient memory for {:#x} bytes at {:#x}",
+					size, address
+				);
+			},
+			PlatformError::InitrdOverlap {
+				address,
+				size,
+			} => {
+				return write!(
+					f,
+					"{:#x} bytes at {:#x} overlap the initrd",
+					size, address
+				);
+			},
+			PlatformError::InvalidDtb(error) => return error.fmt(f),
+			PlatformError::InvalidElf(error) => return error.fmt(f),
+			PlatformError::Halt {
+				code,
+			} => return write!(f, "test {} failed", code),
+			PlatformError::InsnLimitReached {
+				pc,
+				instret,
+			} => {
+				return write!(
+					f,
+					"gave up after {} instructions at pc {:#x}",
+					instret, pc
+				);
+			},
+			PlatformError::TrapLoop {
+				cause,
+				epc,
+			} => {
+				return write!(
+					f,
+					"{} traps in a row without retiring an instruction, the \
+					 last with cause {} at pc {:#x}",
+					TRAP_LOOP_LIMIT, cause, epc
+				);
+			},
+			PlatformError::BusError(error) => return error.fmt(f),
+			PlatformError::Io(error) => return error.fmt(f),
 		}
+	}
+}
 
-		let memory_load_offset = load_address - memory.start;
-		let memory_load_end = memory_load_offset + blob.len();
-		memory.memory[memory_load_offset..memory_load_end]
-			.copy_from_slice(&blob[..]);
+impl Error for PlatformError {}
 
-		return Ok(());
+impl From<bus::Error> for PlatformError
+{
+	fn from(error: bus::Error) -> PlatformError
+	{
+		return PlatformError::BusError(error);
 	}
+}
 
-	pub fn emulate(&mut self) -> Result<(), Box<dyn Error>>
+impl From<std::io::Error> for PlatformError
+{
+	fn from(error: std::io::Error) -> PlatformError
 	{
-		self.reservation_sets.push(ReservationSet::default());
+		return PlatformError::Io(error);
+	}
+}
 
-		loop {
-			let pc = self.hart.pc as usize - self.memory.start;
-			let insn_bits: &[u8] = &self.memory.memory[pc..(pc + 4)];
-			let insn: u32 = u8s_to_insn(insn_bits.try_into()?);
-			let mut insn: Insn = Insn::from(insn);
+// The HTIF tohost protocol, as used by riscv-tests & spike: the top byte of
+// a tohost value selects a device, the next one a command & the rest is the
+// payload.
+const HTIF_DEVICE_SHIFT: u64 = 56;
+const HTIF_COMMAND_SHIFT: u64 = 48;
+const HTIF_PAYLOAD_MASK: u64 = (1 << HTIF_COMMAND_SHIFT) - 1;
+const HTIF_DEVICE_SYSCALL: u64 = 0;
+const HTIF_DEVICE_CONSOLE: u64 = 1;
+const HTIF_CONSOLE_PUTCHAR: u64 = 1;
 
-			insn.handle(self);
-		}
+impl ReservationSet
+{
+	/// Each hart has one reservation set, tagged with its id from the
+	/// start so that stores from other harts are always told apart from
+	/// its own, whether or not it has ever taken a reservation.
+	fn new(hart_id: usize) -> ReservationSet
+	{
+		return ReservationSet {
+			address: 0,
+			size: 0,
+			valid: false,
+			hart_id,
+		};
 	}
 
-	/// Claim a reservation set for this hart, replacing any existing one.
-	/// Must be called with the bus write lock taken.
-	pub fn claim_reservation_set<T>(
-		&mut self, hart_id: usize, address: T, size: usize,
-	) where
-		T: Into<usize>,
+	/// Check whether an access of size bytes at address touches any byte
+	/// of the reserved region.
+	fn overlaps(&self, address: usize, size: usize) -> bool
 	{
-		// TODO; what if reservation exists?
-		let address = usize::try_from(address).unwrap();
-		let reservation_set = &mut self.reservation_sets[hart_id];
+		// We have to check that no bytes of the access intersect
+		// with the reserved region. So for a 2 byte write, we
+		// need to check that the second byte is not the first
+		// of the reservation & so on. The reservation may sit at
+		// address zero, so saturate rather than underflow.
+		let start = self.address.saturating_sub(size - 1);
+		let end = self.address + self.size;
+		return (start..end).contains(&address);
+	}
+}
 
-		reservation_set.hart_id = hart_id;
-		reservation_set.address = address;
-		reservation_set.size = size;
-		reservation_set.valid = true;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind
+{
+	Read,
+	Write,
+}
+
+/// A model of the memory system, consulted on every access made through
+/// the platform's bus. The latencies it returns are summed into a stall
+/// counter, separate from the instruction count, to get an idea of how
+/// much time a guest would spend waiting on memory without needing to
+/// model the timing of the whole core.
+pub trait LatencyModel
+{
+	/// How many cycles an access of size bytes at address stalls for.
+	fn latency(&mut self, address: usize, size: usize, kind: AccessKind)
+		-> u64;
+}
+
+/// Every access stalls for the same number of cycles.
+pub struct FixedLatency(pub u64);
+
+impl LatencyModel for FixedLatency
+{
+	fn latency(&mut self, _: usize, _: usize, _: AccessKind) -> u64
+	{
+		return self.0;
 	}
+}
 
-	/// Invalidates reservations taken by other harts that overlap with a
-	/// store from this hart.
-	/// Must be called with the bus write lock taken.
-	pub fn invalidate_reservation_sets<T>(
-		&mut self, hart_id: usize, address: T, size: usize,
-	) where
-		T: Into<usize>,
+/// A range of memory that was loaded as data, so should never be executed.
+#[derive(Debug)]
+struct DataRegion
+{
+	name: String,
+	start: usize,
+	end: usize,
+	/// Whether a fetch from it has been reported in the diagnostics yet
+	reported: bool,
+}
+
+pub struct Platform
+{
+	/// The hart being stepped. Harts take turns, so this changes from one
+	/// step to the next when more than one is running.
+	pub hart: Hart,
+	/// Every hart, by id, except that the slot belonging to the one in
+	/// hart holds a stand-in until it is swapped back.
+	harts: Vec<Hart>,
+	pub break_on_cause: Option<u64>,
+	pub misaligned_accesses: MisalignedAccesses,
+	/// Stepping errors out once a hart's minstret gets this far
+	pub max_insns: Option<u64>,
+	breakpoints: HashSet<u64>,
+	watchpoints: Vec<Watchpoint>,
+	/// The hart & pc last stopped at by a breakpoint, which the next step
+	/// of that hart executes instead of stopping again
+	resume_breakpoint: Option<(usize, u64)>,
+	/// What TRAP_CSRS held before the last TrapBreak wrote over them
+	trap_break_csrs: Option<[u64; 3]>,
+	/// Whether the last step retired an instruction
+	retired: bool,
+	// Loads go through &self too
+	watchpoint_hit: Cell<Option<u64>>,
+	devices: DeviceMap,
+	reservation_sets: Vec<ReservationSet>,
+	reservation_stats: ReservationStats,
+	/// Each hart's decoded instructions, by physical address. A hart's
+	/// stores don't reach its cache until it executes a FENCE.I.
+	decode_caches: Vec<HashMap<usize, Insn>>,
+	trap_streaks: Vec<TrapStreak>,
+	/// Harts whose timer S-mode set through the SBI, so raises STIP
+	sbi_timers: Vec<bool>,
+	relocation_base: Option<usize>,
+	/// Where the initrd was loaded, start & end
+	initrd: Option<(usize, usize)>,
+	/// Where everything else was loaded, which the initrd must stay clear
+	/// of
+	loads: Vec<(usize, usize)>,
+	data_regions: Vec<DataRegion>,
+	diagnostics: Vec<String>,
+	trace: Option<Box<dyn Write>>,
+	insn_hook: Option<InsnHook>,
+	// Reads go through &self, so both of these need interior mutability
+	latency_model: RefCell<Option<Box<dyn LatencyModel>>>,
+	stall_cycles: Cell<u64>,
+	tohost: Option<usize>,
+	fromhost: Option<usize>,
+	tohost_written: bool,
+}
+
+impl Default for Platform
+{
+	fn default() -> Platform
 	{
-		let address = usize::try_from(address).unwrap();
+		return Platform::new(MEMORY_BASE, MEMORY_SIZE, 1).unwrap();
+	}
+}
 
-		for reservation_set in self.reservation_sets.iter_mut() {
-			if reservation_set.hart_id == hart_id {
-				continue;
-			}
+/// Called with each instruction a hart retires, & the hart as it is after.
+pub type InsnHook = Box<dyn FnMut(&Insn, &Hart)>;
 
-			if !reservation_set.valid {
-				continue;
-			}
+/// The UART the guest uses as its console.
+pub type ConsoleUart = Uart<Box<dyn Write>, Box<dyn Read>>;
 
-			// We have to check that no bytes of the store intersect
-			// with the reserved region. So for a 2 byte write, we
-			// need to check that the second byte is not the first
-			// of the reservation & so on
-			let start = reservation_set.address - (size - 1);
-			let end = reservation_set.address + reservation_set.size;
-			if (start..end).contains(&address) {
-				reservation_set.valid = false;
-			}
+/// The block device the guest's disk is attached to.
+pub type Disk = VirtioBlock<Box<dyn Backing>>;
+
+impl Platform
+{
+	/// A platform with memory_size bytes of memory at memory_base, which
+	/// must not overlap the other devices, & hart_count harts. Hart 0 boots
+	/// & the others are stopped until started.
+	pub fn new(
+		memory_base: usize, memory_size: usize, hart_count: usize,
+	) -> Result<Platform, bus::Error>
+	{
+		return Platform::with_console(
+			memory_base,
+			memory_size,
+			hart_count,
+			Box::new(std::io::stdout()),
+		);
+	}
+
+	/// As with new(), but with the console UART transmitting to output
+	/// rather than stdout.
+	pub fn with_console(
+		memory_base: usize, memory_size: usize, hart_count: usize,
+		output: Box<dyn Write>,
+	) -> Result<Platform, bus::Error>
+	{
+		if hart_count == 0 {
+			return Err(bus::Error::new(
+				bus::ErrorKind::OutOfBounds,
+				"a platform needs at least one hart",
+			));
 		}
+
+		let mut devices = DeviceMap::default();
+
+		devices.register(
+			"clint",
+			CLINT_BASE,
+			CLINT_SIZE,
+			Box::new(Clint::new(hart_count)),
+		)?;
+		devices.register(
+			"plic",
+			PLIC_BASE,
+			PLIC_SIZE,
+			Box::new(Plic::new(hart_count)),
+		)?;
+		devices.register(
+			"uart",
+			UART_BASE,
+			UART_SIZE,
+			Box::new(ConsoleUart::new(output, Box::new(std::io::empty()))),
+		)?;
+		devices.register("rtc", RTC_BASE, RTC_SIZE, Box::<Rtc>::default())?;
+		// Memory can be large, so only allocate it once it's known to fit
+		devices.check_free("memory", memory_base, memory_size)?;
+		devices.register(
+			"memory",
+			memory_base,
+			memory_size,
+			Box::new(Memory::new(memory_base, memory_size)),
+		)?;
+
+		let mut harts: Vec<Hart> = (0..hart_count).map(Hart::new).collect();
+		let mut hart = Hart::default();
+		std::mem::swap(&mut hart, &mut harts[0]);
+
+		return Ok(Platform {
+			hart,
+			harts,
+			break_on_cause: None,
+			misaligned_accesses: MisalignedAccesses::default(),
+			max_insns: None,
+			breakpoints: HashSet::new(),
+			watchpoints: Vec::new(),
+			resume_breakpoint: None,
+			trap_break_csrs: None,
+			retired: false,
+			watchpoint_hit: Cell::new(None),
+			devices,
+			reservation_sets: (0..hart_count)
+				.map(ReservationSet::new)
+				.collect(),
+			reservation_stats: ReservationStats::default(),
+			decode_caches: vec![HashMap::new(); hart_count],
+			trap_streaks: vec![TrapStreak::default(); hart_count],
+			sbi_timers: vec![false; hart_count],
+			relocation_base: None,
+			initrd: None,
+			loads: Vec::new(),
+			data_regions: Vec::new(),
+			diagnostics: Vec::new(),
+			trace: None,
+			insn_hook: None,
+			latency_model: RefCell::new(None),
+			stall_cycles: Cell::new(0),
+			tohost: None,
+			fromhost: None,
+			tohost_written: false,
+		});
 	}
 
-	/// Check if a reservation for this hart is still valid, and if it is,
-	/// invalidate it.
-	/// Must be called with the bus write lock taken.
-	pub fn check_invalidate_reservation_set<T>(
-		&mut self, hart_id: usize, address: T, size: usize,
-	) -> bool
-	where
-		T: Into<usize>,
+	/// Put the platform back as it was when new: every hart reset, memory
+	/// zeroed & the devices back at their power on state. Anything loaded
+	/// is gone, along with what was learnt from loading it, but settings
+	/// like tracing, break_on_cause & breakpoints are kept.
+	pub fn reset(&mut self)
 	{
-		let address = usize::try_from(address).unwrap();
-		let reservation_set = &mut self.reservation_sets[hart_id];
-		if !reservation_set.valid {
-			return false;
+		self.switch_hart(0);
+		self.hart.reset();
+		for hart in &mut self.harts {
+			hart.reset();
 		}
 
-		// We have to check that no bytes of the store intersect
-		// with the reserved region. So for a 2 byte write, we
-		// need to check that the second byte is not the first
-		// of the reservation & so on
-		let start = reservation_set.address - (size - 1);
-		let end = reservation_set.address + reservation_set.size;
-		if !(start..end).contains(&address) {
-			return false;
+		let hart_count = self.harts.len();
+		// A fresh allocation comes zeroed by the OS, which beats zeroing
+		// the old one a byte at a time
+		let memory = self.memory_mut();
+		memory.memory = heap_allocate_memory(memory.memory.len());
+		*self.clint_mut() = Clint::new(hart_count);
+		*self.plic_mut() = Plic::new(hart_count);
+		self.uart_mut().reset();
+		self.rtc_mut().reset();
+		if let Some(disk) = self.disk_mut() {
+			disk.reset();
 		}
 
-		reservation_set.valid = false;
-		return true;
+		self.reservation_sets =
+			(0..hart_count).map(ReservationSet::new).collect();
+		self.reservation_stats = ReservationStats::default();
+		self.invalidate_decode_caches();
+		self.trap_streaks = vec![TrapStreak::default(); hart_count];
+		self.sbi_timers = vec![false; hart_count];
+		self.relocation_base = None;
+		self.initrd = None;
+		self.loads.clear();
+		self.data_regions.clear();
+		self.diagnostics.clear();
+		self.stall_cycles.set(0);
+		self.tohost = None;
+		self.fromhost = None;
+		self.tohost_written = false;
+		self.resume_breakpoint = None;
+		self.trap_break_csrs = None;
+		self.watchpoint_hit.set(None);
 	}
 
-	pub fn write_from_hart<T>(
-		&mut self, hart_id: usize, address: usize, value: T,
-	) -> Result<(), bus::Error>
-	where
-		T: LeBytes,
-		[(); <T as LeBytes>::SIZE]:,
+	/// Capture everything needed to carry on from this point later: the
+	/// harts, memory, devices & LR/SC reservations. Memory is mostly
+	/// zeroes, so only the pages that aren't are kept.
+	pub fn snapshot(&self) -> Snapshot
 	{
-		self.invalidate_reservation_sets(
-			hart_id,
-			address,
-			<T as LeBytes>::SIZE,
-		);
+		let mut snapshot = Snapshot::default();
 
-		return self.write(address, value);
+		for id in 0..self.harts.len() {
+			let mut hart = Encoder::default();
+			self.hart_by_id(id).save(&mut hart);
+			snapshot.add(&format!("hart{}", id), hart);
+		}
+
+		let mut memory = Encoder::default();
+		let zeroes = [0_u8; SNAPSHOT_PAGE_SIZE];
+		let pages: Vec<(usize, &[u8])> = self
+			.memory()
+			.memory
+			.chunks(SNAPSHOT_PAGE_SIZE)
+			.enumerate()
+			.filter(|(_, page)| {
+				return *page != &zeroes[..page.len()];
+			})
+			.collect();
+		memory.u64(self.memory().memory.len() as u64);
+		memory.u64(pages.len() as u64);
+		for (index, page) in pages {
+			memory.u64((index * SNAPSHOT_PAGE_SIZE) as u64);
+			memory.bytes(page);
+		}
+		snapshot.add("memory", memory);
+
+		let mut clint = Encoder::default();
+		self.clint().save(&mut clint);
+		snapshot.add("clint", clint);
+		let mut plic = Encoder::default();
+		self.plic().save(&mut plic);
+		snapshot.add("plic", plic);
+		let mut uart = Encoder::default();
+		self.uart().save(&mut uart);
+		snapshot.add("uart", uart);
+		let mut rtc = Encoder::default();
+		self.rtc().save(&mut rtc);
+		snapshot.add("rtc", rtc);
+		if let Some(disk) = self.disk() {
+			let mut virtio = Encoder::default();
+			disk.save(&mut virtio);
+			snapshot.add("virtio-blk", virtio);
+		}
+
+		snapshot.add("platform", self.save());
+
+		return snapshot;
 	}
-}
 
-impl Bus for Platform
-{
-	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
-	where
-		T: LeBytes,
-		[(); <T as LeBytes>::SIZE]:,
+	/// The platform's own state, as opposed to its harts' & devices'.
+	fn save(&self) -> Encoder
 	{
-		let memory = &self.memory;
-		if (memory.start..memory.end).contains(&address) {
-			return self.memory.read(address - MEMORY_BASE);
+		let mut out = Encoder::default();
+		out.u64(self.hart.id as u64);
+		for set in &self.reservation_sets {
+			out.u64(set.address as u64);
+			out.u64(set.size as u64);
+			out.u64(set.valid as u64);
 		}
 
-		return Err(bus::Error::new(
-			bus::ErrorKind::Unimplemented,
-			&format!("addr: {:}", address),
-		));
+		let stats = &self.reservation_stats;
+		out.u64(stats.lr);
+		out.u64(stats.sc_success);
+		out.u64(stats.sc_failure);
+		out.u64(stats.invalidated_by_self);
+		out.u64(stats.invalidated_by_other);
+
+		out.option(self.relocation_base);
+		out.option(self.initrd.map(|(start, _)| return start));
+		out.option(self.initrd.map(|(_, end)| return end));
+		out.u64(self.data_regions.len() as u64);
+		for region in &self.data_regions {
+			out.bytes(region.name.as_bytes());
+			out.u64(region.start as u64);
+			out.u64(region.end as u64);
+		}
+		out.option(self.tohost);
+		out.option(self.fromhost);
+		out.u64(self.tohost_written as u64);
+		out.u64(self.stall_cycles.get());
+		for timer in &self.sbi_timers {
+			out.u64(*timer as u64);
+		}
+
+		return out;
 	}
 
-	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
-	where
-		T: LeBytes,
-		U: Into<usize>,
-		[(); <T as LeBytes>::SIZE]:,
+	/// Put the platform back to where it was when snapshot was taken. The
+	/// snapshot must be from a platform with the same memory size & number
+	/// of harts, but settings like tracing are left as they are.
+	pub fn restore(&mut self, snapshot: &Snapshot)
+		-> Result<(), Box<dyn Error>>
 	{
-		let address = address.into();
-		let memory = &self.memory;
-		if (memory.start..memory.end).contains(&address) {
-			return self.memory.write(address - MEMORY_BASE, value);
+		// Try it on a stand-in of the same shape first, so that a snapshot
+		// that turns out to be bad partway through leaves this one alone
+		let memory = self.memory();
+		let mut scratch = Platform::with_console(
+			memory.start,
+			memory.memory.len(),
+			self.harts.len(),
+			Box::new(std::io::sink()),
+		)?;
+		if self.disk().is_some() {
+			scratch.attach_disk(Box::new(std::io::Cursor::new(Vec::new())))?;
 		}
+		scratch.apply_snapshot(snapshot)?;
 
-		return Err(bus::Error::new(
-			bus::ErrorKind::Unimplemented,
-			&format!("addr: {:}", address),
-		));
+		return self.apply_snapshot(snapshot);
 	}
-}
 
-const MEMORY_BASE: usize = 0x8000_0000;
-const MEMORY_SIZE: usize = 0x1000_0000;
-const MEMORY_END: usize = MEMORY_BASE + MEMORY_SIZE;
+	fn apply_snapshot(
+		&mut self, snapshot: &Snapshot,
+	) -> Result<(), Box<dyn Error>>
+	{
+		for id in 0..self.harts.len() {
+			let mut hart = snapshot.section(&format!("hart{}", id))?;
+			self.hart_by_id_mut(id).restore(&mut hart)?;
+		}
 
-fn heap_allocate_memory() -> Box<[u8]>
-{
-	let memory: Box<[u8]> = vec![0u8; MEMORY_SIZE].into_boxed_slice();
-	return memory;
-}
+		let mut memory = snapshot.section("memory")?;
+		let size = self.memory().memory.len();
+		memory.expect("bytes of memory", size)?;
+		let mut contents = heap_allocate_memory(size);
+		for _ in 0..memory.u64()? {
+			let offset = memory.usize()?;
+			let page = memory.bytes()?;
+			let end = offset.checked_add(page.len()).unwrap_or(usize::MAX);
+			let Some(destination) = contents.get_mut(offset..end) else {
+				return Err(format!("page past memory: {:#x}", offset).into());
+			};
+			destination.copy_from_slice(page);
+		}
+		self.memory_mut().memory = contents;
 
-pub struct Memory
-{
-	start: usize,
-	end: usize,
-	memory: Box<[u8]>,
-}
+		self.clint_mut().restore(&mut snapshot.section("clint")?)?;
+		self.plic_mut().restore(&mut snapshot.section("plic")?)?;
+		self.uart_mut().restore(&mut snapshot.section("uart")?)?;
+		self.rtc_mut().restore(&mut snapshot.section("rtc")?)?;
+		if let Some(disk) = self.disk_mut() {
+			disk.restore(&mut snapshot.section("virtio-blk")?)?;
+		}
+		self.restore_self(&mut snapshot.section("platform")?)?;
+		self.invalidate_decode_caches();
 
-impl Memory
-{
-	pub fn size(self) -> usize
-	{
-		return self.end - self.start;
+		return Ok(());
 	}
-}
 
-impl Default for Memory
-{
-	fn default() -> Memory
+	fn restore_self(
+		&mut self, input: &mut Decoder,
+	) -> Result<(), Box<dyn Error>>
 	{
-		return Memory {
-			start: MEMORY_BASE,
-			end: MEMORY_END,
-			memory: heap_allocate_memory(),
+		let current = input.usize()?;
+		if current >= self.harts.len() {
+			return Err(format!("no hart {} to switch to", current).into());
+		}
+		self.switch_hart(current);
+
+		for set in &mut self.reservation_sets {
+			set.address = input.usize()?;
+			set.size = input.usize()?;
+			set.valid = input.bool()?;
+		}
+
+		self.reservation_stats = ReservationStats {
+			lr: input.u64()?,
+			sc_success: input.u64()?,
+			sc_failure: input.u64()?,
+			invalidated_by_self: input.u64()?,
+			invalidated_by_other: input.u64()?,
 		};
+
+		self.relocation_base = input.option()?;
+		self.initrd = input.option()?.zip(input.option()?);
+		self.loads.clear();
+		self.data_regions.clear();
+		for _ in 0..input.u64()? {
+			self.data_regions.push(DataRegion {
+				name: String::from_utf8(input.bytes()?.to_vec())?,
+				start: input.usize()?,
+				end: input.usize()?,
+				reported: false,
+			});
+		}
+		self.tohost = input.option()?;
+		self.fromhost = input.option()?;
+		self.tohost_written = input.bool()?;
+		self.stall_cycles.set(input.u64()?);
+		for timer in &mut self.sbi_timers {
+			*timer = input.bool()?;
+		}
+
+		return Ok(());
 	}
-}
 
-impl Bus for Memory
-{
-	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
-	where
-		T: LeBytes,
-		[(); <T as LeBytes>::SIZE]:,
+	/// Switch every hart between RV32 & RV64.
+	pub fn set_xlen(&mut self, xlen: Xlen)
 	{
-		return Ok(T::from_le_bytes(
-			self.memory[address..address + <T as LeBytes>::SIZE]
-				.try_into()
-				.unwrap(),
-		));
+		self.hart.set_xlen(xlen);
+		for hart in self.harts.iter_mut() {
+			hart.set_xlen(xlen);
+		}
+		// Compressed instructions expand differently for each
+		self.invalidate_decode_caches();
 	}
 
-	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
-	where
-		T: LeBytes,
-		U: Into<usize>,
-		[(); <T as LeBytes>::SIZE]:,
+	/// The current hart's pc, registers & the CSRs that matter most when
+	/// working out how it got where it is, for printing when things stop.
+	pub fn dump_state(&self) -> String
 	{
-		let address = address.into();
-		let tmp: [u8; <T as LeBytes>::SIZE] = value.to_le_bytes();
-		self.memory[address..address + <T as LeBytes>::SIZE]
-			.copy_from_slice(&tmp[..<T as LeBytes>::SIZE]);
+		let hart = &self.hart;
+		let mut dump = format!("hart {} pc: {:#018x}\n", hart.id, hart.pc);
 
-		return Ok(());
+		for (index, name) in REGISTER_NAMES.iter().enumerate() {
+			let value = hart.read_register(index);
+			let end = if index % 4 == 3 { "\n" } else { " " };
+			dump += &format!("{:>4}: {:#018x}{}", name, value, end);
+		}
+
+		for (index, csr) in DUMP_CSRS.iter().enumerate() {
+			let name = csr_name(*csr).unwrap();
+			let value = hart.read_csr(*csr);
+			let end = if index % 3 == 2 { "\n" } else { " " };
+			dump += &format!("{:>7}: {:#018x}{}", name, value, end);
+		}
+
+		return dump;
 	}
-}
 
-#[cfg(test)]
-mod test
-{
-	use crate::platform::MEMORY_SIZE;
+	pub fn capabilities(&self) -> Capabilities
+	{
+		return Capabilities {
+			xlen: self.hart.xlen.bits() as usize,
+			extensions: EXTENSIONS.to_vec(),
+			harts: self.harts.len(),
+			memory_size: self.memory().size(),
+			devices: self.devices.names(),
+		};
+	}
 
-	use super::heap_allocate_memory;
+	// The devices registered by default are always there, so these can't
+	// fail to find them.
 
-	#[test]
-	fn can_heap_alloc()
+	fn memory(&self) -> &Memory
 	{
-		let memory = heap_allocate_memory();
+		return self.devices.get::<Memory>().unwrap();
+	}
+
+	fn memory_mut(&mut self) -> &mut Memory
+	{
+		return self.devices.get_mut::<Memory>().unwrap();
+	}
+
+	pub fn clint(&self) -> &Clint
+	{
+		return self.devices.get::<Clint>().unwrap();
+	}
+
+	pub fn clint_mut(&mut self) -> &mut Clint
+	{
+		return self.devices.get_mut::<Clint>().unwrap();
+	}
+
+	pub fn plic(&self) -> &Plic
+	{
+		return self.devices.get::<Plic>().unwrap();
+	}
+
+	pub fn plic_mut(&mut self) -> &mut Plic
+	{
+		return self.devices.get_mut::<Plic>().unwrap();
+	}
+
+	pub fn uart(&self) -> &ConsoleUart
+	{
+		return self.devices.get::<ConsoleUart>().unwrap();
+	}
+
+	pub fn uart_mut(&mut self) -> &mut ConsoleUart
+	{
+		return self.devices.get_mut::<ConsoleUart>().unwrap();
+	}
+
+	pub fn rtc(&self) -> &Rtc
+	{
+		return self.devices.get::<Rtc>().unwrap();
+	}
+
+	pub fn rtc_mut(&mut self) -> &mut Rtc
+	{
+		return self.devices.get_mut::<Rtc>().unwrap();
+	}
+
+	/// Attach a disk, which appears to the guest as a virtio block device
+	/// at VIRTIO_BASE, interrupting through the PLIC on VIRTIO_IRQ. The
+	/// device tree has to describe it for the guest to find it.
+	pub fn attach_disk(
+		&mut self, backing: Box<dyn Backing>,
+	) -> Result<(), Box<dyn Error>>
+	{
+		let disk: Disk = VirtioBlock::new(backing)?;
+		self.devices.register(
+			"virtio-blk",
+			VIRTIO_BASE,
+			VIRTIO_SIZE,
+			Box::new(disk),
+		)?;
+
+		return Ok(());
+	}
+
+	pub fn disk(&self) -> Option<&Disk>
+	{
+		return self.devices.get::<Disk>();
+	}
+
+	pub fn disk_mut(&mut self) -> Option<&mut Disk>
+	{
+		return self.devices.get_mut::<Disk>();
+	}
+
+	/// Carry out any requests the guest has made of the disk, which reads
+	/// & writes guest memory directly, without going through the harts.
+	fn service_disk(&mut self)
+	{
+		let notified = self.disk().map_or(false, |disk| return disk.notified());
+		if !notified {
+			return;
+		}
+
+		let result = self.devices.with_device(|disk: &mut Disk, devices| {
+			return disk.process_requests(devices);
+		});
+		if let Some(Err(error)) = result {
+			self.diagnostics.push(format!("virtio-blk: {}", error));
+		}
+	}
+
+	pub fn reservation_stats(&self) -> ReservationStats
+	{
+		return self.reservation_stats;
+	}
+
+	pub fn load_dtb(
+		&mut self, dtb: Vec<u8>, load_address: usize,
+	) -> Result<(), PlatformError>
+	{
+		dtb::validate(&dtb).map_err(PlatformError::InvalidDtb)?;
+		self.load_file(dtb, load_address)?;
+
+		self.hart.registers[RegisterNames::a1 as usize] = load_address as u64;
+
+		return Ok(());
+	}
+
+	/// Describe the harts, memory & devices in a devicetree, for kernels
+	/// booted without one supplied. Only devices that are actually mapped
+	/// are included, at wherever they are mapped.
+	pub fn generate_dtb(&self) -> Vec<u8>
+	{
+		let hart_count = self.harts.len();
+		// Each hart's interrupt controller is phandle hart id + 1, with the
+		// PLIC after them
+		let intc = |hart: usize| return hart as u32 + 1;
+		let plic_phandle = hart_count as u32 + 1;
+		let reg = |start: usize, size: usize| {
+			let (start, size) = (start as u64, size as u64);
+			return [
+				(start >> 32) as u32,
+				start as u32,
+				(size >> 32) as u32,
+				size as u32,
+			];
+		};
+
+		let xlen = self.hart.xlen.bits();
+		let isa: String = EXTENSIONS
+			.iter()
+			.filter(|extension| return !matches!(extension, 'S' | 'U'))
+			.map(|extension| return extension.to_ascii_lowercase())
+			.collect();
+
+		let mut fdt = dtb::Builder::default();
+		fdt.begin_node("");
+		fdt.property_cells("#address-cells", &[2]);
+		fdt.property_cells("#size-cells", &[2]);
+		fdt.property_strings("compatible", &["thing"]);
+		fdt.property_strings("model", &["thing"]);
+
+		fdt.begin_node("cpus");
+		fdt.property_cells("#address-cells", &[1]);
+		fdt.property_cells("#size-cells", &[0]);
+		fdt.property_cells("timebase-frequency", &[TIMEBASE_FREQUENCY]);
+		for hart in 0..hart_count {
+			fdt.begin_node(&format!("cpu@{}", hart));
+			fdt.property_strings("device_type", &["cpu"]);
+			fdt.property_cells("reg", &[hart as u32]);
+			fdt.property_strings("status", &["okay"]);
+			fdt.property_strings("compatible", &["riscv"]);
+			fdt.property_strings("riscv,isa", &[&format!("rv{}{}", xlen, isa)]);
+			if xlen == 64 {
+				fdt.property_strings("mmu-type", &["riscv,sv39"]);
+			}
+
+			fdt.begin_node("interrupt-controller");
+			fdt.property_cells("#interrupt-cells", &[1]);
+			fdt.property("interrupt-controller", &[]);
+			fdt.property_strings("compatible", &["riscv,cpu-intc"]);
+			fdt.property_cells("phandle", &[intc(hart)]);
+			fdt.end_node();
+
+			fdt.end_node();
+		}
+		fdt.end_node();
+
+		let mut soc: Vec<(&str, usize, usize)> = Vec::new();
+		for (name, start, size) in self.devices.regions() {
+			if name == "memory" {
+				fdt.begin_node(&format!("memory@{:x}", start));
+				fdt.property_strings("device_type", &["memory"]);
+				fdt.property_cells("reg", &reg(start, size));
+				fdt.end_node();
+			} else {
+				soc.push((name, start, size));
+			}
+		}
+
+		fdt.begin_node("soc");
+		fdt.property_cells("#address-cells", &[2]);
+		fdt.property_cells("#size-cells", &[2]);
+		fdt.property_strings("compatible", &["simple-bus"]);
+		fdt.property("ranges", &[]);
+
+		let mut stdout_path: Option<String> = None;
+		for (name, start, size) in soc {
+			match name {
+				"clint" => {
+					fdt.begin_node(&format!("clint@{:x}", start));
+					fdt.property_strings(
+						"compatible",
+						&["sifive,clint0", "riscv,clint0"],
+					);
+					// Software & timer interrupts, for M-mode
+					let interrupts: Vec<u32> = (0..hart_count)
+						.flat_map(|hart| return [intc(hart), 3, intc(hart), 7])
+						.collect();
+					fdt.property_cells("interrupts-extended", &interrupts);
+				},
+				"plic" => {
+					fdt.begin_node(&format!("plic@{:x}", start));
+					fdt.property_strings(
+						"compatible",
+						&["sifive,plic-1.0.0", "riscv,plic0"],
+					);
+					fdt.property_cells("#interrupt-cells", &[1]);
+					fdt.property_cells("#address-cells", &[0]);
+					fdt.property("interrupt-controller", &[]);
+					fdt.property_cells("riscv,ndev", &[RTC_IRQ as u32]);
+					// Each hart has a single context, for M-mode external
+					// interrupts
+					let interrupts: Vec<u32> = (0..hart_count)
+						.flat_map(|hart| return [intc(hart), 11])
+						.collect();
+					fdt.property_cells("interrupts-extended", &interrupts);
+					fdt.property_cells("phandle", &[plic_phandle]);
+				},
+				"uart" => {
+					let node = format!("serial@{:x}", start);
+					stdout_path = Some(format!("/soc/{}", node));
+					fdt.begin_node(&node);
+					fdt.property_strings("compatible", &["ns16550a"]);
+					fdt.property_cells("clock-frequency", &[UART_CLOCK]);
+					fdt.property_cells("interrupt-parent", &[plic_phandle]);
+					fdt.property_cells("interrupts", &[UART_IRQ as u32]);
+				},
+				"rtc" => {
+					fdt.begin_node(&format!("rtc@{:x}", start));
+					fdt.property_strings(
+						"compatible",
+						&["google,goldfish-rtc"],
+					);
+					fdt.property_cells("interrupt-parent", &[plic_phandle]);
+					fdt.property_cells("interrupts", &[RTC_IRQ as u32]);
+				},
+				"virtio-blk" => {
+					fdt.begin_node(&format!("virtio_mmio@{:x}", start));
+					fdt.property_strings("compatible", &["virtio,mmio"]);
+					fdt.property_cells("interrupt-parent", &[plic_phandle]);
+					fdt.property_cells("interrupts", &[VIRTIO_IRQ as u32]);
+				},
+				// Nothing a kernel would know how to drive
+				_ => continue,
+			}
+			fdt.property_cells("reg", &reg(start, size));
+			fdt.end_node();
+		}
+		fdt.end_node();
+
+		fdt.begin_node("chosen");
+		if let Some(path) = stdout_path {
+			fdt.property_strings("stdout-path", &[&path]);
+		}
+		if let Some((start, end)) = self.initrd {
+			let (start, end) = (start as u64, end as u64);
+			fdt.property_cells(
+				"linux,initrd-start",
+				&[(start >> 32) as u32, start as u32],
+			);
+			fdt.property_cells(
+				"linux,initrd-end",
+				&[(end >> 32) as u32, end as u32],
+			);
+		}
+		fdt.end_node();
+
+		fdt.end_node();
+
+		return fdt.finish();
+	}
+
+	pub fn load_kernel(
+		&mut self, kernel: Vec<u8>, load_address: usize, entry_point: usize,
+	) -> Result<(), PlatformError>
+	{
+		self.hart.pc = entry_point as u64;
+		return self.load_file(kernel, load_address);
+	}
+
+	/// Load each loadable segment of an ELF executable at its physical
+	/// address, zeroing any bss, and enter it at its entry point.
+	/// Segments without execute permission fault if the hart fetches from
+	/// them. If it has a tohost symbol, it is taken to be a riscv-tests
+	/// style binary & the value written there is acted on.
+	pub fn load_elf(&mut self, blob: &[u8]) -> Result<(), PlatformError>
+	{
+		let elf = elf::parse(blob).map_err(PlatformError::InvalidElf)?;
+		self.tohost = elf.symbol("tohost");
+		self.fromhost = elf.symbol("fromhost");
+
+		for (index, segment) in elf.segments.into_iter().enumerate() {
+			let start = segment.physical_address;
+			// Check before making room for the bss, which could be anything
+			let end = self.check_load(start, segment.memory_size)?;
+			let mut data = segment.data;
+			data.resize(segment.memory_size, 0);
+			self.load_file(data, start)?;
+
+			if !segment.executable {
+				self.data_regions.push(DataRegion {
+					name: format!("elf segment {}", index),
+					start,
+					end,
+					reported: false,
+				});
+			}
+		}
+
+		self.hart.pc = elf.entry as u64;
+
+		return Ok(());
+	}
+
+	/// Load a position-independent blob, which starts executing from its
+	/// first byte wherever it is put. The address it was loaded at is
+	/// kept so that tooling can map addresses back into the blob.
+	pub fn load_relocatable(
+		&mut self, blob: Vec<u8>, load_address: usize,
+	) -> Result<(), PlatformError>
+	{
+		self.load_kernel(blob, load_address, load_address)?;
+		self.relocation_base = Some(load_address);
+
+		return Ok(());
+	}
+
+	/// Load an initial ramdisk, which the devicetree tells the kernel
+	/// about in its chosen node.
+	pub fn load_initrd(
+		&mut self, blob: Vec<u8>, load_address: usize,
+	) -> Result<(), PlatformError>
+	{
+		let end = self.check_load(load_address, blob.len())?;
+		let overlaps = |&(start, load_end): &(usize, usize)| {
+			return load_address < load_end && start < end;
+		};
+		if self.loads.iter().any(overlaps) {
+			return Err(PlatformError::InitrdOverlap {
+				address: load_address,
+				size: blob.len(),
+			});
+		}
+
+		self.copy_to_memory(&blob, load_address);
+		self.initrd = Some((load_address, end));
+
+		return Ok(());
+	}
+
+	/// Where the initrd was loaded, start & end, if there was one.
+	pub fn initrd(&self) -> Option<(usize, usize)>
+	{
+		return self.initrd;
+	}
+
+	/// Start the harts from a ROM at reset_address, as firmware like
+	/// OpenSBI expects, rather than going straight to entry. The ROM holds a
+	/// trampoline that jumps to entry with a0 holding the hart's id & a1
+	/// pointing at dtb.
+	pub fn install_reset_trampoline(
+		&mut self, reset_address: usize, entry: u64, dtb: u64,
+	) -> Result<(), PlatformError>
+	{
+		let code = match self.hart.xlen {
+			Xlen::Rv32 => TRAMPOLINE_RV32,
+			Xlen::Rv64 => TRAMPOLINE_RV64,
+		};
+		let mut contents: Vec<u8> =
+			code.iter().flat_map(|insn| return insn.to_le_bytes()).collect();
+		contents.resize(TRAMPOLINE_ENTRY_OFFSET, 0);
+		contents.extend_from_slice(&entry.to_le_bytes());
+		contents.resize(TRAMPOLINE_DTB_OFFSET, 0);
+		contents.extend_from_slice(&dtb.to_le_bytes());
+
+		let rom = Rom::new(contents, ROM_SIZE);
+		self.devices.register("rom", reset_address, ROM_SIZE, Box::new(rom))?;
+		self.hart.pc = reset_address as u64;
+
+		return Ok(());
+	}
+
+	/// Where the last relocatable blob was loaded, if there was one.
+	pub fn relocation_base(&self) -> Option<usize>
+	{
+		return self.relocation_base;
+	}
+
+	/// Write a line to output for every instruction retired, in the same
+	/// format as spike's commit log, less the register writes.
+	pub fn set_trace(&mut self, output: Box<dyn Write>)
+	{
+		self.trace = Some(output);
+	}
+
+	/// Call hook with every instruction retired, for profiling & coverage.
+	/// Steps that only take a trap don't retire anything, so don't call it.
+	pub fn set_insn_hook(&mut self, hook: InsnHook)
+	{
+		self.insn_hook = Some(hook);
+	}
+
+	/// Charge every memory access the latency given by model, from now on.
+	pub fn set_latency_model(&mut self, model: Box<dyn LatencyModel>)
+	{
+		*self.latency_model.get_mut() = Some(model);
+	}
+
+	/// The total latency of all memory accesses since a model was set.
+	pub fn stall_cycles(&self) -> u64
+	{
+		return self.stall_cycles.get();
+	}
+
+	fn account_access(&self, address: usize, size: usize, kind: AccessKind)
+	{
+		if let Some(model) = self.latency_model.borrow_mut().as_mut() {
+			let latency = model.latency(address, size, kind);
+			self.stall_cycles.set(self.stall_cycles.get() + latency);
+		}
+	}
+
+	/// Problems noticed while running that are likely bugs in the guest,
+	/// but which it is architecturally allowed to recover from.
+	pub fn diagnostics(&self) -> &[String]
+	{
+		return &self.diagnostics;
+	}
+
+	/// Translate an address the current hart is accessing into a physical
+	/// one. Fetches are made at the current privilege, loads & stores at
+	/// the one mstatus.MPRV says they should be.
+	pub fn translate(&self, address: u64, access: Access) -> Result<u64, Trap>
+	{
+		let privilege = match access {
+			Access::Fetch => self.hart.privilege,
+			Access::Load | Access::Store => self.hart.data_privilege(),
+		};
+
+		return mmu::translate(
+			self,
+			self.hart.read_csr(CSR_SATP),
+			address,
+			access,
+			privilege,
+			self.hart.read_csr(CSR_MSTATUS),
+		);
+	}
+
+	/// Check whether pc may be fetched from, which it may not if it is
+	/// inside a region loaded as data. Executing data is almost always the
+	/// result of a wild jump, so say where it went, the first time it goes
+	/// to each region. A guest whose trap handler returns to the fault
+	/// would otherwise add to the diagnostics every step.
+	fn fetch_permitted(&mut self, pc: usize) -> bool
+	{
+		let Some(region) = self
+			.data_regions
+			.iter_mut()
+			.find(|region| return (region.start..region.end).contains(&pc))
+		else {
+			return true;
+		};
+
+		if !region.reported {
+			region.reported = true;
+			self.diagnostics.push(format!(
+				"pc {:#x} executing from data region {} ({:#x}-{:#x})",
+				pc, region.name, region.start, region.end
+			));
+		}
+
+		return false;
+	}
+
+	/// Check that size bytes at load_address fit in memory, returning
+	/// where they end.
+	fn check_load(
+		&self, load_address: usize, size: usize,
+	) -> Result<usize, PlatformError>
+	{
+		let memory = self.memory();
+
+		if !(memory.start..memory.end).contains(&load_address) {
+			return Err(PlatformError::LoadAddressOutOfBounds {
+				address: load_address,
+			});
+		}
+
+		let end = load_address.checked_add(size);
+		if end.map_or(true, |end| return end > memory.end) {
+			return Err(PlatformError::InsufficientMemory {
+				address: load_address,
+				size,
+			});
+		}
+
+		return Ok(end.unwrap());
+	}
+
+	fn load_file(
+		&mut self, blob: Vec<u8>, load_address: usize,
+	) -> Result<(), PlatformError>
+	{
+		let end = self.check_load(load_address, blob.len())?;
+		if let Some((start, initrd_end)) = self.initrd {
+			if load_address < initrd_end && start < end {
+				return Err(PlatformError::InitrdOverlap {
+					address: load_address,
+					size: blob.len(),
+				});
+			}
+		}
+
+		self.copy_to_memory(&blob, load_address);
+		self.loads.push((load_address, end));
+
+		return Ok(());
+	}
+
+	/// Copy blob into memory at load_address, which check_load has passed.
+	fn copy_to_memory(&mut self, blob: &[u8], load_address: usize)
+	{
+		let memory = self.memory_mut();
+		let memory_load_offset = load_address - memory.start;
+		let memory_load_end = memory_load_offset + blob.len();
+		memory.memory[memory_load_offset..memory_load_end]
+			.copy_from_slice(blob);
+		self.invalidate_decode_caches();
+	}
+
+	pub fn emulate(&mut self) -> Result<HaltReason, PlatformError>
+	{
+		loop {
+			if let Some(reason) = self.step()? {
+				return Ok(reason);
+			}
+		}
+	}
+
+	/// Step until pc arrives at target, giving up with InstructionLimit
+	/// after max_insns steps. Breakpoints, watchpoints & break_on_cause
+	/// stop it early just as they do emulate(). pc already being at target
+	/// doesn't count, so this can be called again to go round a loop.
+	pub fn run_until(
+		&mut self, target: u64, max_insns: u64,
+	) -> Result<HaltReason, PlatformError>
+	{
+		for _ in 0..max_insns {
+			if let Some(reason) = self.step()? {
+				return Ok(reason);
+			}
+
+			if self.hart.pc == target {
+				return Ok(HaltReason::Reached(target));
+			}
+		}
+
+		return Ok(HaltReason::InstructionLimit);
+	}
+
+	/// Fetch, decode and execute a single instruction.
+	/// Instructions are decoded once & then run from the hart's decode
+	/// cache, so a hart only observes stores to code after a FENCE.I.
+	/// Interrupts are checked before the fetch, so taking one does not
+	/// retire an instruction.
+	/// Nothing is left half done on return, so stopping between steps
+	/// always leaves the hart in a coherent state.
+	/// Every step takes one cycle, whether or not it retires anything.
+	/// Running harts take it in turns to step.
+	pub fn step(&mut self) -> Result<Option<HaltReason>, PlatformError>
+	{
+		self.retired = false;
+		self.switch_hart(self.next_hart());
+
+		let at = (self.hart.id, self.hart.pc);
+		if self.resume_breakpoint == Some(at) {
+			self.resume_breakpoint = None;
+		} else if self.breakpoints.contains(&self.hart.pc) {
+			self.resume_breakpoint = Some(at);
+			return Ok(Some(HaltReason::Breakpoint(self.hart.pc)));
+		}
+
+		self.hart.mcycle_written = false;
+		let result = self.execute();
+
+		// An instruction writing mcycle sets what the next one reads, so
+		// don't count on top of the write
+		if !self.hart.mcycle_written {
+			let cycle = self.hart.read_csr(CSR_MCYCLE);
+			self.hart.write_csr(CSR_MCYCLE, cycle.wrapping_add(1));
+		}
+
+		let instret = self.hart.read_csr(CSR_MINSTRET);
+		let limited = self.max_insns.map_or(false, |max| return instret >= max);
+		if limited && matches!(result, Ok(None)) {
+			return Err(PlatformError::InsnLimitReached {
+				pc: self.hart.pc,
+				instret,
+			});
+		}
+
+		let streak = self.trap_streaks[self.hart.id];
+		if streak.count >= TRAP_LOOP_LIMIT && matches!(result, Ok(None)) {
+			return Err(PlatformError::TrapLoop {
+				cause: streak.cause,
+				epc: streak.epc,
+			});
+		}
+
+		let hit = self.watchpoint_hit.take();
+		if let (Ok(None), Some(address)) = (&result, hit) {
+			return Ok(Some(HaltReason::Watchpoint(address)));
+		}
+
+		return result;
+	}
+
+	/// Whether the last step retired an instruction, rather than taking a
+	/// trap or stopping before it.
+	pub fn retired(&self) -> bool
+	{
+		return self.retired;
+	}
+
+	/// Stop before executing the instruction at pc, whichever hart gets
+	/// there.
+	pub fn add_breakpoint(&mut self, pc: u64)
+	{
+		self.breakpoints.insert(pc);
+	}
+
+	pub fn remove_breakpoint(&mut self, pc: u64) -> bool
+	{
+		return self.breakpoints.remove(&pc);
+	}
+
+	/// Stop after an instruction loads or stores, depending on kind, any of
+	/// the length bytes from the virtual address address.
+	pub fn add_watchpoint(&mut self, address: u64, length: u64, kind: WatchKind)
+	{
+		self.watchpoints.push(Watchpoint {
+			address,
+			length,
+			kind,
+		});
+	}
+
+	pub fn remove_watchpoint(&mut self, address: u64) -> bool
+	{
+		let count = self.watchpoints.len();
+		self.watchpoints
+			.retain(|watchpoint| return watchpoint.address != address);
+
+		return self.watchpoints.len() != count;
+	}
+
+	/// Note an access by the current hart, for step() to stop after if it
+	/// hits a watchpoint. Instructions that trap make no access, so taking
+	/// a trap forgets it.
+	pub fn check_watchpoints(&self, address: u64, size: u64, kind: WatchKind)
+	{
+		let hit = self.watchpoints.iter().any(|watchpoint| {
+			return watchpoint.matches(address, size, kind);
+		});
+		if hit {
+			self.watchpoint_hit.set(Some(address));
+		}
+	}
+
+	pub fn hart_count(&self) -> usize
+	{
+		return self.harts.len();
+	}
+
+	/// Look up a hart by id, wherever it is.
+	pub fn hart_by_id(&self, id: usize) -> &Hart
+	{
+		if id == self.hart.id {
+			return &self.hart;
+		}
+
+		return &self.harts[id];
+	}
+
+	pub fn hart_by_id_mut(&mut self, id: usize) -> &mut Hart
+	{
+		if id == self.hart.id {
+			return &mut self.hart;
+		}
+
+		return &mut self.harts[id];
+	}
+
+	/// Start a stopped hart at start_address in S-mode, with its id in a0 &
+	/// opaque in a1, as the SBI HSM extension's hart_start does.
+	pub fn start_hart(&mut self, id: usize, start_address: u64, opaque: u64)
+	{
+		let hart = self.hart_by_id_mut(id);
+
+		hart.pc = start_address;
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.write_register(RegisterNames::a0 as usize, id as u64);
+		hart.write_register(RegisterNames::a1 as usize, opaque);
+		hart.state = HartState::Started;
+	}
+
+	/// FENCE.I: make the current hart's instruction fetches see every
+	/// store made before it.
+	pub fn flush_decode_cache(&mut self)
+	{
+		self.decode_caches[self.hart.id].clear();
+	}
+
+	/// Drop every hart's decoded instructions, for when memory has been
+	/// changed from outside the guest, which has no reason to FENCE.I.
+	pub fn invalidate_decode_caches(&mut self)
+	{
+		for cache in &mut self.decode_caches {
+			cache.clear();
+		}
+	}
+
+	/// Set the current hart's timer for S-mode, as the SBI's set_timer
+	/// does. There's no firmware to pass the machine timer interrupt on
+	/// when it fires, so STIP is raised along with MTIP, & stays pending
+	/// until the next deadline is set.
+	pub fn set_supervisor_timer(&mut self, deadline: u64)
+	{
+		let hart_id = self.hart.id;
+		self.clint_mut().mtimecmp[hart_id] = deadline;
+		self.sbi_timers[hart_id] = true;
+		self.hart.set_pending(MIP_STIP, false);
+	}
+
+	/// WFI: let time pass until an interrupt the current hart has enabled
+	/// in mie is pending. With only one hart running, nothing but the timer
+	/// can become pending without outside help, so skip mtime forward to
+	/// the hart's mtimecmp. Otherwise WFI is a nop, which it is allowed to
+	/// be, & the guest's idle loop does the waiting.
+	pub fn wait_for_interrupt(&mut self)
+	{
+		let enabled = self.hart.read_csr(CSR_MIE);
+		if self.hart.read_csr(CSR_MIP) & enabled != 0 {
+			return;
+		}
+
+		let mut timer = MIP_MTIP;
+		if self.sbi_timers[self.hart.id] {
+			timer |= MIP_STIP;
+		}
+
+		let running = (0..self.harts.len())
+			.filter(|id| {
+				return self.hart_by_id(*id).state == HartState::Started;
+			})
+			.count();
+		if enabled & timer == 0 || running != 1 {
+			return;
+		}
+
+		// An mtimecmp of u64::MAX is how software turns the timer off
+		let deadline = self.clint().mtimecmp[self.hart.id];
+		if deadline != u64::MAX && deadline > self.clint().mtime {
+			self.clint_mut().mtime = deadline;
+		}
+	}
+
+	/// The next running hart after the current one, or the current one if
+	/// nothing else is running.
+	fn next_hart(&self) -> usize
+	{
+		let count = self.harts.len();
+
+		for offset in 1..=count {
+			let id = (self.hart.id + offset) % count;
+			if self.hart_by_id(id).state == HartState::Started {
+				return id;
+			}
+		}
+
+		return self.hart.id;
+	}
+
+	fn switch_hart(&mut self, id: usize)
+	{
+		if id == self.hart.id {
+			return;
+		}
+
+		let current = self.hart.id;
+		std::mem::swap(&mut self.hart, &mut self.harts[current]);
+		std::mem::swap(&mut self.hart, &mut self.harts[id]);
+	}
+
+	/// Reading a device's registers can have side effects, like consuming
+	/// the UART's received data, so only memory & ROM are executable.
+	fn executable(&self, physical_address: usize) -> bool
+	{
+		return self.devices.device_at(physical_address).map_or(
+			false,
+			|device| {
+				let device = device.as_any();
+				return device.is::<Memory>() || device.is::<Rom>();
+			},
+		);
+	}
+
+	/// Fetch & decode the instruction at physical_pc.
+	fn fetch(&self, physical_pc: usize) -> Result<Insn, Trap>
+	{
+		let fault = Trap {
+			cause: CAUSE_INSN_ACCESS_FAULT,
+			tval: self.hart.pc,
+		};
+
+		if !self.executable(physical_pc) {
+			return Err(fault);
+		}
+
+		// Fetch a halfword first, the second is only part of the
+		// instruction if the first isn't a compressed one.
+		let halfword: u16 =
+			self.devices.read(physical_pc).map_err(|_| return fault)?;
+
+		if halfword & INSN_LENGTH_MASK != INSN_LENGTH_32 {
+			return Insn::from_compressed(halfword, self.hart.xlen);
+		}
+
+		// The second halfword can be on the next page, which needs a
+		// translation of its own. Faults on it are reported at its
+		// address, not the instruction's.
+		let upper_pc = self.hart.pc.wrapping_add(2);
+		let physical_upper = if upper_pc % PAGE_SIZE == 0 {
+			self.translate(upper_pc, Access::Fetch)? as usize
+		} else {
+			physical_pc.wrapping_add(2)
+		};
+		let fault = Trap {
+			cause: CAUSE_INSN_ACCESS_FAULT,
+			tval: upper_pc,
+		};
+
+		if !self.executable(physical_upper) {
+			return Err(fault);
+		}
+
+		let upper: u16 =
+			self.devices.read(physical_upper).map_err(|_| return fault)?;
+
+		return Ok(Insn::from((upper as u32) << 16 | halfword as u32));
+	}
+
+	fn execute(&mut self) -> Result<Option<HaltReason>, PlatformError>
+	{
+		let hart_id = self.hart.id;
+		let mtime = self.clint().mtime;
+		self.hart.write_csr(CSR_TIME, mtime);
+		let timer_pending = self.clint().timer_pending(hart_id);
+		self.hart.set_pending(MIP_MTIP, timer_pending);
+		if timer_pending && self.sbi_timers[hart_id] {
+			self.hart.set_pending(MIP_STIP, true);
+		}
+		let software_pending = self.clint().software_pending(hart_id);
+		self.hart.set_pending(MIP_MSIP, software_pending);
+		let uart_pending = self.uart().interrupt_pending();
+		self.plic_mut().set_level(UART_IRQ, uart_pending);
+		self.rtc_mut().poll();
+		let rtc_pending = self.rtc().interrupt_pending();
+		self.plic_mut().set_level(RTC_IRQ, rtc_pending);
+		let disk_pending =
+			self.disk().map_or(false, |disk| return disk.interrupt_pending());
+		self.plic_mut().set_level(VIRTIO_IRQ, disk_pending);
+		let external_pending = self.plic().interrupt_pending(hart_id);
+		self.hart.set_pending(MIP_MEIP, external_pending);
+
+		if let Some(cause) = self.hart.pending_interrupt() {
+			return Ok(self.trap(cause, 0));
+		}
+
+		let physical_pc = match self.translate(self.hart.pc, Access::Fetch) {
+			Ok(physical_pc) => physical_pc as usize,
+			Err(trap) => return Ok(self.trap(trap.cause, trap.tval)),
+		};
+
+		if !self.fetch_permitted(physical_pc) {
+			return Ok(self.trap(CAUSE_INSN_ACCESS_FAULT, self.hart.pc));
+		}
+
+		let cache = &mut self.decode_caches[hart_id];
+		let mut insn = match cache.get(&physical_pc) {
+			Some(insn) => insn.clone(),
+			None => {
+				match self.fetch(physical_pc) {
+					Ok(insn) => {
+						// The second page of one that straddles
+						// two may be remapped, so don't cache it
+						let offset = physical_pc as u64 % PAGE_SIZE;
+						if insn.length == 2 || offset != PAGE_SIZE - 2 {
+							let cache = &mut self.decode_caches[hart_id];
+							cache.insert(physical_pc, insn.clone());
+						}
+						insn
+					},
+					Err(trap) => return Ok(self.trap(trap.cause, trap.tval)),
+				}
+			},
+		};
+
+		// misa.C can be cleared after a compressed instruction is cached
+		let compressed = insn.length == 2;
+		if compressed && self.hart.read_csr(CSR_MISA) & MISA_C == 0 {
+			return Ok(self.trap(CAUSE_ILLEGAL_INSN, insn.bits as u64));
+		}
+
+		let pc = self.hart.pc;
+		self.hart.minstret_written = false;
+		if let Err(trap) = insn.handle(self) {
+			return Ok(self.trap(trap.cause, trap.tval));
+		}
+
+		// As with mcycle, a write to minstret takes the place of the count
+		if !self.hart.minstret_written {
+			let instret = self.hart.read_csr(CSR_MINSTRET);
+			self.hart.write_csr(CSR_MINSTRET, instret.wrapping_add(1));
+		}
+		self.retired = true;
+		self.trap_streaks[hart_id].count = 0;
+
+		if let Some(hook) = &mut self.insn_hook {
+			hook(&insn, &self.hart);
+		}
+
+		if self.tohost_written {
+			self.tohost_written = false;
+			if let Some(reason) = self.handle_tohost()? {
+				return Ok(Some(reason));
+			}
+		}
+
+		if let Some(trace) = &mut self.trace {
+			writeln!(
+				trace,
+				"core {:>3}: {:#018x} ({:#010x}) {}",
+				hart_id,
+				pc,
+				insn.bits,
+				format!("{:<7} {}", insn.mnemonic, insn.operands()).trim_end()
+			)?;
+		}
+
+		self.clint_mut().tick();
+		self.uart_mut().poll();
+		self.service_disk();
+
+		return Ok(None);
+	}
+
+	/// Act on a value written to tohost. The accesses here go straight to
+	/// the devices, so as not to be mistaken for the guest's.
+	fn handle_tohost(&mut self) -> Result<Option<HaltReason>, PlatformError>
+	{
+		let tohost = self.tohost.unwrap();
+		let value: u64 = self.devices.read(tohost)?;
+		// Writing tohost back to zero is the guest's own business
+		if value == 0 {
+			return Ok(None);
+		}
+
+		let device = value >> HTIF_DEVICE_SHIFT;
+		let command = (value >> HTIF_COMMAND_SHIFT) & 0xff;
+		let payload = value & HTIF_PAYLOAD_MASK;
+
+		match (device, command) {
+			(HTIF_DEVICE_SYSCALL, _) if payload & 1 == 1 => {
+				let test = payload >> 1;
+				if test == 0 {
+					return Ok(Some(HaltReason::TestPassed));
+				}
+				return Err(PlatformError::Halt {
+					code: test,
+				});
+			},
+			(HTIF_DEVICE_CONSOLE, HTIF_CONSOLE_PUTCHAR) => {
+				self.uart_mut().console_write(payload as u8);
+			},
+			_ => {
+				self.diagnostics
+					.push(format!("unsupported tohost value {:#x}", value));
+			},
+		}
+
+		// Acknowledge the request, so the guest can make another
+		self.devices.write(tohost, 0_u64)?;
+		if let Some(fromhost) = self.fromhost {
+			let ack =
+				device << HTIF_DEVICE_SHIFT | command << HTIF_COMMAND_SHIFT | 1;
+			self.devices.write(fromhost, ack)?;
+		}
+
+		return Ok(None);
+	}
+
+	fn trap(&mut self, cause: u64, tval: u64) -> Option<HaltReason>
+	{
+		self.watchpoint_hit.set(None);
+
+		if self.break_on_cause == Some(cause) {
+			let hart = &self.hart;
+			self.trap_break_csrs =
+				Some(TRAP_CSRS.map(|csr| return hart.read_csr(csr)));
+			self.hart.record_trap(cause, tval);
+			return Some(HaltReason::TrapBreak);
+		}
+
+		// An LR/SC sequence must not straddle a trap handler, as the
+		// handler may itself use LR/SC or touch the reserved address
+		// without the SC being able to tell.
+		self.reservation_sets[self.hart.id].valid = false;
+
+		let streak = &mut self.trap_streaks[self.hart.id];
+		streak.count += 1;
+		streak.cause = cause;
+		streak.epc = self.hart.pc;
+
+		self.hart.take_trap(cause, tval);
+		return None;
+	}
+
+	/// Put back the mepc, mcause & mtval that the last TrapBreak wrote
+	/// over, for when its trap is skipped rather than taken.
+	pub fn dismiss_trap_break(&mut self)
+	{
+		let Some(values) = self.trap_break_csrs.take() else {
+			return;
+		};
+
+		for (csr, value) in TRAP_CSRS.into_iter().zip(values) {
+			self.hart.write_csr(csr, value);
+		}
+	}
+
+	/// Claim a reservation set for this hart, replacing any existing one.
+	pub fn claim_reservation_set<T>(
+		&mut self, hart_id: usize, address: T, size: usize,
+	) where
+		T: Into<usize>,
+	{
+		let address = usize::try_from(address).unwrap();
+		let reservation_set = &mut self.reservation_sets[hart_id];
+
+		self.reservation_stats.lr += 1;
+		if reservation_set.valid {
+			self.reservation_stats.invalidated_by_self += 1;
+		}
+
+		reservation_set.address = address;
+		reservation_set.size = size;
+		reservation_set.valid = true;
+	}
+
+	/// Invalidates reservations taken by other harts that overlap with a
+	/// store from this hart.
+	pub fn invalidate_reservation_sets<T>(
+		&mut self, hart_id: usize, address: T, size: usize,
+	) where
+		T: Into<usize>,
+	{
+		let address = usize::try_from(address).unwrap();
+
+		for reservation_set in self.reservation_sets.iter_mut() {
+			if reservation_set.hart_id == hart_id {
+				continue;
+			}
+
+			if !reservation_set.valid {
+				continue;
+			}
+
+			if reservation_set.overlaps(address, size) {
+				reservation_set.valid = false;
+				self.reservation_stats.invalidated_by_other += 1;
+			}
+		}
+	}
+
+	/// Check if a reservation for this hart is still valid, and if it is,
+	/// invalidate it.
+	pub fn check_invalidate_reservation_set<T>(
+		&mut self, hart_id: usize, address: T, size: usize,
+	) -> bool
+	where
+		T: Into<usize>,
+	{
+		let address = usize::try_from(address).unwrap();
+		let reservation_set = &mut self.reservation_sets[hart_id];
+		if !reservation_set.valid || !reservation_set.overlaps(address, size) {
+			self.reservation_stats.sc_failure += 1;
+			return false;
+		}
+
+		reservation_set.valid = false;
+		self.reservation_stats.sc_success += 1;
+		return true;
+	}
+
+	/// Load a T from a virtual address on behalf of the current hart.
+	pub fn load<T>(&self, address: u64) -> Result<T, Trap>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let fault = |_| return load_access_fault(address);
+		self.check_watchpoints(address, T::SIZE as u64, WatchKind::Read);
+
+		if address % T::SIZE as u64 == 0 {
+			let physical = self.translate(address, Access::Load)? as usize;
+			return self.read::<T>(physical).map_err(fault);
+		}
+
+		if self.misaligned_accesses == MisalignedAccesses::Trap {
+			return Err(Trap {
+				cause: CAUSE_LOAD_MISALIGNED,
+				tval: address,
+			});
+		}
+
+		let mut bytes = [0; <T as LeBytes>::SIZE];
+		for (offset, byte) in bytes.iter_mut().enumerate() {
+			let address =
+				self.hart.zero_extend(address.wrapping_add(offset as u64));
+			let physical = self.translate(address, Access::Load)? as usize;
+			*byte = self.read::<u8>(physical).map_err(fault)?;
+		}
+
+		return Ok(T::from_le_bytes(bytes));
+	}
+
+	/// Store a T to a virtual address on behalf of the current hart.
+	/// Misaligned stores are only made once every byte has been
+	/// translated, so one that faults part way through changes nothing.
+	pub fn store<T>(&mut self, address: u64, value: T) -> Result<(), Trap>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let hart_id = self.hart.id;
+		let fault = |_| return store_access_fault(address);
+		self.check_watchpoints(address, T::SIZE as u64, WatchKind::Write);
+
+		if address % T::SIZE as u64 == 0 {
+			let physical = self.translate(address, Access::Store)? as usize;
+			return self
+				.write_from_hart(hart_id, physical, value)
+				.map_err(fault);
+		}
+
+		if self.misaligned_accesses == MisalignedAccesses::Trap {
+			return Err(Trap {
+				cause: CAUSE_STORE_MISALIGNED,
+				tval: address,
+			});
+		}
+
+		let mut physical = [0; <T as LeBytes>::SIZE];
+		for (offset, byte) in physical.iter_mut().enumerate() {
+			let address =
+				self.hart.zero_extend(address.wrapping_add(offset as u64));
+			*byte = self.translate(address, Access::Store)? as usize;
+		}
+
+		for (physical, byte) in physical.iter().zip(value.to_le_bytes()) {
+			self.write_from_hart::<u8>(hart_id, *physical, byte)
+				.map_err(fault)?;
+		}
+
+		return Ok(());
+	}
+
+	pub fn write_from_hart<T>(
+		&mut self, hart_id: usize, address: usize, value: T,
+	) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		self.invalidate_reservation_sets(
+			hart_id,
+			address,
+			<T as LeBytes>::SIZE,
+		);
+
+		return self.write(address, value);
+	}
+}
+
+impl Bus for Platform
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		self.account_access(address, T::SIZE, AccessKind::Read);
+		return self.devices.read(address);
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address: usize = address.into();
+		self.account_access(address, T::SIZE, AccessKind::Write);
+
+		if let Some(tohost) = self.tohost {
+			// Accesses off the top of the address space fault on the bus
+			let end = address.saturating_add(T::SIZE);
+			if address < tohost.saturating_add(8) && tohost < end {
+				self.tohost_written = true;
+			}
+		}
+
+		return self.devices.write(address, value);
+	}
+}
+
+pub const MEMORY_BASE: usize = 0x8000_0000;
+pub const MEMORY_SIZE: usize = 0x1000_0000;
+
+// Where QEMU's virt machine has its reset vector, in a small ROM
+pub const RESET_ADDRESS: usize = 0x1000;
+const ROM_SIZE: usize = 0x1000;
+
+// The reset trampoline puts the hart id in a0 & the dtb's address in a1,
+// then jumps to the entry point. Both addresses are stored after the code.
+const TRAMPOLINE_ENTRY_OFFSET: usize = 0x18;
+const TRAMPOLINE_DTB_OFFSET: usize = 0x20;
+const TRAMPOLINE_RV64: [u32; 5] = [
+	0x0000_0297, // auipc t0, 0
+	0xf140_2573, // csrr a0, mhartid
+	0x0202_b583, // ld a1, 32(t0)
+	0x0182_b283, // ld t0, 24(t0)
+	0x0002_8067, // jr t0
+];
+const TRAMPOLINE_RV32: [u32; 5] = [
+	0x0000_0297, // auipc t0, 0
+	0xf140_2573, // csrr a0, mhartid
+	0x0202_a583, // lw a1, 32(t0)
+	0x0182_a283, // lw t0, 24(t0)
+	0x0002_8067, // jr t0
+];
+
+fn heap_allocate_memory(size: usize) -> Box<[u8]>
+{
+	let memory: Box<[u8]> = vec![0u8; size].into_boxed_slice();
+	return memory;
+}
+
+pub struct Memory
+{
+	start: usize,
+	end: usize,
+	memory: Box<[u8]>,
+}
+
+impl Memory
+{
+	pub fn new(base: usize, size: usize) -> Memory
+	{
+		return Memory {
+			start: base,
+			end: base + size,
+			memory: heap_allocate_memory(size),
+		};
+	}
+
+	pub fn size(&self) -> usize
+	{
+		return self.end - self.start;
+	}
+}
+
+impl Bus for Memory
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let Some(bytes) = self
+			.memory
+			.get(address..address.saturating_add(<T as LeBytes>::SIZE))
+		else {
+			return Err(out_of_bounds(address, <T as LeBytes>::SIZE));
+		};
+
+		return Ok(T::from_le_bytes(bytes.try_into().unwrap()));
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address = address.into();
+		let tmp: [u8; <T as LeBytes>::SIZE] = value.to_le_bytes();
+		let Some(bytes) = self
+			.memory
+			.get_mut(address..address.saturating_add(<T as LeBytes>::SIZE))
+		else {
+			return Err(out_of_bounds(address, <T as LeBytes>::SIZE));
+		};
+
+		bytes.copy_from_slice(&tmp[..<T as LeBytes>::SIZE]);
+
+		return Ok(());
+	}
+}
+
+/// Read-only memory, holding code that runs before anything is loaded into
+/// RAM, like the reset trampoline.
+pub struct Rom
+{
+	contents: Vec<u8>,
+}
+
+impl Rom
+{
+	/// A ROM of size bytes, starting with contents & zero after them.
+	pub fn new(mut contents: Vec<u8>, size: usize) -> Rom
+	{
+		contents.resize(size, 0);
+		return Rom {
+			contents,
+		};
+	}
+}
+
+impl Bus for Rom
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let Some(bytes) = self
+			.contents
+			.get(address..address.saturating_add(<T as LeBytes>::SIZE))
+		else {
+			return Err(out_of_bounds(address, <T as LeBytes>::SIZE));
+		};
+
+		return Ok(T::from_le_bytes(bytes.try_into().unwrap()));
+	}
+
+	fn write<T, U>(&mut self, address: U, _value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		return Err(bus::Error::new(
+			bus::ErrorKind::OutOfBounds,
+			&format!("write to rom offset: {:x}", address.into()),
+		));
+	}
+}
+
+fn out_of_bounds(offset: usize, size: usize) -> bus::Error
+{
+	return bus::Error::new(
+		bus::ErrorKind::OutOfBounds,
+		&format!("memory offset: {:x} size: {}", offset, size),
+	);
+}
+
+#[cfg(test)]
+pub mod test
+{
+	use crate::hart::{
+		RegisterNames, CAUSE_ILLEGAL_INSN, CAUSE_INSN_ACCESS_FAULT,
+		CAUSE_INSN_MISALIGNED, CAUSE_LOAD_ACCESS_FAULT, CAUSE_LOAD_PAGE_FAULT,
+		CAUSE_MACHINE_SOFTWARE, CAUSE_MACHINE_TIMER, CAUSE_STORE_ACCESS_FAULT,
+		CAUSE_STORE_PAGE_FAULT, CSR_MCAUSE, CSR_MCYCLE, CSR_MEPC, CSR_MHARTID,
+		CSR_MIE, CSR_MINSTRET, CSR_MIP, CSR_MISA, CSR_MSCRATCH, CSR_MSTATUS,
+		CSR_MTVAL, CSR_MTVEC, CSR_SATP, CSR_SSCRATCH, MIP_MSIP, MIP_MTIP,
+		MISA_C, MSTATUS_MIE, MSTATUS_MPP_SHIFT, MSTATUS_MPRV, PRIV_SUPERVISOR,
+	};
+	use crate::hart::{
+		CAUSE_ECALL_UMODE, CSR_MEDELEG, CSR_SCAUSE, CSR_SEPC, CSR_SSTATUS,
+		CSR_STVEC, MSTATUS_MPP_MASK, MSTATUS_SPP, PRIV_MACHINE, PRIV_USER,
+	};
+	use crate::hart::{
+		CAUSE_INSN_PAGE_FAULT, CAUSE_MACHINE_EXTERNAL, MIP_MEIP,
+	};
+	use crate::hart::{
+		CAUSE_SUPERVISOR_SOFTWARE, CAUSE_SUPERVISOR_TIMER, CSR_MIDELEG,
+		MIP_SSIP, MIP_STIP, MSTATUS_SIE,
+	};
+	use crate::hart::{
+		COUNTEREN_CY, COUNTEREN_IR, COUNTEREN_TM, CSR_MCOUNTEREN,
+		CSR_SCOUNTEREN,
+	};
+	use crate::mmu::{
+		PTE_A, PTE_D, PTE_R, PTE_U, PTE_V, PTE_W, PTE_X, SATP_MODE_SHIFT,
+		SATP_MODE_SV39,
+	};
+	use crate::platform::{
+		AccessKind, FixedLatency, HaltReason, LatencyModel, Platform,
+		PlatformError, ReservationSet, WatchKind, MEMORY_BASE, MEMORY_SIZE,
+		RESET_ADDRESS,
+	};
+	use crate::plic::{PLIC_BASE, UART_IRQ};
+	use crate::snapshot::Snapshot;
+
+	use super::{heap_allocate_memory, Memory};
+	use crate::bus::Bus;
+	use crate::clint::CLINT_BASE;
+	use crate::dtb::test::minimal_fdt;
+	use crate::elf::test::{
+		elf_with_segments, elf_with_symbols, minimal_elf, PF_RW, PF_RWX,
+	};
+	use crate::uart::{Uart, UART_BASE};
+	use std::cell::{Cell, RefCell};
+	use std::io::Write;
+	use std::rc::Rc;
+
+	/// A writer that can be handed to a device while the test keeps a
+	/// handle to inspect what was written.
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedBuffer
+	{
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+		{
+			return self.0.borrow_mut().write(buf);
+		}
+
+		fn flush(&mut self) -> std::io::Result<()>
+		{
+			return Ok(());
+		}
+	}
+
+	/// Load program at the start of memory & point the hart at it.
+	pub fn load_program(platform: &mut Platform, program: &[u32])
+	{
+		let blob: Vec<u8> =
+			program.iter().flat_map(|insn| return insn.to_le_bytes()).collect();
+		platform.load_kernel(blob, MEMORY_BASE, MEMORY_BASE).unwrap();
+	}
+
+	#[test]
+	fn can_heap_alloc()
+	{
+		let memory = heap_allocate_memory(MEMORY_SIZE);
 		assert_eq!(memory.len(), MEMORY_SIZE);
 	}
+
+	#[test]
+	fn hart_fetches_its_own_stores_after_fence_i()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0000_0297, // auipc t0, 0
+			0x0142_a303, // lw t1, 20(t0)
+			0x0062_a823, // sw t1, 16(t0)
+			0x0000_100f, // fence.i
+			0x0010_0513, // li a0, 1
+			0x0020_0513, // li a0, 2
+		];
+
+		load_program(&mut platform, &program);
+		// Run the instruction that's about to be replaced first, so
+		// that a stale copy of it would be around to be run again
+		platform.hart.pc = MEMORY_BASE as u64 + 16;
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_register(RegisterNames::a0 as usize), 1);
+
+		platform.hart.pc = MEMORY_BASE as u64;
+		for _ in 0..5 {
+			platform.step().unwrap();
+		}
+
+		let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+		assert_eq!(a0, 2);
+	}
+
+	#[test]
+	fn fence_i_only_flushes_the_executing_harts_cache()
+	{
+		let mut platform = Platform::new(MEMORY_BASE, MEMORY_SIZE, 2).unwrap();
+		let hart0_program = [
+			0x0010_0513, // li a0, 1
+			0xfff5_8593, // addi a1, a1, -1
+			0xfe05_9ce3, // bnez a1, -8
+			0x0000_100f, // fence.i
+			0xff1f_f06f, // j -16
+		];
+		let hart1_program = [
+			0x0062_a023, // sw t1, 0(t0)
+			0x0000_100f, // fence.i
+			0x0000_006f, // j 0
+		];
+		let hart1_start = MEMORY_BASE + 0x100;
+
+		load_program(&mut platform, &hart0_program);
+		for (n, insn) in hart1_program.iter().enumerate() {
+			platform.write(hart1_start + 4 * n, *insn).unwrap();
+		}
+		platform.hart.write_register(RegisterNames::a1 as usize, 2);
+
+		// Hart 0 decodes its li before hart 1 is started
+		platform.step().unwrap();
+		platform.start_hart(1, hart1_start as u64, 0);
+		let hart1 = platform.hart_by_id_mut(1);
+		hart1.write_register(RegisterNames::t0 as usize, MEMORY_BASE as u64);
+		// li a0, 2
+		hart1.write_register(RegisterNames::t1 as usize, 0x0020_0513);
+
+		// The harts take turns, hart 1 first: its sw & fence.i come
+		// before hart 0 runs the li again
+		for _ in 0..6 {
+			platform.step().unwrap();
+		}
+		let hart0 = platform.hart_by_id(0);
+		assert_eq!(hart0.pc, MEMORY_BASE as u64 + 4);
+		assert_eq!(hart0.read_register(RegisterNames::a0 as usize), 1);
+
+		// Until hart 0 runs a fence.i of its own
+		for _ in 0..10 {
+			platform.step().unwrap();
+		}
+		let hart0 = platform.hart_by_id(0);
+		assert_eq!(hart0.pc, MEMORY_BASE as u64 + 4);
+		assert_eq!(hart0.read_register(RegisterNames::a0 as usize), 2);
+	}
+
+	#[test]
+	fn break_on_illegal_insn_stops_at_faulting_insn()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0000_0013, // nop
+			0xffff_ffff, // not a valid instruction
+		];
+
+		load_program(&mut platform, &program);
+		platform.break_on_cause = Some(CAUSE_ILLEGAL_INSN);
+
+		assert_eq!(platform.step().unwrap(), None);
+		assert_eq!(platform.step().unwrap(), Some(HaltReason::TrapBreak));
+
+		let hart = &platform.hart;
+		let faulting_pc = MEMORY_BASE as u64 + 4;
+		assert_eq!(hart.pc, faulting_pc);
+		assert_eq!(hart.read_csr(CSR_MEPC), faulting_pc);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_ILLEGAL_INSN);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0xffff_ffff);
+	}
+
+	#[test]
+	fn illegal_insn_traps_to_mtvec()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0000_0013, // nop
+			0xffff_ffff, // not a valid instruction
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+		platform.step().unwrap();
+		assert_eq!(platform.step().unwrap(), None);
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64 + 4);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_ILLEGAL_INSN);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0xffff_ffff);
+	}
+
+	#[test]
+	fn unknown_funct_traps_instead_of_panicking()
+	{
+		for garbage in [
+			0x0000_2063, // branch with func3 0b010
+			0x0000_7003, // load with func3 0b111
+			0x0000_201b, // reg imm32 with func3 0b010
+			0x0200_103b, // reg reg32, M with func3 0b001
+			0x0200_0073, // system, func3 0, imm 0x20
+		] {
+			let mut platform = Platform::default();
+
+			load_program(&mut platform, &[garbage]);
+			platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+			assert_eq!(platform.step().unwrap(), None);
+
+			let hart = &platform.hart;
+			assert_eq!(hart.pc, 0x8000_1000);
+			assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64);
+			assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_ILLEGAL_INSN);
+			assert_eq!(hart.read_csr(CSR_MTVAL), garbage as u64);
+		}
+	}
+
+	#[test]
+	fn load_access_fault_records_address_in_mtval()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x4000_02b7, // lui t0, 0x40000
+			0x0082_b303, // ld t1, 8(t0)
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+		platform.step().unwrap();
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64 + 4);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_LOAD_ACCESS_FAULT);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0x4000_0008);
+	}
+
+	#[test]
+	fn negative_offsets_below_memory_fault()
+	{
+		let program = [
+			0x8005_b503, // ld a0, -2048(a1)
+			0xfea5_bc23, // sd a0, -8(a1)
+			0xfff6_6503, // lwu a0, -1(a2)
+		];
+		let expected = [
+			(CAUSE_LOAD_ACCESS_FAULT, MEMORY_BASE as u64 - 2048),
+			(CAUSE_STORE_ACCESS_FAULT, MEMORY_BASE as u64 - 8),
+			(CAUSE_LOAD_ACCESS_FAULT, u64::MAX),
+		];
+
+		let mut platform = Platform::default();
+		load_program(&mut platform, &program);
+		let hart = &mut platform.hart;
+		hart.write_register(RegisterNames::a0 as usize, 0x1234);
+		hart.write_register(RegisterNames::a1 as usize, MEMORY_BASE as u64);
+		hart.write_register(RegisterNames::a2 as usize, 0);
+
+		for (index, (cause, address)) in expected.into_iter().enumerate() {
+			let pc = MEMORY_BASE as u64 + 4 * index as u64;
+			platform.hart.pc = pc;
+			platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+			platform.step().unwrap();
+
+			let hart = &platform.hart;
+			assert_eq!(hart.pc, 0x8000_1000);
+			assert_eq!(hart.read_csr(CSR_MEPC), pc);
+			assert_eq!(hart.read_csr(CSR_MCAUSE), cause);
+			assert_eq!(hart.read_csr(CSR_MTVAL), address);
+			assert_eq!(hart.read_register(RegisterNames::a0 as usize), 0x1234);
+		}
+	}
+
+	#[test]
+	fn timer_interrupt_vectors_to_mtvec()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0200_42b7, // lui t0, 0x2004
+			0x0080_0313, // li t1, 8
+			0x0062_b023, // sd t1, 0(t0)
+			0x0800_0393, // li t2, MIP_MTIP
+			0x3043_a073, // csrs mie, t2
+			0x3004_6073, // csrsi mstatus, MSTATUS_MIE
+			0x0000_006f, // j .
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+		for _ in 0..16 {
+			platform.step().unwrap();
+			if platform.hart.pc == 0x8000_1000 {
+				break;
+			}
+		}
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_MACHINE_TIMER);
+		assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64 + 24);
+		assert!(platform.clint().mtime >= 8);
+	}
+
+	#[test]
+	fn software_interrupt_is_taken_before_timer()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0000_0013, // nop
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		platform.hart.write_csr(CSR_MIE, MIP_MSIP | MIP_MTIP);
+		platform.hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		platform.clint_mut().msip[0] = 1;
+		platform.clint_mut().mtimecmp[0] = 0;
+
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_MACHINE_SOFTWARE);
+		assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64);
+		assert_eq!(hart.read_csr(CSR_MIP), MIP_MSIP | MIP_MTIP);
+
+		// Once the handler has cleared msip & re-enabled interrupts, the
+		// timer interrupt is still there to be taken
+		platform.clint_mut().msip[0] = 0;
+		platform.hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MCAUSE), CAUSE_MACHINE_TIMER);
+	}
+
+	#[test]
+	fn wfi_returns_at_once_with_an_interrupt_pending()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x1050_0073, // wfi
+			0x1050_0073, // wfi
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MIE, MIP_MSIP | MIP_MTIP);
+		platform.clint_mut().msip[0] = 1;
+		platform.clint_mut().mtimecmp[0] = 1000;
+
+		// With mstatus.MIE clear, the interrupt wakes the hart but isn't
+		// taken, & the timer is left alone
+		platform.step().unwrap();
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 4);
+		assert_eq!(platform.clint().mtime, 1);
+
+		// With nothing enabled, there is nothing to wait for
+		platform.hart.write_csr(CSR_MIE, 0);
+		platform.step().unwrap();
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 8);
+		assert_eq!(platform.clint().mtime, 2);
+	}
+
+	#[test]
+	fn wfi_skips_ahead_to_the_timer()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x1050_0073, // wfi
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		platform.hart.write_csr(CSR_MIE, MIP_MTIP);
+		platform.hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		platform.clint_mut().mtimecmp[0] = 1000;
+
+		platform.step().unwrap();
+		assert!(platform.clint().mtime >= 1000);
+
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_MACHINE_TIMER);
+		assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64 + 4);
+	}
+
+	#[test]
+	fn delegated_ecall_from_user_mode_vectors_to_stvec()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0000_0073, // ecall
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.privilege = PRIV_USER;
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		platform.hart.write_csr(CSR_STVEC, 0x8000_2000);
+		platform.hart.write_csr(CSR_MEDELEG, 1 << CAUSE_ECALL_UMODE);
+
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_2000);
+		assert_eq!(hart.privilege, PRIV_SUPERVISOR);
+		assert_eq!(hart.read_csr(CSR_SCAUSE), CAUSE_ECALL_UMODE);
+		assert_eq!(hart.read_csr(CSR_SEPC), MEMORY_BASE as u64);
+		assert_eq!(hart.read_csr(CSR_SSTATUS) & MSTATUS_SPP, 0);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), 0);
+		assert_eq!(hart.read_csr(CSR_MEPC), 0);
+		assert_eq!(hart.read_csr(CSR_MSTATUS) & MSTATUS_MPP_MASK, 0);
+	}
+
+	#[test]
+	fn delegated_interrupt_reaches_stvec()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x1441_6073, // csrsi sip, 2
+			0x0000_0013, // nop
+		];
+
+		load_program(&mut platform, &program);
+		let hart = &mut platform.hart;
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		hart.write_csr(CSR_STVEC, 0x8000_2000);
+		hart.write_csr(CSR_MIDELEG, MIP_SSIP);
+		hart.write_csr(CSR_MIE, MIP_SSIP);
+		hart.write_csr(CSR_SSTATUS, MSTATUS_SIE);
+
+		platform.step().unwrap();
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_2000);
+		assert_eq!(hart.privilege, PRIV_SUPERVISOR);
+		assert_eq!(hart.read_csr(CSR_SCAUSE), CAUSE_SUPERVISOR_SOFTWARE);
+		assert_eq!(hart.read_csr(CSR_SEPC), MEMORY_BASE as u64 + 4);
+		assert_eq!(hart.read_csr(CSR_SSTATUS) & MSTATUS_SIE, 0);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), 0);
+	}
+
+	#[test]
+	fn sbi_timer_interrupts_supervisor_mode()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x5449_58b7, // lui a7, 0x54495
+			0xd458_8893, // addi a7, a7, -699, making EID_TIME
+			0x0010_0537, // lui a0, 0x100
+			0x0000_0813, // li a6, 0
+			0x0000_0073, // ecall
+			0x1050_0073, // wfi
+			0x0000_006f, // j .
+		];
+
+		load_program(&mut platform, &program);
+		let hart = &mut platform.hart;
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.write_csr(CSR_STVEC, 0x8000_2000);
+		hart.write_csr(CSR_MIDELEG, MIP_STIP);
+		hart.write_csr(CSR_MIE, MIP_STIP);
+		hart.write_csr(CSR_SSTATUS, MSTATUS_SIE);
+
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_2000);
+		assert_eq!(hart.read_csr(CSR_SCAUSE), CAUSE_SUPERVISOR_TIMER);
+		assert_eq!(hart.read_csr(CSR_SEPC), MEMORY_BASE as u64 + 24);
+		assert!(platform.clint().mtime >= 0x10_0000);
+	}
+
+	#[test]
+	fn sbi_putchar_from_supervisor_mode_reaches_output()
+	{
+		let mut platform = Platform::default();
+		let output = SharedBuffer::default();
+		let program = [
+			0x0010_0893, // li a7, 1
+			0x06f0_0513, // li a0, 'o'
+			0x0000_0073, // ecall
+			0x06b0_0513, // li a0, 'k'
+			0x0000_0073, // ecall
+		];
+
+		load_program(&mut platform, &program);
+		*platform.uart_mut() =
+			Uart::new(Box::new(output.clone()), Box::new(std::io::empty()));
+		platform.hart.privilege = PRIV_SUPERVISOR;
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		assert_eq!(*output.0.borrow(), b"ok");
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 20);
+		assert_eq!(platform.hart.read_register(RegisterNames::a0 as usize), 0);
+	}
+
+	#[test]
+	fn uart_stores_reach_output()
+	{
+		let mut platform = Platform::default();
+		let output = SharedBuffer::default();
+		let program = [
+			0x1000_02b7, // lui t0, 0x10000
+			0x06f0_0313, // li t1, 'o'
+			0x0062_8023, // sb t1, 0(t0)
+			0x06b0_0313, // li t1, 'k'
+			0x0062_8023, // sb t1, 0(t0)
+		];
+
+		load_program(&mut platform, &program);
+		*platform.uart_mut() =
+			Uart::new(Box::new(output.clone()), Box::new(std::io::empty()));
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		assert_eq!(UART_BASE, 0x1000_0000);
+		assert_eq!(*output.0.borrow(), b"ok");
+	}
+
+	#[test]
+	fn console_output_is_chosen_at_construction()
+	{
+		let output = SharedBuffer::default();
+		let mut platform = Platform::with_console(
+			MEMORY_BASE,
+			MEMORY_SIZE,
+			1,
+			Box::new(output.clone()),
+		)
+		.unwrap();
+		let program = [
+			0x1000_02b7, // lui t0, 0x10000
+			0x0690_0313, // li t1, 'i'
+			0x0062_8023, // sb t1, 0(t0)
+		];
+
+		load_program(&mut platform, &program);
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		assert_eq!(*output.0.borrow(), b"i");
+	}
+
+	#[test]
+	fn uart_interrupts_are_claimed_through_the_plic()
+	{
+		let mut platform = Platform::default();
+		let claim = PLIC_BASE + 0x20_0004;
+		let program = [
+			0x0000_0013, // nop
+			0x0000_0013, // nop
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, MEMORY_BASE as u64 + 4);
+		platform.hart.write_csr(CSR_MIE, MIP_MEIP);
+		platform.hart.write_csr(CSR_MSTATUS, MSTATUS_MIE);
+		platform.write(PLIC_BASE + 4 * UART_IRQ, 1_u32).unwrap();
+		platform.write(PLIC_BASE + 0x2000, 1_u32 << UART_IRQ).unwrap();
+		// Enable the UART's transmitter empty interrupt, which it raises
+		// straight away
+		platform.write(UART_BASE + 1, 0x2_u8).unwrap();
+
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_MACHINE_EXTERNAL);
+		assert_eq!(hart.pc, MEMORY_BASE as u64 + 4);
+
+		assert_eq!(platform.read::<u32>(claim).unwrap(), UART_IRQ as u32);
+		assert_eq!(platform.read::<u32>(claim).unwrap(), 0);
+		// Reading IIR acknowledges the UART's interrupt
+		platform.read::<u8>(UART_BASE + 2).unwrap();
+		platform.write(claim, UART_IRQ as u32).unwrap();
+
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MIP) & MIP_MEIP, 0);
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 8);
+		assert!(!platform.plic().interrupt_pending(0));
+	}
+
+	#[test]
+	fn cached_instructions_stay_stale_until_fence_i()
+	{
+		let mut platform = Platform::default();
+		let a0 = |platform: &Platform| {
+			return platform.hart.read_register(RegisterNames::a0 as usize);
+		};
+		let program = [
+			0x0000_0297, // auipc t0, 0
+			0x0182_a303, // lw t1, 24(t0)
+			0x0062_aa23, // sw t1, 20(t0)
+			0x0000_0013, // nop
+			0x0000_100f, // fence.i
+			0x0010_0513, // li a0, 1
+			0x0020_0513, // li a0, 2
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.pc = MEMORY_BASE as u64 + 20;
+		platform.step().unwrap();
+		assert_eq!(a0(&platform), 1);
+
+		// Overwrite li a0, 1 with li a0, 2 then run it again, skipping
+		// the fence.i
+		platform.hart.pc = MEMORY_BASE as u64;
+		for _ in 0..3 {
+			platform.step().unwrap();
+		}
+		platform.hart.pc = MEMORY_BASE as u64 + 20;
+		platform.step().unwrap();
+		assert_eq!(a0(&platform), 1);
+
+		platform.hart.pc = MEMORY_BASE as u64 + 16;
+		platform.step().unwrap();
+		platform.step().unwrap();
+		assert_eq!(a0(&platform), 2);
+	}
+
+	#[test]
+	fn reset_returns_to_a_clean_state()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0050_0513, // li a0, 5
+			0x0000_0297, // auipc t0, 0
+			0x00a2_a023, // sw a0, 0(t0)
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.privilege = PRIV_USER;
+		platform.hart.write_csr(CSR_MSCRATCH, 0x1234);
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		platform.reset();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0);
+		assert_eq!(hart.registers, [0; 32]);
+		assert_eq!(hart.read_csr(CSR_MSCRATCH), 0);
+		assert_eq!(hart.read_csr(CSR_MINSTRET), 0);
+		assert_eq!(hart.privilege, PRIV_MACHINE);
+		assert_ne!(hart.read_csr(CSR_MISA), 0);
+		assert_eq!(platform.read::<u32>(MEMORY_BASE).unwrap(), 0);
+		assert_eq!(platform.clint().mtime, 0);
+	}
+
+	#[test]
+	fn restoring_a_snapshot_carries_on_from_where_it_was_taken()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0015_0513, // addi a0, a0, 1
+			0x0000_0297, // auipc t0, 0
+			0x00a2_a023, // sw a0, 0(t0)
+			0xff5f_f06f, // j -12
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MSCRATCH, 0x1234);
+		for _ in 0..6 {
+			platform.step().unwrap();
+		}
+
+		let bytes = platform.snapshot().to_bytes();
+		let pc = platform.hart.pc;
+		let registers = platform.hart.registers;
+		let mtime = platform.clint().mtime;
+		let word = platform.read::<u32>(MEMORY_BASE + 4).unwrap();
+
+		for _ in 0..10 {
+			platform.step().unwrap();
+		}
+		assert_ne!(platform.hart.registers, registers);
+
+		let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+		platform.restore(&snapshot).unwrap();
+		assert_eq!(platform.hart.pc, pc);
+		assert_eq!(platform.hart.registers, registers);
+		assert_eq!(platform.hart.read_csr(CSR_MSCRATCH), 0x1234);
+		assert_eq!(platform.clint().mtime, mtime);
+		assert_eq!(platform.read::<u32>(MEMORY_BASE + 4).unwrap(), word);
+		assert_eq!(platform.snapshot().to_bytes(), bytes);
+	}
+
+	#[test]
+	fn snapshots_only_fit_the_same_machine()
+	{
+		let snapshot = Platform::default().snapshot();
+		let mut platform = Platform::new(MEMORY_BASE, 0x10000, 1).unwrap();
+		assert!(platform.restore(&snapshot).is_err());
+	}
+
+	#[test]
+	fn bad_snapshot_changes_nothing()
+	{
+		// Hart 0 & memory would restore fine, but the CLINT has a hart too
+		// many, so the snapshot must be turned away before either is
+		// touched
+		let mut other = Platform::new(MEMORY_BASE, MEMORY_SIZE, 2).unwrap();
+		load_program(&mut other, &[0x0000_006f]); // j .
+		let snapshot = other.snapshot();
+
+		let mut platform = Platform::default();
+		platform.hart.pc = 0x1234;
+		assert!(platform.restore(&snapshot).is_err());
+		assert_eq!(platform.hart.pc, 0x1234);
+		assert_eq!(platform.read::<u32>(MEMORY_BASE).unwrap(), 0);
+	}
+
+	#[test]
+	fn dump_state_labels_registers_and_csrs()
+	{
+		let mut platform = Platform::default();
+		let hart = &mut platform.hart;
+		hart.pc = MEMORY_BASE as u64;
+		hart.write_register(RegisterNames::ra as usize, 0x1234);
+		hart.write_register(RegisterNames::t6 as usize, 0xdead_beef);
+		hart.write_csr(CSR_MCAUSE, CAUSE_ILLEGAL_INSN);
+
+		let dump = platform.dump_state();
+		let lines: Vec<&str> = dump.lines().collect();
+		assert_eq!(lines.len(), 1 + 8 + 2);
+		assert_eq!(lines[0], "hart 0 pc: 0x0000000080000000");
+		assert_eq!(
+			lines[1],
+			"zero: 0x0000000000000000   ra: 0x0000000000001234   \
+			 sp: 0x0000000000000000   gp: 0x0000000000000000"
+		);
+		assert!(lines[8].ends_with("  t6: 0x00000000deadbeef"));
+		assert!(lines[9].starts_with("mstatus: "));
+		assert!(lines[9].contains(" mcause: 0x0000000000000002"));
+		assert!(lines[10].ends_with("   satp: 0x0000000000000000"));
+	}
+
+	#[test]
+	fn capabilities_report_implemented_extensions()
+	{
+		let platform = Platform::default();
+		let capabilities = platform.capabilities();
+
+		assert_eq!(capabilities.xlen, 64);
+		assert_eq!(
+			capabilities.extensions,
+			vec!['I', 'M', 'A', 'F', 'C', 'S', 'U']
+		);
+		assert_eq!(capabilities.harts, 1);
+		assert_eq!(capabilities.memory_size, MEMORY_SIZE);
+		assert_eq!(
+			capabilities.devices,
+			vec!["clint", "plic", "uart", "rtc", "memory"]
+		);
+	}
+
+	#[test]
+	fn reservation_at_address_zero_does_not_underflow()
+	{
+		let mut platform = Platform::default();
+		platform.reservation_sets.push(ReservationSet::new(1));
+
+		// An LR with rs1 == x0 reserves address zero
+		platform.claim_reservation_set(1, 0_usize, 4);
+		platform.invalidate_reservation_sets(0, 8_usize, 8);
+		assert!(platform.reservation_sets[1].valid);
+
+		platform.invalidate_reservation_sets(0, 0_usize, 8);
+		assert!(!platform.reservation_sets[1].valid);
+
+		platform.claim_reservation_set(0, 0_usize, 8);
+		assert!(platform.check_invalidate_reservation_set(0, 0_usize, 8));
+	}
+
+	#[test]
+	fn lr_into_x0_still_takes_the_reservation()
+	{
+		let mut platform = Platform::default();
+		let address = MEMORY_BASE + 0x800;
+		let program = [
+			0x1005_302f, // lr.d zero, (a0)
+			0x1865_32af, // sc.d t0, t1, (a0)
+		];
+
+		load_program(&mut platform, &program);
+		platform.write(address, 0x1234_u64).unwrap();
+		let hart = &mut platform.hart;
+		hart.write_register(RegisterNames::a0 as usize, address as u64);
+		hart.write_register(RegisterNames::t0 as usize, 0xff);
+		hart.write_register(RegisterNames::t1 as usize, 0x5678);
+
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_register(0_usize), 0);
+		let reservation = &platform.reservation_sets[0];
+		assert!(reservation.valid);
+		assert_eq!(reservation.address, address);
+
+		platform.step().unwrap();
+		let t0 = platform.hart.read_register(RegisterNames::t0 as usize);
+		assert_eq!(t0, 0);
+		assert_eq!(platform.read::<u64>(address).unwrap(), 0x5678);
+	}
+
+	#[test]
+	fn store_from_hart_without_reservation_invalidates_others()
+	{
+		let mut platform = Platform::default();
+		platform.reservation_sets.push(ReservationSet::new(1));
+		let address = MEMORY_BASE + 0x100;
+
+		// Hart 0 has never issued an LR, so its set is untouched
+		assert_eq!(platform.reservation_sets[0].hart_id, 0);
+		assert!(!platform.reservation_sets[0].valid);
+
+		platform.claim_reservation_set(1, address, 8);
+		platform.write_from_hart(0, address, 0_u64).unwrap();
+
+		assert!(!platform.reservation_sets[1].valid);
+		assert!(!platform.check_invalidate_reservation_set(1, address, 8));
+	}
+
+	#[test]
+	fn reservation_stats_count_lr_sc_and_invalidations()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x1002_b32f, // lr.d t1, (t0)
+			0x19c2_b3af, // sc.d t2, t3, (t0)
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_register(
+			RegisterNames::t0 as usize,
+			MEMORY_BASE as u64 + 0x100,
+		);
+
+		platform.step().unwrap();
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_register(RegisterNames::t2 as usize), 0);
+
+		let stats = platform.reservation_stats();
+		assert_eq!(stats.lr, 1);
+		assert_eq!(stats.sc_success, 1);
+		assert_eq!(stats.sc_failure, 0);
+
+		// A store from another hart breaks the reservation
+		platform.reservation_sets.push(ReservationSet::new(1));
+		let address = MEMORY_BASE + 0x200;
+		platform.claim_reservation_set(1, address, 8);
+		platform.write_from_hart(0, address + 4, 0_u32).unwrap();
+		assert_eq!(platform.reservation_stats().invalidated_by_other, 1);
+		assert!(!platform.check_invalidate_reservation_set(1, address, 8));
+		assert_eq!(platform.reservation_stats().sc_failure, 1);
+	}
+
+	#[test]
+	fn store_to_any_byte_of_word_reservation_invalidates_it()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x1002_a32f, // lr.w t1, (t0)
+			0x19c2_a3af, // sc.w t2, t3, (t0)
+		];
+		let address = MEMORY_BASE + 0x100;
+
+		load_program(&mut platform, &program);
+		platform.reservation_sets.push(ReservationSet::new(1));
+
+		for offset in 1..4 {
+			platform.hart.pc = MEMORY_BASE as u64;
+			platform
+				.hart
+				.write_register(RegisterNames::t0 as usize, address as u64);
+
+			platform.step().unwrap();
+			platform.write_from_hart(1, address + offset, 0_u8).unwrap();
+			platform.step().unwrap();
+
+			let t2 = platform.hart.read_register(RegisterNames::t2 as usize);
+			assert_ne!(t2, 0, "store to byte {} left the reservation", offset);
+		}
+	}
+
+	#[test]
+	fn reservation_survives_csr_access_but_not_trap()
+	{
+		let address = MEMORY_BASE as u64 + 0x100;
+		let sc_result = |middle: u32| {
+			let mut platform = Platform::default();
+			let program = [
+				0x1002_a32f, // lr.w t1, (t0)
+				middle,
+				0x19c2_a3af, // sc.w t2, t3, (t0)
+			];
+
+			load_program(&mut platform, &program);
+			platform.hart.write_register(RegisterNames::t0 as usize, address);
+			platform.hart.write_register(RegisterNames::t2 as usize, 0xff);
+			// Trap straight to the sc, so only the trap separates it
+			// from the lr
+			platform.hart.write_csr(CSR_MTVEC, MEMORY_BASE as u64 + 8);
+
+			for _ in 0..3 {
+				platform.step().unwrap();
+			}
+
+			return platform.hart.read_register(RegisterNames::t2 as usize);
+		};
+
+		// csrrw zero, mscratch, t1
+		assert_eq!(sc_result(0x3403_1073), 0);
+		// ecall
+		assert_eq!(sc_result(0x0000_0073), 1);
+	}
+
+	#[test]
+	fn trace_lists_retired_instructions()
+	{
+		let mut platform = Platform::default();
+		let output = SharedBuffer::default();
+		let program = [
+			0x0050_0513, // li a0, 5
+			0x0005_0613, // mv a2, a0
+			0x00a5_b023, // sd a0, 0(a1)
+			0x00a5_0463, // beq a0, a0, 8
+			0xc000_1073, // unimp, branched over
+			0x0000_0073, // ecall, which does not retire
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, MEMORY_BASE as u64 + 0x100);
+		platform.hart.write_register(
+			RegisterNames::a1 as usize,
+			MEMORY_BASE as u64 + 0x80,
+		);
+		platform.set_trace(Box::new(output.clone()));
+		for _ in 0..5 {
+			platform.step().unwrap();
+		}
+
+		let trace = String::from_utf8(output.0.borrow().clone()).unwrap();
+		assert_eq!(
+			trace,
+			"core   0: 0x0000000080000000 (0x00500513) addi    a0, zero, 5\n\
+			 core   0: 0x0000000080000004 (0x00050613) mv      a2, a0, 0\n\
+			 core   0: 0x0000000080000008 (0x00a5b023) sd      a0, 0(a1)\n\
+			 core   0: 0x000000008000000c (0x00a50463) beq     a0, a0, pc + 0x8\n"
+		);
+	}
+
+	#[test]
+	fn fixed_latency_accumulates_per_load()
+	{
+		let mut platform = Platform::default();
+		let loads = 5;
+		let mut program: Vec<u32> = vec![0x0005_b503; loads]; // ld a0, 0(a1)
+		program.push(0x0000_0513); // li a0, 0
+
+		load_program(&mut platform, &program);
+		platform.hart.write_register(
+			RegisterNames::a1 as usize,
+			MEMORY_BASE as u64 + 0x80,
+		);
+		platform.set_latency_model(Box::new(FixedLatency(3)));
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		// Fetches & non-memory instructions are free
+		assert_eq!(platform.stall_cycles(), 3 * loads as u64);
+	}
+
+	#[test]
+	fn latency_model_sees_each_access()
+	{
+		#[derive(Clone, Default)]
+		struct Recorder(Rc<RefCell<Vec<(usize, usize, AccessKind)>>>);
+
+		impl LatencyModel for Recorder
+		{
+			fn latency(
+				&mut self, address: usize, size: usize, kind: AccessKind,
+			) -> u64
+			{
+				self.0.borrow_mut().push((address, size, kind));
+				return size as u64;
+			}
+		}
+
+		let mut platform = Platform::default();
+		let recorder = Recorder::default();
+		let address = MEMORY_BASE + 0x80;
+
+		platform.set_latency_model(Box::new(recorder.clone()));
+		platform.write(address, 0_u32).unwrap();
+		platform.read::<u64>(address).unwrap();
+
+		assert_eq!(
+			*recorder.0.borrow(),
+			vec![
+				(address, 4, AccessKind::Write),
+				(address, 8, AccessKind::Read)
+			]
+		);
+		assert_eq!(platform.stall_cycles(), 12);
+	}
+
+	#[test]
+	fn trap_entry_swaps_sp_with_scratch()
+	{
+		let scratch_area = MEMORY_BASE as u64 + 0x1000;
+		let stack = MEMORY_BASE as u64 + 0x2000;
+
+		for (csr, swap) in [
+			(CSR_MSCRATCH, 0x3401_1173), // csrrw sp, mscratch, sp
+			(CSR_SSCRATCH, 0x1401_1173), // csrrw sp, sscratch, sp
+		] {
+			let mut platform = Platform::default();
+			let program = [
+				swap,
+				0x0051_3023, // sd t0, 0(sp)
+				swap,
+			];
+
+			load_program(&mut platform, &program);
+			platform.hart.write_csr(csr, scratch_area);
+			platform.hart.write_register(RegisterNames::sp as usize, stack);
+			platform.hart.write_register(RegisterNames::t0 as usize, 0x1234);
+
+			platform.step().unwrap();
+			assert_eq!(
+				platform.hart.read_register(RegisterNames::sp as usize),
+				scratch_area
+			);
+			assert_eq!(platform.hart.read_csr(csr), stack);
+
+			platform.step().unwrap();
+			platform.step().unwrap();
+			assert_eq!(
+				platform.hart.read_register(RegisterNames::sp as usize),
+				stack
+			);
+			assert_eq!(platform.hart.read_csr(csr), scratch_area);
+			assert_eq!(
+				platform.read::<u64>(scratch_area as usize).unwrap(),
+				0x1234
+			);
+		}
+	}
+
+	#[test]
+	fn minstret_counts_retired_instructions()
+	{
+		let mut platform = Platform::default();
+		let nops = 10;
+		let mut program: Vec<u32> = vec![0x0000_0013; nops];
+		program.push(0xc020_2573); // csrr a0, instret
+		program.push(0xffff_ffff); // illegal, so does not retire
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, MEMORY_BASE as u64 + 0x100);
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		let hart = &platform.hart;
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), nops as u64);
+		assert_eq!(hart.read_csr(CSR_MINSTRET), nops as u64 + 1);
+		assert_eq!(hart.read_csr(CSR_MCYCLE), nops as u64 + 2);
+	}
+
+	#[test]
+	fn time_csr_follows_mtime()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0xc010_2573, // csrr a0, time
+			0x0000_0013, // nop
+			0x0000_0013, // nop
+			0xc010_25f3, // csrr a1, time
+		];
+
+		load_program(&mut platform, &program);
+		platform.clint_mut().mtime = 1000;
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		let first = platform.hart.read_register(RegisterNames::a0 as usize);
+		let second = platform.hart.read_register(RegisterNames::a1 as usize);
+		assert_eq!(first, 1000);
+		assert!(second > first);
+		assert_eq!(second, platform.clint().mtime - 1);
+	}
+
+	#[test]
+	fn counters_need_enabling_for_user_mode()
+	{
+		let program = [
+			0xc000_2573, // rdcycle a0
+		];
+		let run = |mcounteren: u64| {
+			let mut platform = Platform::default();
+			load_program(&mut platform, &program);
+			let hart = &mut platform.hart;
+			hart.write_csr(CSR_MTVEC, 0x8000_1000);
+			hart.write_csr(CSR_MCYCLE, 1000);
+			hart.write_csr(CSR_MCOUNTEREN, mcounteren);
+			hart.write_csr(CSR_SCOUNTEREN, COUNTEREN_CY);
+			hart.privilege = PRIV_USER;
+			platform.step().unwrap();
+			return platform.hart;
+		};
+
+		let hart = run(COUNTEREN_TM | COUNTEREN_IR);
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_ILLEGAL_INSN);
+		assert_eq!(hart.read_csr(CSR_MTVAL), program[0] as u64);
+
+		let hart = run(COUNTEREN_CY);
+		assert_eq!(hart.pc, MEMORY_BASE as u64 + 4);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 1000);
+	}
+
+	#[test]
+	fn insn_hook_sees_every_retired_instruction()
+	{
+		let mut platform = Platform::default();
+		let retired = Rc::new(Cell::new(0));
+		let program = [
+			0x0000_0013, // nop
+			0x0010_0513, // li a0, 1
+			0x0000_0013, // nop
+		];
+
+		load_program(&mut platform, &program);
+		let count = retired.clone();
+		platform.set_insn_hook(Box::new(move |insn, hart| {
+			count.set(count.get() + 1);
+			assert_eq!(hart.pc, MEMORY_BASE as u64 + 4 * count.get());
+			assert_eq!(insn.bits, program[count.get() as usize - 1]);
+		}));
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		assert_eq!(retired.get(), program.len() as u64);
+	}
+
+	#[test]
+	fn minstret_writes_set_the_count()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0xb022_9073, // csrw minstret, t0
+			0x0000_0013, // nop
+			0xb020_2573, // csrr a0, minstret
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_register(RegisterNames::t0 as usize, 100);
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+		assert_eq!(a0, 101);
+	}
+
+	#[test]
+	fn counter_writes_of_the_same_value_are_not_counted_over()
+	{
+		let mut platform = Platform::default();
+		// Each writes the value the counter already has
+		let program = [
+			0xb020_1073, // csrw minstret, zero
+			0xb002_9073, // csrw mcycle, t0
+			0xb000_25f3, // csrr a1, mcycle
+			0xb020_2573, // csrr a0, minstret
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_register(RegisterNames::t0 as usize, 1);
+		for _ in 0..program.len() {
+			platform.step().unwrap();
+		}
+
+		let hart = &platform.hart;
+		assert_eq!(hart.read_register(RegisterNames::a1 as usize), 1);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 2);
+	}
+
+	#[test]
+	fn writing_minstret_still_retires()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0xb020_1073, // csrw minstret, zero
+			0xffff_ffff, // illegal, so does not retire
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_csr(CSR_MTVEC, MEMORY_BASE as u64 + 0x100);
+
+		platform.step().unwrap();
+		assert!(platform.retired());
+		assert_eq!(platform.hart.read_csr(CSR_MINSTRET), 0);
+
+		platform.step().unwrap();
+		assert!(!platform.retired());
+	}
+
+	#[test]
+	fn custom_memory_range_bounds_accesses()
+	{
+		let base = 0x4000_0000;
+		let size = 0x1000;
+		let mut platform = Platform::new(base, size, 1).unwrap();
+
+		platform.write(base, 0x1234_u32).unwrap();
+		platform.write(base + size - 8, u64::MAX).unwrap();
+		assert_eq!(platform.read::<u32>(base).unwrap(), 0x1234);
+		assert_eq!(platform.capabilities().memory_size, size);
+
+		assert!(platform.read::<u32>(base - 4).is_err());
+		assert!(platform.read::<u32>(base + size).is_err());
+		assert!(platform.write(base + size, 0_u32).is_err());
+		assert!(platform.write(MEMORY_BASE, 0_u32).is_err());
+
+		assert!(platform.load_kernel(vec![0; 0x100], base, base).is_ok());
+		assert!(platform.load_kernel(vec![0; 0x1001], base, base).is_err());
+		assert!(platform
+			.load_kernel(vec![0; 4], MEMORY_BASE, MEMORY_BASE)
+			.is_err());
+
+		// Running off the end of memory is an access fault, not a crash
+		platform.hart.pc = (base + size) as u64;
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MCAUSE), CAUSE_INSN_ACCESS_FAULT);
+	}
+
+	#[test]
+	fn quadword_accesses_round_trip()
+	{
+		let mut platform = Platform::default();
+		let value: u128 = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeff;
+
+		platform.write(MEMORY_BASE + 0x100, value).unwrap();
+		assert_eq!(platform.read::<u128>(MEMORY_BASE + 0x100).unwrap(), value);
+		// Little endian, so the low doubleword comes first
+		assert_eq!(
+			platform.read::<u64>(MEMORY_BASE + 0x100).unwrap(),
+			0x8899_aabb_ccdd_eeff
+		);
+		assert_eq!(platform.read::<u8>(MEMORY_BASE + 0x10f).unwrap(), 0x00);
+
+		let end = MEMORY_BASE + MEMORY_SIZE;
+		assert!(platform.read::<u128>(end - 8).is_err());
+		assert!(platform.write(end - 8, value).is_err());
+	}
+
+	#[test]
+	fn memory_access_straddling_the_end_errors()
+	{
+		let mut memory = Memory::new(MEMORY_BASE, 0x1000);
+
+		assert!(memory.read::<u32>(0xffc).is_ok());
+		assert!(memory.read::<u64>(0xffc).is_err());
+		assert!(memory.write(0xffc_usize, 0_u64).is_err());
+		assert!(memory.read::<u8>(usize::MAX).is_err());
+	}
+
+	#[test]
+	fn compressed_instructions_advance_pc_by_2()
+	{
+		let mut platform = Platform::default();
+		let target = MEMORY_BASE as u64 + 0x100;
+		let mut blob = Vec::new();
+		blob.extend(0x0505_u16.to_le_bytes()); // c.addi a0, 1
+		blob.extend(0x0015_0513_u32.to_le_bytes()); // addi a0, a0, 1
+		blob.extend(0x9782_u16.to_le_bytes()); // c.jalr a5
+
+		platform.load_kernel(blob, MEMORY_BASE, MEMORY_BASE).unwrap();
+		platform.hart.write_register(RegisterNames::a5 as usize, target);
+
+		platform.step().unwrap();
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 2);
+		platform.step().unwrap();
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 6);
+		platform.step().unwrap();
+		assert_eq!(platform.hart.pc, target);
+
+		let hart = &platform.hart;
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 2);
+		assert_eq!(
+			hart.read_register(RegisterNames::ra as usize),
+			MEMORY_BASE as u64 + 8
+		);
+	}
+
+	#[test]
+	fn unaligned_jump_targets_trap()
+	{
+		for jump in [
+			0x0060_006f, // jal zero, 6
+			0x0000_0363, // beq zero, zero, 6
+		] {
+			let mut platform = Platform::default();
+			load_program(&mut platform, &[jump]);
+			platform.step().unwrap();
+			// 2 byte alignment is enough for compressed instructions
+			assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 6);
+
+			let mut platform = Platform::default();
+			load_program(&mut platform, &[jump]);
+			let misa = platform.hart.read_csr(CSR_MISA);
+			platform.hart.write_csr(CSR_MISA, misa & !MISA_C);
+			platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+			platform.step().unwrap();
+
+			let hart = &platform.hart;
+			assert_eq!(hart.pc, 0x8000_1000);
+			assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_INSN_MISALIGNED);
+			assert_eq!(hart.read_csr(CSR_MEPC), MEMORY_BASE as u64);
+			assert_eq!(hart.read_csr(CSR_MTVAL), MEMORY_BASE as u64 + 6);
+		}
+	}
+
+	#[test]
+	fn fetch_from_unmapped_pc_faults()
+	{
+		let mut platform = Platform::default();
+		let unmapped = 0x4000_0000;
+
+		load_program(&mut platform, &[]);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		platform.hart.pc = unmapped;
+
+		assert_eq!(platform.step().unwrap(), None);
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_INSN_ACCESS_FAULT);
+		assert_eq!(hart.read_csr(CSR_MEPC), unmapped);
+		assert_eq!(hart.read_csr(CSR_MTVAL), unmapped);
+	}
+
+	#[test]
+	fn fetch_from_a_device_faults()
+	{
+		let mut platform = Platform::default();
+
+		load_program(&mut platform, &[]);
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		platform.hart.pc = UART_BASE as u64;
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_INSN_ACCESS_FAULT);
+		assert_eq!(hart.read_csr(CSR_MTVAL), UART_BASE as u64);
+	}
+
+	#[test]
+	fn fetch_from_an_unmapped_page_faults()
+	{
+		let mut platform = Platform::default();
+		// An empty root table maps nothing
+		let root = MEMORY_BASE + 0x1_0000;
+
+		load_program(&mut platform, &[]);
+		let hart = &mut platform.hart;
+		hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		hart.write_csr(
+			CSR_SATP,
+			SATP_MODE_SV39 << SATP_MODE_SHIFT | (root as u64 >> 12),
+		);
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.pc = 0x1000;
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_INSN_PAGE_FAULT);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0x1000);
+	}
+
+	#[test]
+	fn fetch_translates_both_pages_an_instruction_straddles()
+	{
+		let mut platform = Platform::default();
+		let root = MEMORY_BASE + 0x1_0000;
+		let level1 = MEMORY_BASE + 0x1_1000;
+		let level0 = MEMORY_BASE + 0x1_2000;
+		// Physically, the second page comes before the first
+		let first = MEMORY_BASE + 0x3_0000;
+		let second = MEMORY_BASE + 0x2_0000;
+		let pointer = |table: usize| return ((table as u64) >> 2) | PTE_V;
+		let leaf = |page: usize| return pointer(page) | PTE_X | PTE_A;
+		// addi a0, zero, 5
+		let insn: u32 = 0x0050_0513;
+
+		load_program(&mut platform, &[]);
+		// Map virtual page 0x1000 to first & 0x2000 to second
+		platform.write(root, pointer(level1)).unwrap();
+		platform.write(level1, pointer(level0)).unwrap();
+		platform.write(level0 + 8, leaf(first)).unwrap();
+		platform.write(level0 + 16, leaf(second)).unwrap();
+		platform.write(first + 0xffe, insn as u16).unwrap();
+		platform.write(second, (insn >> 16) as u16).unwrap();
+		// & what would be the second half, were it fetched physically
+		platform.write(first + 0x1000, 0xffff_u16).unwrap();
+
+		let hart = &mut platform.hart;
+		hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		hart.write_csr(
+			CSR_SATP,
+			SATP_MODE_SV39 << SATP_MODE_SHIFT | (root as u64 >> 12),
+		);
+		hart.privilege = PRIV_SUPERVISOR;
+		hart.pc = 0x1ffe;
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x2002);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 5);
+
+		// Without the second page, it's the second half that faults
+		platform.write(level0 + 16, 0_u64).unwrap();
+		platform.hart.pc = 0x1ffe;
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_INSN_PAGE_FAULT);
+		assert_eq!(hart.read_csr(CSR_MEPC), 0x1ffe);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0x2000);
+	}
+
+	#[test]
+	fn sv39_load_goes_through_the_page_tables()
+	{
+		let mut platform = Platform::default();
+		let root = MEMORY_BASE + 0x1_0000;
+		let level1 = MEMORY_BASE + 0x1_1000;
+		let level0 = MEMORY_BASE + 0x1_2000;
+		let page = MEMORY_BASE + 0x2_0000;
+		let pointer = |table: usize| return ((table as u64) >> 2) | PTE_V;
+		let program = [
+			0x0085_b503, // ld a0, 8(a1)
+			0x0006_3503, // ld a0, 0(a2)
+		];
+
+		load_program(&mut platform, &program);
+		// Map virtual page 0x1000 to page, through all three levels
+		platform.write(root, pointer(level1)).unwrap();
+		platform.write(level1, pointer(level0)).unwrap();
+		platform
+			.write(level0 + 8, pointer(page) | PTE_R | PTE_W | PTE_A | PTE_D)
+			.unwrap();
+		platform.write(page + 8, 0x1234_u64).unwrap();
+
+		let hart = &mut platform.hart;
+		hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		hart.write_csr(
+			CSR_SATP,
+			SATP_MODE_SV39 << SATP_MODE_SHIFT | (root as u64 >> 12),
+		);
+		// Loads & stores are made as S-mode through MPRV
+		hart.write_csr(
+			CSR_MSTATUS,
+			MSTATUS_MPRV | PRIV_SUPERVISOR << MSTATUS_MPP_SHIFT,
+		);
+		hart.write_register(RegisterNames::a1 as usize, 0x1000);
+		hart.write_register(RegisterNames::a2 as usize, 0x2000);
+
+		platform.step().unwrap();
+		assert_eq!(
+			platform.hart.read_register(RegisterNames::a0 as usize),
+			0x1234
+		);
+
+		// The next page is not mapped
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_LOAD_PAGE_FAULT);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0x2000);
+	}
+
+	#[test]
+	fn mprv_loads_use_mpp_permissions_but_fetches_do_not()
+	{
+		let mut platform = Platform::default();
+		let root = MEMORY_BASE + 0x1_0000;
+		let level1 = MEMORY_BASE + 0x1_1000;
+		let level0 = MEMORY_BASE + 0x1_2000;
+		let user = MEMORY_BASE + 0x2_0000;
+		let supervisor = MEMORY_BASE + 0x3_0000;
+		let pointer = |table: usize| return ((table as u64) >> 2) | PTE_V;
+		let program = [
+			0x0085_b503, // ld a0, 8(a1)
+			0x0086_3503, // ld a0, 8(a2)
+		];
+
+		load_program(&mut platform, &program);
+		// Virtual page 0x1000 is a user page, 0x2000 a supervisor one
+		platform.write(root, pointer(level1)).unwrap();
+		platform.write(level1, pointer(level0)).unwrap();
+		platform
+			.write(level0 + 8, pointer(user) | PTE_R | PTE_U | PTE_A)
+			.unwrap();
+		platform
+			.write(level0 + 16, pointer(supervisor) | PTE_R | PTE_A)
+			.unwrap();
+		platform.write(user + 8, 0x1234_u64).unwrap();
+		platform.write(supervisor + 8, 0x5678_u64).unwrap();
+
+		// The program itself is only reachable untranslated, as M-mode
+		// fetches it
+		let hart = &mut platform.hart;
+		hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		hart.write_csr(
+			CSR_SATP,
+			SATP_MODE_SV39 << SATP_MODE_SHIFT | (root as u64 >> 12),
+		);
+		hart.write_csr(
+			CSR_MSTATUS,
+			MSTATUS_MPRV | PRIV_USER << MSTATUS_MPP_SHIFT,
+		);
+		hart.write_register(RegisterNames::a1 as usize, 0x1000);
+		hart.write_register(RegisterNames::a2 as usize, 0x2000);
+
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, MEMORY_BASE as u64 + 4);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 0x1234);
+
+		// U-mode may not read a supervisor page, even though M-mode is
+		// doing the reading
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_LOAD_PAGE_FAULT);
+		assert_eq!(hart.read_csr(CSR_MTVAL), 0x2008);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 0x1234);
+	}
+
+	#[test]
+	fn remapping_after_sfence_vma_takes_effect()
+	{
+		let mut platform = Platform::default();
+		let root = MEMORY_BASE + 0x1_0000;
+		let level1 = MEMORY_BASE + 0x1_1000;
+		let level0 = MEMORY_BASE + 0x1_2000;
+		let first = MEMORY_BASE + 0x2_0000;
+		let second = MEMORY_BASE + 0x3_0000;
+		let pointer = |table: usize| return ((table as u64) >> 2) | PTE_V;
+		let leaf = |page: usize| {
+			return pointer(page) | PTE_R | PTE_W | PTE_A | PTE_D;
+		};
+		let program = [
+			0x0085_b503, // ld a0, 8(a1)
+			0x1200_0073, // sfence.vma
+			0x0085_b503, // ld a0, 8(a1)
+		];
+
+		load_program(&mut platform, &program);
+		platform.write(root, pointer(level1)).unwrap();
+		platform.write(level1, pointer(level0)).unwrap();
+		platform.write(level0 + 8, leaf(first)).unwrap();
+		platform.write(first + 8, 0x1111_u64).unwrap();
+		platform.write(second + 8, 0x2222_u64).unwrap();
+
+		let hart = &mut platform.hart;
+		hart.write_csr(
+			CSR_SATP,
+			SATP_MODE_SV39 << SATP_MODE_SHIFT | (root as u64 >> 12),
+		);
+		hart.write_csr(
+			CSR_MSTATUS,
+			MSTATUS_MPRV | PRIV_SUPERVISOR << MSTATUS_MPP_SHIFT,
+		);
+		hart.write_register(RegisterNames::a1 as usize, 0x1000);
+
+		platform.step().unwrap();
+		let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+		assert_eq!(a0, 0x1111);
+
+		platform.write(level0 + 8, leaf(second)).unwrap();
+		platform.step().unwrap();
+		platform.step().unwrap();
+		let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+		assert_eq!(a0, 0x2222);
+	}
+
+	#[test]
+	fn misaligned_accesses_are_translated_a_byte_at_a_time()
+	{
+		let mut platform = Platform::default();
+		let root = MEMORY_BASE + 0x1_0000;
+		let level1 = MEMORY_BASE + 0x1_1000;
+		let level0 = MEMORY_BASE + 0x1_2000;
+		let low = MEMORY_BASE + 0x2_1000;
+		let high = MEMORY_BASE + 0x2_0000;
+		let pointer = |table: usize| return ((table as u64) >> 2) | PTE_V;
+		let leaf = |page: usize| return pointer(page) | PTE_R | PTE_A;
+		let program = [
+			0xffc5_b503, // ld a0, -4(a1)
+			0x00a5_b023, // sd a0, 0(a1)
+		];
+
+		load_program(&mut platform, &program);
+		// Map virtual pages 0x1000 & 0x2000 the wrong way round, the
+		// second read-only
+		platform.write(root, pointer(level1)).unwrap();
+		platform.write(level1, pointer(level0)).unwrap();
+		platform.write(level0 + 8, leaf(low) | PTE_W | PTE_D).unwrap();
+		platform.write(level0 + 16, leaf(high)).unwrap();
+		platform.write(low + 0xffc, 0x5566_7788_u32).unwrap();
+		platform.write(high, 0x1122_3344_u32).unwrap();
+
+		let hart = &mut platform.hart;
+		hart.write_csr(CSR_MTVEC, 0x8000_1000);
+		hart.write_csr(
+			CSR_SATP,
+			SATP_MODE_SV39 << SATP_MODE_SHIFT | (root as u64 >> 12),
+		);
+		hart.write_csr(
+			CSR_MSTATUS,
+			MSTATUS_MPRV | PRIV_SUPERVISOR << MSTATUS_MPP_SHIFT,
+		);
+		hart.write_register(RegisterNames::a1 as usize, 0x2000);
+
+		platform.step().unwrap();
+		assert_eq!(
+			platform.hart.read_register(RegisterNames::a0 as usize),
+			0x1122_3344_5566_7788
+		);
+
+		// Half of the store would land in the read-only page, so none of
+		// it does
+		platform.hart.write_register(RegisterNames::a1 as usize, 0x1ffc);
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MCAUSE), CAUSE_STORE_PAGE_FAULT);
+		assert_eq!(platform.read::<u32>(low + 0xffc).unwrap(), 0x5566_7788);
+	}
+
+	#[test]
+	fn memory_may_not_overlap_devices()
+	{
+		assert!(Platform::new(0, 0x1000_0000, 1).is_err());
+	}
+
+	#[test]
+	fn platform_needs_a_hart()
+	{
+		assert!(Platform::new(MEMORY_BASE, MEMORY_SIZE, 0).is_err());
+	}
+
+	#[test]
+	fn memory_must_fit_the_address_space()
+	{
+		assert!(Platform::new(usize::MAX - 0xfff, 0x2000, 1).is_err());
+		assert!(Platform::new(CLINT_BASE, MEMORY_SIZE, 1).is_err());
+	}
+
+	#[test]
+	fn store_from_one_hart_breaks_anothers_reservation()
+	{
+		let mut platform = Platform::new(MEMORY_BASE, MEMORY_SIZE, 2).unwrap();
+		let address = MEMORY_BASE as u64 + 0x800;
+		let hart0_program = [
+			0x0062_b023, // sd t1, 0(t0)
+		];
+		let hart1_program = [
+			0x1002_b32f, // lr.d t1, (t0)
+			0x19c2_b3af, // sc.d t2, t3, (t0)
+		];
+		let hart1_start = MEMORY_BASE + 0x100;
+
+		load_program(&mut platform, &hart0_program);
+		for (n, insn) in hart1_program.iter().enumerate() {
+			platform.write(hart1_start + 4 * n, *insn).unwrap();
+		}
+		platform.start_hart(1, hart1_start as u64, 0x1234);
+
+		for id in 0..2 {
+			let hart = platform.hart_by_id_mut(id);
+			hart.write_register(RegisterNames::t0 as usize, address);
+			hart.write_register(RegisterNames::t1 as usize, 0xff);
+		}
+
+		// The harts take turns: hart 1's lr, hart 0's sd, hart 1's sc
+		for _ in 0..3 {
+			platform.step().unwrap();
+		}
+
+		let hart1 = platform.hart_by_id(1);
+		assert_eq!(hart1.read_register(RegisterNames::a0 as usize), 1);
+		assert_eq!(hart1.read_register(RegisterNames::a1 as usize), 0x1234);
+		assert_eq!(hart1.read_register(RegisterNames::t2 as usize), 1);
+		assert_eq!(platform.hart_by_id(0).pc, MEMORY_BASE as u64 + 4);
+		assert_eq!(platform.reservation_stats().invalidated_by_other, 1);
+	}
+
+	#[test]
+	fn harts_have_their_own_ids_and_only_hart0_boots()
+	{
+		let mut platform = Platform::new(MEMORY_BASE, MEMORY_SIZE, 3).unwrap();
+		load_program(&mut platform, &[0x0000_0013; 4]);
+
+		for id in 0..3 {
+			let hart = platform.hart_by_id(id);
+			assert_eq!(hart.id, id);
+			assert_eq!(hart.read_csr(CSR_MHARTID), id as u64);
+		}
+		assert_eq!(platform.capabilities().harts, 3);
+
+		// With the others parked, hart 0 gets every step
+		for _ in 0..4 {
+			platform.step().unwrap();
+		}
+		assert_eq!(platform.hart_by_id(0).pc, MEMORY_BASE as u64 + 16);
+		assert_eq!(platform.hart_by_id(1).pc, 0);
+		assert_eq!(platform.hart_by_id(2).pc, 0);
+	}
+
+	/// Load a riscv-tests style binary, which writes t0 to tohost for
+	/// each of values.
+	fn tohost_platform(values: &[u64]) -> (Platform, SharedBuffer)
+	{
+		let mut platform = Platform::default();
+		let output = SharedBuffer::default();
+		let code_address = MEMORY_BASE as u64;
+		let tohost = code_address + 0x1000;
+		let fromhost = tohost + 0x40;
+		let program: [u32; 4] = [
+			0x0003_3283, // ld t0, 0(t1)
+			0x0053_b023, // sd t0, 0(t2)
+			0x0083_0313, // addi t1, t1, 8
+			0xff5f_f06f, // j -12
+		];
+		let mut code: Vec<u8> =
+			program.iter().flat_map(|insn| return insn.to_le_bytes()).collect();
+		let values_offset = code.len() as u64;
+		code.extend(values.iter().flat_map(|value| return value.to_le_bytes()));
+
+		let blob = elf_with_symbols(
+			code_address,
+			&[(code_address, PF_RWX, &code, 0)],
+			&[("tohost", tohost), ("fromhost", fromhost)],
+		);
+		platform.load_elf(&blob).unwrap();
+		*platform.uart_mut() =
+			Uart::new(Box::new(output.clone()), Box::new(std::io::empty()));
+		let hart = &mut platform.hart;
+		hart.write_register(
+			RegisterNames::t1 as usize,
+			code_address + values_offset,
+		);
+		hart.write_register(RegisterNames::t2 as usize, tohost);
+
+		return (platform, output);
+	}
+
+	#[test]
+	fn tohost_pass_ends_emulation()
+	{
+		let (mut platform, _) = tohost_platform(&[1]);
+
+		assert_eq!(platform.emulate().unwrap(), HaltReason::TestPassed);
+	}
+
+	#[test]
+	fn stores_off_the_top_fault_despite_tohost()
+	{
+		let (mut platform, _) = tohost_platform(&[1]);
+		let program: [u32; 2] = [
+			0xff80_0513, // li a0, -8
+			0x0005_3023, // sd zero, 0(a0)
+		];
+		for (index, insn) in program.into_iter().enumerate() {
+			platform.write(MEMORY_BASE + 4 * index, insn).unwrap();
+		}
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+		platform.step().unwrap();
+		assert_eq!(platform.step().unwrap(), None);
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_STORE_ACCESS_FAULT);
+		assert_eq!(hart.read_csr(CSR_MTVAL), u64::MAX - 7);
+	}
+
+	#[test]
+	fn tohost_failure_reports_test_number()
+	{
+		let (mut platform, _) = tohost_platform(&[3 << 1 | 1]);
+		let error = platform.emulate().unwrap_err();
+
+		assert!(matches!(
+			error,
+			PlatformError::Halt {
+				code: 3
+			}
+		));
+	}
+
+	#[test]
+	fn max_insns_stops_an_infinite_loop()
+	{
+		let mut platform = Platform::default();
+		load_program(&mut platform, &[0x0000_006f]); // j .
+		platform.max_insns = Some(10);
+
+		let mut steps = 0;
+		let error = loop {
+			steps += 1;
+			if let Err(error) = platform.step() {
+				break error;
+			}
+		};
+
+		assert_eq!(steps, 10);
+		assert!(matches!(
+			error,
+			PlatformError::InsnLimitReached {
+				pc,
+				instret: 10,
+			} if pc == MEMORY_BASE as u64
+		));
+	}
+
+	#[test]
+	fn faulting_trap_handler_is_reported()
+	{
+		let mut platform = Platform::default();
+		// The handler is all zeroes, which is an illegal instruction
+		load_program(&mut platform, &[0x0000_0000]);
+		platform.hart.write_csr(CSR_MTVEC, MEMORY_BASE as u64);
+
+		let error = platform.emulate().unwrap_err();
+		assert!(matches!(
+			error,
+			PlatformError::TrapLoop {
+				cause: CAUSE_ILLEGAL_INSN,
+				epc,
+			} if epc == MEMORY_BASE as u64
+		));
+		let minstret = platform.hart.read_csr(CSR_MINSTRET);
+		assert_eq!(minstret, 0);
+	}
+
+	#[test]
+	fn run_until_stops_at_the_target()
+	{
+		let mut platform = Platform::default();
+		let program = [
+			0x0050_0293, // li t0, 5
+			0xfff2_8293, // addi t0, t0, -1
+			0xfe02_9ee3, // bnez t0, -4
+			0x0000_0013, // nop
+		];
+		let target = MEMORY_BASE as u64 + 12;
+
+		load_program(&mut platform, &program);
+		// Not enough to get round the loop
+		assert_eq!(
+			platform.run_until(target, 4).unwrap(),
+			HaltReason::InstructionLimit
+		);
+
+		assert_eq!(
+			platform.run_until(target, 100).unwrap(),
+			HaltReason::Reached(target)
+		);
+		assert_eq!(platform.hart.pc, target);
+		assert_eq!(platform.hart.read_register(RegisterNames::t0 as usize), 0);
+		assert_eq!(platform.hart.read_csr(CSR_MINSTRET), 11);
+	}
+
+	#[test]
+	fn breakpoints_stop_before_the_instruction_runs()
+	{
+		let mut platform = Platform::default();
+		let breakpoint = MEMORY_BASE as u64 + 4;
+		load_program(&mut platform, &[0x0000_0013; 3]); // nop
+		platform.add_breakpoint(breakpoint);
+
+		assert_eq!(platform.step().unwrap(), None);
+		assert_eq!(
+			platform.step().unwrap(),
+			Some(HaltReason::Breakpoint(breakpoint))
+		);
+		assert_eq!(platform.hart.pc, breakpoint);
+		assert_eq!(platform.hart.read_csr(CSR_MINSTRET), 1);
+
+		// Carrying on runs the instruction, rather than stopping again
+		assert_eq!(platform.step().unwrap(), None);
+		assert_eq!(platform.hart.pc, breakpoint + 4);
+
+		assert!(platform.remove_breakpoint(breakpoint));
+		assert!(!platform.remove_breakpoint(breakpoint));
+	}
+
+	#[test]
+	fn stores_into_a_watched_range_stop()
+	{
+		let mut platform = Platform::default();
+		let base = MEMORY_BASE as u64 + 0x100;
+		let program = [
+			0x0000_0013, // nop
+			0x00a5_a223, // sw a0, 4(a1)
+		];
+
+		load_program(&mut platform, &program);
+		platform.hart.write_register(10_usize, 0x1234_5678);
+		platform.hart.write_register(11_usize, base);
+		// Only the top half of the word is watched, & not for reads
+		platform.add_watchpoint(base + 6, 2, WatchKind::Write);
+		platform.add_watchpoint(base, 8, WatchKind::Read);
+
+		assert_eq!(platform.step().unwrap(), None);
+		assert_eq!(
+			platform.step().unwrap(),
+			Some(HaltReason::Watchpoint(base + 4))
+		);
+		// The store went ahead, & the sw retired
+		assert_eq!(
+			platform.read::<u32>(base as usize + 4).unwrap(),
+			0x1234_5678
+		);
+		assert_eq!(platform.hart.pc, MEMORY_BASE as u64 + 8);
+	}
+
+	#[test]
+	fn tohost_console_writes_reach_the_uart()
+	{
+		let putchar = |c: u8| return 1 << 56 | 1 << 48 | c as u64;
+		let (mut platform, output) =
+			tohost_platform(&[putchar(b'o'), putchar(b'k'), 1]);
+
+		assert_eq!(platform.emulate().unwrap(), HaltReason::TestPassed);
+		assert_eq!(*output.0.borrow(), b"ok");
+		// Each request was acknowledged through fromhost
+		let fromhost = MEMORY_BASE + 0x1040;
+		assert_eq!(platform.read::<u64>(fromhost).unwrap(), putchar(1));
+	}
+
+	#[test]
+	fn relocatable_blob_runs_from_its_load_address()
+	{
+		let mut platform = Platform::default();
+		let base = MEMORY_BASE + 0x12_3400;
+		let program: [u32; 2] = [
+			0x0000_0297, // auipc t0, 0
+			0x0050_0513, // li a0, 5
+		];
+		let blob: Vec<u8> =
+			program.iter().flat_map(|insn| return insn.to_le_bytes()).collect();
+
+		platform.load_relocatable(blob, base).unwrap();
+		assert_eq!(platform.relocation_base(), Some(base));
+		assert_eq!(platform.hart.pc, base as u64);
+
+		platform.step().unwrap();
+		platform.step().unwrap();
+		let hart = &platform.hart;
+		assert_eq!(hart.read_register(RegisterNames::t0 as usize), base as u64);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 5);
+	}
+
+	#[test]
+	fn elf_segments_load_at_their_physical_address()
+	{
+		let mut platform = Platform::default();
+		let address = MEMORY_BASE + 0x20_0000;
+		let code: [u32; 2] = [
+			0x0000_0013, // nop
+			0x0050_0513, // li a0, 5
+		];
+		let code: Vec<u8> =
+			code.iter().flat_map(|insn| return insn.to_le_bytes()).collect();
+
+		// Leave something behind for the bss to clear
+		platform.write(address + 8, u64::MAX).unwrap();
+
+		let blob = minimal_elf(address as u64, address as u64 + 4, &code, 8);
+		platform.load_elf(&blob).unwrap();
+
+		assert_eq!(platform.hart.pc, address as u64 + 4);
+		assert_eq!(platform.read::<u32>(address + 4).unwrap(), 0x0050_0513);
+		assert_eq!(platform.read::<u64>(address + 8).unwrap(), 0);
+
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_register(RegisterNames::a0 as usize), 5);
+	}
+
+	#[test]
+	fn jump_into_data_segment_faults()
+	{
+		let mut platform = Platform::default();
+		let code_address = MEMORY_BASE + 0x20_0000;
+		let data_address = code_address + 0x1000;
+		let code = 0x0000_106f_u32.to_le_bytes(); // j 0x1000
+		let data = 0x0050_0513_u32.to_le_bytes(); // li a0, 5
+
+		let blob = elf_with_segments(
+			code_address as u64,
+			&[
+				(code_address as u64, PF_RWX, &code, 0),
+				(data_address as u64, PF_RW, &data, 0),
+			],
+		);
+		platform.load_elf(&blob).unwrap();
+		platform.hart.write_csr(CSR_MTVEC, 0x8000_1000);
+
+		platform.step().unwrap();
+		assert!(platform.diagnostics().is_empty());
+		platform.step().unwrap();
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, 0x8000_1000);
+		assert_eq!(hart.read_csr(CSR_MCAUSE), CAUSE_INSN_ACCESS_FAULT);
+		assert_eq!(hart.read_csr(CSR_MEPC), data_address as u64);
+		assert_eq!(hart.read_csr(CSR_MTVAL), data_address as u64);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 0);
+
+		// Faulting on the same region again isn't reported again
+		platform.hart.write_csr(CSR_MTVAL, 0);
+		platform.hart.pc = data_address as u64;
+		platform.step().unwrap();
+		assert_eq!(platform.hart.read_csr(CSR_MTVAL), data_address as u64);
+
+		let diagnostics = platform.diagnostics();
+		assert_eq!(diagnostics.len(), 1);
+		assert!(diagnostics[0].contains("elf segment 1"));
+		assert!(diagnostics[0].contains(&format!("{:#x}", data_address)));
+	}
+
+	#[test]
+	fn loads_outside_memory_are_rejected()
+	{
+		let mut platform = Platform::default();
+
+		let error =
+			platform.load_kernel(vec![0; 4], 0x1000, 0x1000).unwrap_err();
+		assert!(matches!(
+			error,
+			PlatformError::LoadAddressOutOfBounds {
+				address: 0x1000
+			}
+		));
+
+		let end = MEMORY_BASE + MEMORY_SIZE;
+		let error = platform.load_kernel(vec![0; 8], end - 4, end).unwrap_err();
+		assert!(matches!(
+			error,
+			PlatformError::InsufficientMemory {
+				size: 8,
+				..
+			}
+		));
+
+		// Turned away before any room is made for the bss
+		let bss_size = 1 << 40;
+		let blob = minimal_elf(MEMORY_BASE as u64, 0, &[0; 4], bss_size);
+		let error = platform.load_elf(&blob).unwrap_err();
+		assert!(matches!(
+			error,
+			PlatformError::InsufficientMemory {
+				size,
+				..
+			} if size == 4 + bss_size as usize
+		));
+	}
+
+	#[test]
+	fn initrd_loads_alongside_the_kernel()
+	{
+		let mut platform = Platform::default();
+		let initrd_address = MEMORY_BASE + 0x20_0000;
+		let initrd = vec![0xa5; 0x1800];
+
+		load_program(&mut platform, &[0x0000_006f]); // j .
+		platform.load_initrd(initrd.clone(), initrd_address).unwrap();
+
+		assert_eq!(
+			platform.initrd(),
+			Some((initrd_address, initrd_address + initrd.len()))
+		);
+		let memory = &platform.memory().memory;
+		let offset = initrd_address - MEMORY_BASE;
+		assert_eq!(memory[offset..offset + initrd.len()], initrd[..]);
+		assert_eq!(memory[..4], 0x0000_006f_u32.to_le_bytes());
+
+		let dtb = platform.generate_dtb();
+		crate::dtb::validate(&dtb).unwrap();
+		let end = (initrd_address + initrd.len()) as u32;
+		assert!(dtb.windows(4).any(|cells| return cells == end.to_be_bytes()));
+	}
+
+	#[test]
+	fn initrd_may_not_overlap_other_loads()
+	{
+		let mut platform = Platform::default();
+		let initrd = vec![0xa5; 0x1800];
+
+		// Whichever comes first, the second is rejected
+		load_program(&mut platform, &[0x0000_006f]); // j .
+		let error = platform.load_initrd(initrd.clone(), MEMORY_BASE);
+		assert!(matches!(error, Err(PlatformError::InitrdOverlap { .. })));
+		assert_eq!(platform.initrd(), None);
+
+		platform.reset();
+		platform.load_initrd(initrd, MEMORY_BASE + 0x1000).unwrap();
+		let error = platform.load_dtb(minimal_fdt(), MEMORY_BASE + 0x2000);
+		assert!(matches!(
+			error,
+			Err(PlatformError::InitrdOverlap {
+				address: 0x8000_2000,
+				..
+			})
+		));
+
+		// Nor can it run off the end of memory
+		let end = MEMORY_BASE + MEMORY_SIZE;
+		let error = platform.load_initrd(vec![0; 0x10], end - 8);
+		assert!(matches!(error, Err(PlatformError::InsufficientMemory { .. })));
+	}
+
+	#[test]
+	fn reset_trampoline_jumps_to_the_entry_point()
+	{
+		let mut platform = Platform::default();
+		let entry = MEMORY_BASE + 0x20_0000;
+		let dtb = MEMORY_BASE + 0x10_0000;
+
+		platform.load_kernel(vec![0x6f, 0, 0, 0], entry, entry).unwrap();
+		platform
+			.install_reset_trampoline(RESET_ADDRESS, entry as u64, dtb as u64)
+			.unwrap();
+		assert_eq!(platform.hart.pc, RESET_ADDRESS as u64);
+
+		for _ in 0..5 {
+			platform.step().unwrap();
+		}
+
+		let hart = &platform.hart;
+		assert_eq!(hart.pc, entry as u64);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 0);
+		assert_eq!(hart.read_register(RegisterNames::a1 as usize), dtb as u64);
+		assert!(platform.write(RESET_ADDRESS, 0_u32).is_err());
+	}
+
+	#[test]
+	fn load_dtb_rejects_non_fdt_blobs()
+	{
+		let mut platform = Platform::default();
+		let dtb_address = MEMORY_BASE + 0x10_0000;
+
+		// Something the size of a kernel image, say
+		let error =
+			platform.load_dtb(vec![0x13; 0x1000], dtb_address).unwrap_err();
+		assert!(error.to_string().contains("bad magic"));
+
+		platform.load_dtb(minimal_fdt(), dtb_address).unwrap();
+		assert_eq!(
+			platform.hart.read_register(RegisterNames::a1 as usize),
+			dtb_address as u64
+		);
+	}
+
+	#[test]
+	fn generated_dtb_describes_the_configured_memory()
+	{
+		let memory_size = 0x0123_4000;
+		let mut platform = Platform::new(MEMORY_BASE, memory_size, 2).unwrap();
+
+		let dtb = platform.generate_dtb();
+		crate::dtb::validate(&dtb).unwrap();
+
+		let contains = |needle: &[u8]| {
+			return dtb
+				.windows(needle.len())
+				.any(|window| return window == needle);
+		};
+		assert!(contains(b"memory@80000000\0"));
+		assert!(contains(b"cpu@1\0"));
+		let reg: Vec<u8> = [0_u32, MEMORY_BASE as u32, 0, memory_size as u32]
+			.iter()
+			.flat_map(|cell| return cell.to_be_bytes())
+			.collect();
+		assert!(contains(&reg));
+
+		let dtb_address = MEMORY_BASE + 0x10_0000;
+		platform.load_dtb(dtb, dtb_address).unwrap();
+	}
 }
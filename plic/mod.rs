@@ -0,0 +1,362 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::{self, Bus};
+use crate::lebytes::LeBytes;
+use crate::snapshot::{Decoder, Encoder};
+use std::cell::Cell;
+use std::error::Error;
+
+pub const PLIC_BASE: usize = 0x0c00_0000;
+pub const PLIC_SIZE: usize = 0x40_0000;
+
+// Interrupt sources, numbered as on QEMU's virt machine. Source 0 is reserved
+// to mean "no interrupt".
+pub const VIRTIO_IRQ: usize = 1;
+pub const UART_IRQ: usize = 10;
+pub const RTC_IRQ: usize = 11;
+const SOURCES: usize = 32;
+
+// The layout used by the SiFive PLIC, which everyone else copies. The
+// registers are offsets from PLIC_BASE & are all 32 bits wide. There's one
+// priority per source, a bit per source in the pending & enable words and a
+// threshold & claim/complete register per context. Each hart has a single
+// context, for M-mode.
+const PRIORITY_OFFSET: usize = 0x0;
+const PENDING_OFFSET: usize = 0x1000;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD_OFFSET: usize = 0x0;
+const CLAIM_OFFSET: usize = 0x4;
+
+// Priorities & thresholds run from 0 to 7, priority 0 never interrupting
+const PRIORITY_MASK: u32 = 0b111;
+
+/// Platform-level interrupt controller, routing device interrupts to the
+/// harts' external interrupt lines.
+#[derive(Debug)]
+pub struct Plic
+{
+	pub priority: [u32; SOURCES],
+	pub enable: Vec<u32>,
+	pub threshold: Vec<u32>,
+	// Claiming an interrupt is done by reading, but bus reads only get a
+	// shared reference to the device.
+	pending: Cell<u32>,
+	claimed: Cell<u32>,
+}
+
+impl Default for Plic
+{
+	fn default() -> Plic
+	{
+		return Plic::new(1);
+	}
+}
+
+impl Plic
+{
+	/// A PLIC with a context for each of harts harts. Everything starts
+	/// masked, with priorities, enables & thresholds all zero.
+	pub fn new(harts: usize) -> Plic
+	{
+		return Plic {
+			priority: [0; SOURCES],
+			enable: vec![0; harts],
+			threshold: vec![0; harts],
+			pending: Cell::new(0),
+			claimed: Cell::new(0),
+		};
+	}
+
+	/// Drive a source's interrupt line. A source that has been claimed
+	/// doesn't become pending again until its handler completes.
+	pub fn set_level(&mut self, source: usize, level: bool)
+	{
+		let bit = 1 << source;
+		let pending = self.pending.get();
+
+		if !level {
+			self.pending.set(pending & !bit);
+		} else if self.claimed.get() & bit == 0 {
+			self.pending.set(pending | bit);
+		}
+	}
+
+	/// The pending source a context should be interrupted by, which is
+	/// the enabled one with the highest priority above the context's
+	/// threshold. Ties go to the lowest numbered source.
+	fn highest_pending(&self, context: usize) -> Option<usize>
+	{
+		let candidates = self.pending.get() & self.enable[context];
+		let mut highest = None;
+		let mut highest_priority = self.threshold[context];
+
+		for source in 1..SOURCES {
+			let priority = self.priority[source];
+			if candidates & (1 << source) != 0 && priority > highest_priority {
+				highest = Some(source);
+				highest_priority = priority;
+			}
+		}
+
+		return highest;
+	}
+
+	/// Whether a context's external interrupt line is asserted.
+	pub fn interrupt_pending(&self, context: usize) -> bool
+	{
+		return self.highest_pending(context).is_some();
+	}
+
+	/// Claim the interrupt a context should handle, returning 0 if there
+	/// isn't one.
+	fn claim(&self, context: usize) -> u32
+	{
+		let Some(source) = self.highest_pending(context) else {
+			return 0;
+		};
+
+		let bit = 1 << source;
+		self.pending.set(self.pending.get() & !bit);
+		self.claimed.set(self.claimed.get() | bit);
+
+		return source as u32;
+	}
+
+	/// Signal that a context has finished handling source. Completing a
+	/// source that isn't enabled for the context is ignored.
+	fn complete(&mut self, context: usize, source: u32)
+	{
+		let source = source as usize;
+		if source >= SOURCES || self.enable[context] & (1 << source) == 0 {
+			return;
+		}
+
+		self.claimed.set(self.claimed.get() & !(1 << source));
+	}
+
+	pub fn save(&self, out: &mut Encoder)
+	{
+		for priority in self.priority {
+			out.u64(priority as u64);
+		}
+		out.u64(self.enable.len() as u64);
+		for context in 0..self.enable.len() {
+			out.u64(self.enable[context] as u64);
+			out.u64(self.threshold[context] as u64);
+		}
+		out.u64(self.pending.get() as u64);
+		out.u64(self.claimed.get() as u64);
+	}
+
+	pub fn restore(&mut self, input: &mut Decoder)
+		-> Result<(), Box<dyn Error>>
+	{
+		for priority in &mut self.priority {
+			*priority = input.u64()? as u32;
+		}
+		input.expect("contexts", self.enable.len())?;
+		for context in 0..self.enable.len() {
+			self.enable[context] = input.u64()? as u32;
+			self.threshold[context] = input.u64()? as u32;
+		}
+		self.pending.set(input.u64()? as u32);
+		self.claimed.set(input.u64()? as u32);
+
+		return Ok(());
+	}
+
+	fn locate(&self, address: usize) -> Option<Register>
+	{
+		let contexts = self.enable.len();
+
+		if address < PENDING_OFFSET {
+			let source = (address - PRIORITY_OFFSET) / 4;
+			if source < SOURCES {
+				return Some(Register::Priority(source));
+			}
+			return None;
+		}
+
+		if address == PENDING_OFFSET {
+			return Some(Register::Pending);
+		}
+
+		if (ENABLE_OFFSET..CONTEXT_OFFSET).contains(&address) {
+			let context = (address - ENABLE_OFFSET) / ENABLE_STRIDE;
+			let offset = (address - ENABLE_OFFSET) % ENABLE_STRIDE;
+			if context < contexts && offset == 0 {
+				return Some(Register::Enable(context));
+			}
+			return None;
+		}
+
+		if address >= CONTEXT_OFFSET {
+			let context = (address - CONTEXT_OFFSET) / CONTEXT_STRIDE;
+			if context >= contexts {
+				return None;
+			}
+
+			match (address - CONTEXT_OFFSET) % CONTEXT_STRIDE {
+				THRESHOLD_OFFSET => return Some(Register::Threshold(context)),
+				CLAIM_OFFSET => return Some(Register::Claim(context)),
+				_ => return None,
+			}
+		}
+
+		return None;
+	}
+}
+
+enum Register
+{
+	Priority(usize),
+	Pending,
+	Enable(usize),
+	Threshold(usize),
+	Claim(usize),
+}
+
+/// Every register is 32 bits wide & must be accessed as a whole.
+fn check_access(
+	location: Option<Register>, address: usize, size: usize,
+) -> Result<Register, bus::Error>
+{
+	let Some(register) = location else {
+		return Err(bus::Error::new(
+			bus::ErrorKind::OutOfBounds,
+			&format!("plic offset: {:x}", address),
+		));
+	};
+
+	if size != 4 || address % 4 != 0 {
+		return Err(bus::Error::new(
+			bus::ErrorKind::Unimplemented,
+			&format!("plic access of {} bytes at {:x}", size, address),
+		));
+	}
+
+	return Ok(register);
+}
+
+impl Bus for Plic
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let size = <T as LeBytes>::SIZE;
+		let value = match check_access(self.locate(address), address, size)? {
+			Register::Priority(source) => self.priority[source],
+			Register::Pending => self.pending.get(),
+			Register::Enable(context) => self.enable[context],
+			Register::Threshold(context) => self.threshold[context],
+			Register::Claim(context) => self.claim(context),
+		};
+
+		return Ok(T::from_le_bytes(
+			value.to_le_bytes()[..size].try_into().unwrap(),
+		));
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address = address.into();
+		let size = <T as LeBytes>::SIZE;
+		let register = check_access(self.locate(address), address, size)?;
+		let mut bytes = [0_u8; 4];
+		bytes.copy_from_slice(&value.to_le_bytes()[..4]);
+		let value = u32::from_le_bytes(bytes);
+
+		match register {
+			// Source 0 doesn't exist, so neither does its priority
+			Register::Priority(0) => (),
+			Register::Priority(source) => {
+				self.priority[source] = value & PRIORITY_MASK;
+			},
+			// Pending bits are read-only, set by the sources themselves
+			Register::Pending => (),
+			Register::Enable(context) => self.enable[context] = value & !1,
+			Register::Threshold(context) => {
+				self.threshold[context] = value & PRIORITY_MASK;
+			},
+			Register::Claim(context) => self.complete(context, value),
+		}
+
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		Plic, CLAIM_OFFSET, CONTEXT_OFFSET, ENABLE_OFFSET, PENDING_OFFSET,
+		PRIORITY_OFFSET, UART_IRQ,
+	};
+	use crate::bus::Bus;
+
+	const CLAIM: usize = CONTEXT_OFFSET + CLAIM_OFFSET;
+
+	fn enable(plic: &mut Plic, source: usize, priority: u32)
+	{
+		let enabled = plic.enable[0];
+		plic.write(PRIORITY_OFFSET + 4 * source, priority).unwrap();
+		plic.write(ENABLE_OFFSET, enabled | 1 << source).unwrap();
+	}
+
+	#[test]
+	fn claims_go_to_the_highest_priority_above_threshold()
+	{
+		let mut plic = Plic::default();
+		enable(&mut plic, 1, 2);
+		enable(&mut plic, 2, 5);
+		plic.set_level(1, true);
+		plic.set_level(2, true);
+
+		plic.write(CONTEXT_OFFSET, 5_u32).unwrap();
+		assert!(!plic.interrupt_pending(0));
+		assert_eq!(plic.read::<u32>(CLAIM).unwrap(), 0);
+
+		plic.write(CONTEXT_OFFSET, 1_u32).unwrap();
+		assert_eq!(plic.read::<u32>(PENDING_OFFSET).unwrap(), 0b110);
+		assert_eq!(plic.read::<u32>(CLAIM).unwrap(), 2);
+		assert_eq!(plic.read::<u32>(CLAIM).unwrap(), 1);
+		assert_eq!(plic.read::<u32>(CLAIM).unwrap(), 0);
+	}
+
+	#[test]
+	fn claimed_sources_wait_for_completion()
+	{
+		let mut plic = Plic::default();
+		enable(&mut plic, UART_IRQ, 1);
+		plic.set_level(UART_IRQ, true);
+		assert_eq!(plic.read::<u32>(CLAIM).unwrap(), UART_IRQ as u32);
+
+		// Still asserting its line, but already being handled
+		plic.set_level(UART_IRQ, true);
+		assert!(!plic.interrupt_pending(0));
+
+		plic.write(CLAIM, UART_IRQ as u32).unwrap();
+		plic.set_level(UART_IRQ, true);
+		assert!(plic.interrupt_pending(0));
+	}
+
+	#[test]
+	fn accesses_must_be_whole_registers()
+	{
+		let mut plic = Plic::default();
+		assert!(plic.read::<u8>(PRIORITY_OFFSET + 4).is_err());
+		assert!(plic.write(PRIORITY_OFFSET + 4, 1_u64).is_err());
+		assert!(plic.read::<u32>(CONTEXT_OFFSET + 0x1000).is_err());
+	}
+}
@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::{self, Bus};
+use crate::lebytes::LeBytes;
+use crate::snapshot::{Decoder, Encoder};
+use std::cell::Cell;
+use std::error::Error;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Where QEMU's virt machine puts its goldfish RTC, so the same device trees
+// work.
+pub const RTC_BASE: usize = 0x0010_1000;
+pub const RTC_SIZE: usize = 0x1000;
+
+// The goldfish RTC's registers, all 32 bits wide. Times are nanoseconds
+// since the epoch, split into two halves. Reading the low half of the time
+// latches the high half, & writing the low half of the time or the alarm
+// commits the high half written before it.
+const TIME_LOW_OFFSET: usize = 0x00;
+const TIME_HIGH_OFFSET: usize = 0x04;
+const ALARM_LOW_OFFSET: usize = 0x08;
+const ALARM_HIGH_OFFSET: usize = 0x0c;
+const IRQ_ENABLED_OFFSET: usize = 0x10;
+const CLEAR_ALARM_OFFSET: usize = 0x14;
+const ALARM_STATUS_OFFSET: usize = 0x18;
+const CLEAR_INTERRUPT_OFFSET: usize = 0x1c;
+
+/// A goldfish RTC, telling the host's wall-clock time, or whatever the
+/// guest has set it to since.
+#[derive(Debug)]
+pub struct Rtc
+{
+	/// The host's time when the RTC was created, along with when that
+	/// was, so that time never goes backwards while the guest is running
+	epoch: u64,
+	started: Instant,
+	/// What the guest set the time to, relative to the host's
+	offset: u64,
+	time_high: Cell<u32>,
+	alarm: u64,
+	alarm_high: u32,
+	alarm_running: bool,
+	irq_enabled: bool,
+	irq_pending: bool,
+}
+
+impl Default for Rtc
+{
+	fn default() -> Rtc
+	{
+		return Rtc::new();
+	}
+}
+
+impl Rtc
+{
+	pub fn new() -> Rtc
+	{
+		// Before 1970 or after 2554, the RTC starts at zero instead
+		let epoch = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |since| return since.as_nanos() as u64);
+
+		return Rtc {
+			epoch,
+			started: Instant::now(),
+			offset: 0,
+			time_high: Cell::new(0),
+			alarm: 0,
+			alarm_high: 0,
+			alarm_running: false,
+			irq_enabled: false,
+			irq_pending: false,
+		};
+	}
+
+	/// Put the registers back as they were at power on, along with the
+	/// time.
+	pub fn reset(&mut self)
+	{
+		*self = Rtc::new();
+	}
+
+	/// The time, in nanoseconds since the epoch.
+	pub fn time(&self) -> u64
+	{
+		let elapsed = self.started.elapsed().as_nanos() as u64;
+		return self.epoch.wrapping_add(elapsed).wrapping_add(self.offset);
+	}
+
+	fn set_time(&mut self, time: u64)
+	{
+		self.offset = self.offset.wrapping_add(time.wrapping_sub(self.time()));
+	}
+
+	/// Fire the alarm if its time has come.
+	pub fn poll(&mut self)
+	{
+		if self.alarm_running && self.time() >= self.alarm {
+			self.alarm_running = false;
+			self.irq_pending = true;
+		}
+	}
+
+	pub fn interrupt_pending(&self) -> bool
+	{
+		return self.irq_pending && self.irq_enabled;
+	}
+
+	/// Save the RTC for a snapshot. The time itself comes from the host,
+	/// so only any change the guest has made to it is kept.
+	pub fn save(&self, out: &mut Encoder)
+	{
+		out.u64(self.offset);
+		out.u64(self.time_high.get() as u64);
+		out.u64(self.alarm);
+		out.u64(self.alarm_high as u64);
+		out.u64(self.alarm_running as u64);
+		out.u64(self.irq_enabled as u64);
+		out.u64(self.irq_pending as u64);
+	}
+
+	pub fn restore(&mut self, input: &mut Decoder)
+		-> Result<(), Box<dyn Error>>
+	{
+		self.offset = input.u64()?;
+		self.time_high.set(input.u64()? as u32);
+		self.alarm = input.u64()?;
+		self.alarm_high = input.u64()? as u32;
+		self.alarm_running = input.bool()?;
+		self.irq_enabled = input.bool()?;
+		self.irq_pending = input.bool()?;
+
+		return Ok(());
+	}
+}
+
+fn check_access(address: usize, size: usize) -> Result<(), bus::Error>
+{
+	if size != 4 || address % 4 != 0 || address > CLEAR_INTERRUPT_OFFSET {
+		return Err(bus::Error::new(
+			bus::ErrorKind::OutOfBounds,
+			&format!("rtc access of {} bytes: {:x}", size, address),
+		));
+	}
+
+	return Ok(());
+}
+
+impl Bus for Rtc
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let size = <T as LeBytes>::SIZE;
+		check_access(address, size)?;
+
+		let value = match address {
+			TIME_LOW_OFFSET => {
+				let time = self.time();
+				self.time_high.set((time >> 32) as u32);
+				time as u32
+			},
+			TIME_HIGH_OFFSET => self.time_high.get(),
+			ALARM_LOW_OFFSET => self.alarm as u32,
+			ALARM_HIGH_OFFSET => (self.alarm >> 32) as u32,
+			IRQ_ENABLED_OFFSET => self.irq_enabled as u32,
+			ALARM_STATUS_OFFSET => self.alarm_running as u32,
+			// The clearing registers are write-only
+			_ => 0,
+		};
+
+		let bytes = value.to_le_bytes();
+		return Ok(T::from_le_bytes(bytes[..size].try_into().unwrap()));
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address = address.into();
+		check_access(address, <T as LeBytes>::SIZE)?;
+
+		let bytes = value.to_le_bytes();
+		let value = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+		match address {
+			TIME_LOW_OFFSET => {
+				let high = self.time_high.get() as u64;
+				self.set_time(high << 32 | value as u64);
+			},
+			TIME_HIGH_OFFSET => self.time_high.set(value),
+			ALARM_LOW_OFFSET => {
+				self.alarm = (self.alarm_high as u64) << 32 | value as u64;
+				self.alarm_running = true;
+			},
+			ALARM_HIGH_OFFSET => self.alarm_high = value,
+			IRQ_ENABLED_OFFSET => self.irq_enabled = value & 1 != 0,
+			CLEAR_ALARM_OFFSET => self.alarm_running = false,
+			CLEAR_INTERRUPT_OFFSET => self.irq_pending = false,
+			// Alarm status is read-only
+			_ => (),
+		}
+
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		Rtc, ALARM_HIGH_OFFSET, ALARM_LOW_OFFSET, ALARM_STATUS_OFFSET,
+		CLEAR_INTERRUPT_OFFSET, IRQ_ENABLED_OFFSET, TIME_HIGH_OFFSET,
+		TIME_LOW_OFFSET,
+	};
+	use crate::bus::Bus;
+	use std::time::Duration;
+
+	fn read_time(rtc: &Rtc) -> u64
+	{
+		let low = rtc.read::<u32>(TIME_LOW_OFFSET).unwrap() as u64;
+		let high = rtc.read::<u32>(TIME_HIGH_OFFSET).unwrap() as u64;
+		return high << 32 | low;
+	}
+
+	#[test]
+	fn time_goes_forwards()
+	{
+		let rtc = Rtc::new();
+
+		let before = read_time(&rtc);
+		std::thread::sleep(Duration::from_millis(1));
+		let after = read_time(&rtc);
+
+		assert!(after > before);
+		// Some time after 2020
+		assert!(before > 1_577_836_800 * 1_000_000_000);
+	}
+
+	#[test]
+	fn time_can_be_set()
+	{
+		let mut rtc = Rtc::new();
+
+		rtc.write(TIME_HIGH_OFFSET, 0_u32).unwrap();
+		rtc.write(TIME_LOW_OFFSET, 1000_u32).unwrap();
+
+		let time = read_time(&rtc);
+		assert!((1000..1_000_000_000).contains(&time));
+	}
+
+	#[test]
+	fn alarm_interrupts_once_enabled()
+	{
+		let mut rtc = Rtc::new();
+
+		// An alarm in the past goes off straight away
+		rtc.write(ALARM_HIGH_OFFSET, 0_u32).unwrap();
+		rtc.write(ALARM_LOW_OFFSET, 1_u32).unwrap();
+		assert_eq!(rtc.read::<u32>(ALARM_STATUS_OFFSET).unwrap(), 1);
+
+		rtc.poll();
+		assert_eq!(rtc.read::<u32>(ALARM_STATUS_OFFSET).unwrap(), 0);
+		assert!(!rtc.interrupt_pending());
+
+		rtc.write(IRQ_ENABLED_OFFSET, 1_u32).unwrap();
+		assert!(rtc.interrupt_pending());
+
+		rtc.write(CLEAR_INTERRUPT_OFFSET, 1_u32).unwrap();
+		assert!(!rtc.interrupt_pending());
+	}
+}
@@ -0,0 +1,158 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::hart::{HartState, RegisterNames, Xlen};
+use crate::platform::Platform;
+
+// The SBI is the interface S-mode software, like Linux, uses to ask the M-mode
+// firmware beneath it for things. There's no firmware here, so ecalls from
+// S-mode are serviced by the emulator instead. The extension ID goes in a7 &
+// the function ID in a6, with the error & value coming back in a0 & a1.
+pub const EID_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+// The legacy extensions, from before the SBI had versions, only return a0
+const EID_LEGACY_LAST: u64 = 0x0f;
+pub const EID_TIME: u64 = 0x5449_4d45;
+pub const EID_HSM: u64 = 0x0048_534d;
+
+const FID_SET_TIMER: u64 = 0;
+const FID_HART_START: u64 = 0;
+
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// Service an ecall made from S-mode, as the SBI firmware would.
+pub fn handle(platform: &mut Platform)
+{
+	let hart = &platform.hart;
+	let eid = hart.read_register(RegisterNames::a7 as usize);
+	let fid = hart.read_register(RegisterNames::a6 as usize);
+	let a0 = hart.read_register(RegisterNames::a0 as usize);
+	let a1 = hart.read_register(RegisterNames::a1 as usize);
+	let a2 = hart.read_register(RegisterNames::a2 as usize);
+
+	let (error, value) = match (eid, fid) {
+		(EID_LEGACY_CONSOLE_PUTCHAR, _) => {
+			platform.uart_mut().console_write(a0 as u8);
+			(SBI_SUCCESS, 0)
+		},
+
+		(EID_TIME, FID_SET_TIMER) => {
+			// RV32 splits the 64-bit deadline across a0 & a1
+			let deadline = match hart.xlen {
+				Xlen::Rv32 => hart.zero_extend(a0) | a1 << 32,
+				Xlen::Rv64 => a0,
+			};
+			platform.set_supervisor_timer(deadline);
+			(SBI_SUCCESS, 0)
+		},
+
+		(EID_HSM, FID_HART_START) => {
+			let id = a0 as usize;
+			if id >= platform.hart_count() {
+				(SBI_ERR_INVALID_PARAM, 0)
+			} else if platform.hart_by_id(id).state == HartState::Started {
+				(SBI_ERR_ALREADY_AVAILABLE, 0)
+			} else {
+				platform.start_hart(id, a1, a2);
+				(SBI_SUCCESS, 0)
+			}
+		},
+
+		_ => (SBI_ERR_NOT_SUPPORTED, 0),
+	};
+
+	let hart = &mut platform.hart;
+	hart.write_register(RegisterNames::a0 as usize, error as u64);
+	if eid > EID_LEGACY_LAST {
+		hart.write_register(RegisterNames::a1 as usize, value);
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		handle, EID_HSM, EID_LEGACY_CONSOLE_PUTCHAR, EID_TIME,
+		SBI_ERR_ALREADY_AVAILABLE, SBI_ERR_INVALID_PARAM,
+		SBI_ERR_NOT_SUPPORTED, SBI_SUCCESS,
+	};
+	use crate::hart::{
+		HartState, RegisterNames, Xlen, CSR_MIP, MIP_STIP, PRIV_SUPERVISOR,
+	};
+	use crate::platform::{Platform, MEMORY_BASE, MEMORY_SIZE};
+
+	fn ecall(platform: &mut Platform, eid: u64, fid: u64, args: [u64; 2])
+	{
+		let hart = &mut platform.hart;
+		hart.write_register(RegisterNames::a7 as usize, eid);
+		hart.write_register(RegisterNames::a6 as usize, fid);
+		hart.write_register(RegisterNames::a0 as usize, args[0]);
+		hart.write_register(RegisterNames::a1 as usize, args[1]);
+		handle(platform);
+	}
+
+	#[test]
+	fn set_timer_programs_mtimecmp()
+	{
+		let mut platform = Platform::default();
+		platform.hart.set_pending(MIP_STIP, true);
+		ecall(&mut platform, EID_TIME, 0, [0x1234_5678, 0]);
+		assert_eq!(platform.clint().mtimecmp[0], 0x1234_5678);
+		let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+		assert_eq!(a0 as i64, SBI_SUCCESS);
+		// Setting the next deadline acknowledges the last
+		assert_eq!(platform.hart.read_csr(CSR_MIP) & MIP_STIP, 0);
+
+		platform.set_xlen(Xlen::Rv32);
+		ecall(&mut platform, EID_TIME, 0, [0xffff_ffff_8000_0000, 1]);
+		assert_eq!(platform.clint().mtimecmp[0], 0x1_8000_0000);
+	}
+
+	#[test]
+	fn hart_start_starts_a_stopped_hart()
+	{
+		let mut platform = Platform::new(MEMORY_BASE, MEMORY_SIZE, 2).unwrap();
+		let a0 = |platform: &Platform| {
+			let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+			return a0 as i64;
+		};
+
+		ecall(&mut platform, EID_HSM, 0, [1, 0x8020_0000]);
+		assert_eq!(a0(&platform), SBI_SUCCESS);
+		let hart = platform.hart_by_id(1);
+		assert_eq!(hart.state, HartState::Started);
+		assert_eq!(hart.pc, 0x8020_0000);
+		assert_eq!(hart.privilege, PRIV_SUPERVISOR);
+		assert_eq!(hart.read_register(RegisterNames::a0 as usize), 1);
+
+		ecall(&mut platform, EID_HSM, 0, [1, 0x8020_0000]);
+		assert_eq!(a0(&platform), SBI_ERR_ALREADY_AVAILABLE);
+		ecall(&mut platform, EID_HSM, 0, [2, 0x8020_0000]);
+		assert_eq!(a0(&platform), SBI_ERR_INVALID_PARAM);
+	}
+
+	#[test]
+	fn unknown_extensions_are_not_supported()
+	{
+		let mut platform = Platform::default();
+		ecall(&mut platform, 0x0a00_0000, 0, [0, 0]);
+		let a0 = platform.hart.read_register(RegisterNames::a0 as usize);
+		assert_eq!(a0 as i64, SBI_ERR_NOT_SUPPORTED);
+	}
+
+	#[test]
+	fn legacy_extensions_leave_a1_alone()
+	{
+		let mut platform = Platform::default();
+		ecall(&mut platform, EID_LEGACY_CONSOLE_PUTCHAR, 0, [b'x' as u64, 7]);
+		let a1 = platform.hart.read_register(RegisterNames::a1 as usize);
+		assert_eq!(a1, 7);
+
+		ecall(&mut platform, EID_TIME, 0, [0, 7]);
+		let a1 = platform.hart.read_register(RegisterNames::a1 as usize);
+		assert_eq!(a1, 0);
+	}
+}
@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use std::error::Error;
+
+// A snapshot file is the magic followed by the sections, each a name & its
+// contents. Everything is little-endian & made of u64s or length-prefixed
+// byte strings, so that there's only one thing for a reader to get wrong.
+// The encoding is written by hand rather than derived with serde, which
+// isn't a dependency, so a part of the machine that gains state has to
+// add it to both its save & restore, in the same order.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"thingsnp";
+
+fn invalid(reason: String) -> Box<dyn Error>
+{
+	return Box::<dyn Error>::from(format!("invalid snapshot: {}", reason));
+}
+
+/// Builds up the contents of a section.
+#[derive(Debug, Default)]
+pub struct Encoder
+{
+	bytes: Vec<u8>,
+}
+
+impl Encoder
+{
+	pub fn u64(&mut self, value: u64)
+	{
+		self.bytes.extend_from_slice(&value.to_le_bytes());
+	}
+
+	pub fn bytes(&mut self, bytes: &[u8])
+	{
+		self.u64(bytes.len() as u64);
+		self.bytes.extend_from_slice(bytes);
+	}
+
+	pub fn option(&mut self, value: Option<usize>)
+	{
+		self.u64(value.is_some() as u64);
+		self.u64(value.unwrap_or(0) as u64);
+	}
+}
+
+/// Reads back what an Encoder wrote, in the same order.
+pub struct Decoder<'a>
+{
+	bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a>
+{
+	pub fn new(bytes: &'a [u8]) -> Decoder<'a>
+	{
+		return Decoder {
+			bytes,
+		};
+	}
+
+	fn take(&mut self, size: usize) -> Result<&'a [u8], Box<dyn Error>>
+	{
+		if size > self.bytes.len() {
+			return Err(invalid(format!("{} bytes short", size)));
+		}
+
+		let (taken, rest) = self.bytes.split_at(size);
+		self.bytes = rest;
+		return Ok(taken);
+	}
+
+	pub fn u64(&mut self) -> Result<u64, Box<dyn Error>>
+	{
+		return Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()));
+	}
+
+	pub fn usize(&mut self) -> Result<usize, Box<dyn Error>>
+	{
+		return Ok(self.u64()?.try_into()?);
+	}
+
+	pub fn bool(&mut self) -> Result<bool, Box<dyn Error>>
+	{
+		return Ok(self.u64()? != 0);
+	}
+
+	pub fn bytes(&mut self) -> Result<&'a [u8], Box<dyn Error>>
+	{
+		let size = self.usize()?;
+		return self.take(size);
+	}
+
+	pub fn option(&mut self) -> Result<Option<usize>, Box<dyn Error>>
+	{
+		let present = self.bool()?;
+		let value = self.usize()?;
+		return Ok(present.then_some(value));
+	}
+
+	/// Check that a count read back matches the one expected, for things
+	/// like the number of harts that can't change on restore.
+	pub fn expect(
+		&mut self, what: &str, expected: usize,
+	) -> Result<(), Box<dyn Error>>
+	{
+		let found = self.usize()?;
+		if found != expected {
+			return Err(invalid(format!(
+				"{} {} where {} were expected",
+				found, what, expected
+			)));
+		}
+
+		return Ok(());
+	}
+}
+
+/// The state of a whole machine at one point in time, as named sections
+/// that each part of it writes & reads back itself.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot
+{
+	sections: Vec<(String, Vec<u8>)>,
+}
+
+impl Snapshot
+{
+	pub fn add(&mut self, name: &str, section: Encoder)
+	{
+		self.sections.push((name.to_string(), section.bytes));
+	}
+
+	pub fn section(&self, name: &str) -> Result<Decoder, Box<dyn Error>>
+	{
+		return self
+			.sections
+			.iter()
+			.find(|(section, _)| return section == name)
+			.map(|(_, bytes)| return Decoder::new(bytes))
+			.ok_or_else(|| return invalid(format!("no {} section", name)));
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8>
+	{
+		let mut file = Encoder::default();
+		file.u64(self.sections.len() as u64);
+		for (name, bytes) in &self.sections {
+			file.bytes(name.as_bytes());
+			file.bytes(bytes);
+		}
+
+		let mut bytes = SNAPSHOT_MAGIC.to_vec();
+		bytes.extend(file.bytes);
+		return bytes;
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, Box<dyn Error>>
+	{
+		let Some(file) = bytes.strip_prefix(SNAPSHOT_MAGIC) else {
+			return Err(invalid("bad magic".to_string()));
+		};
+
+		let mut file = Decoder::new(file);
+		let mut snapshot = Snapshot::default();
+		for _ in 0..file.u64()? {
+			let name = String::from_utf8(file.bytes()?.to_vec())?;
+			let bytes = file.bytes()?.to_vec();
+			snapshot.sections.push((name, bytes));
+		}
+
+		return Ok(snapshot);
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{Encoder, Snapshot};
+
+	#[test]
+	fn sections_survive_a_trip_through_bytes()
+	{
+		let mut section = Encoder::default();
+		section.u64(0x1234);
+		section.bytes(b"abc");
+
+		let mut snapshot = Snapshot::default();
+		snapshot.add("test", section);
+		let bytes = snapshot.to_bytes();
+		let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+
+		let mut section = snapshot.section("test").unwrap();
+		assert_eq!(section.u64().unwrap(), 0x1234);
+		assert_eq!(section.bytes().unwrap(), b"abc");
+		assert!(section.u64().is_err());
+		assert!(snapshot.section("missing").is_err());
+
+		assert!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+		assert!(Snapshot::from_bytes(b"notasnap").is_err());
+	}
+}
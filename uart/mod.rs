@@ -0,0 +1,632 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::{self, Bus};
+use crate::gen_mask;
+use crate::lebytes::LeBytes;
+use crate::snapshot::{Decoder, Encoder};
+use std::cell::Cell;
+use std::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Where QEMU's virt machine puts its 16550, so the same device trees work.
+pub const UART_BASE: usize = 0x1000_0000;
+pub const UART_SIZE: usize = 0x100;
+
+// Register offsets. Some offsets are shared by a pair of registers, with
+// reads going to one & writes to the other.
+const RBR_THR_OFFSET: usize = 0;
+const IER_OFFSET: usize = 1;
+const IIR_FCR_OFFSET: usize = 2;
+const LCR_OFFSET: usize = 3;
+const MCR_OFFSET: usize = 4;
+const LSR_OFFSET: usize = 5;
+const MSR_OFFSET: usize = 6;
+const SCR_OFFSET: usize = 7;
+// With LCR.DLAB set, the first two offsets hold the baud rate divisor instead
+const DLL_OFFSET: usize = 0;
+const DLM_OFFSET: usize = 1;
+
+const IER_RX_AVAILABLE: u8 = gen_mask!(0, 0, u8);
+const IER_THR_EMPTY: u8 = gen_mask!(1, 1, u8);
+
+// The interrupt identification codes, with bit 0 clear meaning that an
+// interrupt is pending. Only one cause is reported at a time, the received
+// data interrupt taking priority over the transmitter one. There are no line
+// or modem status events to report, so those causes are never raised.
+const IIR_NO_INTERRUPT: u8 = gen_mask!(0, 0, u8);
+const IIR_RX_AVAILABLE: u8 = 0b0100;
+const IIR_THR_EMPTY: u8 = 0b0010;
+
+const LCR_DLAB: u8 = gen_mask!(7, 7, u8);
+
+const LSR_DR: u8 = gen_mask!(0, 0, u8);
+const LSR_THRE: u8 = gen_mask!(5, 5, u8);
+const LSR_TEMT: u8 = gen_mask!(6, 6, u8);
+
+#[derive(Debug, Default, Clone)]
+struct Register
+{
+	bits: u8,
+}
+
+impl Register
+{
+	fn read(&self) -> u8
+	{
+		return self.bits;
+	}
+
+	fn write(&mut self, value: u8)
+	{
+		self.bits = value;
+	}
+}
+
+/// Writes to these are dropped on the floor.
+#[derive(Debug, Default, Clone)]
+struct ReadOnlyRegister
+{
+	bits: u8,
+}
+
+impl ReadOnlyRegister
+{
+	fn read(&self) -> u8
+	{
+		return self.bits;
+	}
+}
+
+/// These share an offset with a readable register, so can't be read back.
+#[derive(Debug, Default, Clone)]
+struct WriteOnlyRegister
+{
+	bits: u8,
+}
+
+impl WriteOnlyRegister
+{
+	fn write(&mut self, value: u8)
+	{
+		self.bits = value;
+	}
+}
+
+#[derive(Debug, Default, Clone)]
+struct UartRegisters
+{
+	receiver_buffer: ReadOnlyRegister,
+	transmitter_holding: WriteOnlyRegister,
+	interrupt_enable: Register,
+	fifo_control: WriteOnlyRegister,
+	line_control: Register,
+	modem_control: Register,
+	line_status: Register,
+	modem_status: Register,
+	scratch: Register,
+	divisor_latch_ls: Register,
+	divisor_latch_ms: Register,
+}
+
+impl UartRegisters
+{
+	/// Every register's bits, in a fixed order for snapshots.
+	fn bits_mut(&mut self) -> [&mut u8; 11]
+	{
+		return [
+			&mut self.receiver_buffer.bits,
+			&mut self.transmitter_holding.bits,
+			&mut self.interrupt_enable.bits,
+			&mut self.fifo_control.bits,
+			&mut self.line_control.bits,
+			&mut self.modem_control.bits,
+			&mut self.line_status.bits,
+			&mut self.modem_status.bits,
+			&mut self.scratch.bits,
+			&mut self.divisor_latch_ls.bits,
+			&mut self.divisor_latch_ms.bits,
+		];
+	}
+}
+
+/// A 16550-ish UART, with everything transmitted going to output and
+/// anything received coming from input.
+pub struct Uart<T: Write, R: Read>
+{
+	registers: UartRegisters,
+	output: T,
+	input: R,
+	// Reading the receiver buffer consumes the byte in it, but bus reads
+	// only get a shared reference to the device. The same goes for reading
+	// IIR, which acknowledges a transmitter empty interrupt.
+	data_ready: Cell<bool>,
+	thr_interrupt: Cell<bool>,
+}
+
+impl<T: Write, R: Read> Uart<T, R>
+{
+	pub fn new(output: T, input: R) -> Uart<T, R>
+	{
+		let mut uart = Uart {
+			registers: UartRegisters::default(),
+			output,
+			input,
+			data_ready: Cell::new(false),
+			thr_interrupt: Cell::new(false),
+		};
+
+		uart.reset();
+		return uart;
+	}
+
+	/// Take received bytes from input instead, from now on.
+	pub fn set_input(&mut self, input: R)
+	{
+		self.input = input;
+	}
+
+	/// Put the registers back as they were at power on, keeping the output
+	/// & input. Anything received but not yet read is lost.
+	pub fn reset(&mut self)
+	{
+		self.registers = UartRegisters::default();
+		// Transmission is instant, so the transmitter is always empty
+		self.registers.line_status.write(LSR_THRE | LSR_TEMT);
+		self.data_ready.set(false);
+		self.thr_interrupt.set(false);
+	}
+
+	/// Save the registers for a snapshot. Input & output are the host's
+	/// business, so only a byte already received is kept.
+	pub fn save(&self, out: &mut Encoder)
+	{
+		for bits in self.registers.clone().bits_mut() {
+			out.u64(*bits as u64);
+		}
+		out.u64(self.data_ready.get() as u64);
+		out.u64(self.thr_interrupt.get() as u64);
+	}
+
+	pub fn restore(&mut self, input: &mut Decoder)
+		-> Result<(), Box<dyn Error>>
+	{
+		for bits in self.registers.bits_mut() {
+			*bits = input.u64()? as u8;
+		}
+		self.data_ready.set(input.bool()?);
+		self.thr_interrupt.set(input.bool()?);
+
+		return Ok(());
+	}
+
+	/// The highest priority interrupt that is both enabled & pending, as
+	/// an IIR code.
+	fn pending_interrupt(&self) -> Option<u8>
+	{
+		let enabled = self.registers.interrupt_enable.read();
+
+		if enabled & IER_RX_AVAILABLE != 0 && self.data_ready.get() {
+			return Some(IIR_RX_AVAILABLE);
+		}
+
+		if enabled & IER_THR_EMPTY != 0 && self.thr_interrupt.get() {
+			return Some(IIR_THR_EMPTY);
+		}
+
+		return None;
+	}
+
+	/// Send a byte straight to the output, without going through the
+	/// registers, for consoles that share the UART's output.
+	pub fn console_write(&mut self, value: u8)
+	{
+		// The guest has no way to find out about a failure on the host
+		// side, so there's nothing to do with it
+		let _ = self.output.write_all(&[value]);
+		let _ = self.output.flush();
+	}
+
+	/// Whether the UART is asserting its interrupt line.
+	pub fn interrupt_pending(&self) -> bool
+	{
+		return self.pending_interrupt().is_some();
+	}
+
+	fn interrupt_ident(&self) -> u8
+	{
+		match self.pending_interrupt() {
+			Some(IIR_THR_EMPTY) => {
+				// Reporting it is enough to acknowledge it
+				self.thr_interrupt.set(false);
+				return IIR_THR_EMPTY;
+			},
+			Some(cause) => return cause,
+			None => return IIR_NO_INTERRUPT,
+		}
+	}
+
+	/// Latch a byte from input into the receiver buffer, if one is available
+	/// and the guest has consumed the last one. Input sources that have
+	/// nothing to offer yet should return an error rather than block.
+	pub fn poll(&mut self)
+	{
+		if self.data_ready.get() {
+			return;
+		}
+
+		let mut byte = [0_u8; 1];
+		if let Ok(1) = self.input.read(&mut byte) {
+			self.registers.receiver_buffer = ReadOnlyRegister {
+				bits: byte[0],
+			};
+			self.data_ready.set(true);
+		}
+	}
+
+	fn divisor_latch_access(&self) -> bool
+	{
+		return self.registers.line_control.read() & LCR_DLAB != 0;
+	}
+
+	fn read_at(&self, offset: usize) -> Result<u8, bus::Error>
+	{
+		let registers = &self.registers;
+		let dlab = self.divisor_latch_access();
+
+		match offset {
+			DLL_OFFSET if dlab => return Ok(registers.divisor_latch_ls.read()),
+			DLM_OFFSET if dlab => return Ok(registers.divisor_latch_ms.read()),
+			RBR_THR_OFFSET => {
+				self.data_ready.set(false);
+				return Ok(registers.receiver_buffer.read());
+			},
+			IER_OFFSET => return Ok(registers.interrupt_enable.read()),
+			IIR_FCR_OFFSET => return Ok(self.interrupt_ident()),
+			LCR_OFFSET => return Ok(registers.line_control.read()),
+			MCR_OFFSET => return Ok(registers.modem_control.read()),
+			LSR_OFFSET => {
+				let data_ready = if self.data_ready.get() { LSR_DR } else { 0 };
+				return Ok(registers.line_status.read() | data_ready);
+			},
+			MSR_OFFSET => return Ok(registers.modem_status.read()),
+			SCR_OFFSET => return Ok(registers.scratch.read()),
+			_ => {
+				return Err(bus::Error::new(
+					bus::ErrorKind::OutOfBounds,
+					&format!("uart offset: {:x}", offset),
+				));
+			},
+		}
+	}
+
+	fn write_at(&mut self, offset: usize, value: u8) -> Result<(), bus::Error>
+	{
+		let dlab = self.divisor_latch_access();
+		let registers = &mut self.registers;
+
+		match offset {
+			// The divisor only sets the baud rate, which has no meaning
+			// here, so it is just stored
+			DLL_OFFSET if dlab => registers.divisor_latch_ls.write(value),
+			DLM_OFFSET if dlab => registers.divisor_latch_ms.write(value),
+			RBR_THR_OFFSET => {
+				registers.transmitter_holding.write(value);
+				self.console_write(value);
+				// Transmission is instant, so the holding register is
+				// already empty again
+				self.thr_interrupt.set(true);
+			},
+			IER_OFFSET => {
+				// Enabling the interrupt while the holding register is
+				// empty raises it straight away
+				let enabled = registers.interrupt_enable.read();
+				if enabled & IER_THR_EMPTY == 0 && value & IER_THR_EMPTY != 0 {
+					self.thr_interrupt.set(true);
+				}
+				registers.interrupt_enable.write(value);
+			},
+			IIR_FCR_OFFSET => registers.fifo_control.write(value),
+			LCR_OFFSET => registers.line_control.write(value),
+			MCR_OFFSET => registers.modem_control.write(value),
+			// The status registers are read-only
+			LSR_OFFSET | MSR_OFFSET => (),
+			SCR_OFFSET => registers.scratch.write(value),
+			_ => {
+				return Err(bus::Error::new(
+					bus::ErrorKind::OutOfBounds,
+					&format!("uart offset: {:x}", offset),
+				));
+			},
+		}
+
+		return Ok(());
+	}
+}
+
+impl Default for Uart<Box<dyn Write>, Box<dyn Read>>
+{
+	fn default() -> Uart<Box<dyn Write>, Box<dyn Read>>
+	{
+		// stdin isn't hooked up by default, as the debugger may want it
+		return Uart::new(
+			Box::new(std::io::stdout()),
+			Box::new(std::io::empty()),
+		);
+	}
+}
+
+/// Reads stdin from a thread of its own, so that polling it never blocks.
+pub struct NonBlockingStdin
+{
+	receiver: Receiver<u8>,
+}
+
+impl NonBlockingStdin
+{
+	pub fn spawn() -> NonBlockingStdin
+	{
+		let (sender, receiver) = mpsc::channel();
+
+		thread::spawn(move || {
+			for byte in std::io::stdin().bytes() {
+				let Ok(byte) = byte else {
+					return;
+				};
+				if sender.send(byte).is_err() {
+					return;
+				}
+			}
+		});
+
+		return NonBlockingStdin {
+			receiver,
+		};
+	}
+}
+
+impl Read for NonBlockingStdin
+{
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+	{
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		match self.receiver.try_recv() {
+			Ok(byte) => {
+				buf[0] = byte;
+				return Ok(1);
+			},
+			Err(TryRecvError::Empty) => {
+				return Err(ErrorKind::WouldBlock.into());
+			},
+			Err(TryRecvError::Disconnected) => return Ok(0),
+		}
+	}
+}
+
+impl<T: Write, R: Read> Bus for Uart<T, R>
+{
+	fn read<U>(&self, address: usize) -> Result<U, bus::Error>
+	where
+		U: LeBytes,
+		[(); <U as LeBytes>::SIZE]:,
+	{
+		let size = <U as LeBytes>::SIZE;
+		let mut bytes = [0_u8; <U as LeBytes>::SIZE];
+
+		// Drivers using wider accesses treat each register as the low
+		// byte of a wider one. Reading the neighbours instead could have
+		// side effects, like consuming received data, so the rest of the
+		// access reads as zero.
+		if size > 4 {
+			return Err(bus::Error::new(
+				bus::ErrorKind::Unimplemented,
+				&format!("uart read of {} bytes", size),
+			));
+		}
+
+		bytes[0] = self.read_at(address)?;
+
+		return Ok(U::from_le_bytes(bytes));
+	}
+
+	fn write<U, V>(&mut self, address: V, value: U) -> Result<(), bus::Error>
+	where
+		U: LeBytes,
+		V: Into<usize>,
+		[(); <U as LeBytes>::SIZE]:,
+	{
+		let size = <U as LeBytes>::SIZE;
+
+		// As for reads, only the low byte of a wider access matters
+		if size > 4 {
+			return Err(bus::Error::new(
+				bus::ErrorKind::Unimplemented,
+				&format!("uart write of {} bytes", size),
+			));
+		}
+
+		return self.write_at(address.into(), value.to_le_bytes()[0]);
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		ReadOnlyRegister, Register, Uart, WriteOnlyRegister, IER_OFFSET,
+		IER_RX_AVAILABLE, IER_THR_EMPTY, IIR_FCR_OFFSET, IIR_NO_INTERRUPT,
+		IIR_RX_AVAILABLE, IIR_THR_EMPTY, LCR_DLAB, LCR_OFFSET, LSR_DR,
+		LSR_OFFSET, LSR_TEMT, LSR_THRE, MCR_OFFSET, MSR_OFFSET, RBR_THR_OFFSET,
+		SCR_OFFSET,
+	};
+	use crate::bus::Bus;
+	use std::io::{empty, Cursor};
+
+	#[test]
+	fn register_classes_store_bits()
+	{
+		let mut register = Register::default();
+		register.write(0xa5);
+		assert_eq!(register.read(), 0xa5);
+
+		let read_only = ReadOnlyRegister {
+			bits: 0x5a,
+		};
+		assert_eq!(read_only.read(), 0x5a);
+
+		let mut write_only = WriteOnlyRegister::default();
+		write_only.write(0x3c);
+		assert_eq!(write_only.bits, 0x3c);
+	}
+
+	#[test]
+	fn read_write_registers_read_back()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		for offset in [IER_OFFSET, LCR_OFFSET, MCR_OFFSET, SCR_OFFSET] {
+			uart.write(offset, 0x0c_u8).unwrap();
+			assert_eq!(uart.read::<u8>(offset).unwrap(), 0x0c);
+		}
+	}
+
+	#[test]
+	fn status_registers_ignore_writes()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		// Polling drivers spin on THRE, so it must be set out of reset
+		let lsr: u8 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr, LSR_THRE | LSR_TEMT);
+
+		uart.write(LSR_OFFSET, 0_u8).unwrap();
+		uart.write(MSR_OFFSET, 0xff_u8).unwrap();
+		assert_eq!(uart.read::<u8>(LSR_OFFSET).unwrap(), LSR_THRE | LSR_TEMT);
+		assert_eq!(uart.read::<u8>(MSR_OFFSET).unwrap(), 0);
+	}
+
+	#[test]
+	fn transmitted_bytes_reach_output()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		uart.write(RBR_THR_OFFSET, b'h').unwrap();
+		uart.write(RBR_THR_OFFSET, b'i').unwrap();
+
+		assert_eq!(uart.output, b"hi");
+		let lsr: u8 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr, LSR_THRE | LSR_TEMT);
+	}
+
+	#[test]
+	fn word_accesses_use_the_low_byte()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		let lsr: u32 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr, (LSR_THRE | LSR_TEMT) as u32);
+
+		uart.write(SCR_OFFSET, 0xdead_be5a_u32).unwrap();
+		assert_eq!(uart.read::<u16>(SCR_OFFSET).unwrap(), 0x5a);
+
+		uart.write(RBR_THR_OFFSET, 0x4241_u16).unwrap();
+		assert_eq!(uart.output, b"A");
+	}
+
+	#[test]
+	fn doubleword_accesses_are_rejected()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		assert!(uart.write(RBR_THR_OFFSET, 0x41_u64).is_err());
+		assert!(uart.read::<u64>(LSR_OFFSET).is_err());
+		assert!(uart.output.is_empty());
+	}
+
+	#[test]
+	fn received_bytes_reach_receiver_buffer()
+	{
+		let mut uart = Uart::new(Vec::new(), Cursor::new(b"ok".to_vec()));
+
+		let lsr: u8 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr & LSR_DR, 0);
+
+		uart.poll();
+		let lsr: u8 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr & LSR_DR, LSR_DR);
+
+		// Polling again mustn't overwrite a byte the guest hasn't read
+		uart.poll();
+		assert_eq!(uart.read::<u8>(RBR_THR_OFFSET).unwrap(), b'o');
+		let lsr: u8 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr & LSR_DR, 0);
+
+		uart.poll();
+		assert_eq!(uart.read::<u8>(RBR_THR_OFFSET).unwrap(), b'k');
+
+		// Once input runs dry, nothing more is latched
+		uart.poll();
+		let lsr: u8 = uart.read(LSR_OFFSET).unwrap();
+		assert_eq!(lsr & LSR_DR, 0);
+	}
+
+	#[test]
+	fn thr_empty_interrupt_is_raised_and_acknowledged()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		assert!(!uart.interrupt_pending());
+		assert_eq!(uart.read::<u8>(IIR_FCR_OFFSET).unwrap(), IIR_NO_INTERRUPT);
+
+		uart.write(IER_OFFSET, IER_THR_EMPTY).unwrap();
+		assert!(uart.interrupt_pending());
+		assert_eq!(uart.read::<u8>(IIR_FCR_OFFSET).unwrap(), IIR_THR_EMPTY);
+
+		// Reading IIR acknowledged it, until the next byte goes out
+		assert!(!uart.interrupt_pending());
+		uart.write(RBR_THR_OFFSET, b'x').unwrap();
+		assert!(uart.interrupt_pending());
+	}
+
+	#[test]
+	fn received_data_interrupt_takes_priority()
+	{
+		let mut uart = Uart::new(Vec::new(), Cursor::new(b"x".to_vec()));
+
+		uart.write(IER_OFFSET, IER_RX_AVAILABLE | IER_THR_EMPTY).unwrap();
+		uart.poll();
+		assert_eq!(uart.read::<u8>(IIR_FCR_OFFSET).unwrap(), IIR_RX_AVAILABLE);
+
+		// Consuming the byte leaves the transmitter interrupt visible
+		uart.read::<u8>(RBR_THR_OFFSET).unwrap();
+		assert_eq!(uart.read::<u8>(IIR_FCR_OFFSET).unwrap(), IIR_THR_EMPTY);
+		assert!(!uart.interrupt_pending());
+	}
+
+	#[test]
+	fn dlab_redirects_to_divisor_latch()
+	{
+		let mut uart = Uart::new(Vec::new(), empty());
+
+		uart.write(LCR_OFFSET, LCR_DLAB | 0x03).unwrap();
+		uart.write(RBR_THR_OFFSET, 0x01_u8).unwrap();
+		uart.write(IER_OFFSET, 0x02_u8).unwrap();
+
+		assert!(uart.output.is_empty());
+		assert_eq!(uart.registers.divisor_latch_ls.read(), 0x01);
+		assert_eq!(uart.registers.divisor_latch_ms.read(), 0x02);
+		assert_eq!(uart.read::<u8>(RBR_THR_OFFSET).unwrap(), 0x01);
+
+		// Clearing DLAB restores the usual registers
+		uart.write(LCR_OFFSET, 0x03_u8).unwrap();
+		uart.write(RBR_THR_OFFSET, b'x').unwrap();
+		assert_eq!(uart.output, b"x");
+		assert_eq!(uart.read::<u8>(IER_OFFSET).unwrap(), 0);
+	}
+}
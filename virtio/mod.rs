@@ -0,0 +1,790 @@
+// SPDX-License-Identifier: GPL-2.0-only
+#![deny(clippy::implicit_return)]
+#![allow(clippy::needless_return)]
+
+use crate::bus::{self, Bus};
+use crate::lebytes::LeBytes;
+use crate::snapshot::{Decoder, Encoder};
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Where QEMU's virt machine puts its first virtio-mmio transport, so the same
+// device trees work.
+pub const VIRTIO_BASE: usize = 0x1000_1000;
+pub const VIRTIO_SIZE: usize = 0x1000;
+
+// The registers of a version 2 virtio-mmio transport, as offsets from
+// VIRTIO_BASE. They are all 32 bits wide, apart from the device specific
+// config space at the end. 64-bit addresses are split into two halves.
+const MAGIC_VALUE_OFFSET: usize = 0x000;
+const VERSION_OFFSET: usize = 0x004;
+const DEVICE_ID_OFFSET: usize = 0x008;
+const VENDOR_ID_OFFSET: usize = 0x00c;
+const DEVICE_FEATURES_OFFSET: usize = 0x010;
+const DEVICE_FEATURES_SEL_OFFSET: usize = 0x014;
+const DRIVER_FEATURES_OFFSET: usize = 0x020;
+const DRIVER_FEATURES_SEL_OFFSET: usize = 0x024;
+const QUEUE_SEL_OFFSET: usize = 0x030;
+const QUEUE_NUM_MAX_OFFSET: usize = 0x034;
+const QUEUE_NUM_OFFSET: usize = 0x038;
+const QUEUE_READY_OFFSET: usize = 0x044;
+const QUEUE_NOTIFY_OFFSET: usize = 0x050;
+const INTERRUPT_STATUS_OFFSET: usize = 0x060;
+const INTERRUPT_ACK_OFFSET: usize = 0x064;
+const STATUS_OFFSET: usize = 0x070;
+const QUEUE_DESC_LOW_OFFSET: usize = 0x080;
+const QUEUE_DESC_HIGH_OFFSET: usize = 0x084;
+const QUEUE_DRIVER_LOW_OFFSET: usize = 0x090;
+const QUEUE_DRIVER_HIGH_OFFSET: usize = 0x094;
+const QUEUE_DEVICE_LOW_OFFSET: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH_OFFSET: usize = 0x0a4;
+const CONFIG_GENERATION_OFFSET: usize = 0x0fc;
+const CONFIG_OFFSET: usize = 0x100;
+
+const MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const DEVICE_ID_BLOCK: u32 = 2;
+const VENDOR_ID: u32 = 0;
+
+const FEATURE_BLK_FLUSH: u64 = 1 << 9;
+const FEATURE_VERSION_1: u64 = 1 << 32;
+const DEVICE_FEATURES: u64 = FEATURE_BLK_FLUSH | FEATURE_VERSION_1;
+
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 1 << 6;
+
+const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+// There's a single request queue, queue 0
+const QUEUE_NUM_MAX: u32 = 256;
+
+// Split virtqueues: a table of 16 byte descriptors, the driver's ring of
+// available chains & the device's ring of used ones. Both rings start with
+// a 16-bit flags & a 16-bit index.
+const DESC_SIZE: u64 = 16;
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+const RING_HEADER_SIZE: u64 = 4;
+const AVAIL_ELEMENT_SIZE: u64 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+const USED_ELEMENT_SIZE: u64 = 8;
+
+// Block requests are a header, any data buffers & a status byte
+pub const SECTOR_SIZE: u64 = 512;
+const REQUEST_HEADER_SIZE: u32 = 16;
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_T_FLUSH: u32 = 4;
+const BLK_T_GET_ID: u32 = 8;
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+const BLK_ID: &[u8] = b"thing";
+
+/// Something a disk can be backed by, a file or anything else that can be
+/// read, written & seeked around.
+pub trait Backing: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> Backing for T {}
+
+#[derive(Debug, Default, Clone)]
+struct Queue
+{
+	size: u32,
+	ready: bool,
+	desc: u64,
+	driver: u64,
+	device: u64,
+	/// The next entry in the available ring to be processed
+	last_avail: u16,
+	/// The used ring's index, which the device alone writes
+	next_used: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Descriptor
+{
+	address: usize,
+	len: u32,
+	flags: u16,
+}
+
+impl Descriptor
+{
+	fn writable(&self) -> bool
+	{
+		return self.flags & DESC_F_WRITE != 0;
+	}
+}
+
+/// A virtio block device on a virtio-mmio transport, with disk as its
+/// backing store. Requests are only processed when the platform asks, as
+/// the device needs to get at guest memory to do so.
+pub struct VirtioBlock<D: Backing>
+{
+	disk: D,
+	/// The size of the disk, in sectors
+	capacity: u64,
+	device_features_sel: u32,
+	driver_features: u64,
+	driver_features_sel: u32,
+	queue_sel: u32,
+	queue: Queue,
+	status: u32,
+	interrupt_status: u32,
+	notified: bool,
+}
+
+fn malformed(what: &str) -> bus::Error
+{
+	return bus::Error::new(bus::ErrorKind::OutOfBounds, what);
+}
+
+/// Replace the bits of a 64-bit register selected by a 32-bit one, where
+/// half is 0 for the low half & 1 for the high one.
+fn write_half(register: &mut u64, half: u32, value: u32)
+{
+	let shift = 32 * (half & 1);
+	*register &= !(0xffff_ffff << shift);
+	*register |= (value as u64) << shift;
+}
+
+/// An offset from an address the driver handed over, which could be
+/// anything, so mustn't be trusted not to overflow.
+fn guest_address(address: u64, offset: u64) -> Result<usize, bus::Error>
+{
+	return address
+		.checked_add(offset)
+		.and_then(|address| return usize::try_from(address).ok())
+		.ok_or_else(|| return malformed("virtio address overflows"));
+}
+
+fn read_bytes<M: Bus>(
+	memory: &M, address: usize, bytes: &mut [u8],
+) -> Result<(), bus::Error>
+{
+	for (offset, byte) in bytes.iter_mut().enumerate() {
+		*byte = memory.read(guest_address(address as u64, offset as u64)?)?;
+	}
+
+	return Ok(());
+}
+
+fn write_bytes<M: Bus>(
+	memory: &mut M, address: usize, bytes: &[u8],
+) -> Result<(), bus::Error>
+{
+	for (offset, byte) in bytes.iter().enumerate() {
+		memory.write(guest_address(address as u64, offset as u64)?, *byte)?;
+	}
+
+	return Ok(());
+}
+
+impl<D: Backing> VirtioBlock<D>
+{
+	/// A device backed by disk, which is as many sectors long as fit in
+	/// it. Anything left over at the end is out of reach.
+	pub fn new(mut disk: D) -> std::io::Result<VirtioBlock<D>>
+	{
+		let size = disk.seek(SeekFrom::End(0))?;
+
+		return Ok(VirtioBlock {
+			disk,
+			capacity: size / SECTOR_SIZE,
+			device_features_sel: 0,
+			driver_features: 0,
+			driver_features_sel: 0,
+			queue_sel: 0,
+			queue: Queue::default(),
+			status: 0,
+			interrupt_status: 0,
+			notified: false,
+		});
+	}
+
+	/// Put the transport back as it was at power on, which is also what
+	/// the driver writing 0 to status does. The disk is left alone.
+	pub fn reset(&mut self)
+	{
+		self.device_features_sel = 0;
+		self.driver_features = 0;
+		self.driver_features_sel = 0;
+		self.queue_sel = 0;
+		self.queue = Queue::default();
+		self.status = 0;
+		self.interrupt_status = 0;
+		self.notified = false;
+	}
+
+	/// The size of the disk, in sectors.
+	pub fn capacity(&self) -> u64
+	{
+		return self.capacity;
+	}
+
+	pub fn interrupt_pending(&self) -> bool
+	{
+		return self.interrupt_status != 0;
+	}
+
+	/// Whether the driver has made requests since they were last
+	/// processed.
+	pub fn notified(&self) -> bool
+	{
+		return self.notified;
+	}
+
+	/// Save the transport for a snapshot. The disk is the host's business
+	/// & is not included.
+	pub fn save(&self, out: &mut Encoder)
+	{
+		out.u64(self.device_features_sel as u64);
+		out.u64(self.driver_features);
+		out.u64(self.driver_features_sel as u64);
+		out.u64(self.queue_sel as u64);
+		out.u64(self.queue.size as u64);
+		out.u64(self.queue.ready as u64);
+		out.u64(self.queue.desc);
+		out.u64(self.queue.driver);
+		out.u64(self.queue.device);
+		out.u64(self.queue.last_avail as u64);
+		out.u64(self.queue.next_used as u64);
+		out.u64(self.status as u64);
+		out.u64(self.interrupt_status as u64);
+		out.u64(self.notified as u64);
+	}
+
+	pub fn restore(&mut self, input: &mut Decoder)
+		-> Result<(), Box<dyn Error>>
+	{
+		self.device_features_sel = input.u64()? as u32;
+		self.driver_features = input.u64()?;
+		self.driver_features_sel = input.u64()? as u32;
+		self.queue_sel = input.u64()? as u32;
+		self.queue.size = input.u64()? as u32;
+		self.queue.ready = input.bool()?;
+		self.queue.desc = input.u64()?;
+		self.queue.driver = input.u64()?;
+		self.queue.device = input.u64()?;
+		self.queue.last_avail = input.u64()? as u16;
+		self.queue.next_used = input.u64()? as u16;
+		self.status = input.u64()? as u32;
+		self.interrupt_status = input.u64()? as u32;
+		self.notified = input.bool()?;
+
+		return Ok(());
+	}
+
+	/// Process every request the driver has made available, finding the
+	/// queue & the buffers it points at in memory. Chains that don't make
+	/// sense put the device into the needs reset state, as the spec asks.
+	pub fn process_requests<M: Bus>(
+		&mut self, memory: &mut M,
+	) -> Result<(), bus::Error>
+	{
+		self.notified = false;
+		let ready = self.queue.ready && self.queue.size != 0;
+		if !ready || self.status & STATUS_DRIVER_OK == 0 {
+			return Ok(());
+		}
+
+		if let Err(error) = self.process_queue(memory) {
+			self.status |= STATUS_DEVICE_NEEDS_RESET;
+			self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+			return Err(error);
+		}
+
+		return Ok(());
+	}
+
+	fn process_queue<M: Bus>(
+		&mut self, memory: &mut M,
+	) -> Result<(), bus::Error>
+	{
+		let queue = self.queue.clone();
+		let size = queue.size as u64;
+		let avail_flags: u16 = memory.read(guest_address(queue.driver, 0)?)?;
+		let avail_idx: u16 = memory.read(guest_address(queue.driver, 2)?)?;
+		let mut used = false;
+
+		// Slots are within the ring's size, which is at most 16 bits, so
+		// only adding them to the ring's address can overflow
+		while self.queue.last_avail != avail_idx {
+			let slot = self.queue.last_avail as u64 % size;
+			let slot = RING_HEADER_SIZE + AVAIL_ELEMENT_SIZE * slot;
+			let head: u16 = memory.read(guest_address(queue.driver, slot)?)?;
+			let written = self.process_request(memory, head)?;
+
+			let slot = self.queue.next_used as u64 % size;
+			let slot = RING_HEADER_SIZE + USED_ELEMENT_SIZE * slot;
+			memory.write(guest_address(queue.device, slot)?, head as u32)?;
+			memory.write(guest_address(queue.device, slot + 4)?, written)?;
+
+			self.queue.next_used = self.queue.next_used.wrapping_add(1);
+			let used_idx = guest_address(queue.device, 2)?;
+			memory.write(used_idx, self.queue.next_used)?;
+			self.queue.last_avail = self.queue.last_avail.wrapping_add(1);
+			used = true;
+		}
+
+		if used && avail_flags & AVAIL_F_NO_INTERRUPT == 0 {
+			self.interrupt_status |= INTERRUPT_USED_BUFFER;
+		}
+
+		return Ok(());
+	}
+
+	/// The descriptors making up the chain starting at head. A chain can't
+	/// be longer than the table, so one that is must loop.
+	fn descriptor_chain<M: Bus>(
+		&self, memory: &M, head: u16,
+	) -> Result<Vec<Descriptor>, bus::Error>
+	{
+		let mut chain = Vec::new();
+		let mut index = head as u32;
+
+		loop {
+			if index >= self.queue.size
+				|| chain.len() == self.queue.size as usize
+			{
+				return Err(malformed("virtio descriptor chain out of range"));
+			}
+
+			let address = self.queue.desc;
+			let offset = |field: u64| {
+				return guest_address(
+					address,
+					DESC_SIZE * index as u64 + field,
+				);
+			};
+			let descriptor = Descriptor {
+				address: memory.read::<u64>(offset(0)?)? as usize,
+				len: memory.read(offset(8)?)?,
+				flags: memory.read(offset(12)?)?,
+			};
+			chain.push(descriptor);
+
+			if descriptor.flags & DESC_F_NEXT == 0 {
+				return Ok(chain);
+			}
+			index = memory.read::<u16>(offset(14)?)? as u32;
+		}
+	}
+
+	/// Carry out the request whose chain starts at head, returning how many
+	/// bytes were written into its buffers.
+	fn process_request<M: Bus>(
+		&mut self, memory: &mut M, head: u16,
+	) -> Result<u32, bus::Error>
+	{
+		let chain = self.descriptor_chain(memory, head)?;
+		let header = chain[0];
+		let status = chain[chain.len() - 1];
+		if chain.len() < 2
+			|| header.len < REQUEST_HEADER_SIZE
+			|| !status.writable()
+			|| status.len < 1
+		{
+			return Err(malformed("virtio-blk request is malformed"));
+		}
+
+		let kind: u32 = memory.read(header.address)?;
+		let sector: u64 =
+			memory.read(guest_address(header.address as u64, 8)?)?;
+		let buffers = &chain[1..chain.len() - 1];
+		let mut written = 0;
+
+		let result = match kind {
+			BLK_T_IN => {
+				self.read_sectors(memory, sector, buffers, &mut written)?
+			},
+			BLK_T_OUT => self.write_sectors(memory, sector, buffers)?,
+			BLK_T_FLUSH => {
+				if self.disk.flush().is_ok() {
+					BLK_S_OK
+				} else {
+					BLK_S_IOERR
+				}
+			},
+			BLK_T_GET_ID => {
+				// The id is up to 20 bytes, only NUL terminated if shorter
+				let mut id = [0; 20];
+				id[..BLK_ID.len()].copy_from_slice(BLK_ID);
+				match buffers.first() {
+					Some(buffer) if buffer.writable() => {
+						let len = id.len().min(buffer.len as usize);
+						write_bytes(memory, buffer.address, &id[..len])?;
+						written += len as u32;
+						BLK_S_OK
+					},
+					_ => BLK_S_IOERR,
+				}
+			},
+			_ => BLK_S_UNSUPP,
+		};
+
+		memory.write(status.address, result)?;
+
+		return Ok(written + 1);
+	}
+
+	/// Whether len bytes at offset are all on the disk.
+	fn in_range(&self, offset: u64, len: u32) -> bool
+	{
+		let end = offset.checked_add(len as u64);
+		return end
+			.map_or(false, |end| return end <= self.capacity * SECTOR_SIZE);
+	}
+
+	fn read_sectors<M: Bus>(
+		&mut self, memory: &mut M, sector: u64, buffers: &[Descriptor],
+		written: &mut u32,
+	) -> Result<u8, bus::Error>
+	{
+		let mut offset = sector.saturating_mul(SECTOR_SIZE);
+
+		for buffer in buffers {
+			if !buffer.writable() || !self.in_range(offset, buffer.len) {
+				return Ok(BLK_S_IOERR);
+			}
+
+			let mut data = vec![0; buffer.len as usize];
+			let read = self
+				.disk
+				.seek(SeekFrom::Start(offset))
+				.and_then(|_| return self.disk.read_exact(&mut data));
+			if read.is_err() {
+				return Ok(BLK_S_IOERR);
+			}
+
+			write_bytes(memory, buffer.address, &data)?;
+			*written += buffer.len;
+			offset += buffer.len as u64;
+		}
+
+		return Ok(BLK_S_OK);
+	}
+
+	fn write_sectors<M: Bus>(
+		&mut self, memory: &M, sector: u64, buffers: &[Descriptor],
+	) -> Result<u8, bus::Error>
+	{
+		let mut offset = sector.saturating_mul(SECTOR_SIZE);
+
+		for buffer in buffers {
+			if buffer.writable() || !self.in_range(offset, buffer.len) {
+				return Ok(BLK_S_IOERR);
+			}
+
+			let mut data = vec![0; buffer.len as usize];
+			read_bytes(memory, buffer.address, &mut data)?;
+			let write = self
+				.disk
+				.seek(SeekFrom::Start(offset))
+				.and_then(|_| return self.disk.write_all(&data));
+			if write.is_err() {
+				return Ok(BLK_S_IOERR);
+			}
+
+			offset += buffer.len as u64;
+		}
+
+		return Ok(BLK_S_OK);
+	}
+
+	fn read_register(&self, address: usize) -> u32
+	{
+		let selected = self.queue_sel == 0;
+
+		match address {
+			MAGIC_VALUE_OFFSET => return MAGIC_VALUE,
+			VERSION_OFFSET => return VERSION,
+			DEVICE_ID_OFFSET => return DEVICE_ID_BLOCK,
+			VENDOR_ID_OFFSET => return VENDOR_ID,
+			DEVICE_FEATURES_OFFSET => {
+				let shift = 32 * self.device_features_sel.min(2);
+				return DEVICE_FEATURES.checked_shr(shift).unwrap_or(0) as u32;
+			},
+			QUEUE_NUM_MAX_OFFSET if selected => return QUEUE_NUM_MAX,
+			QUEUE_READY_OFFSET if selected => return self.queue.ready as u32,
+			INTERRUPT_STATUS_OFFSET => return self.interrupt_status,
+			STATUS_OFFSET => return self.status,
+			// The config space never changes
+			CONFIG_GENERATION_OFFSET => return 0,
+			// Everything else is write-only
+			_ => return 0,
+		}
+	}
+
+	fn write_register(&mut self, address: usize, value: u32)
+	{
+		let selected = self.queue_sel == 0;
+		let queue = &mut self.queue;
+
+		match address {
+			DEVICE_FEATURES_SEL_OFFSET => self.device_features_sel = value,
+			DRIVER_FEATURES_OFFSET => {
+				let half = self.driver_features_sel;
+				if half < 2 {
+					write_half(&mut self.driver_features, half, value);
+				}
+			},
+			DRIVER_FEATURES_SEL_OFFSET => self.driver_features_sel = value,
+			QUEUE_SEL_OFFSET => self.queue_sel = value,
+			QUEUE_NUM_OFFSET if selected => {
+				queue.size = value.min(QUEUE_NUM_MAX);
+			},
+			QUEUE_READY_OFFSET if selected => queue.ready = value & 1 != 0,
+			QUEUE_NOTIFY_OFFSET => self.notified |= value == 0,
+			INTERRUPT_ACK_OFFSET => self.interrupt_status &= !value,
+			STATUS_OFFSET if value == 0 => self.reset(),
+			STATUS_OFFSET => self.status = value,
+			QUEUE_DESC_LOW_OFFSET if selected => {
+				write_half(&mut queue.desc, 0, value);
+			},
+			QUEUE_DESC_HIGH_OFFSET if selected => {
+				write_half(&mut queue.desc, 1, value);
+			},
+			QUEUE_DRIVER_LOW_OFFSET if selected => {
+				write_half(&mut queue.driver, 0, value);
+			},
+			QUEUE_DRIVER_HIGH_OFFSET if selected => {
+				write_half(&mut queue.driver, 1, value);
+			},
+			QUEUE_DEVICE_LOW_OFFSET if selected => {
+				write_half(&mut queue.device, 0, value);
+			},
+			QUEUE_DEVICE_HIGH_OFFSET if selected => {
+				write_half(&mut queue.device, 1, value);
+			},
+			// Everything else is read-only
+			_ => (),
+		}
+	}
+}
+
+/// The transport registers must be accessed 32 bits at a time, but the
+/// config space may be accessed at any width.
+fn check_access(address: usize, size: usize) -> Result<(), bus::Error>
+{
+	if address >= CONFIG_OFFSET {
+		if address + size > VIRTIO_SIZE {
+			return Err(bus::Error::new(
+				bus::ErrorKind::OutOfBounds,
+				&format!("virtio offset: {:x}", address),
+			));
+		}
+		return Ok(());
+	}
+
+	if size != 4 || address % 4 != 0 {
+		return Err(bus::Error::new(
+			bus::ErrorKind::OutOfBounds,
+			&format!("virtio register access of {} bytes: {:x}", size, address),
+		));
+	}
+
+	return Ok(());
+}
+
+impl<D: Backing> Bus for VirtioBlock<D>
+{
+	fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+	where
+		T: LeBytes,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let size = <T as LeBytes>::SIZE;
+		check_access(address, size)?;
+
+		if address < CONFIG_OFFSET {
+			let bytes = self.read_register(address).to_le_bytes();
+			return Ok(T::from_le_bytes(bytes[..size].try_into().unwrap()));
+		}
+
+		// The config space starts with the capacity, & nothing else in it
+		// is offered
+		let mut config = [0; VIRTIO_SIZE - CONFIG_OFFSET];
+		config[..8].copy_from_slice(&self.capacity.to_le_bytes());
+		let offset = address - CONFIG_OFFSET;
+
+		return Ok(T::from_le_bytes(
+			config[offset..offset + size].try_into().unwrap(),
+		));
+	}
+
+	fn write<T, U>(&mut self, address: U, value: T) -> Result<(), bus::Error>
+	where
+		T: LeBytes,
+		U: Into<usize>,
+		[(); <T as LeBytes>::SIZE]:,
+	{
+		let address = address.into();
+		let size = <T as LeBytes>::SIZE;
+		check_access(address, size)?;
+
+		// The config space is read-only
+		if address >= CONFIG_OFFSET {
+			return Ok(());
+		}
+
+		let bytes = value.to_le_bytes();
+		let value = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+		self.write_register(address, value);
+
+		return Ok(());
+	}
+}
+
+#[cfg(test)]
+mod test
+{
+	use super::{
+		VirtioBlock, BLK_S_OK, BLK_T_IN, DESC_F_NEXT, DESC_F_WRITE,
+		INTERRUPT_ACK_OFFSET, MAGIC_VALUE, MAGIC_VALUE_OFFSET,
+		QUEUE_DESC_HIGH_OFFSET, QUEUE_DESC_LOW_OFFSET, QUEUE_DEVICE_LOW_OFFSET,
+		QUEUE_DRIVER_LOW_OFFSET, QUEUE_NOTIFY_OFFSET, QUEUE_NUM_OFFSET,
+		QUEUE_READY_OFFSET, SECTOR_SIZE, STATUS_DEVICE_NEEDS_RESET,
+		STATUS_DRIVER_OK, STATUS_OFFSET,
+	};
+	use crate::bus::{self, Bus, DeviceMap};
+	use crate::lebytes::LeBytes;
+	use crate::platform::Memory;
+	use std::collections::HashMap;
+	use std::io::Cursor;
+
+	/// Memory that is there at every address, right up to the top of the
+	/// address space, & reads as zero until written.
+	#[derive(Default)]
+	struct Everywhere(HashMap<usize, u8>);
+
+	impl Bus for Everywhere
+	{
+		fn read<T>(&self, address: usize) -> Result<T, bus::Error>
+		where
+			T: LeBytes,
+			[(); <T as LeBytes>::SIZE]:,
+		{
+			let mut bytes = [0_u8; <T as LeBytes>::SIZE];
+			for (offset, byte) in bytes.iter_mut().enumerate() {
+				let address = address.wrapping_add(offset);
+				*byte = self.0.get(&address).copied().unwrap_or(0);
+			}
+			return Ok(T::from_le_bytes(bytes));
+		}
+
+		fn write<T, U>(
+			&mut self, address: U, value: T,
+		) -> Result<(), bus::Error>
+		where
+			T: LeBytes,
+			U: Into<usize>,
+			[(); <T as LeBytes>::SIZE]:,
+		{
+			let address = address.into();
+			for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+				self.0.insert(address.wrapping_add(offset), byte);
+			}
+			return Ok(());
+		}
+	}
+
+	const BASE: usize = 0x8000_0000;
+	const DESC: usize = BASE;
+	const DRIVER: usize = BASE + 0x100;
+	const DEVICE: usize = BASE + 0x200;
+	const HEADER: usize = BASE + 0x300;
+	const DATA: usize = BASE + 0x400;
+	const STATUS: usize = BASE + 0x600;
+
+	fn descriptor(
+		memory: &mut DeviceMap, index: usize, address: usize, len: u32,
+		flags: u16, next: u16,
+	)
+	{
+		let entry = DESC + 16 * index;
+		memory.write(entry, address as u64).unwrap();
+		memory.write(entry + 8, len).unwrap();
+		memory.write(entry + 12, flags).unwrap();
+		memory.write(entry + 14, next).unwrap();
+	}
+
+	#[test]
+	fn read_request_copies_a_sector_into_memory()
+	{
+		// Two sectors, each byte holding its sector number
+		let mut disk = vec![0; 2 * SECTOR_SIZE as usize];
+		disk[SECTOR_SIZE as usize..].fill(1);
+		let mut block = VirtioBlock::new(Cursor::new(disk)).unwrap();
+		assert_eq!(block.capacity(), 2);
+		assert_eq!(block.read::<u32>(MAGIC_VALUE_OFFSET).unwrap(), MAGIC_VALUE);
+
+		let mut memory = DeviceMap::default();
+		let ram = Box::new(Memory::new(BASE, 0x1000));
+		memory.register("memory", BASE, 0x1000, ram).unwrap();
+
+		// A read of sector 1, with the data & status in separate buffers
+		memory.write(HEADER, BLK_T_IN).unwrap();
+		memory.write(HEADER + 8, 1_u64).unwrap();
+		memory.write(STATUS, 0xff_u8).unwrap();
+		descriptor(&mut memory, 0, HEADER, 16, DESC_F_NEXT, 1);
+		let flags = DESC_F_NEXT | DESC_F_WRITE;
+		descriptor(&mut memory, 1, DATA, SECTOR_SIZE as u32, flags, 2);
+		descriptor(&mut memory, 2, STATUS, 1, DESC_F_WRITE, 0);
+		memory.write(DRIVER + 4, 0_u16).unwrap();
+		memory.write(DRIVER + 2, 1_u16).unwrap();
+
+		block.write(QUEUE_NUM_OFFSET, 8_u32).unwrap();
+		block.write(QUEUE_DESC_LOW_OFFSET, DESC as u32).unwrap();
+		block.write(QUEUE_DRIVER_LOW_OFFSET, DRIVER as u32).unwrap();
+		block.write(QUEUE_DEVICE_LOW_OFFSET, DEVICE as u32).unwrap();
+		block.write(QUEUE_READY_OFFSET, 1_u32).unwrap();
+		block.write(STATUS_OFFSET, STATUS_DRIVER_OK).unwrap();
+		block.write(QUEUE_NOTIFY_OFFSET, 0_u32).unwrap();
+		assert!(block.notified());
+
+		block.process_requests(&mut memory).unwrap();
+
+		for offset in [0, SECTOR_SIZE as usize - 1] {
+			assert_eq!(memory.read::<u8>(DATA + offset).unwrap(), 1);
+		}
+		assert_eq!(memory.read::<u8>(DATA + SECTOR_SIZE as usize).unwrap(), 0);
+		assert_eq!(memory.read::<u8>(STATUS).unwrap(), BLK_S_OK);
+
+		// The chain is returned with everything it wrote
+		assert_eq!(memory.read::<u16>(DEVICE + 2).unwrap(), 1);
+		assert_eq!(memory.read::<u32>(DEVICE + 4).unwrap(), 0);
+		assert_eq!(memory.read::<u32>(DEVICE + 8).unwrap(), 513);
+		assert!(block.interrupt_pending());
+		assert!(!block.notified());
+
+		block.write(INTERRUPT_ACK_OFFSET, 1_u32).unwrap();
+		assert!(!block.interrupt_pending());
+	}
+
+	#[test]
+	fn queue_addresses_that_overflow_are_malformed()
+	{
+		let mut block = VirtioBlock::new(Cursor::new(vec![0; 512])).unwrap();
+		let mut memory = Everywhere::default();
+		memory.write(DRIVER + 2, 1_u16).unwrap();
+
+		// The descriptor table's first entry is at the very top of the
+		// address space, so its later fields wrap round to zero
+		block.write(QUEUE_NUM_OFFSET, 8_u32).unwrap();
+		block.write(QUEUE_DESC_LOW_OFFSET, 0xffff_fff8_u32).unwrap();
+		block.write(QUEUE_DESC_HIGH_OFFSET, 0xffff_ffff_u32).unwrap();
+		block.write(QUEUE_DRIVER_LOW_OFFSET, DRIVER as u32).unwrap();
+		block.write(QUEUE_DEVICE_LOW_OFFSET, DEVICE as u32).unwrap();
+		block.write(QUEUE_READY_OFFSET, 1_u32).unwrap();
+		block.write(STATUS_OFFSET, STATUS_DRIVER_OK).unwrap();
+		block.write(QUEUE_NOTIFY_OFFSET, 0_u32).unwrap();
+
+		let error = block.process_requests(&mut memory).unwrap_err();
+		assert!(error.to_string().contains("overflows"));
+		let status = block.read::<u32>(STATUS_OFFSET).unwrap();
+		assert_ne!(status & STATUS_DEVICE_NEEDS_RESET, 0);
+	}
+}